@@ -0,0 +1,58 @@
+use std::hint::black_box;
+use std::num::NonZeroUsize;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use payment_engine::{PaymentEngine, ProcessOptions};
+
+const RECORDS: u32 = 100_000;
+const CLIENTS: u32 = 1_000;
+
+/// Deposit-heavy input, with a withdrawal every tenth record.
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=RECORDS {
+        let kind = if tx % 10 == 0 {
+            "withdrawal"
+        } else {
+            "deposit"
+        };
+        let client = tx % CLIENTS;
+        input.push_str(&format!("{kind},{client},{tx},1.2345\n"));
+    }
+    input
+}
+
+fn bench_process(c: &mut Criterion) {
+    let input = input();
+    let mut group = c.benchmark_group("deposits and withdrawals");
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    group.bench_function("default", |b| {
+        b.iter(|| {
+            let mut engine = PaymentEngine::new();
+            engine.process(black_box(input.as_bytes())).unwrap();
+            engine
+        })
+    });
+    group.bench_function("no disputes", |b| {
+        b.iter(|| {
+            let options = ProcessOptions::new().disputes_present(false);
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(black_box(input.as_bytes())).unwrap();
+            engine
+        })
+    });
+    group.bench_function("4 workers", |b| {
+        b.iter(|| {
+            let mut engine = PaymentEngine::new();
+            let workers = NonZeroUsize::new(4).unwrap();
+            engine
+                .process_parallel(black_box(input.as_bytes()), workers)
+                .unwrap();
+            engine
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_process);
+criterion_main!(benches);
@@ -0,0 +1,171 @@
+use std::fmt;
+use std::io::Write;
+
+use crate::ProcessError;
+use crate::domain::{Account, Amount, ClientID, Currency, Record, format_amount};
+use crate::store::AccountStore;
+use crate::wal::fields;
+
+const HEADER: [&str; 14] = [
+    "row",
+    "type",
+    "tx",
+    "amount",
+    "client",
+    "currency",
+    "available_before",
+    "held_before",
+    "total_before",
+    "available_after",
+    "held_after",
+    "total_after",
+    "locked",
+    "closed",
+];
+
+/// Changes the applied records made to the accounts, written out in CSV format,
+/// see [`PaymentEngine::write_audit_to`](crate::PaymentEngine::write_audit_to).
+pub(crate) struct AuditLog {
+    writer: csv::Writer<Box<dyn Write + Send>>,
+    places: u32,
+}
+
+/// Part of an account the audit log keeps track of.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Balances {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    closed: bool,
+}
+
+impl From<&Account> for Balances {
+    fn from(account: &Account) -> Self {
+        Balances {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+            closed: account.closed,
+        }
+    }
+}
+
+/// Record about to be applied (or an administrative operation about to be
+/// carried out), along with the balances of the accounts it may change, see
+/// [`AuditLog::before`].
+pub(crate) struct Pending {
+    fields: [String; crate::wal::HEADER.len()],
+    before: Vec<(ClientID, Currency, Balances)>,
+}
+
+impl AuditLog {
+    /// Start writing the changes to the `writer`, with the amounts counted in
+    /// minor units of the given number of `places`.
+    pub(crate) fn new<W>(writer: W, delimiter: u8, places: u32) -> Result<Self, ProcessError>
+    where
+        W: Write + Send + 'static,
+    {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .from_writer(writer);
+        writer
+            .write_record(HEADER)
+            .map_err(ProcessError::Serialize)?;
+        writer.flush()?;
+        Ok(AuditLog { writer, places })
+    }
+
+    /// Take the balances of the `clients`' accounts in the `currency` before
+    /// the `record` is applied.
+    pub(crate) fn before(
+        &self,
+        record: &Record,
+        clients: &[ClientID],
+        currency: Currency,
+        accounts: &dyn AccountStore,
+    ) -> Pending {
+        let before = clients
+            .iter()
+            .map(|&client| (client, currency, balances(accounts, client, currency)))
+            .collect();
+        Pending {
+            fields: fields(record, self.places),
+            before,
+        }
+    }
+
+    /// Take the balances of the `client`'s accounts (in each of the currencies)
+    /// before the administrative operation of the `kind` is carried out, e.g.
+    /// see [`PaymentEngine::reset_account`](crate::PaymentEngine::reset_account).
+    pub(crate) fn before_operation(
+        &self,
+        kind: &str,
+        client: ClientID,
+        accounts: &dyn AccountStore,
+    ) -> Pending {
+        let before = accounts
+            .iter()
+            .filter(|account| account.client == client)
+            .map(|account| (client, account.currency, Balances::from(account)))
+            .collect();
+        Pending {
+            fields: [kind, &client.to_string(), "", "", "", ""].map(String::from),
+            before,
+        }
+    }
+
+    /// Write out the changes the record at the `row` made to the accounts, one
+    /// line per account whose balances have changed.
+    ///
+    /// For an administrative operation, the `row` is the one of the last record
+    /// applied before it.
+    ///
+    /// The lines are flushed right away, for the log to be complete up to the
+    /// last record applied even if the processing fails later on.
+    pub(crate) fn write(
+        &mut self,
+        row: u64,
+        pending: Pending,
+        accounts: &dyn AccountStore,
+    ) -> Result<(), ProcessError> {
+        let [kind, _, tx, amount, _, _] = &pending.fields;
+        for (client, currency, before) in pending.before {
+            let after = balances(accounts, client, currency);
+            if after == before {
+                continue;
+            }
+            let amounts = [before, after].into_iter().flat_map(|balances| {
+                [balances.available, balances.held, balances.total]
+                    .map(|amount| format_amount(amount, self.places))
+            });
+            let line: Vec<String> = [row.to_string(), kind.clone(), tx.clone(), amount.clone()]
+                .into_iter()
+                .chain([client.to_string(), currency.as_str().to_string()])
+                .chain(amounts)
+                .chain([after.locked.to_string(), after.closed.to_string()])
+                .collect();
+            self.writer
+                .write_record(&line)
+                .map_err(ProcessError::Serialize)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Balances of the `client`'s account in the `currency`, all zero if there is none.
+fn balances(accounts: &dyn AccountStore, client: ClientID, currency: Currency) -> Balances {
+    accounts
+        .get(client, currency)
+        .map(Balances::from)
+        .unwrap_or_default()
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
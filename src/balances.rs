@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+
+use crate::domain::{Account, Amount, ClientID, Currency};
+use crate::{Discrepancy, ProcessError, ProcessOptions, Rounding};
+
+/// Read the accounts from the `reader` in the output CSV format, i.e. with the
+/// `client`, `available`, `held`, `total` and `locked` columns (in any order),
+/// along with the `currency` one, if any.
+///
+/// The other columns (e.g. `closed`, see [`PaymentEngine::compare_balances`])
+/// are ignored, and the amounts are expected at the [`ProcessOptions::precision`].
+/// An account listed more than once is malformed.
+///
+/// [`PaymentEngine::compare_balances`]: crate::PaymentEngine::compare_balances
+pub(crate) fn read<R>(reader: R, options: &ProcessOptions) -> Result<Vec<Account>, ProcessError>
+where
+    R: Read,
+{
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let malformed = |row, line, record: String, reason: String| ProcessError::Malformed {
+        row,
+        line,
+        record,
+        reason,
+    };
+    let mut columns = [0; 5];
+    for (idx, name) in ["client", "available", "held", "total", "locked"]
+        .into_iter()
+        .enumerate()
+    {
+        columns[idx] = column(name).ok_or_else(|| {
+            let headers = headers.iter().collect::<Vec<_>>().join(",");
+            malformed(0, Some(1), headers, format!("missing column \"{name}\""))
+        })?;
+    }
+    let [client, available, held, total, locked] = columns;
+    let currency = column("currency");
+    let mut accounts = Vec::new();
+    let mut seen = HashSet::new();
+    for (idx, raw) in rdr.records().enumerate() {
+        let raw = raw?;
+        let row = idx as u64 + 1;
+        let line = raw.position().map(|position| position.line());
+        let record = || raw.iter().collect::<Vec<_>>().join(",");
+        let field = |col: usize| raw.get(col).unwrap_or_default();
+        let amount = |col: usize| {
+            let text = field(col);
+            if Amount::exceeds_places(text, options.precision) {
+                let reason = format!("amount \"{text}\" exceeds supported precision");
+                return Err(malformed(row, line, record(), reason));
+            }
+            Amount::parse_at(text, options.precision, Rounding::Truncate)
+                .map_err(|err| malformed(row, line, record(), err.to_string()))
+        };
+        let client: ClientID = field(client).parse().map_err(|_| {
+            let reason = format!("invalid client \"{}\"", field(client));
+            malformed(row, line, record(), reason)
+        })?;
+        let currency = match currency {
+            Some(col) => Currency::new(field(col)).ok_or_else(|| {
+                let reason = format!("invalid currency \"{}\"", field(col));
+                malformed(row, line, record(), reason)
+            })?,
+            None => Currency::default(),
+        };
+        if !seen.insert((client, currency)) {
+            let reason = if currency.is_implicit() {
+                format!("duplicate account of client {client}")
+            } else {
+                format!("duplicate account of client {client} in {currency}")
+            };
+            return Err(malformed(row, line, record(), reason));
+        }
+        accounts.push(Account {
+            available: amount(available)?,
+            held: amount(held)?,
+            total: amount(total)?,
+            locked: field(locked).parse().map_err(|_| {
+                let reason = format!("invalid locked flag \"{}\"", field(locked));
+                malformed(row, line, record(), reason)
+            })?,
+            ..Account::new(client, currency)
+        });
+    }
+    Ok(accounts)
+}
+
+/// Expected and actual account of a client in a currency, if any.
+type Pair<'a> = (Option<&'a Account>, Option<&'a Account>);
+
+/// Differences of the `actual` accounts from the `expected` ones, by client
+/// and currency.
+pub(crate) fn compare<'a>(
+    expected: impl IntoIterator<Item = &'a Account>,
+    actual: impl IntoIterator<Item = &'a Account>,
+) -> Vec<Discrepancy> {
+    let mut pairs: BTreeMap<(ClientID, Currency), Pair> = BTreeMap::new();
+    for account in expected {
+        pairs
+            .entry((account.client, account.currency))
+            .or_default()
+            .0 = Some(account);
+    }
+    for account in actual {
+        pairs
+            .entry((account.client, account.currency))
+            .or_default()
+            .1 = Some(account);
+    }
+    let mut discrepancies = Vec::new();
+    for ((client, currency), pair) in pairs {
+        let (expected, actual) = match pair {
+            (Some(expected), Some(actual)) => (expected, actual),
+            (Some(_), None) => {
+                discrepancies.push(Discrepancy::Missing { client, currency });
+                continue;
+            }
+            (None, Some(_)) => {
+                discrepancies.push(Discrepancy::Unexpected { client, currency });
+                continue;
+            }
+            (None, None) => continue,
+        };
+        let funds = [
+            ("available", expected.available, actual.available),
+            ("held", expected.held, actual.held),
+            ("total", expected.total, actual.total),
+        ];
+        for (funds, expected, actual) in funds {
+            if expected != actual {
+                discrepancies.push(Discrepancy::Funds {
+                    client,
+                    currency,
+                    funds,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        if expected.locked != actual.locked {
+            discrepancies.push(Discrepancy::Locked {
+                client,
+                currency,
+                expected: expected.locked,
+            });
+        }
+    }
+    discrepancies
+}
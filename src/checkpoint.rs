@@ -0,0 +1,159 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::{CheckpointPolicy, ProcessError};
+
+/// Position in the input a checkpoint has been taken at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Progress {
+    /// Byte offset the first record starts at, i.e. the length of the headers.
+    pub(crate) header_end: u64,
+
+    /// Byte offset the first record not yet processed starts at.
+    pub(crate) offset: u64,
+
+    /// Number of records processed.
+    pub(crate) rows: u64,
+}
+
+/// Tracker of when to take the next checkpoint, see
+/// [`PaymentEngine::process_checkpointed`](crate::PaymentEngine::process_checkpointed).
+#[derive(Debug)]
+pub(crate) struct Checkpointer {
+    pub(crate) path: PathBuf,
+    policy: CheckpointPolicy,
+
+    /// Bytes of the input skipped when resuming, for the offsets in the input
+    /// being read to be translated back to the offsets in the original input.
+    pub(crate) skipped: u64,
+
+    /// Records processed since the last checkpoint.
+    records: usize,
+
+    /// When the last checkpoint was taken.
+    taken: Instant,
+}
+
+impl Checkpointer {
+    pub(crate) fn new(path: PathBuf, policy: CheckpointPolicy, skipped: u64) -> Self {
+        Checkpointer {
+            path,
+            policy,
+            skipped,
+            records: 0,
+            taken: Instant::now(),
+        }
+    }
+
+    /// Count a processed record, telling whether a checkpoint is due.
+    pub(crate) fn due(&mut self) -> bool {
+        self.records += 1;
+        match self.policy {
+            CheckpointPolicy::Never => false,
+            CheckpointPolicy::Every(records) => self.records >= records,
+            CheckpointPolicy::Interval(interval) => self.taken.elapsed() >= interval,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.records = 0;
+        self.taken = Instant::now();
+    }
+}
+
+/// Write the checkpoint to the `path`, with the `snapshot` callback writing the
+/// state of the engine after the progress line, see [`replace`].
+pub(crate) fn write<F>(path: &Path, progress: Progress, snapshot: F) -> Result<(), ProcessError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), ProcessError>,
+{
+    replace(path, |file| {
+        writeln!(
+            file,
+            "checkpoint,{},{},{}",
+            progress.header_end, progress.offset, progress.rows
+        )?;
+        snapshot(file)
+    })
+}
+
+/// Replace the file at the `path` with what the `write` callback writes.
+///
+/// The contents are written to a temporary file next to the `path` first, and
+/// then moved over, so that a crash never leaves a partial file behind. The
+/// temporary file is named uniquely, for concurrent replacements of the same
+/// file to not write over each other, and the directory is synced after the
+/// move, for the new file to survive a crash too.
+pub(crate) fn replace<F>(path: &Path, write: F) -> Result<(), ProcessError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), ProcessError>,
+{
+    static REPLACEMENTS: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = OsString::from(path);
+    tmp.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        REPLACEMENTS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = (|| {
+        let mut file = BufWriter::new(File::create_new(&tmp)?);
+        write(&mut file)?;
+        let file = file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, path)?;
+        sync_dir(path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Sync the directory of the file at the `path`, for its entry to be durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), ProcessError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(File::open(dir)?.sync_all()?)
+}
+
+/// Directories cannot be opened (and so synced) on other platforms, where the
+/// move is left to the file system to persist.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), ProcessError> {
+    Ok(())
+}
+
+/// Read the checkpoint at the `path`, returning the progress along with the
+/// reader of the snapshot that follows, see [`write`].
+pub(crate) fn read(path: &Path) -> Result<(Progress, impl Read), ProcessError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let invalid = || ProcessError::InvalidSnapshot { row: 0 };
+    let mut fields = line.trim_end().split(',');
+    if fields.next() != Some("checkpoint") {
+        return Err(invalid());
+    }
+    let mut next = || -> Result<u64, ProcessError> {
+        fields
+            .next()
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let progress = Progress {
+        header_end: next()?,
+        offset: next()?,
+        rows: next()?,
+    };
+    if progress.offset < progress.header_end {
+        return Err(invalid());
+    }
+    Ok((progress, reader))
+}
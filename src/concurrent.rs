@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::domain::{
+    Account, ClientID, Currency, Record, RecordInner, TxnID, TxnRecord, TxnRecordKind,
+};
+use crate::{EventObserver, MergeError, PaymentEngine, ProcessError, ProcessOptions, TxnEvent};
+
+/// Engine applying the records of different clients in parallel, e.g. for the
+/// request handlers of a server to share.
+///
+/// The clients are spread over a fixed number of shards, each being a [`PaymentEngine`]
+/// behind a mutex of its own (the engine not being [`Sync`], with its stores
+/// and observers), so that the records of a client are applied one at
+/// a time, in the order they are submitted, while the records of the clients
+/// of other shards are applied in parallel.
+///
+/// The shards share an index of the transactions, for the engine to behave
+/// as a single one: a transaction identifier taken by a client of another shard
+/// is recognized as a duplicate, and a dispute resolution record is applied by
+/// the shard of the transaction it references, no matter its client. A transfer
+/// to (or a dispute of a transfer from) a client of another shard locks both
+/// shards, the lower first, and is applied with the recipient's account lent
+/// to the sender's shard for the while, so that the accounts of a client are
+/// all in its own shard. Note that a transaction identifier is taken by the
+/// first record creating it, even if the record is skipped (e.g. a withdrawal
+/// due to insufficient funds).
+#[derive(Debug)]
+pub struct ConcurrentEngine {
+    options: ProcessOptions,
+    shards: Box<[Mutex<PaymentEngine>]>,
+
+    /// Owners of the transactions created by the records applied so far.
+    index: Mutex<HashMap<TxnID, Owner>>,
+
+    /// Number of the records submitted so far, see [`TxnEvent::row`].
+    rows: AtomicU64,
+}
+
+/// Shard of a transaction, along with the recipient's account if a transfer,
+/// see [`ConcurrentEngine`].
+#[derive(Debug, Clone, Copy)]
+struct Owner {
+    shard: usize,
+    recipient: Option<(ClientID, Currency)>,
+}
+
+impl From<PaymentEngine> for ConcurrentEngine {
+    /// A single shard engine, e.g. to serve an engine [restored](PaymentEngine::restore)
+    /// from a snapshot.
+    fn from(engine: PaymentEngine) -> Self {
+        // the transactions of the only shard need no index
+        Self {
+            options: engine.options().clone(),
+            rows: AtomicU64::new(engine.report().records),
+            shards: Box::new([Mutex::new(engine)]),
+            index: Mutex::default(),
+        }
+    }
+}
+
+impl ConcurrentEngine {
+    /// Create an engine of the given number of `shards`, each with the `options`.
+    pub fn new(shards: NonZeroUsize, options: ProcessOptions) -> Self {
+        Self {
+            shards: (0..shards.get())
+                .map(|_| Mutex::new(PaymentEngine::with_options(options.clone())))
+                .collect(),
+            options,
+            index: Mutex::default(),
+            rows: AtomicU64::new(0),
+        }
+    }
+
+    /// Have the `observer` called back with what becomes of each record applied
+    /// from now on, see [`PaymentEngine::add_observer`].
+    ///
+    /// Each shard calls its own clone of the `observer`, and so the events of
+    /// the clients of different shards may come in parallel.
+    pub fn add_observer<O>(&mut self, observer: O)
+    where
+        O: EventObserver + Clone + 'static,
+    {
+        for shard in &mut self.shards {
+            shard
+                .get_mut()
+                .expect("shard not poisoned")
+                .add_observer(observer.clone());
+        }
+    }
+
+    /// The options the engine was created with.
+    #[cfg(feature = "server")]
+    pub(crate) fn options(&self) -> &ProcessOptions {
+        &self.options
+    }
+
+    fn shard(&self, client: ClientID) -> &Mutex<PaymentEngine> {
+        &self.shards[self.shard_of(client)]
+    }
+
+    fn shard_of(&self, client: ClientID) -> usize {
+        client as usize % self.shards.len()
+    }
+
+    fn index(&self) -> MutexGuard<'_, HashMap<TxnID, Owner>> {
+        // each owner is indexed in one go, and so the index is consistent
+        // even if poisoned by a panic
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock(shard: &Mutex<PaymentEngine>) -> MutexGuard<'_, PaymentEngine> {
+        shard.lock().expect("shard not poisoned")
+    }
+
+    /// Apply a single record, telling what became of it, see [`PaymentEngine::apply`].
+    pub fn apply(&self, record: Record) -> Result<TxnEvent, ProcessError> {
+        self.apply_at(None, record)
+    }
+
+    /// Process the records contained in the `reader`, calling `on_event` with what
+    /// became of each of them, see [`PaymentEngine::process_with_events`].
+    ///
+    /// The records are read on the current thread, and applied one at a time,
+    /// each holding the lock of its client's shard only. Note that the limit of
+    /// [`ProcessOptions::max_records`] applies to each input on its own.
+    pub fn process_with_events<R, F>(&self, reader: R, mut on_event: F) -> Result<(), ProcessError>
+    where
+        R: Read,
+        F: FnMut(TxnEvent),
+    {
+        self.parse(reader, |line, record| {
+            on_event(self.apply_at(line, record)?);
+            Ok(())
+        })
+    }
+
+    /// Call `visit` with each record contained in the `reader` along with its
+    /// line, for the caller to [apply](ConcurrentEngine::apply_at) it or not.
+    pub(crate) fn parse<R, F>(&self, reader: R, mut visit: F) -> Result<(), ProcessError>
+    where
+        R: Read,
+        F: FnMut(Option<u64>, Record) -> Result<(), ProcessError>,
+    {
+        let mut parser = PaymentEngine::with_options(self.options.clone());
+        parser.parse(reader, |_, line, record| visit(line, record))
+    }
+
+    /// Apply a single record read at the `line` of an input, see [`ConcurrentEngine::parse`].
+    pub(crate) fn apply_at(
+        &self,
+        line: Option<u64>,
+        record: Record,
+    ) -> Result<TxnEvent, ProcessError> {
+        let row = self.rows.fetch_add(1, Ordering::Relaxed) + 1;
+        let own = self.shard_of(record.client());
+        // the shard to apply the record, whether the transaction identifier
+        // is taken by another one, and the recipient's account (if any)
+        let (shard, taken, recipient) = match (&record.inner, record.tx()) {
+            (RecordInner::TxnRecord(r), Some(tx)) => {
+                let recipient = (r.to_client)
+                    .filter(|_| r.kind == TxnRecordKind::Transfer)
+                    .map(|to_client| (to_client, r.currency));
+                let owner = *self.index().entry(tx).or_insert(Owner {
+                    shard: own,
+                    recipient,
+                });
+                (own, owner.shard != own, recipient)
+            }
+            (RecordInner::DisputeRecord(_), Some(tx)) => match self.index().get(&tx) {
+                Some(owner) => (owner.shard, false, owner.recipient),
+                None => (own, false, None),
+            },
+            _ => (own, false, None),
+        };
+        let lent = recipient.filter(|(client, _)| self.shard_of(*client) != shard);
+        let Some((client, currency)) = lent else {
+            let mut engine = Self::lock(&self.shards[shard]);
+            if taken && let Some(tx) = record.tx() {
+                engine.reserve_taken(tx);
+            }
+            return engine.apply_at(row, line, record);
+        };
+        let other = self.shard_of(client);
+        let (first, second) = (shard.min(other), shard.max(other));
+        let first = Self::lock(&self.shards[first]);
+        let second = Self::lock(&self.shards[second]);
+        let (mut engine, mut lender) = if shard < other {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        if taken && let Some(tx) = record.tx() {
+            engine.reserve_taken(tx);
+        }
+        if let Some(account) = lender.remove_account(client, currency) {
+            engine.insert_account(account);
+        }
+        let event = engine.apply_at(row, line, record);
+        // the account is given back even if the record failed
+        if let Some(account) = engine.remove_account(client, currency) {
+            lender.insert_account(account);
+        }
+        event
+    }
+
+    /// Same as [`ConcurrentEngine::process_with_events`], but without the events.
+    pub fn process<R>(&self, reader: R) -> Result<(), ProcessError>
+    where
+        R: Read,
+    {
+        self.process_with_events(reader, drop)
+    }
+
+    /// The `client`'s accounts in all the currencies, ordered by currency, see
+    /// [`PaymentEngine::balances`].
+    ///
+    /// Fails if the funds of an account combined across the shards would overflow.
+    pub fn balances(&self, client: ClientID) -> Result<Vec<Account>, MergeError> {
+        // the lock (if any) is the one of the client's own shard, which goes first
+        let own = self.shard(client);
+        let shards = std::iter::once(own).chain(
+            self.shards
+                .iter()
+                .filter(|shard| !std::ptr::eq(*shard, own)),
+        );
+        let mut accounts: Vec<Account> = Vec::new();
+        for shard in shards {
+            for account in Self::lock(shard).balances(client) {
+                match accounts
+                    .iter_mut()
+                    .find(|existing| existing.currency == account.currency)
+                {
+                    Some(existing) => {
+                        *existing = existing
+                            .combined(account)
+                            .map_err(|_| MergeError::Overflow(client))?;
+                    }
+                    None => accounts.push(account.clone()),
+                }
+            }
+        }
+        accounts.sort_unstable_by_key(|account| account.currency);
+        Ok(accounts)
+    }
+
+    /// The `client`'s account in the `currency`, if any, see [`ConcurrentEngine::balances`].
+    pub fn account(
+        &self,
+        client: ClientID,
+        currency: Currency,
+    ) -> Result<Option<Account>, MergeError> {
+        let accounts = self.balances(client)?;
+        Ok(accounts
+            .into_iter()
+            .find(|account| account.currency == currency))
+    }
+
+    /// All the accounts, ordered by client and currency, see [`ConcurrentEngine::balances`].
+    pub fn accounts(&self) -> Result<Vec<Account>, MergeError> {
+        let mut clients: Vec<ClientID> = Vec::new();
+        for shard in &self.shards {
+            clients.extend(Self::lock(shard).accounts().map(|account| account.client));
+        }
+        clients.sort_unstable();
+        clients.dedup();
+        let mut accounts = Vec::with_capacity(clients.len());
+        for client in clients {
+            accounts.extend(self.balances(client)?);
+        }
+        Ok(accounts)
+    }
+
+    /// The transaction `tx` along with its dispute state, if retained, see
+    /// [`PaymentEngine::transaction`].
+    pub fn transaction(&self, tx: TxnID) -> Result<Option<TxnRecord>, ProcessError> {
+        let shard = match self.index().get(&tx) {
+            Some(owner) => owner.shard,
+            // the transactions of an engine served as is are not indexed
+            None if self.shards.len() == 1 => 0,
+            None => return Ok(None),
+        };
+        Self::lock(&self.shards[shard]).transaction(tx)
+    }
+
+    /// Lift the lock of the `client`'s account in the `currency`, returning
+    /// whether it was locked, see [`PaymentEngine::unlock`].
+    pub fn unlock(&self, client: ClientID, currency: Currency) -> Result<bool, ProcessError> {
+        Self::lock(self.shard(client)).unlock(client, currency)
+    }
+
+    /// Combine the shards into a single engine, e.g. to write the accounts out
+    /// or take a snapshot, see [`PaymentEngine::merge`].
+    pub fn into_engine(self) -> Result<PaymentEngine, MergeError> {
+        let mut shards = self
+            .shards
+            .into_iter()
+            .map(|shard| shard.into_inner().expect("shard not poisoned"));
+        let mut engine = shards.next().expect("at least one shard");
+        for shard in shards {
+            engine.merge(shard)?;
+        }
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::ConcurrentEngine;
+    use crate::{Currency, PaymentEngine, ProcessOptions, SkipReason, TxnOutcome};
+
+    #[test]
+    fn applies_clients_in_parallel() {
+        let shards = NonZeroUsize::new(4).unwrap();
+        let engine = ConcurrentEngine::new(shards, ProcessOptions::new());
+        std::thread::scope(|scope| {
+            for client in 1..=8u32 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    let mut input = String::from("type,client,tx,amount\n");
+                    for i in 0..100 {
+                        let tx = client * 1000 + i;
+                        input.push_str(&format!("deposit,{client},{tx},1.0\n"));
+                    }
+                    input.push_str(&format!(
+                        "withdrawal,{client},{},0.5\n",
+                        client * 1000 + 100
+                    ));
+                    input.push_str(&format!("dispute,{client},{},\n", client * 1000));
+                    engine.process(input.as_bytes()).unwrap();
+                });
+            }
+        });
+        let accounts = engine.accounts().unwrap();
+        assert_eq!(accounts.len(), 8);
+        for account in &accounts {
+            assert_eq!(account.available, 98.5);
+            assert_eq!(account.held, 1.0);
+        }
+        assert!(engine.transaction(1000).unwrap().is_some());
+
+        let mut events = Vec::new();
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\nchargeback,1,1000,\n";
+        engine
+            .process_with_events(input.as_bytes(), |event| events.push(event))
+            .unwrap();
+        let rows: Vec<_> = events
+            .iter()
+            .map(|event| (event.row, event.outcome))
+            .collect();
+        assert_eq!(
+            rows,
+            [(817, TxnOutcome::Applied), (818, TxnOutcome::Applied)]
+        );
+        assert!(engine.unlock(1, Currency::default()).unwrap());
+
+        let merged = engine.into_engine().unwrap();
+        assert_eq!(merged.accounts().count(), 8);
+        assert_eq!(merged.account(1).unwrap().available, 99.5);
+    }
+
+    #[test]
+    fn combines_transfers_across_shards() {
+        let options = ProcessOptions::new();
+        let engine = ConcurrentEngine::new(NonZeroUsize::new(2).unwrap(), options);
+        let input = [
+            "type,     client, tx, amount, currency, timestamp, to_client",
+            "deposit,  1,      1,  10.0,    ,         ,",
+            "deposit,  2,      2,  1.0,     ,         ,",
+            "transfer, 1,      3,  4.0,     ,         , 2",
+        ]
+        .join("\n");
+        engine.process(input.as_bytes()).unwrap();
+        let account = engine.account(2, Currency::default()).unwrap().unwrap();
+        assert_eq!(account.available, 5.0);
+
+        let mut expected = PaymentEngine::new();
+        expected.process(input.as_bytes()).unwrap();
+        for account in engine.accounts().unwrap() {
+            let other = expected.account(account.client).unwrap();
+            assert_eq!(
+                (account.available, account.total),
+                (other.available, other.total)
+            );
+        }
+    }
+
+    /// Outcomes of the records of the `input` applied by the `engine`.
+    fn outcomes(engine: &ConcurrentEngine, input: &[&str]) -> Vec<TxnOutcome> {
+        let mut outcomes = Vec::new();
+        let input = input.join("\n");
+        engine
+            .process_with_events(input.as_bytes(), |event| outcomes.push(event.outcome))
+            .unwrap();
+        outcomes
+    }
+
+    #[test]
+    fn recognizes_duplicates_across_shards() {
+        let engine = ConcurrentEngine::new(NonZeroUsize::new(2).unwrap(), ProcessOptions::new());
+        let input = [
+            "type,    client, tx, amount",
+            "deposit, 1,      1,  10.0",
+            "deposit, 2,      1,  5.0",
+        ];
+        assert_eq!(
+            outcomes(&engine, &input),
+            [
+                TxnOutcome::Applied,
+                TxnOutcome::Skipped(SkipReason::DuplicateTransaction)
+            ]
+        );
+        // the transaction is looked up in its own shard
+        assert_eq!(engine.transaction(1).unwrap().unwrap().client, 1);
+        assert!(engine.transaction(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_disputes_of_other_clients_across_shards() {
+        let engine = ConcurrentEngine::new(NonZeroUsize::new(2).unwrap(), ProcessOptions::new());
+        let input = [
+            "type,    client, tx, amount",
+            "deposit, 1,      1,  10.0",
+            "dispute, 2,      1,",
+        ];
+        assert_eq!(
+            outcomes(&engine, &input),
+            [
+                TxnOutcome::Applied,
+                TxnOutcome::Skipped(SkipReason::ClientMismatch)
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_to_recipients_of_other_shards() {
+        let engine = ConcurrentEngine::new(NonZeroUsize::new(2).unwrap(), ProcessOptions::new());
+        let input = [
+            "type,       client, tx, amount, to_client",
+            "deposit,    1,      1,  10.0,",
+            "deposit,    2,      2,  1.0,",
+            "dispute,    2,      2,,",
+            "chargeback, 2,      2,,",
+            "transfer,   1,      3,  4.0,    2",
+        ];
+        // the recipient's account is locked in its own shard
+        assert_eq!(
+            outcomes(&engine, &input)[4],
+            TxnOutcome::Skipped(SkipReason::AccountLocked)
+        );
+
+        assert!(engine.unlock(2, Currency::default()).unwrap());
+        let input = [
+            "type,       client, tx, amount, to_client",
+            "transfer,   1,      4,  4.0,    2",
+            "dispute,    1,      4,,",
+            "resolve,    1,      4,,",
+            "withdrawal, 2,      5,  3.0,",
+        ];
+        assert!(
+            outcomes(&engine, &input)
+                .iter()
+                .all(|outcome| *outcome == TxnOutcome::Applied)
+        );
+        let account = engine.account(2, Currency::default()).unwrap().unwrap();
+        assert_eq!(account.available, 1.0);
+        assert_eq!(account.held, 0.0);
+        // the accounts are in the shards of their clients only
+        let shards: Vec<_> = engine
+            .shards
+            .iter()
+            .map(|shard| ConcurrentEngine::lock(shard).accounts().count())
+            .collect();
+        assert_eq!(shards, [1, 1]);
+    }
+}
@@ -0,0 +1,328 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    Amount, DuplicatePolicy, Fee, FeeSchedule, LockedAccountPolicy, NegativeAvailablePolicy,
+    ProcessOptions, Rounding,
+};
+
+/// Options of the engine read from a TOML file (e.g. `payment-engine.toml`),
+/// for the deployments to not have to spell all of them on the command line.
+///
+/// Each of the options is optional, with the [`ProcessOptions`] default applying
+/// to the missing ones, while the unknown ones are an error.
+///
+/// ```
+/// use payment_engine::Config;
+///
+/// let config = Config::parse(
+///     r#"
+///     precision = 2
+///     delimiter = ";"
+///     strict = true
+///     on_duplicate = "reject"
+///     on_locked = "allow_deposits"
+///     rounding = "bankers"
+///
+///     [fees]
+///     withdrawal = { flat = "0.50" }
+///     transfer = { basis_points = 25 }
+///
+///     [server]
+///     listen = "0.0.0.0:8080"
+///     chargeback_webhook = "https://risk.example.com/chargebacks"
+///     "#,
+/// )
+/// .unwrap();
+/// let options = config.options().unwrap();
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Config {
+    /// See [`ProcessOptions::precision`].
+    pub precision: Option<u32>,
+
+    /// Single ASCII character, see [`ProcessOptions::delimiter`].
+    pub delimiter: Option<char>,
+
+    /// See [`ProcessOptions::strict`].
+    pub strict: Option<bool>,
+
+    /// See [`ProcessOptions::allow_unlock`].
+    pub allow_unlock: Option<bool>,
+
+    /// See [`ProcessOptions::lenient`].
+    pub lenient: Option<bool>,
+
+    /// One of `report`, `skip`, or `reject`, see [`ProcessOptions::on_duplicate`].
+    pub on_duplicate: Option<DuplicatePolicy>,
+
+    /// One of `skip_silently`, `skip_with_warning`, `allow_deposits`, or `error`,
+    /// see [`ProcessOptions::on_locked`].
+    pub on_locked: Option<LockedAccountPolicy>,
+
+    /// One of `allow`, `clamp`, or `lock`, see [`ProcessOptions::on_negative_available`].
+    pub on_negative_available: Option<NegativeAvailablePolicy>,
+
+    /// Either `truncate` or `bankers`, see [`ProcessOptions::rounding`].
+    pub rounding: Option<Rounding>,
+
+    /// See [`ProcessOptions::max_dispute_cycles`].
+    pub max_dispute_cycles: Option<u32>,
+
+    /// The `[fees]` table, see [`ProcessOptions::fees`].
+    #[serde(default)]
+    pub fees: FeesConfig,
+
+    /// The `[server]` table.
+    #[serde(default)]
+    pub server: ServerConfig,
+}
+
+/// Fees of the [`Config`], see [`FeeSchedule`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct FeesConfig {
+    pub withdrawal: Option<FeeConfig>,
+    pub transfer: Option<FeeConfig>,
+}
+
+/// Fee of the [`Config`], either `{ flat = "0.5" }` or `{ basis_points = 25 }`,
+/// see [`Fee`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum FeeConfig {
+    /// The decimal amount, read at the configured precision.
+    Flat(String),
+
+    BasisPoints(u32),
+}
+
+/// Server options of the [`Config`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ServerConfig {
+    /// Address to listen on, e.g. `0.0.0.0:8080`.
+    pub listen: Option<String>,
+
+    /// URL to notify of the accounts locked by charge backs, see `Webhook`
+    /// (with the `webhook` feature).
+    pub chargeback_webhook: Option<String>,
+}
+
+impl Config {
+    /// Read the configuration from the TOML file at the `path`, validating it.
+    pub fn load<P>(path: P) -> Result<Self, ConfigError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&text)
+    }
+
+    /// Read the configuration from the TOML `text`, validating it.
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let config: Config =
+            toml::from_str(text).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that the options are within their ranges, e.g. that the delimiter
+    /// is a single ASCII character.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.options().map(drop)
+    }
+
+    /// The [`ProcessOptions`] as configured, with the defaults for the options
+    /// that are not.
+    pub fn options(&self) -> Result<ProcessOptions, ConfigError> {
+        let mut options = ProcessOptions::new();
+        if let Some(places) = self.precision {
+            options = options.precision(places).map_err(|_| {
+                let reason = format!("at most {} places supported", Amount::MAX_PRECISION);
+                invalid("precision", reason)
+            })?;
+        }
+        if let Some(delimiter) = self.delimiter {
+            let delimiter = u8::try_from(delimiter)
+                .ok()
+                .filter(u8::is_ascii)
+                .ok_or_else(|| invalid("delimiter", "a single ASCII character expected"))?;
+            options = options.delimiter(delimiter);
+        }
+        if let Some(strict) = self.strict {
+            options = options.strict(strict);
+        }
+        if let Some(allow_unlock) = self.allow_unlock {
+            options = options.allow_unlock(allow_unlock);
+        }
+        if let Some(lenient) = self.lenient {
+            options = options.lenient(lenient);
+        }
+        if let Some(policy) = self.on_duplicate {
+            options = options.on_duplicate(policy);
+        }
+        if let Some(policy) = self.on_locked {
+            options = options.on_locked(policy);
+        }
+        if let Some(policy) = self.on_negative_available {
+            options = options.on_negative_available(policy);
+        }
+        if let Some(rounding) = self.rounding {
+            options = options.rounding(rounding);
+        }
+        if self.max_dispute_cycles.is_some() {
+            options = options.max_dispute_cycles(self.max_dispute_cycles);
+        }
+        let places = options.precision;
+        let mut fees = FeeSchedule::new();
+        if let Some(fee) = &self.fees.withdrawal {
+            let field = "fees.withdrawal";
+            fees = (fees.withdrawal(fee.fee(field, places)?)).map_err(|err| invalid(field, err))?;
+        }
+        if let Some(fee) = &self.fees.transfer {
+            let field = "fees.transfer";
+            fees = (fees.transfer(fee.fee(field, places)?)).map_err(|err| invalid(field, err))?;
+        }
+        if let Some(listen) = &self.server.listen {
+            let port = listen.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                return Err(invalid("server.listen", "a host and a port expected"));
+            }
+        }
+        if let Some(url) = &self.server.chargeback_webhook
+            && !(url.starts_with("http://") || url.starts_with("https://"))
+        {
+            return Err(invalid("server.chargeback_webhook", "an HTTP URL expected"));
+        }
+        Ok(options.fees(fees))
+    }
+}
+
+impl FeeConfig {
+    /// The [`Fee`] in the minor units of the `places`, with the `field` it has
+    /// been read from to report an invalid one.
+    fn fee(&self, field: &'static str, places: u32) -> Result<Fee, ConfigError> {
+        match self {
+            FeeConfig::Flat(amount) => {
+                // a negative one is rejected by the `FeeSchedule`
+                match Amount::parse_at(amount, places, Rounding::default()) {
+                    Ok(amount) => Ok(Fee::Flat(amount)),
+                    Err(err) => Err(invalid(field, err)),
+                }
+            }
+            FeeConfig::BasisPoints(points) => Ok(Fee::BasisPoints(*points)),
+        }
+    }
+}
+
+/// Error reading the [`Config`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// Failed to read the file.
+    #[error("failed to read \"{}\": {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// The file is not valid TOML, or has unknown or mistyped options, as
+    /// described by the message (e.g. pointing at the offending line).
+    #[error("{0}")]
+    Parse(String),
+
+    /// The option is out of its range.
+    #[error("{field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+fn invalid(field: &'static str, reason: impl std::fmt::Display) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+    use crate::{Amount, Fee, PaymentEngine};
+
+    #[test]
+    fn configures_options() {
+        let config = Config::parse(
+            r#"
+            precision = 2
+            delimiter = ";"
+            on_locked = "allow_deposits"
+
+            [fees]
+            withdrawal = { flat = "0.5" }
+            "#,
+        )
+        .unwrap();
+        let mut engine = PaymentEngine::with_options(config.options().unwrap());
+        engine
+            .process("type;client;tx;amount\ndeposit;1;1;10.0\nwithdrawal;1;2;1.0\n".as_bytes())
+            .unwrap();
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client;available;held;total;locked;fees_collected\n1;8.50;0.00;8.50;false;0.50\n"
+        );
+        assert_eq!(
+            config
+                .fees
+                .withdrawal
+                .unwrap()
+                .fee("fees.withdrawal", 2)
+                .unwrap(),
+            Fee::Flat(Amount::from_minor_units(50))
+        );
+        assert!(Config::parse("").unwrap().options().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let invalid = |text| match Config::parse(text) {
+            Err(ConfigError::Invalid { field, .. }) => Some(field),
+            _ => None,
+        };
+        assert_eq!(invalid("precision = 10"), Some("precision"));
+        assert_eq!(invalid(r#"delimiter = "é""#), Some("delimiter"));
+        let fee = "[fees]\ntransfer = { flat = \"-1\" }";
+        assert_eq!(invalid(fee), Some("fees.transfer"));
+        let listen = "[server]\nlisten = \"localhost\"";
+        assert_eq!(invalid(listen), Some("server.listen"));
+        let webhook = "[server]\nchargeback_webhook = \"risk.example.com\"";
+        assert_eq!(invalid(webhook), Some("server.chargeback_webhook"));
+        assert!(matches!(
+            Config::parse("strict = \"yes\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::parse("verbose = true"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            Config::load("definitely-missing.toml"),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn describes_parse_errors() {
+        let Err(ConfigError::Parse(message)) = Config::parse("verbose = true") else {
+            panic!("unknown option accepted");
+        };
+        assert!(message.contains("verbose"));
+    }
+}
@@ -1,86 +1,641 @@
-use std::{
-    error::Error,
-    ops::{Add, AddAssign, Sub, SubAssign},
-};
-
-// this could be something provided by a command line arg if such a feature
-// is requested, but we in practice this is oftentimes system-wide or well-known
-// parameter and so we hard-code it, which implies that re-build will be needed
-// if we want to adjust it
+use std::{borrow::Cow, fmt, ops::Neg, str::FromStr};
+
+use crate::{AmountError, ProcessError, Rounding};
+
+// this is the precision most of the feeds use, while the others can be
+// catered for with `ProcessOptions::precision`, in which case the amounts
+// are simply counted in other minor units
 const DECIMALS_PRECISION: u32 = 4;
 
+/// Client's _unique_ identifier.
 pub type ClientID = u16;
+
+/// Transaction's _unique_ identifier.
 pub type TxnID = u32;
 
-#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd)]
+/// Point in time a record has been issued at, e.g. the Unix time in seconds
+/// or milliseconds, as long as it is alike across the records.
+pub type Timestamp = u64;
+
+/// Currency code, e.g. `USD`.
+///
+/// Codes up to [`Currency::MAX_LEN`] bytes long are supported, which covers
+/// both ISO 4217 codes and common crypto tickers. The empty code stands for
+/// the implicit currency of the records that do not specify one.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Currency {
+    code: [u8; Currency::MAX_LEN],
+}
+
+impl Currency {
+    pub const MAX_LEN: usize = 8;
+
+    /// Create new [`Currency`] from the `code`.
+    ///
+    /// Will return `None` if the code is longer than [`Currency::MAX_LEN`]
+    /// bytes or contains a NUL character.
+    pub fn new(code: &str) -> Option<Self> {
+        if code.len() > Self::MAX_LEN || code.contains('\0') {
+            return None;
+        }
+        let mut currency = Self::default();
+        currency.code[..code.len()].copy_from_slice(code.as_bytes());
+        Some(currency)
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .code
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(Self::MAX_LEN);
+        std::str::from_utf8(&self.code[..len]).expect("valid UTF-8 by construction")
+    }
+
+    /// Whether this is the implicit currency.
+    pub fn is_implicit(&self) -> bool {
+        self.code[0] == 0
+    }
+}
+
+/// Whether the magnitude `kept` should be rounded up to the nearest even given
+/// the `dropped` digits.
+fn rounds_up(kept: i64, dropped: &str) -> bool {
+    let mut digits = dropped.bytes();
+    match digits.next() {
+        Some(b'6'..=b'9') => true,
+        Some(b'5') => digits.any(|b| b != b'0') || kept % 2 == 1,
+        _ => false,
+    }
+}
+
+/// Sign (whether negative), integer and fraction digits of a decimal number,
+/// see [`split_decimal`].
+type Digits<'a> = (bool, Cow<'a, str>, Cow<'a, str>);
+
+/// Split the decimal number `text` into its sign (whether negative), and its
+/// integer and fraction digits, with the decimal point moved by the exponent
+/// (if any), see [`shift_point`].
+///
+/// Returns `None` if the `text` is not a decimal number, e.g. `inf`.
+fn split_decimal(text: &str, places: u32) -> Option<Result<Digits<'_>, AmountError>> {
+    let (negative, digits) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()),
+        None => (digits, Some(0)),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let is_decimal = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    let exponent = exponent.filter(|_| {
+        integer.len() + fraction.len() > 0 && is_decimal(integer) && is_decimal(fraction)
+    })?;
+    if exponent == 0 {
+        return Some(Ok((negative, integer.into(), fraction.into())));
+    }
+    let shifted = shift_point(integer, fraction, exponent, places);
+    Some(shifted.map(|(integer, fraction)| (negative, integer.into(), fraction.into())))
+}
+
+/// Move the decimal point between the `integer` and the `fraction` digits by
+/// the `exponent`, so that `1.5e2` turns into `150.`.
+///
+/// The zeros put past the decimal point are only as many as can make a
+/// difference at the `places`, while those put before it are bounded by the
+/// range of the amounts.
+fn shift_point(
+    integer: &str,
+    fraction: &str,
+    exponent: i64,
+    places: u32,
+) -> Result<(String, String), AmountError> {
+    let digits = [integer, fraction].concat();
+    let point = (integer.len() as i64).saturating_add(exponent);
+    if point <= 0 {
+        let zeros = point.unsigned_abs().min(places as u64 + 1) as usize;
+        return Ok((String::new(), "0".repeat(zeros) + &digits));
+    }
+    let point = point as u64;
+    if point <= digits.len() as u64 {
+        let (integer, fraction) = digits.split_at(point as usize);
+        return Ok((integer.to_string(), fraction.to_string()));
+    }
+    if digits.bytes().all(|b| b == b'0') {
+        return Ok((digits, String::new()));
+    }
+    let zeros = point - digits.len() as u64;
+    if zeros > i64::MAX.ilog10() as u64 {
+        return Err(AmountError::Overflow);
+    }
+    Ok((digits + &"0".repeat(zeros as usize), String::new()))
+}
+
+/// Format the `amount` counted in minor units of the given number of `places`,
+/// exactly, so that it is parsed back the same.
+pub(crate) fn format_amount(amount: Amount, places: u32) -> String {
+    let units = amount.minor_units();
+    let scale = 10u64.pow(places);
+    let sign = if units < 0 { "-" } else { "" };
+    let abs = units.unsigned_abs();
+    if places == 0 {
+        return format!("{sign}{abs}");
+    }
+    format!(
+        "{sign}{}.{:0width$}",
+        abs / scale,
+        abs % scale,
+        width = places as usize
+    )
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// the minor units are whole in the fixed-point representation, while the
+// decimal one allows for fractions of them, e.g. as a result of a division;
+// either way, the amounts are kept within the range of `i64` minor units
+#[cfg(not(feature = "rust_decimal"))]
+type Units = i64;
+#[cfg(feature = "rust_decimal")]
+type Units = rust_decimal::Decimal;
+
+/// Amount of funds, counted in minor units.
+///
+/// Backed by a fixed-point `i64` by default, or by `rust_decimal::Decimal`
+/// with the `rust_decimal` feature enabled, which allows for exact division
+/// and multiplication by arbitrary scale decimals, e.g. to apply fees or rates.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Amount {
-    inner: i64,
+    inner: Units,
 }
 
 impl Amount {
+    /// Number of places past the decimal point amounts have by default.
+    pub const PRECISION: u32 = DECIMALS_PRECISION;
+
+    /// Maximum number of places past the decimal point, see [`ProcessOptions::precision`](crate::ProcessOptions::precision).
+    ///
+    /// Since the amounts are counted in minor units of an `i64`, every extra place
+    /// cuts their range tenfold, down to about ±9.2 billion at this precision.
+    pub const MAX_PRECISION: u32 = 9;
+
     /// Create new [`Amount`] from an f64 `value`.
     ///
     /// Internally, will store the `value` as i64 (counting in up to four
     /// places past the decimal in the given float), so that 1.53349999 turns
-    /// into 15334.
+    /// into 15334, see [`Amount::try_from_f64_at`] for other precisions.
     ///
     /// This conversion is fallible, since we are not allowing to create an
-    /// [`Amount`] holding a NaN.
-    pub fn try_from_f64(value: f64) -> Result<Self, Box<dyn Error>> {
-        let amount = (value * 10u32.pow(DECIMALS_PRECISION) as f64).trunc();
+    /// [`Amount`] holding a NaN, an infinity, or a value out of the range of
+    /// what can be stored.
+    pub fn try_from_f64(value: f64) -> Result<Self, ProcessError> {
+        Ok(Self::try_from_f64_at(value, DECIMALS_PRECISION)?)
+    }
+
+    /// Same as [`Amount::try_from_f64`], but counting in minor units of the given
+    /// number of `places` past the decimal point.
+    pub fn try_from_f64_at(value: f64, places: u32) -> Result<Self, AmountError> {
+        if value.is_nan() {
+            return Err(AmountError::NotANumber);
+        }
+        if value.is_infinite() {
+            return Err(AmountError::Infinite);
+        }
+        let amount = (value * 10u64.pow(places) as f64).trunc();
+        // the upper bound itself is not representable, since `i64::MAX`
+        // gets rounded up when converted to a float
+        if amount < i64::MIN as f64 || amount >= i64::MAX as f64 {
+            return Err(AmountError::Overflow);
+        }
         Ok(Self {
-            inner: amount as i64,
+            inner: Units::from(amount as i64),
+        })
+    }
+
+    /// Create new [`Amount`] from the exact number of minor `units`.
+    pub fn from_minor_units(units: i64) -> Self {
+        Self {
+            inner: Units::from(units),
+        }
+    }
+
+    /// Same as [`Amount::try_from_f64`], but also rejects negative values, as
+    /// expected for the amounts of the input transactions.
+    pub fn try_from_input(value: f64) -> Result<Self, ProcessError> {
+        let amount = Self::try_from_f64(value)?;
+        if value < 0. {
+            return Err(AmountError::Negative.into());
+        }
+        Ok(amount)
+    }
+
+    /// Parse the decimal `text` into an [`Amount`], exactly.
+    ///
+    /// Unlike going through [`Amount::try_from_f64`], the sign, the integer and
+    /// the fractional parts are read directly, so that `0.1` and large values
+    /// do not suffer floating point errors. The places beyond the supported
+    /// precision are treated according to the `rounding`. The scientific notation
+    /// (e.g. `1.5e3`) is read exactly as well, while the other ones (e.g. `inf`)
+    /// are left to [`Amount::try_from_f64`] to tell what is wrong with them.
+    pub fn parse(text: &str, rounding: Rounding) -> Result<Self, ProcessError> {
+        Self::parse_at(text, DECIMALS_PRECISION, rounding)
+    }
+
+    /// Same as [`Amount::parse`], but counting in minor units of the given
+    /// number of `places` past the decimal point, so that `1.5` turns into `150`
+    /// for two places.
+    pub fn parse_at(text: &str, places: u32, rounding: Rounding) -> Result<Self, ProcessError> {
+        Ok(Self::parse_units(text, places, rounding)?)
+    }
+
+    fn parse_units(text: &str, places: u32, rounding: Rounding) -> Result<Self, AmountError> {
+        let text = text.trim();
+        let Some(split) = split_decimal(text, places) else {
+            let value = text.parse().map_err(|_| AmountError::Malformed)?;
+            return Self::try_from_f64_at(value, places);
+        };
+        let (negative, integer, fraction) = split?;
+        let precision = places as usize;
+        let (kept, dropped) = fraction.split_at(fraction.len().min(precision));
+        let mut units = integer
+            .bytes()
+            .chain(kept.bytes())
+            .chain(std::iter::repeat_n(b'0', precision - kept.len()))
+            .try_fold(0i64, |acc, b| {
+                acc.checked_mul(10)?.checked_add((b - b'0') as i64)
+            })
+            .ok_or(AmountError::Overflow)?;
+        if rounding == Rounding::Bankers && rounds_up(units, dropped) {
+            units = units.checked_add(1).ok_or(AmountError::Overflow)?;
+        }
+        Ok(Self::from_minor_units(if negative {
+            -units
+        } else {
+            units
+        }))
+    }
+
+    /// Whether parsing `text` into an [`Amount`] would discard significant
+    /// places past the decimal point.
+    ///
+    /// Trailing zeros are not significant, so that `5.00009999` exceeds the
+    /// precision, while `5.00000` does not.
+    pub fn exceeds_precision(text: &str) -> bool {
+        Self::exceeds_places(text, DECIMALS_PRECISION)
+    }
+
+    /// Same as [`Amount::exceeds_precision`], but for the given number of `places`.
+    pub fn exceeds_places(text: &str, places: u32) -> bool {
+        // the text that is not read exactly, or is out of range, is rejected
+        // by the parsing itself
+        split_decimal(text.trim(), places).is_some_and(|split| {
+            split.is_ok_and(|(_, _, fraction)| {
+                fraction.trim_end_matches('0').len() > places as usize
+            })
         })
     }
 
+    /// Format the amount counted in minor units of the given number of `places`,
+    /// exactly, e.g. `1.50` for 150 cents, see [`Amount::parse_at`] for the reverse.
+    pub fn format_at(&self, places: u32) -> String {
+        format_amount(*self, places)
+    }
+
     pub fn as_f64(&self) -> f64 {
-        self.inner as f64 / 10u32.pow(DECIMALS_PRECISION) as f64
+        self.as_f64_at(DECIMALS_PRECISION)
+    }
+
+    /// Same as [`Amount::as_f64`], but for the amount counted in minor units
+    /// of the given number of `places`.
+    pub fn as_f64_at(&self, places: u32) -> f64 {
+        #[cfg(not(feature = "rust_decimal"))]
+        let units = self.inner as f64;
+        #[cfg(feature = "rust_decimal")]
+        let units = rust_decimal::prelude::ToPrimitive::to_f64(&self.inner).unwrap_or_default();
+        units / 10u64.pow(places) as f64
+    }
+
+    /// The exact amount in minor units, i.e. `1.5` becomes `15000`.
+    ///
+    /// With the `rust_decimal` feature, the fractions of a minor unit (if any)
+    /// are truncated.
+    pub fn minor_units(&self) -> i64 {
+        #[cfg(not(feature = "rust_decimal"))]
+        return self.inner;
+        #[cfg(feature = "rust_decimal")]
+        return i64::try_from(self.inner.trunc()).expect("within the range of i64");
+    }
+
+    /// Same as [`Amount::minor_units`], but with the fractions of a minor unit
+    /// (if any) treated according to the `rounding`, so that `1.00015` becomes
+    /// `10002` for [`Rounding::Bankers`].
+    pub fn round_minor_units(&self, rounding: Rounding) -> i64 {
+        #[cfg(not(feature = "rust_decimal"))]
+        let _ = rounding;
+        #[cfg(not(feature = "rust_decimal"))]
+        return self.inner;
+        #[cfg(feature = "rust_decimal")]
+        return match rounding {
+            Rounding::Truncate => self.minor_units(),
+            Rounding::Bankers => {
+                i64::try_from(self.inner.round()).expect("within the range of i64")
+            }
+        };
+    }
+
+    /// Whether this amount is less than zero.
+    pub fn is_negative(&self) -> bool {
+        *self < Amount::default()
+    }
+
+    /// The magnitude of this amount, so that `-1.5` becomes `1.5`.
+    ///
+    /// The magnitude of the least amount there is is out of the range, and so
+    /// saturates to the greatest amount, see [`Amount::checked_abs`].
+    pub fn abs(self) -> Self {
+        self.checked_abs().unwrap_or(Self::greatest())
+    }
+
+    /// Whether this amount equals the `value` as if the latter was stored.
+    ///
+    /// The `value` gets truncated the same way as in [`Amount::try_from_f64`],
+    /// so that `5.00009` equals the stored `5.0000`. Non-finite values are never
+    /// equal to an amount.
+    pub fn eq_f64(&self, value: f64) -> bool {
+        value.is_finite() && Amount::try_from_f64(value).is_ok_and(|other| other == *self)
     }
 }
 
-impl Add for Amount {
-    type Output = Self;
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            inner: self.inner + rhs.inner,
+impl Amount {
+    /// Add the amounts, returning `None` on overflow.
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Self::within_range(self.inner.checked_add(rhs.inner)?)
+    }
+
+    /// Subtract the amounts, returning `None` on overflow.
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Self::within_range(self.inner.checked_sub(rhs.inner)?)
+    }
+
+    /// Add the amounts, saturating to the least or the greatest amount there
+    /// is on overflow.
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        self.checked_add(rhs).unwrap_or(if rhs.is_negative() {
+            Self::least()
+        } else {
+            Self::greatest()
+        })
+    }
+
+    /// Subtract the amounts, saturating to the least or the greatest amount
+    /// there is on overflow.
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        self.checked_sub(rhs).unwrap_or(if rhs.is_negative() {
+            Self::greatest()
+        } else {
+            Self::least()
+        })
+    }
+
+    /// Share of the amount in basis points, i.e. hundredths of a percent, e.g.
+    /// a fee, truncated to the minor unit.
+    ///
+    /// Returns `None` on overflow.
+    pub fn checked_basis_points(self, points: u32) -> Option<Self> {
+        let units = i128::from(self.minor_units()) * i128::from(points) / 10_000;
+        i64::try_from(units).ok().map(Amount::from_minor_units)
+    }
+
+    /// The magnitude of the amount, returning `None` on overflow, same as
+    /// [`Amount::checked_neg`].
+    pub fn checked_abs(self) -> Option<Self> {
+        if self.is_negative() {
+            self.checked_neg()
+        } else {
+            Some(self)
+        }
+    }
+
+    /// The greatest amount there is, i.e. [`i64::MAX`] minor units.
+    fn greatest() -> Self {
+        Self::from_minor_units(i64::MAX)
+    }
+
+    /// The least amount there is, i.e. [`i64::MIN`] minor units.
+    fn least() -> Self {
+        Self::from_minor_units(i64::MIN)
+    }
+
+    /// Negate the amount, returning `None` on overflow, i.e. for the least
+    /// amount there is, whose magnitude is out of the range.
+    pub fn checked_neg(self) -> Option<Self> {
+        #[cfg(not(feature = "rust_decimal"))]
+        return Self::within_range(self.inner.checked_neg()?);
+        #[cfg(feature = "rust_decimal")]
+        return Self::within_range(-self.inner);
+    }
+
+    fn within_range(inner: Units) -> Option<Self> {
+        #[cfg(feature = "rust_decimal")]
+        if inner < Units::from(i64::MIN) || inner > Units::from(i64::MAX) {
+            return None;
         }
+        Some(Self { inner })
+    }
+}
+
+#[cfg(not(feature = "rust_decimal"))]
+impl Amount {
+    /// Multiply the amount by the integer `factor`, e.g. to add up a number of
+    /// equal payments.
+    ///
+    /// Returns `None` on overflow.
+    pub fn checked_mul(self, factor: i64) -> Option<Self> {
+        Self::within_range(self.inner.checked_mul(factor)?)
     }
 }
-impl AddAssign for Amount {
-    fn add_assign(&mut self, rhs: Self) {
-        self.inner = self.inner + rhs.inner;
+
+#[cfg(feature = "rust_decimal")]
+impl Amount {
+    /// Create new [`Amount`] from the decimal `value`, keeping all of its places.
+    ///
+    /// Returns `None` if the `value` is out of the range of what can be stored.
+    pub fn from_decimal(value: rust_decimal::Decimal) -> Option<Self> {
+        Self::within_range(value.checked_mul(Self::scale())?)
+    }
+
+    /// The exact amount as a decimal, i.e. `1.5` stays `1.5`.
+    pub fn to_decimal(&self) -> rust_decimal::Decimal {
+        self.inner / Self::scale()
+    }
+
+    /// Multiply the amount by the `factor`, exactly, e.g. to apply a rate, or
+    /// by an integer one.
+    ///
+    /// Returns `None` on overflow.
+    pub fn checked_mul(self, factor: impl Into<rust_decimal::Decimal>) -> Option<Self> {
+        Self::within_range(self.inner.checked_mul(factor.into())?)
+    }
+
+    /// Divide the amount by the `divisor`, keeping the fractions of a minor unit.
+    ///
+    /// Returns `None` on overflow or division by zero.
+    pub fn checked_div(self, divisor: rust_decimal::Decimal) -> Option<Self> {
+        Self::within_range(self.inner.checked_div(divisor)?)
+    }
+
+    fn scale() -> rust_decimal::Decimal {
+        rust_decimal::Decimal::from(10u64.pow(DECIMALS_PRECISION))
+    }
+}
+
+impl PartialEq<f64> for Amount {
+    fn eq(&self, other: &f64) -> bool {
+        self.eq_f64(*other)
     }
 }
-impl Sub for Amount {
+
+/// Saturates to the greatest amount on overflow, same as [`Amount::abs`], see
+/// [`Amount::checked_neg`].
+impl Neg for Amount {
     type Output = Self;
-    fn sub(self, rhs: Self) -> Self::Output {
-        Self {
-            inner: self.inner - rhs.inner,
-        }
+    fn neg(self) -> Self::Output {
+        self.checked_neg().unwrap_or(Self::greatest())
     }
 }
-impl SubAssign for Amount {
-    fn sub_assign(&mut self, rhs: Self) {
-        self.inner = self.inner - rhs.inner;
+
+/// Parses the decimal text the same as [`Amount::parse`] with [`Rounding::Truncate`],
+/// so that `"12.34567".parse()` gives `12.3456`.
+///
+/// This is always at the default [`Amount::PRECISION`], see [`Amount::parse_at`]
+/// for the amounts counted at another one.
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Self::parse_units(text, DECIMALS_PRECISION, Rounding::Truncate)
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Formats the amount with exactly four places past the decimal point,
+/// so that `-1.5` is displayed as `-1.5000`, unless given another precision,
+/// e.g. `{:.2}` for `-1.50`, in which case the extra places are truncated.
+///
+/// With the `rust_decimal` feature, the fractions of a minor unit (if any)
+/// are displayed as well, unless given a precision.
+///
+/// The amount is taken to be counted at the default [`Amount::PRECISION`], see
+/// [`Amount::format_at`] for the amounts counted at another one.
+impl fmt::Display for Amount {
+    #[cfg(feature = "rust_decimal")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut value = self.to_decimal().normalize();
+        match f.precision() {
+            Some(places) => {
+                let places = places as u32;
+                value =
+                    value.round_dp_with_strategy(places, rust_decimal::RoundingStrategy::ToZero);
+                value.rescale(places);
+            }
+            None if value.scale() < DECIMALS_PRECISION => value.rescale(DECIMALS_PRECISION),
+            None => {}
+        }
+        write!(f, "{value}")
+    }
+
+    #[cfg(not(feature = "rust_decimal"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let places = f.precision().unwrap_or(DECIMALS_PRECISION as usize);
+        let abs = self.inner.unsigned_abs();
+        let scale = 10u64.pow(DECIMALS_PRECISION);
+        let fraction = format!(
+            "{:0width$}",
+            abs % scale,
+            width = DECIMALS_PRECISION as usize
+        );
+        let fraction: String = fraction
+            .chars()
+            .chain(std::iter::repeat('0'))
+            .take(places)
+            .collect();
+        // the amount truncated to zero is not negative any longer
+        let negative = self.inner < 0 && (abs >= scale || fraction.bytes().any(|b| b != b'0'));
+        let sign = if negative { "-" } else { "" };
+        write!(f, "{sign}{}", abs / scale)?;
+        if places > 0 {
+            write!(f, ".{fraction}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind of a [`TxnRecord`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum TxnRecordKind {
+    /// Credit of the client's account.
     Deposit,
+
+    /// Debit of the client's account.
     Withdrawal,
+
+    /// Debit of the client's account credited to the [`TxnRecord::to_client`].
+    Transfer,
+}
+
+impl fmt::Display for TxnRecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TxnRecordKind::Deposit => "deposit",
+            TxnRecordKind::Withdrawal => "withdrawal",
+            TxnRecordKind::Transfer => "transfer",
+        })
+    }
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+/// Dispute state of a [`TxnRecord`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum TxnState {
+    /// No part of the transaction is under dispute.
     #[default]
     Undisputed,
+
+    /// The [`TxnRecord::disputed`] part of the transaction is under dispute.
     Disputed,
+
+    /// All of the transaction has been charged back.
     Reversed,
 }
 
-#[derive(Debug, Deserialize)]
+impl fmt::Display for TxnState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TxnState::Undisputed => "undisputed",
+            TxnState::Disputed => "disputed",
+            TxnState::Reversed => "reversed",
+        })
+    }
+}
+
+/// Record creating a transaction, i.e. a deposit, a withdrawal, or a transfer,
+/// see [`Record::deposit`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct TxnRecord {
     #[serde(rename = "type")]
     pub kind: TxnRecordKind,
@@ -89,28 +644,129 @@ pub struct TxnRecord {
     pub client: ClientID,
 
     /// Transaction's _unique_ identifier.
-    #[allow(unused)]
     pub tx: TxnID,
 
     /// Transaction ammount.
+    #[serde(deserialize_with = "utils::deserialize_input_amount")]
     pub amount: Amount,
 
-    /// Wether this transaction is under dispute.
+    /// Transaction currency.
+    #[serde(default, skip_serializing_if = "Currency::is_implicit")]
+    pub currency: Currency,
+
+    /// Client credited with a [`TxnRecordKind::Transfer`].
+    ///
+    /// Expected for transfers only, and to be other than the [`TxnRecord::client`].
+    #[serde(
+        default,
+        deserialize_with = "utils::deserialize_optional_client",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub to_client: Option<ClientID>,
+
+    // the rest is the engine's bookkeeping, not expected in the input, where
+    // it defaults to an undisputed transaction, but preserved when the
+    // transaction gets persisted, see the accessors below
+    #[serde(default)]
+    pub(crate) state: TxnState,
+
+    #[serde(default, skip_serializing_if = "utils::is_zero")]
+    pub(crate) disputed: Amount,
+
+    #[serde(default, skip_serializing_if = "utils::is_zero")]
+    pub(crate) reversed: Amount,
+
+    #[serde(default, skip_serializing_if = "utils::is_zero")]
+    pub(crate) shortfall: Amount,
+
+    #[serde(default, skip_serializing_if = "utils::is_zero_count")]
+    pub(crate) cycles: u32,
+
     #[serde(skip)]
-    pub state: TxnState,
+    pub(crate) timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Deserialize)]
+impl TxnRecord {
+    /// Transaction of the `kind` of the `amount`, in the implicit currency.
+    pub fn new(kind: TxnRecordKind, client: ClientID, tx: TxnID, amount: Amount) -> Self {
+        TxnRecord {
+            kind,
+            client,
+            tx,
+            amount,
+            currency: Currency::default(),
+            to_client: None,
+            state: TxnState::default(),
+            disputed: Amount::default(),
+            reversed: Amount::default(),
+            shortfall: Amount::default(),
+            cycles: 0,
+            timestamp: None,
+        }
+    }
+
+    /// Part of the amount that is neither under dispute, nor charged back.
+    pub fn undisputed(&self) -> Amount {
+        self.amount
+            .saturating_sub(self.disputed)
+            .saturating_sub(self.reversed)
+    }
+
+    /// Wether this transaction is under dispute.
+    pub fn state(&self) -> TxnState {
+        self.state
+    }
+
+    /// Part of the amount currently under dispute, see [`DisputeRecord::amount`].
+    pub fn disputed(&self) -> Amount {
+        self.disputed
+    }
+
+    /// Part of the amount charged back so far.
+    pub fn reversed(&self) -> Amount {
+        self.reversed
+    }
+
+    /// Part of the amount under dispute that could not be held, and so is to be
+    /// settled along with the dispute, see [`Account::hold_covered`].
+    pub fn shortfall(&self) -> Amount {
+        self.shortfall
+    }
+
+    /// Number of times the transaction has been put under dispute, with the
+    /// partial disputes of the same cycle counted once.
+    pub fn cycles(&self) -> u32 {
+        self.cycles
+    }
+
+    /// Timestamp of the record the transaction came in, if any, see
+    /// [`ProcessOptions::dispute_window`](crate::ProcessOptions::dispute_window).
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+}
+
+/// Kind of a [`DisputeRecord`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum DisputeRecordKind {
+    /// Put (a part of) the transaction under dispute, holding the disputed funds.
     Dispute,
+
+    /// Release the funds held for the dispute.
     Resolve,
+
+    /// Reverse the disputed part of the transaction, locking the account.
     ChargeBack,
 }
 
+/// Record referencing a transaction in order to dispute it, or settle the
+/// dispute, see [`Record::dispute`].
 #[derive(Debug, Deserialize)]
+#[non_exhaustive]
 pub struct DisputeRecord {
-    /// Dispite record type.
+    /// Dispute record type.
     #[serde(rename = "type")]
     pub kind: DisputeRecordKind,
 
@@ -119,6 +775,45 @@ pub struct DisputeRecord {
 
     /// Disputed transaction's identifier.
     pub tx: TxnID,
+
+    /// Disputed transaction's currency.
+    #[serde(default)]
+    pub currency: Currency,
+
+    /// Disputed part of the transaction's amount, all of what is left of it by default.
+    ///
+    /// Ignored for the resolutions and charge backs, which settle all of the
+    /// amount under dispute at once.
+    #[serde(skip)]
+    pub amount: Option<Amount>,
+}
+
+/// Kind of an [`AccountRecord`].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum AccountRecordKind {
+    /// Close the account, after which no further activity is applied to it.
+    Close,
+
+    /// Lift the lock of the account, see [`ProcessOptions::allow_unlock`](crate::ProcessOptions::allow_unlock).
+    Unlock,
+}
+
+/// Administrative record on the client's account as a whole, see [`Record::close`].
+#[derive(Debug, Deserialize)]
+#[non_exhaustive]
+pub struct AccountRecord {
+    /// Account operation type.
+    #[serde(rename = "type")]
+    pub kind: AccountRecordKind,
+
+    /// Client's identifier.
+    pub client: ClientID,
+
+    /// Currency of the client's account.
+    #[serde(default)]
+    pub currency: Currency,
 }
 
 /// Operation record.
@@ -126,12 +821,17 @@ pub struct DisputeRecord {
 /// An operation can ether be a transaction one (debit or credit), which is
 /// described as [`TxnRecord`], or a dispute resolution one ([`DisputeRecord`]).
 /// The latter does not contain `amount`, it is rather referencing a transaction,
-/// which - in its turn - always holds the amount in question.
+/// which - in its turn - always holds the amount in question. Finally, there are
+/// administrative operations on the client's account as a whole ([`AccountRecord`]),
+/// which neither hold an amount, nor reference a transaction.
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
+#[allow(clippy::enum_variant_names)]
+#[non_exhaustive]
 pub enum RecordInner {
     TxnRecord(TxnRecord),
     DisputeRecord(DisputeRecord),
+    AccountRecord(AccountRecord),
 }
 
 // an alternative approach would be to keep things flat: make the amount
@@ -143,18 +843,179 @@ pub enum RecordInner {
 //
 // we need a hack here to make serde crate play nicely with the csv crate, see:
 // https://github.com/BurntSushi/rust-csv/issues/357
+/// Record to be applied, see [`RecordInner`].
+///
+/// Besides being read from the input, records can be built and applied one by
+/// one, e.g. when the engine is embedded in another service:
+///
+/// ```
+/// use payment_engine::{Amount, Engine, Record};
+///
+/// let amount = |value| Amount::try_from_f64(value).unwrap();
+/// let mut engine = Engine::new();
+/// engine.apply(Record::deposit(1, 1, amount(10.0))).unwrap();
+/// engine.apply(Record::withdrawal(1, 2, amount(2.5))).unwrap();
+/// engine.apply(Record::dispute(1, 1)).unwrap();
+/// let account = engine.account(1).unwrap();
+/// assert_eq!(account.available, -2.5);
+/// assert_eq!(account.held, 10.0);
+/// ```
 #[derive(Debug, Deserialize)]
 pub struct Record {
     #[serde(flatten)]
     pub inner: RecordInner,
+
+    /// See [`Record::timestamp`].
+    #[serde(skip)]
+    pub(crate) timestamp: Option<Timestamp>,
 }
 
-#[derive(Debug, Serialize)]
+impl Record {
+    /// Deposit of the `amount` to the `client`'s account as the transaction `tx`.
+    pub fn deposit(client: ClientID, tx: TxnID, amount: Amount) -> Self {
+        Self::txn(TxnRecord::new(TxnRecordKind::Deposit, client, tx, amount))
+    }
+
+    /// Withdrawal of the `amount` from the `client`'s account as the transaction `tx`.
+    pub fn withdrawal(client: ClientID, tx: TxnID, amount: Amount) -> Self {
+        Self::txn(TxnRecord::new(
+            TxnRecordKind::Withdrawal,
+            client,
+            tx,
+            amount,
+        ))
+    }
+
+    /// Transfer of the `amount` from the `client`'s account to the one of the
+    /// `to_client` as the transaction `tx`.
+    pub fn transfer(client: ClientID, tx: TxnID, amount: Amount, to_client: ClientID) -> Self {
+        Self::txn(TxnRecord {
+            to_client: Some(to_client),
+            ..TxnRecord::new(TxnRecordKind::Transfer, client, tx, amount)
+        })
+    }
+
+    /// Dispute of all of what is left of the `client`'s transaction `tx`.
+    pub fn dispute(client: ClientID, tx: TxnID) -> Self {
+        Self::settlement(DisputeRecordKind::Dispute, client, tx)
+    }
+
+    /// Resolution of the dispute of the `client`'s transaction `tx`.
+    pub fn resolve(client: ClientID, tx: TxnID) -> Self {
+        Self::settlement(DisputeRecordKind::Resolve, client, tx)
+    }
+
+    /// Charge back of the disputed part of the `client`'s transaction `tx`.
+    pub fn chargeback(client: ClientID, tx: TxnID) -> Self {
+        Self::settlement(DisputeRecordKind::ChargeBack, client, tx)
+    }
+
+    /// Closing of the `client`'s account.
+    pub fn close(client: ClientID) -> Self {
+        Self::account(AccountRecordKind::Close, client)
+    }
+
+    /// Lifting of the lock of the `client`'s account.
+    pub fn unlock(client: ClientID) -> Self {
+        Self::account(AccountRecordKind::Unlock, client)
+    }
+
+    /// The same record in the `currency`.
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        match &mut self.inner {
+            RecordInner::TxnRecord(r) => r.currency = currency,
+            RecordInner::DisputeRecord(r) => r.currency = currency,
+            RecordInner::AccountRecord(r) => r.currency = currency,
+        }
+        self
+    }
+
+    /// The same record issued at the `timestamp`.
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// When this record has been issued, if known.
+    ///
+    /// Read from the optional `timestamp` column, see
+    /// [`ProcessOptions::timestamp_order`](crate::ProcessOptions::timestamp_order).
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    fn txn(record: TxnRecord) -> Self {
+        Record {
+            inner: RecordInner::TxnRecord(record),
+            timestamp: None,
+        }
+    }
+
+    fn settlement(kind: DisputeRecordKind, client: ClientID, tx: TxnID) -> Self {
+        Record {
+            inner: RecordInner::DisputeRecord(DisputeRecord {
+                kind,
+                client,
+                tx,
+                currency: Currency::default(),
+                amount: None,
+            }),
+            timestamp: None,
+        }
+    }
+
+    fn account(kind: AccountRecordKind, client: ClientID) -> Self {
+        Record {
+            inner: RecordInner::AccountRecord(AccountRecord {
+                kind,
+                client,
+                currency: Currency::default(),
+            }),
+            timestamp: None,
+        }
+    }
+
+    /// Client this record is about.
+    pub fn client(&self) -> ClientID {
+        match &self.inner {
+            RecordInner::TxnRecord(r) => r.client,
+            RecordInner::DisputeRecord(r) => r.client,
+            RecordInner::AccountRecord(r) => r.client,
+        }
+    }
+
+    /// Transaction this record creates or references, if any.
+    pub fn tx(&self) -> Option<TxnID> {
+        match &self.inner {
+            RecordInner::TxnRecord(r) => Some(r.tx),
+            RecordInner::DisputeRecord(r) => Some(r.tx),
+            RecordInner::AccountRecord(_) => None,
+        }
+    }
+
+    /// Currency of the account this record is about.
+    pub fn currency(&self) -> Currency {
+        match &self.inner {
+            RecordInner::TxnRecord(r) => r.currency,
+            RecordInner::DisputeRecord(r) => r.currency,
+            RecordInner::AccountRecord(r) => r.currency,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 #[cfg_attr(test, derive(Deserialize))]
+#[non_exhaustive]
 pub struct Account {
     /// Client's _unique_ identifier.
     pub client: ClientID,
 
+    /// Currency of this account.
+    ///
+    /// A client holds a separate account per currency.
+    #[serde(default)]
+    pub currency: Currency,
+
     /// Available funds.
     ///
     /// Total funds available for trading, staking, withdrawal, etc.
@@ -172,23 +1033,69 @@ pub struct Account {
     ///
     /// An account gets locked when a charge back is taking place.
     pub locked: bool,
+
+    /// Whether this account is closed.
+    ///
+    /// Unlike [`Account::locked`], this is a result of an explicit operator
+    /// action, after which no further activity is applied to the account.
+    #[serde(default)]
+    pub closed: bool,
+
+    /// Number of charge backs that took place on this account since it was
+    /// last unlocked, see [`Account::unlock`].
+    #[serde(default)]
+    pub chargebacks: u32,
+
+    /// Total amount of the transactions charged back on this account since it
+    /// was last unlocked, see [`Account::unlock`].
+    #[serde(default)]
+    pub charged_back: Amount,
+
+    /// Total amount deposited to this account, see [`ProcessOptions::stats`](crate::ProcessOptions::stats).
+    #[serde(default)]
+    pub gross_deposits: Amount,
+
+    /// Total amount withdrawn from this account, the failed withdrawals not
+    /// counted, see [`ProcessOptions::stats`](crate::ProcessOptions::stats).
+    #[serde(default)]
+    pub gross_withdrawals: Amount,
+
+    /// Total fees paid from this account, see [`ProcessOptions::fees`](crate::ProcessOptions::fees).
+    #[serde(default)]
+    pub fees: Amount,
+
+    /// Part of the funds under dispute that could not be held, since exceeding
+    /// the available funds, see [`NegativeAvailablePolicy::Clamp`](crate::NegativeAvailablePolicy::Clamp).
+    #[serde(default, skip_serializing_if = "utils::is_zero")]
+    pub shortfall: Amount,
 }
 
 impl Account {
-    pub fn new(client: ClientID) -> Self {
+    pub fn new(client: ClientID, currency: Currency) -> Self {
         Account {
             client,
+            currency,
             available: Amount::default(),
             held: Amount::default(),
             total: Amount::default(),
             locked: false,
+            closed: false,
+            chargebacks: 0,
+            charged_back: Amount::default(),
+            gross_deposits: Amount::default(),
+            gross_withdrawals: Amount::default(),
+            fees: Amount::default(),
+            shortfall: Amount::default(),
         }
     }
 
     /// Credit the client's account.
-    pub fn deposit(&mut self, amount: Amount) {
-        self.available += amount;
-        self.total += amount;
+    pub fn deposit(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let available = add(self.available, amount)?;
+        let total = add(self.total, amount)?;
+        self.available = available;
+        self.total = total;
+        Ok(())
     }
 
     /// Debit the client's account.
@@ -197,60 +1104,657 @@ impl Account {
     /// the operation will return `false` leaving the account intact, otherwise
     /// their [`Account::available`] and [`Account::total`] will be reduced by
     /// the provided `amount`.
-    pub fn withdraw(&mut self, amount: Amount) -> bool {
+    pub fn withdraw(&mut self, amount: Amount) -> Result<bool, AmountError> {
         if self.available < amount {
-            return false;
+            return Ok(false);
         }
-        self.available -= amount;
-        self.total -= amount;
-        true
+        let available = sub(self.available, amount)?;
+        let total = sub(self.total, amount)?;
+        self.available = available;
+        self.total = total;
+        Ok(true)
     }
 
-    pub fn hold(&mut self, amount: Amount) {
-        self.available -= amount;
-        self.held += amount;
+    /// Debit the client's account, considering the held funds usable.
+    ///
+    /// Same as [`Account::withdraw`], but the funds are considered sufficient if
+    /// they are covered by [`Account::available`] plus [`Account::held`], which
+    /// means the available funds can become negative.
+    pub fn withdraw_including_held(&mut self, amount: Amount) -> Result<bool, AmountError> {
+        if add(self.available, self.held)? < amount {
+            return Ok(false);
+        }
+        let available = sub(self.available, amount)?;
+        let total = sub(self.total, amount)?;
+        self.available = available;
+        self.total = total;
+        Ok(true)
     }
 
-    /// Unblock the previously disputed amount.
-    pub fn resolve(&mut self, amount: Amount) {
-        self.held -= amount;
-        self.available += amount;
+    pub fn hold(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let available = sub(self.available, amount)?;
+        let held = add(self.held, amount)?;
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
+
+    /// Block the disputed amount as far as the available funds cover it,
+    /// keeping track of the rest as the [`Account::shortfall`], so that the
+    /// available funds do not go negative.
+    ///
+    /// Returns the part of the amount that could not be held, which is to be
+    /// settled along with the dispute of the transaction it belongs to, see
+    /// [`TxnRecord::shortfall`] and [`Account::resolve_covered`].
+    pub fn hold_covered(&mut self, amount: Amount) -> Result<Amount, AmountError> {
+        let covered = amount.min(self.available.max(Amount::default()));
+        let uncovered = sub(amount, covered)?;
+        let shortfall = add(self.shortfall, uncovered)?;
+        self.hold(covered)?;
+        self.shortfall = shortfall;
+        Ok(uncovered)
     }
 
     /// Unblock the previously disputed amount.
-    pub fn charge_back(&mut self, amount: Amount) {
-        self.held -= amount;
-        self.total -= amount;
+    pub fn resolve(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let held = sub(self.held, amount)?;
+        let available = add(self.available, amount)?;
+        self.held = held;
+        self.available = available;
+        Ok(())
+    }
+
+    /// Unblock the previously disputed amount, of which the `shortfall` could
+    /// not be held, see [`Account::hold_covered`].
+    pub fn resolve_covered(
+        &mut self,
+        amount: Amount,
+        shortfall: Amount,
+    ) -> Result<(), AmountError> {
+        let remaining = sub(self.shortfall, shortfall)?;
+        self.resolve(sub(amount, shortfall)?)?;
+        self.shortfall = remaining;
+        Ok(())
+    }
+
+    /// Reverse the previously disputed amount.
+    pub fn charge_back(&mut self, amount: Amount) -> Result<(), AmountError> {
+        self.charge_back_covered(amount, Amount::default())
+    }
+
+    /// Reverse the previously disputed amount, of which the `shortfall` could
+    /// not be held, see [`Account::hold_covered`].
+    ///
+    /// All of the amount counts as charged back, while only the part of it held
+    /// leaves the account.
+    pub fn charge_back_covered(
+        &mut self,
+        amount: Amount,
+        shortfall: Amount,
+    ) -> Result<(), AmountError> {
+        let charged_back = add(self.charged_back, amount)?;
+        let remaining = sub(self.shortfall, shortfall)?;
+        let held = sub(amount, shortfall)?;
+        let total = sub(self.total, held)?;
+        let held = sub(self.held, held)?;
+        self.held = held;
+        self.total = total;
+        self.shortfall = remaining;
+        self.chargebacks += 1;
+        self.charged_back = charged_back;
+        Ok(())
+    }
+
+    /// Hold the previously withdrawn amount under dispute.
+    ///
+    /// The funds have already left the account, and so it is the held and the
+    /// total funds that go up, while the available ones stay intact.
+    pub fn hold_withdrawal(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let held = add(self.held, amount)?;
+        let total = add(self.total, amount)?;
+        self.held = held;
+        self.total = total;
+        Ok(())
+    }
+
+    /// Let the previously disputed withdrawal stand.
+    pub fn resolve_withdrawal(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let held = sub(self.held, amount)?;
+        let total = sub(self.total, amount)?;
+        self.held = held;
+        self.total = total;
+        Ok(())
+    }
+
+    /// Give up the previously disputed amount, e.g. of a transfer charged back
+    /// to its sender.
+    ///
+    /// Same as [`Account::charge_back`], but without it counting as a charge
+    /// back of this account.
+    pub fn forfeit(&mut self, amount: Amount) -> Result<(), AmountError> {
+        self.forfeit_covered(amount, Amount::default())
+    }
+
+    /// Give up the previously disputed amount, of which the `shortfall` could
+    /// not be held, see [`Account::hold_covered`].
+    pub fn forfeit_covered(
+        &mut self,
+        amount: Amount,
+        shortfall: Amount,
+    ) -> Result<(), AmountError> {
+        let remaining = sub(self.shortfall, shortfall)?;
+        let held = sub(amount, shortfall)?;
+        let total = sub(self.total, held)?;
+        let held = sub(self.held, held)?;
+        self.held = held;
+        self.total = total;
+        self.shortfall = remaining;
+        Ok(())
+    }
+
+    /// Credit the previously disputed transfer back to its sender.
+    pub fn charge_back_transfer(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let available = add(self.available, amount)?;
+        let total = add(self.total, amount)?;
+        let charged_back = add(self.charged_back, amount)?;
+        self.available = available;
+        self.total = total;
+        self.chargebacks += 1;
+        self.charged_back = charged_back;
+        Ok(())
+    }
+
+    /// Credit the previously disputed withdrawal back to the client.
+    pub fn charge_back_withdrawal(&mut self, amount: Amount) -> Result<(), AmountError> {
+        let held = sub(self.held, amount)?;
+        let available = add(self.available, amount)?;
+        let charged_back = add(self.charged_back, amount)?;
+        self.held = held;
+        self.available = available;
+        self.chargebacks += 1;
+        self.charged_back = charged_back;
+        Ok(())
+    }
+
+    /// Combine this account with the `other` one of the same client, adding
+    /// up the funds and the counters.
+    pub(crate) fn combined(&self, other: &Account) -> Result<Account, AmountError> {
+        Ok(Account {
+            available: add(self.available, other.available)?,
+            held: add(self.held, other.held)?,
+            total: add(self.total, other.total)?,
+            chargebacks: self.chargebacks + other.chargebacks,
+            charged_back: add(self.charged_back, other.charged_back)?,
+            gross_deposits: add(self.gross_deposits, other.gross_deposits)?,
+            gross_withdrawals: add(self.gross_withdrawals, other.gross_withdrawals)?,
+            fees: add(self.fees, other.fees)?,
+            shortfall: add(self.shortfall, other.shortfall)?,
+            ..*self
+        })
     }
 
     pub fn lock(&mut self) {
         self.locked = true;
     }
+
+    /// Lift the lock, starting the count of the charge backs over, for the
+    /// ones reviewed not to lock the account again, see
+    /// [`ProcessOptions::lock_on_chargeback`](crate::ProcessOptions::lock_on_chargeback).
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.chargebacks = 0;
+        self.charged_back = Amount::default();
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
+// the account operations are not applied partially on overflow, hence
+// computing all the new balances before updating any of them
+fn add(lhs: Amount, rhs: Amount) -> Result<Amount, AmountError> {
+    lhs.checked_add(rhs).ok_or(AmountError::Overflow)
+}
+
+fn sub(lhs: Amount, rhs: Amount) -> Result<Amount, AmountError> {
+    lhs.checked_sub(rhs).ok_or(AmountError::Overflow)
 }
 
 mod utils {
-    use super::Amount;
-    use serde::de::Error;
+    use super::{Amount, AmountError, ClientID, Currency, DECIMALS_PRECISION, ProcessError};
+    use serde::de::{Error, Visitor};
     use serde::{Deserialize, Deserializer};
     use serde::{Serialize, Serializer};
 
+    /// Accepts both a number and a decimal string, such as the amount is
+    /// serialized to, which is parsed exactly.
     impl<'de> Deserialize<'de> for Amount {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: Deserializer<'de>,
         {
-            let value: f64 = Deserialize::deserialize(deserializer)?;
-            let amount = Self::try_from_f64(value).map_err(|e| Error::custom(e.to_string()))?;
-            Ok(amount)
+            deserializer.deserialize_any(AmountVisitor { input: false })
         }
     }
 
+    pub(super) fn deserialize_input_amount<'de, D>(deserializer: D) -> Result<Amount, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(AmountVisitor { input: true })
+    }
+
+    /// Visitor of an [`Amount`], rejecting the negative ones if an `input` one,
+    /// same as [`Amount::try_from_input`].
+    struct AmountVisitor {
+        input: bool,
+    }
+
+    impl Visitor<'_> for AmountVisitor {
+        type Value = Amount;
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "amount as a number or a decimal string")
+        }
+        fn visit_f64<E: Error>(self, value: f64) -> Result<Self::Value, E> {
+            match self.input {
+                true => Amount::try_from_input(value).map_err(|e| E::custom(e.to_string())),
+                false => Amount::try_from_f64(value).map_err(|e| E::custom(e.to_string())),
+            }
+        }
+        // the integers are read exactly, same as the decimal strings
+        fn visit_i64<E: Error>(self, value: i64) -> Result<Self::Value, E> {
+            self.visit_str(&value.to_string())
+        }
+        fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+            self.visit_str(&value.to_string())
+        }
+        fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+            if self.input && value.trim_start().starts_with('-') {
+                return Err(E::custom(ProcessError::from(AmountError::Negative)));
+            }
+            value
+                .parse()
+                .map_err(|e: AmountError| E::custom(ProcessError::from(e)))
+        }
+    }
+
+    // the fields of a record are buffered for the untagged enum, at which point
+    // an empty one is an empty string rather than a missing value
+    pub(super) fn deserialize_optional_client<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<ClientID>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ClientVisitor;
+        impl<'de> Visitor<'de> for ClientVisitor {
+            type Value = Option<ClientID>;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "client identifier, if any")
+            }
+            fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+                let client = ClientID::try_from(value)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(value), &self))?;
+                Ok(Some(client))
+            }
+            fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+                if value.is_empty() {
+                    return Ok(None);
+                }
+                let client = value
+                    .parse()
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Str(value), &self))?;
+                Ok(Some(client))
+            }
+            fn visit_none<E: Error>(self) -> Result<Self::Value, E> {
+                Ok(None)
+            }
+            fn visit_unit<E: Error>(self) -> Result<Self::Value, E> {
+                Ok(None)
+            }
+            fn visit_some<D: Deserializer<'de>>(
+                self,
+                deserializer: D,
+            ) -> Result<Self::Value, D::Error> {
+                deserializer.deserialize_any(self)
+            }
+        }
+        deserializer.deserialize_any(ClientVisitor)
+    }
+
+    pub(super) fn is_zero(amount: &Amount) -> bool {
+        *amount == Amount::default()
+    }
+
+    pub(super) fn is_zero_count(count: &u32) -> bool {
+        *count == 0
+    }
+
+    /// Serializes the amount as a decimal string with all the places of the
+    /// default [`Amount::PRECISION`], e.g. `"5.0000"`, rather than as a float,
+    /// which is not always exact.
+    ///
+    /// Same as when displayed, the amount is taken to be counted at the default
+    /// precision, and so the outputs of an engine counting at another one
+    /// serialize theirs at the configured precision instead, see
+    /// [`Amount::format_at`].
     impl Serialize for Amount {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: Serializer,
         {
-            serializer.serialize_f64(self.as_f64())
+            serializer.serialize_str(&super::format_amount(*self, DECIMALS_PRECISION))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Currency {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            struct CurrencyVisitor;
+            impl Visitor<'_> for CurrencyVisitor {
+                type Value = Currency;
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    write!(f, "currency code of up to {} bytes", Currency::MAX_LEN)
+                }
+                fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+                    Currency::new(value)
+                        .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+                }
+            }
+            deserializer.deserialize_str(CurrencyVisitor)
+        }
+    }
+
+    impl Serialize for Currency {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_str(self.as_str())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Account, Amount, Currency, TxnRecord, TxnRecordKind, TxnState, format_amount};
+    use crate::{AmountError, ProcessError, Rounding};
+
+    #[test]
+    fn txn_state_round_trips() {
+        let txn = TxnRecord {
+            kind: TxnRecordKind::Deposit,
+            client: 1,
+            tx: 2,
+            amount: Amount::try_from_f64(5.5).unwrap(),
+            currency: Currency::default(),
+            to_client: None,
+            state: TxnState::Disputed,
+            disputed: Amount::default(),
+            reversed: Amount::default(),
+            shortfall: Amount::default(),
+            cycles: 0,
+            timestamp: None,
+        };
+        let json = serde_json::to_string(&txn).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"deposit","client":1,"tx":2,"amount":"5.5000","state":"disputed"}"#
+        );
+        let restored: TxnRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.state, TxnState::Disputed);
+        assert_eq!(restored.amount, txn.amount);
+
+        // state is optional and defaults to undisputed
+        let json = r#"{"type":"deposit","client":1,"tx":2,"amount":5.5}"#;
+        let txn: TxnRecord = serde_json::from_str(json).unwrap();
+        assert_eq!(txn.state, TxnState::Undisputed);
+    }
+
+    #[test]
+    fn saturates_magnitude_of_least_amount() {
+        let least = Amount::from_minor_units(i64::MIN);
+        let greatest = Amount::from_minor_units(i64::MAX);
+        assert_eq!(least.checked_abs(), None);
+        assert_eq!(least.abs(), greatest);
+        assert_eq!(-least, greatest);
+        assert_eq!(greatest.checked_abs(), Some(greatest));
+        assert_eq!((-greatest).checked_abs(), Some(greatest));
+        assert_eq!(
+            Amount::from_minor_units(-1)
+                .checked_abs()
+                .unwrap()
+                .minor_units(),
+            1
+        );
+    }
+
+    #[test]
+    fn negates_and_displays_amount() {
+        let amount = Amount::try_from_f64(1.5).unwrap();
+        assert_eq!(amount.to_string(), "1.5000");
+        assert_eq!((-amount).to_string(), "-1.5000");
+        assert_eq!((-amount).minor_units(), -15000);
+        assert_eq!(-(-amount), amount);
+        assert_eq!(amount.checked_add(-amount), Some(Amount::default()));
+        assert_eq!(amount.checked_neg(), Some(-amount));
+        assert_eq!(Amount::from_minor_units(i64::MIN).checked_neg(), None);
+        assert_eq!(
+            Amount::from_minor_units(i64::MAX).checked_neg(),
+            Some(Amount::from_minor_units(-i64::MAX))
+        );
+
+        let amount = Amount::from_minor_units(-5);
+        assert_eq!(amount.to_string(), "-0.0005");
+        assert_eq!(Amount::from_minor_units(-123_4567).to_string(), "-123.4567");
+        assert_eq!(Amount::default().to_string(), "0.0000");
+
+        // negative amounts round-trip through serialization
+        let json = serde_json::to_string(&Amount::from_minor_units(-15000)).unwrap();
+        assert_eq!(json, r#""-1.5000""#);
+        let amount: Amount = serde_json::from_str(&json).unwrap();
+        assert_eq!(amount.minor_units(), -15000);
+    }
+
+    #[test]
+    fn parses_and_formats_amount() {
+        let amount: Amount = "12.3456".parse().unwrap();
+        assert_eq!(amount.minor_units(), 12_3456);
+        assert_eq!(
+            "-12.34567".parse::<Amount>().unwrap().minor_units(),
+            -12_3456
+        );
+        assert_eq!("12.x".parse::<Amount>(), Err(AmountError::Malformed));
+        assert_eq!(format!("{amount:.2}"), "12.34");
+        assert_eq!(format!("{amount:.0}"), "12");
+        assert_eq!(format!("{amount:.6}"), "12.345600");
+        assert_eq!(format!("{:.2}", -amount), "-12.34");
+        assert_eq!(format!("{:.2}", Amount::from_minor_units(-5)), "0.00");
+        // the same minor units at other precisions
+        let cents = Amount::parse_at("-1.5", 2, Rounding::Truncate).unwrap();
+        assert_eq!(cents.minor_units(), -150);
+        assert_eq!(cents.format_at(2), "-1.50");
+        assert_eq!(cents.format_at(0), "-150");
+        assert_eq!(
+            Amount::try_from_f64_at(1.5, 2),
+            Ok(Amount::from_minor_units(150))
+        );
+
+        assert!((-amount).is_negative());
+        assert!(!Amount::default().is_negative());
+        assert_eq!((-amount).abs(), amount);
+        assert_eq!(amount.abs(), amount);
+        assert_eq!(amount.checked_mul(3).unwrap().minor_units(), 37_0368);
+        assert_eq!(Amount::from_minor_units(i64::MAX).checked_mul(2), None);
+        // 25 basis points, truncated to the minor unit
+        assert_eq!(amount.checked_basis_points(25).unwrap().minor_units(), 308);
+        assert_eq!(Amount::from_minor_units(amount.minor_units()), amount);
+    }
+
+    #[test]
+    fn compares_amount_to_float() {
+        let amount = Amount::try_from_f64(5.).unwrap();
+        assert!(amount.eq_f64(5.));
+        assert!(amount.eq_f64(5.00009));
+        assert!(!amount.eq_f64(5.0001));
+        assert!(!amount.eq_f64(f64::NAN));
+
+        assert!(!amount.eq_f64(f64::INFINITY));
+        assert_eq!(amount, 5.);
+        assert_ne!(amount, 4.9999);
+        assert_eq!(-Amount::try_from_f64(1.5).unwrap(), -1.5);
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        let cases = [
+            (f64::NAN, AmountError::NotANumber),
+            (f64::INFINITY, AmountError::Infinite),
+            (f64::NEG_INFINITY, AmountError::Infinite),
+            (1e15, AmountError::Overflow),
+            (-1e15, AmountError::Overflow),
+            (-1., AmountError::Negative),
+            (-0.0001, AmountError::Negative),
+        ];
+        for (value, expected) in cases {
+            let err = Amount::try_from_input(value).unwrap_err();
+            assert!(
+                matches!(err, ProcessError::InvalidAmount(err) if err == expected),
+                "{value}"
+            );
+        }
+        // negative amounts are fine as long as they are not the input ones
+        assert_eq!(Amount::try_from_f64(-0.0001).unwrap().minor_units(), -1);
+        assert_eq!(Amount::try_from_input(-0.).unwrap().minor_units(), 0);
+        // close to the largest amount that can be stored
+        assert!(Amount::try_from_input(922_337_203_685_477.).is_ok());
+    }
+
+    #[test]
+    fn applies_account_operations_atomically() {
+        let max = Amount::parse("922337203685477.5807", Rounding::Truncate).unwrap();
+        let one = Amount::try_from_f64(1.).unwrap();
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!((-max).checked_sub(one), None);
+        let below = Amount::parse("922337203685476.5807", Rounding::Truncate).unwrap();
+        assert_eq!(max.checked_sub(one), Some(below));
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(below.saturating_add(one), max);
+        let least = Amount::from_minor_units(i64::MIN);
+        assert_eq!(least.saturating_sub(one), least);
+        assert_eq!(least.saturating_add(-one), least);
+        assert_eq!(max.saturating_sub(-one), max);
+
+        let mut account = Account::new(1, Currency::default());
+        account.deposit(max).unwrap();
+        assert_eq!(account.deposit(one), Err(AmountError::Overflow));
+        account.hold(max).unwrap();
+        // the available funds would be fine, but the held ones overflow
+        assert_eq!(account.resolve(-one), Err(AmountError::Overflow));
+        assert_eq!(account.available, Amount::default());
+        assert_eq!(account.held, max);
+        assert_eq!(account.total, max);
+    }
+
+    #[test]
+    fn formats_amounts_exactly() {
+        assert_eq!(format_amount(Amount::from_minor_units(-5), 4), "-0.0005");
+        assert_eq!(format_amount(Amount::from_minor_units(150), 2), "1.50");
+        assert_eq!(format_amount(Amount::from_minor_units(7), 0), "7");
+    }
+
+    #[test]
+    fn detects_precision_loss_in_scientific_notation() {
+        let cases = [
+            ("5.00009999", true),
+            ("5.00000", false),
+            ("1e-5", true),
+            ("1.5e-4", true),
+            ("1e-4", false),
+            ("1.00000e0", false),
+            ("1.23456e1", false),
+            ("1.234567e1", true),
+            ("0e-99", false),
+            ("inf", false),
+        ];
+        for (text, exceeds) in cases {
+            assert_eq!(Amount::exceeds_precision(text), exceeds, "{text}");
+        }
+        assert!(Amount::exceeds_places("1.5e-2", 2));
+        assert!(!Amount::exceeds_places("1.5e-1", 2));
+    }
+
+    #[test]
+    fn parses_decimal_text() {
+        let units = |text, rounding| Amount::parse(text, rounding).unwrap().minor_units();
+        let cases = [
+            ("0.1", 1000, 1000),
+            ("1.0003", 10003, 10003),
+            ("+2", 20000, 20000),
+            ("-2.5", -25000, -25000),
+            (".5", 5000, 5000),
+            ("5.", 50000, 50000),
+            ("1.00015", 10001, 10002),
+            ("1.00025", 10002, 10002),
+            ("1.000250001", 10002, 10003),
+            ("-1.00015", -10001, -10002),
+            ("1.00014999", 10001, 10001),
+            ("922337203685477.58071", i64::MAX, i64::MAX),
+            ("1e3", 10_000_000, 10_000_000),
+            ("-2.5E1", -250_000, -250_000),
+            ("1.5e-4", 1, 2),
+            ("1e-99", 0, 0),
+            ("0e99", 0, 0),
+            (
+                "9.2233720368547758e14",
+                9_223_372_036_854_775_800,
+                9_223_372_036_854_775_800,
+            ),
+        ];
+        for (text, truncated, rounded) in cases {
+            assert_eq!(units(text, Rounding::Truncate), truncated, "{text}");
+            assert_eq!(units(text, Rounding::Bankers), rounded, "{text}");
+        }
+        let err = |text, rounding| match Amount::parse(text, rounding) {
+            Err(ProcessError::InvalidAmount(err)) => err,
+            other => panic!("{text}: {other:?}"),
+        };
+        assert_eq!(err("", Rounding::Truncate), AmountError::Malformed);
+        assert_eq!(err("-", Rounding::Truncate), AmountError::Malformed);
+        assert_eq!(err("1.2.3", Rounding::Truncate), AmountError::Malformed);
+        assert_eq!(err("NaN", Rounding::Truncate), AmountError::NotANumber);
+        assert_eq!(
+            err("922337203685478", Rounding::Truncate),
+            AmountError::Overflow
+        );
+        assert_eq!(err("1e15", Rounding::Truncate), AmountError::Overflow);
+        assert_eq!(err("1e99999", Rounding::Truncate), AmountError::Overflow);
+        assert_eq!(err("1e", Rounding::Truncate), AmountError::Malformed);
+        assert_eq!(
+            err("922337203685477.58075", Rounding::Bankers),
+            AmountError::Overflow
+        );
+    }
+
+    #[cfg(feature = "rust_decimal")]
+    #[test]
+    fn divides_exactly() {
+        use rust_decimal::Decimal;
+
+        let amount = Amount::try_from_f64(1.).unwrap();
+        let third = amount.checked_div(Decimal::from(3)).unwrap();
+        assert_eq!(third.minor_units(), 3333);
+        assert_eq!(third.checked_mul(Decimal::from(3)).unwrap(), amount);
+        assert_eq!(amount.checked_div(Decimal::ZERO), None);
+        // the fractions of a minor unit are kept
+        let amount = Amount::from_decimal(Decimal::new(1_00005, 5)).unwrap();
+        assert_eq!(amount.to_string(), "1.00005");
+        assert_eq!(amount.to_decimal(), Decimal::new(1_00005, 5));
+        assert_eq!(Amount::from_decimal(Decimal::MAX), None);
+        let max = Amount::from_minor_units(i64::MAX);
+        assert_eq!(max.checked_mul(Decimal::TWO), None);
+    }
+}
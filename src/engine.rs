@@ -0,0 +1,7222 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    num::{NonZeroU64, NonZeroUsize},
+    path::Path,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use crate::audit::AuditLog;
+use crate::checkpoint::{Checkpointer, Progress};
+use crate::observer::EventObserver;
+
+use crate::domain::{
+    Account, AccountRecordKind, Amount, ClientID, Currency, DisputeRecordKind, Record, RecordInner,
+    Timestamp, TxnID, TxnRecord, TxnRecordKind, TxnState,
+};
+use crate::output::{AccountRow, RowWriter};
+use crate::rejects::Rejects;
+use crate::statement::Statement;
+use crate::store::{AccountStore, MemoryAccountStore, MemoryTxnStore, TxnStore};
+use crate::wal::Wal;
+use crate::{
+    AmountError, ClientSpan, ColumnMap, Discrepancy, DuplicatePolicy, InputFormat, Issue,
+    IssueKind, LockedAccountPolicy, NegativeAvailablePolicy, ProcessError, ProcessOptions,
+    ProcessReport, ReadProgress, ReconcileReport, SkipReason, Skipped, Summary, TimestampOrder,
+    TxnEvent, TxnOutcome, ValidationReport, Violation, Warning, WarningKind,
+};
+
+/// Number of records handed over to a worker at once, see [`PaymentEngine::process_parallel`].
+const SHARD_BATCH: usize = 1024;
+
+/// Number of batches that can be queued up for a worker.
+const SHARD_QUEUE: usize = 16;
+
+/// Length of the history of a client past which the evicted transactions are
+/// dropped from it, see [`PaymentEngine::history`].
+const PRUNE_HISTORY_AT: usize = 64;
+
+/// Record routed to a worker, along with its row and line in the input.
+type Routed = (u64, Option<u64>, Record);
+
+/// Work handed over to a worker, see [`PaymentEngine::process_parallel`].
+enum Job {
+    /// Apply the record, along with whether its transaction identifier has
+    /// been taken by a record routed to another worker, and the recipient's
+    /// account to borrow if in the shard of another worker.
+    Apply(Routed, bool, Option<Loan>),
+
+    /// Lend the account to the worker applying a transfer to it (or a dispute
+    /// of one), waiting for it to be given back.
+    Lend(Loan),
+}
+
+/// Account lent by the worker of its shard to another worker, handed over
+/// through a channel and given back through another one, see [`Job`].
+struct Loan {
+    client: ClientID,
+    currency: Currency,
+    send: mpsc::Sender<Option<Account>>,
+    receive: mpsc::Receiver<Option<Account>>,
+}
+
+impl Loan {
+    /// The two sides of the loan of the `client`'s account in the `currency`,
+    /// the borrower's one first.
+    fn new(client: ClientID, currency: Currency) -> (Self, Self) {
+        let (lend, lent) = mpsc::channel();
+        let (give_back, given_back) = mpsc::channel();
+        let borrower = Loan {
+            client,
+            currency,
+            send: give_back,
+            receive: lent,
+        };
+        let lender = Loan {
+            client,
+            currency,
+            send: lend,
+            receive: given_back,
+        };
+        (borrower, lender)
+    }
+}
+
+/// Callback of the progress of reading the inputs, see [`PaymentEngine::on_progress`].
+struct ProgressCallback {
+    every: NonZeroU64,
+    last: ReadProgress,
+    callback: Box<dyn FnMut(ReadProgress) + Send>,
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressCallback")
+            .field("every", &self.every)
+            .field("last", &self.last)
+            .finish_non_exhaustive()
+    }
+}
+
+/// State of reading an input, see [`PaymentEngine::read`].
+struct Reading {
+    headers: csv::StringRecord,
+
+    /// Index of the amount column, if any.
+    ///
+    /// We are holding on to the raw amount, since the precision loss (if any)
+    /// cannot be reliably detected once the value has been parsed.
+    amount_idx: Option<usize>,
+
+    /// Byte offset the first record starts at.
+    header_end: u64,
+
+    /// Byte offset the last record read ends at.
+    consumed: u64,
+
+    /// Records deferred until the input is exhausted, see [`ProcessOptions::two_pass`].
+    deferred: Vec<Routed>,
+
+    /// Records held back to be reordered, see [`ProcessOptions::reorder_window`].
+    held: BinaryHeap<Reverse<Held>>,
+
+    /// Timestamp of the last record read, if any.
+    timestamp: Option<Timestamp>,
+}
+
+/// Record held back to be reordered, ordered by its timestamp and then row.
+struct Held {
+    timestamp: Timestamp,
+    routed: Routed,
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.routed.0).cmp(&(other.timestamp, other.routed.0))
+    }
+}
+
+impl Reading {
+    /// Reading an input with the `headers`, already renamed as per the
+    /// [`ProcessOptions::column_map`] (if at all).
+    fn new(headers: csv::StringRecord, header_end: u64) -> Self {
+        let amount_idx = headers.iter().position(|header| header == "amount");
+        Reading {
+            headers,
+            amount_idx,
+            header_end,
+            consumed: header_end,
+            deferred: Vec::new(),
+            held: BinaryHeap::new(),
+            timestamp: None,
+        }
+    }
+}
+
+/// Input being merged with the other ones, see [`PaymentEngine::process_merged`].
+struct MergedInput<R> {
+    rdr: csv::Reader<R>,
+
+    /// Index in the merged headers of each of the input's columns.
+    columns: Vec<usize>,
+
+    /// Index of the input's timestamp column, if any.
+    timestamp_idx: Option<usize>,
+
+    /// Timestamp of the next record, or of the last one if there are no more,
+    /// if any has been read yet.
+    timestamp: Option<u64>,
+
+    /// Next record, with the fields at the merged headers' indices, or the
+    /// failure to read it, which is only reported once it is its turn.
+    next: Option<Result<csv::StringRecord, ProcessError>>,
+
+    /// Byte offset the last record taken from the input ends at.
+    consumed: u64,
+}
+
+impl<R: Read> MergedInput<R> {
+    /// Read the next record of the input, if any, with `width` merged columns.
+    fn advance(&mut self, width: usize) {
+        self.next = self.read(width).transpose();
+    }
+
+    fn read(&mut self, width: usize) -> Result<Option<csv::StringRecord>, ProcessError> {
+        let mut raw = csv::StringRecord::new();
+        if !self.rdr.read_record(&mut raw)? {
+            return Ok(None);
+        }
+        let value = self.timestamp_idx.and_then(|idx| raw.get(idx));
+        // the row is the one in its own input, as is the line
+        let invalid = |value: &str| ProcessError::InvalidTimestamp {
+            row: raw.position().map_or(0, |position| position.record()),
+            line: raw.position().map(|position| position.line()),
+            value: value.to_string(),
+        };
+        match value {
+            Some(value) if !value.is_empty() => {
+                self.timestamp = Some(value.parse().map_err(|_| invalid(value))?);
+            }
+            // there is no telling where the record goes otherwise
+            _ if self.timestamp.is_none() => return Err(invalid("")),
+            _ => {}
+        }
+        let mut fields = vec![""; width];
+        for (idx, field) in self.columns.iter().zip(raw.iter()) {
+            fields[*idx] = field;
+        }
+        let mut merged: csv::StringRecord = fields.into_iter().collect();
+        merged.set_position(raw.position().cloned());
+        Ok(Some(merged))
+    }
+}
+
+/// Error merging two [`PaymentEngine`] instances.
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    /// Transaction identifiers are globally unique, and so the same `tx`
+    /// processed by both engines indicates that the shards overlap.
+    #[error("transaction {0} is present in both engines")]
+    TxnCollision(TxnID),
+
+    /// The client's account is present in both engines and cannot be
+    /// combined, e.g. because it got locked in one of them.
+    #[error("client {0} is present in both engines with conflicting state")]
+    ClientConflict(ClientID),
+
+    /// The client's account is present in both engines and the combined
+    /// funds would overflow.
+    #[error("client {0} funds overflow when combined")]
+    Overflow(ClientID),
+
+    /// Failed to read the transactions of this engine, see [`TxnStore::get`].
+    #[error("failed to read the transactions: {0}")]
+    Store(#[from] Box<ProcessError>),
+}
+
+/// Stateful payment engine.
+///
+/// Holds the clients' accounts along with the transactions that the further
+/// dispute resolution records may reference. Records can be fed to the engine
+/// in batches (see [`PaymentEngine::process`]) with the state carried over from
+/// one batch to the next one, and the engines that processed
+/// independent shards of the input can be combined with [`PaymentEngine::merge`].
+///
+/// The transactions and the accounts are kept in memory by default, while other
+/// storage can be plugged in with [`PaymentEngine::with_stores`], and the
+/// records of different clients can be applied in parallel with a
+/// [`ConcurrentEngine`](crate::ConcurrentEngine).
+#[derive(Debug)]
+pub struct PaymentEngine {
+    options: ProcessOptions,
+    report: ProcessReport,
+    txns: Box<dyn TxnStore>,
+    accounts: Box<dyn AccountStore>,
+
+    /// Number of records fed to this engine so far.
+    rows: u64,
+
+    /// Number of the records replayed from the write-ahead log, which are not
+    /// counted against [`ProcessOptions::max_records`].
+    replayed: u64,
+
+    /// Operations already applied, see [`ProcessOptions::idempotent`].
+    applied: HashSet<IdempotencyKey>,
+
+    /// Transactions skipped since their account is locked or closed, whose
+    /// identifiers are taken all the same.
+    reserved: HashSet<TxnID>,
+
+    /// Rows referencing each client, see [`ProcessOptions::client_spans`].
+    spans: HashMap<ClientID, ClientSpan>,
+
+    /// Transactions of each client in the order applied, see [`ProcessOptions::history`].
+    history: HashMap<ClientID, Vec<HistoryEntry>>,
+
+    /// Rows the transactions were recorded at, see [`ProcessOptions::undisputed_window`].
+    recorded: HashMap<TxnID, u64>,
+
+    /// Clients whose accounts have been written out, see [`PaymentEngine::process_streaming`].
+    flushed: HashSet<ClientID>,
+
+    /// Line of the input the current record starts at, if known.
+    line: Option<u64>,
+
+    /// Issues with the records failed to be read, collected rather than failing
+    /// the processing, see [`PaymentEngine::validate`].
+    issues: Option<Vec<Issue>>,
+
+    /// Log the records get appended to, see [`PaymentEngine::open_with_wal`].
+    wal: Option<Wal>,
+
+    /// Output of the rejected records, see [`PaymentEngine::write_rejects_to`].
+    rejects: Option<Rejects>,
+
+    /// Output of the changes to the accounts, see [`PaymentEngine::write_audit_to`].
+    audit: Option<AuditLog>,
+
+    /// Observers of the records applied, see [`PaymentEngine::add_observer`].
+    observers: Vec<Box<dyn EventObserver>>,
+
+    /// Tracker of the checkpoints, see [`PaymentEngine::process_checkpointed`].
+    checkpointer: Option<Checkpointer>,
+
+    /// Callback of the reading progress, see [`PaymentEngine::on_progress`].
+    progress: Option<ProgressCallback>,
+
+    /// Bytes of the inputs read to the end so far.
+    bytes_read: u64,
+
+    /// Timestamp of the last record applied, see [`ProcessOptions::timestamp_order`].
+    timestamp: Option<Timestamp>,
+
+    /// Timestamp of the last record applied per client, see [`ProcessOptions::timestamp_order`].
+    timestamps: HashMap<ClientID, Timestamp>,
+
+    /// Latest timestamp seen so far, standing in for the one of a dispute
+    /// without it, see [`ProcessOptions::dispute_window`].
+    latest: Option<Timestamp>,
+}
+
+/// Identity of an operation, for a replayed one to be recognized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum IdempotencyKey {
+    Txn(TxnID),
+    Dispute(TxnID, DisputeRecordKind),
+}
+
+/// Entry of the history of a client, see [`PaymentEngine::history`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HistoryEntry {
+    Txn(TxnID),
+
+    /// Part of the transaction charged back, at the time of the chargeback.
+    ChargeBack {
+        tx: TxnID,
+        amount: Amount,
+        timestamp: Option<Timestamp>,
+    },
+}
+
+impl HistoryEntry {
+    /// Transaction the entry is about.
+    pub(crate) fn tx(&self) -> TxnID {
+        match self {
+            HistoryEntry::Txn(tx) | HistoryEntry::ChargeBack { tx, .. } => *tx,
+        }
+    }
+}
+
+/// Kind of a record, for the observers to be told what it did, see [`EventObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Txn(TxnRecordKind),
+    Dispute(DisputeRecordKind),
+    Account,
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        Self::with_options(ProcessOptions::default())
+    }
+}
+
+impl PaymentEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: ProcessOptions) -> Self {
+        let txns = MemoryTxnStore::new(options.retention);
+        Self::with_stores(options, txns, MemoryAccountStore::default())
+    }
+
+    /// Create an engine keeping the transactions and the accounts in the given
+    /// stores, which are expected to be empty.
+    ///
+    /// Note that it is up to the `txns` store which of the transactions to retain,
+    /// i.e. [`ProcessOptions::retention`] only applies to the default store.
+    pub fn with_stores<T, A>(options: ProcessOptions, txns: T, accounts: A) -> Self
+    where
+        T: TxnStore + 'static,
+        A: AccountStore + 'static,
+    {
+        PaymentEngine {
+            options,
+            report: ProcessReport::default(),
+            txns: Box::new(txns),
+            accounts: Box::new(accounts),
+            rows: 0,
+            replayed: 0,
+            applied: HashSet::new(),
+            reserved: HashSet::new(),
+            spans: HashMap::new(),
+            history: HashMap::new(),
+            recorded: HashMap::new(),
+            flushed: HashSet::new(),
+            line: None,
+            issues: None,
+            wal: None,
+            rejects: None,
+            audit: None,
+            observers: Vec::new(),
+            checkpointer: None,
+            progress: None,
+            bytes_read: 0,
+            timestamp: None,
+            timestamps: HashMap::new(),
+            latest: None,
+        }
+    }
+
+    /// Open an engine recovering its state from the write-ahead log at the `path`.
+    ///
+    /// The records in the log (if any) are replayed first, after which each record
+    /// fed to the engine gets appended to the log as soon as it is applied (unless
+    /// rejected, see [`ProcessOptions::strict`]), and the log is synced to the disk
+    /// as per [`ProcessOptions::fsync`]. A record that has only been partially
+    /// written to the log (e.g. due to a crash) is dropped. The replayed records
+    /// are neither part of the [`PaymentEngine::report`], nor counted against
+    /// [`ProcessOptions::max_records`], since they have been processed already.
+    ///
+    /// The log is in the input CSV format. Note that the records are logged as they
+    /// are applied, e.g. the disputes deferred with [`ProcessOptions::two_pass`] end
+    /// up after the transactions, and that merging another engine into this one is
+    /// not logged, see [`PaymentEngine::merge`].
+    pub fn open_with_wal<P>(path: P, options: ProcessOptions) -> Result<Self, ProcessError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut engine = Self::with_options(options);
+        if path.exists() {
+            crate::wal::truncate_torn_tail(path)?;
+            engine.replay(std::io::BufReader::new(File::open(path)?))?;
+        }
+        engine.wal = Some(Wal::open(
+            path,
+            engine.options.fsync,
+            engine.options.precision,
+        )?);
+        Ok(engine)
+    }
+
+    /// Sync the write-ahead log (if any) to the disk, whatever the [`ProcessOptions::fsync`],
+    /// e.g. before acknowledging the records applied so far to their source.
+    pub fn sync_wal(&mut self) -> Result<(), ProcessError> {
+        match &mut self.wal {
+            Some(wal) => wal.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Write each record that is not applied from now on to the `writer`, in
+    /// the CSV format with the configured [`ProcessOptions::delimiter`].
+    ///
+    /// The records are written in the columns of the input (normalized, e.g.
+    /// with the exact amounts), along with a `reason` column, for them to be
+    /// corrected and submitted again. These are the skipped records, the dispute
+    /// resolution records referencing unknown transactions, and the ones rejected
+    /// in the strict mode (right before the processing fails), see [`TxnOutcome`],
+    /// as well as the ones that cannot be read, which are written as they are
+    /// in the input, with the error as the reason. The records rejected by the
+    /// workers of [`PaymentEngine::process_parallel`] are written out once all
+    /// of them are done, in the input order.
+    pub fn write_rejects_to<W>(&mut self, writer: W) -> Result<(), ProcessError>
+    where
+        W: Write + Send + 'static,
+    {
+        let rejects = Rejects::new(writer, self.options.delimiter, self.options.precision)?;
+        self.rejects = Some(rejects);
+        Ok(())
+    }
+
+    /// Write each change the records applied from now on make to the accounts
+    /// to the `writer`, in the CSV format with the configured [`ProcessOptions::delimiter`].
+    ///
+    /// Every applied record gets a line per account it changed (e.g. both the
+    /// sender's and the recipient's for a transfer), with its `row`, `type`, `tx`
+    /// and `amount`, the account's `client` and `currency`, its available, held
+    /// and total funds before and after the record was applied, and whether it
+    /// is `locked` and `closed` afterwards, so that each final balance can be
+    /// reconstructed step by step. Likewise, the accounts reset with
+    /// [`PaymentEngine::reset_account`] and unlocked with [`PaymentEngine::unlock`]
+    /// get a line each, of the `reset` and `unlock` types respectively, at the
+    /// row of the last record applied before. Note that the workers
+    /// of [`PaymentEngine::process_parallel`] do not write the changes out.
+    pub fn write_audit_to<W>(&mut self, writer: W) -> Result<(), ProcessError>
+    where
+        W: Write + Send + 'static,
+    {
+        let audit = AuditLog::new(writer, self.options.delimiter, self.options.precision)?;
+        self.audit = Some(audit);
+        Ok(())
+    }
+
+    /// Have the `observer` called back with what becomes of each record applied
+    /// from now on, see [`EventObserver`].
+    ///
+    /// Several observers are called in the order they were added. Note that the
+    /// workers of [`PaymentEngine::process_parallel`] do not call the observers.
+    pub fn add_observer<O>(&mut self, observer: O)
+    where
+        O: EventObserver + 'static,
+    {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Have the `callback` called with the progress of reading the inputs every
+    /// so many `records`, and once each input has been read to the end, see
+    /// [`ReadProgress`].
+    ///
+    /// Meant for reporting the progress of processing a huge input, e.g. with
+    /// a progress bar. The callback is called synchronously, in between the records.
+    pub fn on_progress<F>(&mut self, records: NonZeroU64, callback: F)
+    where
+        F: FnMut(ReadProgress) + Send + 'static,
+    {
+        self.progress = Some(ProgressCallback {
+            every: records,
+            last: ReadProgress::default(),
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Call back with the progress of reading, the current input having been
+    /// read up to the `consumed` byte, see [`PaymentEngine::on_progress`].
+    fn report_progress(&mut self, consumed: u64, due: bool) {
+        let Some(progress) = &mut self.progress else {
+            return;
+        };
+        let current = ReadProgress {
+            records: self.rows,
+            bytes: self.bytes_read + consumed,
+        };
+        if current != progress.last && (due || self.rows.is_multiple_of(progress.every.get())) {
+            progress.last = current;
+            (progress.callback)(current);
+        }
+    }
+
+    /// Apply the records of the write-ahead log in the order they were logged.
+    fn replay<R>(&mut self, reader: R) -> Result<(), ProcessError>
+    where
+        R: Read,
+    {
+        // the log is always in the same format, with the records in the order
+        // they got applied
+        let options = self.options.clone();
+        self.options = options
+            .clone()
+            .two_pass(false)
+            .input_format(InputFormat::Csv)
+            .delimiter(b',')
+            .quote(b'"')
+            .has_headers(true)
+            .comment(None)
+            .column_map(ColumnMap::default())
+            .max_records(None);
+        // the rejected records are not logged, while the logs written by the
+        // earlier versions may still have them
+        let result = self.read(reader, |engine, _, record| {
+            match engine.apply_record(record) {
+                Err(ProcessError::Rejected { .. } | ProcessError::UnexpectedDispute { .. }) => {
+                    Ok(())
+                }
+                result => result,
+            }
+        });
+        self.options = options;
+        self.replayed = self.rows;
+        self.report = ProcessReport::default();
+        result
+    }
+
+    /// Write the state of the engine to the `writer`, for it to be brought back
+    /// with [`PaymentEngine::restore`] instead of processing the history again.
+    ///
+    /// The snapshot is in CSV format and holds the accounts along with the
+    /// retained transactions, including their dispute state, with the amounts
+    /// written exactly at [`ProcessOptions::precision`]. So are the operations
+    /// applied so far (see [`ProcessOptions::idempotent`]) and the identifiers of
+    /// the transactions skipped on a locked or closed account, for the duplicates
+    /// to be recognized after restoring, and the history of each client in the
+    /// order applied, see [`ProcessOptions::history`]. The report and the rest
+    /// of the bookkeeping (e.g. for [`ProcessOptions::client_spans`]) are not
+    /// part of it.
+    pub fn snapshot<W>(&self, writer: W) -> Result<(), ProcessError>
+    where
+        W: Write,
+    {
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_by_key(|account| (account.client, account.currency));
+        let mut txns = Vec::with_capacity(self.txns.len());
+        self.txns.for_each(&mut |txn| txns.push(txn.clone()));
+        txns.sort_by_key(|txn| txn.tx);
+        let mut applied: Vec<_> = self.applied.iter().copied().collect();
+        applied.sort_by_key(|key| match key {
+            IdempotencyKey::Txn(tx) => (*tx, None),
+            IdempotencyKey::Dispute(tx, kind) => (*tx, Some(*kind as u8)),
+        });
+        let mut reserved: Vec<_> = self.reserved.iter().copied().collect();
+        reserved.sort_unstable();
+        let mut clients: Vec<_> = self.history.keys().copied().collect();
+        clients.sort_unstable();
+        let history: Vec<_> = clients
+            .into_iter()
+            .flat_map(|client| {
+                let entries = self.history[&client].iter();
+                entries.map(move |&entry| (client, entry))
+            })
+            .collect();
+        crate::snapshot::write(
+            writer,
+            accounts.into_iter(),
+            &txns,
+            &applied,
+            &reserved,
+            &history,
+            self.options.precision,
+        )
+    }
+
+    /// Replace the accounts and the transactions of the engine with the ones
+    /// from the snapshot in the `reader`, see [`PaymentEngine::snapshot`].
+    ///
+    /// The snapshot is expected to be taken at the same [`ProcessOptions::precision`].
+    /// Nothing is restored if the snapshot is malformed, in which case
+    /// [`ProcessError::InvalidSnapshot`] is returned. Otherwise, the rest of the
+    /// bookkeeping of the records processed so far (e.g. for [`ProcessOptions::client_spans`])
+    /// is reset, since it no longer matches the state, while the report is kept. Note that restoring is not
+    /// logged to the write-ahead log, see [`PaymentEngine::open_with_wal`].
+    pub fn restore<R>(&mut self, reader: R) -> Result<(), ProcessError>
+    where
+        R: Read,
+    {
+        let entries = crate::snapshot::read(reader, self.options.precision)?;
+        self.accounts.drain();
+        self.txns.retain(&mut |_| false);
+        self.applied.clear();
+        self.reserved.clear();
+        self.spans.clear();
+        self.history.clear();
+        self.recorded.clear();
+        self.flushed.clear();
+        self.timestamp = None;
+        self.timestamps.clear();
+        self.latest = None;
+        // the history is rebuilt in the order of the transactions, unless the
+        // order they were applied in has been written out
+        let ordered = entries
+            .iter()
+            .any(|entry| matches!(entry, crate::snapshot::Entry::History(..)));
+        for entry in entries {
+            match entry {
+                crate::snapshot::Entry::Account(account) => self.accounts.insert(account),
+                crate::snapshot::Entry::Txn(txn) => {
+                    self.latest = self.latest.max(txn.timestamp);
+                    if !ordered {
+                        self.index(&txn);
+                    }
+                    self.txns.insert(txn)
+                }
+                crate::snapshot::Entry::History(client, entry) => {
+                    if self.options.history {
+                        self.history.entry(client).or_default().push(entry);
+                    }
+                }
+                crate::snapshot::Entry::Applied(key) => {
+                    self.applied.insert(key);
+                }
+                crate::snapshot::Entry::Reserved(tx) => {
+                    self.reserved.insert(tx);
+                }
+            }
+        }
+        self.txns.flush()
+    }
+
+    /// Warnings and other details collected while processing.
+    pub fn report(&self) -> &ProcessReport {
+        &self.report
+    }
+
+    /// Consume the engine, returning the report, see [`PaymentEngine::report`].
+    pub fn into_report(self) -> ProcessReport {
+        self.report
+    }
+
+    /// Process the records contained in the `reader` in CSV format.
+    ///
+    /// See [`crate::process`] for the details on the expected format and
+    /// the assumptions we are making.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn process<R>(&mut self, reader: R) -> Result<(), ProcessError>
+    where
+        R: Read,
+    {
+        self.read(reader, |engine, _, record| engine.apply_record(record))
+    }
+
+    /// Same as [`PaymentEngine::process`], but calls `on_event` with what
+    /// became of each of the records once applied, see [`TxnOutcome`].
+    ///
+    /// Meant for producing a per-record trail of the input, e.g. a file of the
+    /// rejected records. A record failing the processing gets no event.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn process_with_events<R, F>(
+        &mut self,
+        reader: R,
+        mut on_event: F,
+    ) -> Result<(), ProcessError>
+    where
+        R: Read,
+        F: FnMut(TxnEvent),
+    {
+        self.read(reader, |engine, _, record| {
+            on_event(engine.apply_outcome(record)?);
+            Ok(())
+        })
+    }
+
+    /// Process the records contained in the `reader` in CSV format with the
+    /// given number of `workers`, each on its own thread.
+    ///
+    /// Since the accounts of different clients are independent, the records are
+    /// parsed on the current thread, and routed to the workers by the client's
+    /// identifier, so that each worker owns a shard of the accounts and applies
+    /// the records of its clients in the input order. The shards are then merged
+    /// into this engine, see [`PaymentEngine::merge`].
+    ///
+    /// The workers keep the transactions and the accounts in memory, starting
+    /// afresh, and so this fails with [`ProcessError::NotEmpty`] unless the engine
+    /// has not processed anything yet. The reading thread keeps track of the
+    /// worker each transaction identifier went to, so that a dispute resolution
+    /// record reaches the worker of the transaction it references (and gets
+    /// skipped for a client mismatch, if need be), and a transaction identifier
+    /// reused by a client from another shard is skipped as a duplicate. Unlike
+    /// with [`PaymentEngine::process`], the identifier is taken by its first
+    /// record then, even if that one is skipped itself, e.g. for insufficient
+    /// funds. A transfer to a client of another shard (or a dispute of one) is
+    /// applied by the sender's worker with the recipient's account lent to it
+    /// by the recipient's worker, which waits for the account to be given back,
+    /// so that the two workers apply it in the input order, same as the
+    /// [`ConcurrentEngine`](crate::ConcurrentEngine) does. If several workers
+    /// fail, the error of the first of them is returned.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn process_parallel<R>(
+        &mut self,
+        reader: R,
+        workers: NonZeroUsize,
+    ) -> Result<(), ProcessError>
+    where
+        R: Read,
+    {
+        if self.rows > 0 || !self.accounts.is_empty() || !self.txns.is_empty() {
+            return Err(ProcessError::NotEmpty);
+        }
+        let workers = workers.get();
+        // the rejects of the shards (and the ones read here) are held, for
+        // them to be written out in the input order once all are in
+        let places = self.options.precision;
+        let rejects = self.rejects.take();
+        if rejects.is_some() {
+            self.rejects = Some(Rejects::held(places));
+        }
+        let (mut shards, result) = std::thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(workers);
+            let mut handles = Vec::with_capacity(workers);
+            for _ in 0..workers {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<Job>>(SHARD_QUEUE);
+                let mut shard = PaymentEngine::with_options(self.options.clone());
+                shard.rejects = self.rejects.as_ref().map(|_| Rejects::held(places));
+                senders.push(sender);
+                handles.push(scope.spawn(move || {
+                    for batch in receiver {
+                        for job in batch {
+                            // the other side of a loan is only gone if its
+                            // worker failed, which is reported once joined
+                            if !shard.work(job)? {
+                                return Ok(shard);
+                            }
+                        }
+                    }
+                    Ok::<_, ProcessError>(shard)
+                }));
+            }
+            let mut batches: Vec<Vec<_>> = (0..workers).map(|_| Vec::new()).collect();
+            // the worker of each transaction, along with the recipient's account
+            // if a transfer
+            let mut owners: HashMap<TxnID, (usize, Option<(ClientID, Currency)>)> = HashMap::new();
+            // the worker is only gone if it failed, which is reported below
+            let send = |sender: &mpsc::SyncSender<Vec<Job>>, batch| {
+                sender
+                    .send(batch)
+                    .map_err(|_| ProcessError::from(std::io::Error::other("worker stopped")))
+            };
+            let result = self.read(reader, |engine, _, record| {
+                let own = record.client() as usize % workers;
+                let (idx, taken, recipient) = match (&record.inner, record.tx()) {
+                    (RecordInner::TxnRecord(r), Some(tx)) => {
+                        let recipient = (r.to_client)
+                            .filter(|_| r.kind == TxnRecordKind::Transfer)
+                            .map(|to_client| (to_client, r.currency));
+                        let (owner, _) = *owners.entry(tx).or_insert((own, recipient));
+                        (own, owner != own, recipient)
+                    }
+                    (RecordInner::DisputeRecord(_), Some(tx)) => match owners.get(&tx) {
+                        Some((owner, recipient)) => (*owner, false, *recipient),
+                        None => (own, false, None),
+                    },
+                    _ => (own, false, None),
+                };
+                let lender = recipient
+                    .map(|(client, currency)| (client, currency, client as usize % workers))
+                    .filter(|(_, _, lender)| *lender != idx);
+                let routed = (engine.rows, engine.line, record);
+                let Some((client, currency, lender)) = lender else {
+                    batches[idx].push(Job::Apply(routed, taken, None));
+                    if batches[idx].len() < SHARD_BATCH {
+                        return Ok(());
+                    }
+                    let batch =
+                        std::mem::replace(&mut batches[idx], Vec::with_capacity(SHARD_BATCH));
+                    return send(&senders[idx], batch);
+                };
+                // both sides are handed over at once, for neither to wait for
+                // the other one being held back here
+                let (borrower, loan) = Loan::new(client, currency);
+                batches[lender].push(Job::Lend(loan));
+                batches[idx].push(Job::Apply(routed, taken, Some(borrower)));
+                for idx in [lender, idx] {
+                    let batch =
+                        std::mem::replace(&mut batches[idx], Vec::with_capacity(SHARD_BATCH));
+                    send(&senders[idx], batch)?;
+                }
+                Ok(())
+            });
+            for (sender, batch) in senders.into_iter().zip(batches) {
+                let _ = sender.send(batch);
+            }
+            let shards: Vec<_> = handles
+                .into_iter()
+                .map(|handle| handle.join().expect("worker not to panic"))
+                .collect();
+            (shards, result)
+        });
+        let held = self.rejects.take().map(|mut held| held.take_held());
+        self.rejects = rejects;
+        if let (Some(rejects), Some(mut held)) = (&mut self.rejects, held) {
+            for shard in shards.iter_mut().flatten() {
+                held.extend(
+                    shard
+                        .rejects
+                        .as_mut()
+                        .map(Rejects::take_held)
+                        .unwrap_or_default(),
+                );
+            }
+            rejects.write_held(held)?;
+        }
+        let shards = shards.into_iter().collect::<Result<Vec<_>, _>>()?;
+        result?;
+        for shard in shards {
+            self.merge(shard)?;
+        }
+        // the records have been skipped in the input order within each shard only
+        self.report.skipped.sort_by_key(|skipped| skipped.row);
+        self.report.malformed.sort_by_key(|issue| issue.row);
+        Ok(())
+    }
+
+    /// Do the `job` of a worker, see [`PaymentEngine::process_parallel`].
+    ///
+    /// Returns `false` if the other side of the loan of an account is gone.
+    fn work(&mut self, job: Job) -> Result<bool, ProcessError> {
+        let ((row, line, record), taken, loan) = match job {
+            Job::Apply(routed, taken, loan) => (routed, taken, loan),
+            Job::Lend(loan) => {
+                let account = self.remove_account(loan.client, loan.currency);
+                let _ = loan.send.send(account);
+                let Ok(account) = loan.receive.recv() else {
+                    return Ok(false);
+                };
+                if let Some(account) = account {
+                    self.insert_account(account);
+                }
+                return Ok(true);
+            }
+        };
+        self.rows = row;
+        self.line = line;
+        if taken && let Some(tx) = record.tx() {
+            self.reserved.insert(tx);
+        }
+        let Some(loan) = loan else {
+            let result = self.apply_record(record);
+            return self.collect_issue(result).map(|()| true);
+        };
+        let Ok(account) = loan.receive.recv() else {
+            return Ok(false);
+        };
+        if let Some(account) = account {
+            self.insert_account(account);
+        }
+        let result = self.apply_record(record);
+        // the account is given back even if the record failed
+        let account = self.remove_account(loan.client, loan.currency);
+        let _ = loan.send.send(account);
+        self.collect_issue(result).map(|()| true)
+    }
+
+    /// Process the records contained in the async `reader` in CSV format, and
+    /// write the accounts to the async `writer` in CSV format.
+    ///
+    /// Same as [`PaymentEngine::process`] followed by [`PaymentEngine::write`],
+    /// but the input is decoded as it arrives, without blocking the executor.
+    /// The accounts are serialized in memory before being written out. Note that
+    /// the input is always read in CSV format, see [`ProcessOptions::input_format`].
+    #[cfg(feature = "tokio")]
+    pub async fn process_async<R, W>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+    ) -> Result<(), ProcessError>
+    where
+        R: tokio::io::AsyncRead + Unpin + Send,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
+
+        let mut rdr = csv_async::AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .delimiter(self.options.delimiter)
+            .quote(self.options.quote)
+            .has_headers(self.options.has_headers)
+            .comment(self.options.comment)
+            .create_reader(reader);
+        let headers = match self.options.has_headers {
+            true => (self.options.column_map).apply(&rdr.headers().await?.iter().collect()),
+            false => crate::COLUMNS.into_iter().collect(),
+        };
+        let mut reading = Reading::new(headers, rdr.position().byte());
+        let mut record = csv_async::StringRecord::new();
+        let mut raw = csv::StringRecord::new();
+        let mut visit =
+            |engine: &mut Self, _: &csv::StringRecord, record| engine.apply_record(record);
+        while rdr.read_record(&mut record).await? {
+            if !self.admit()? {
+                break;
+            }
+            reading.consumed = rdr.position().byte();
+            raw.clear();
+            record.iter().for_each(|field| raw.push_field(field));
+            raw.set_position(record.position().map(|position| {
+                let mut pos = csv::Position::new();
+                pos.set_byte(position.byte())
+                    .set_line(position.line())
+                    .set_record(position.record());
+                pos
+            }));
+            let result = self.read_record(&mut reading, &mut raw, &mut visit);
+            self.collect_issue(result)?;
+        }
+        self.finish_reading(reading, visit)?;
+        let mut output = Vec::new();
+        self.write(&mut output)?;
+        writer.write_all(&output).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Process the records contained in the `reader` in CSV format, checkpointing
+    /// the progress to the `path` as per [`ProcessOptions::checkpoint`].
+    ///
+    /// A checkpoint holds the byte offset of the first record not yet processed
+    /// along with a snapshot of the engine (see [`PaymentEngine::snapshot`]), and
+    /// replaces the previous one atomically. A checkpoint is also taken once the
+    /// input is exhausted, while with [`ProcessOptions::two_pass`] that is the
+    /// only one taken, since the deferred records are only applied at the end.
+    /// Processing interrupted midway can be continued with
+    /// [`PaymentEngine::resume_checkpointed`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn process_checkpointed<R, P>(&mut self, reader: R, path: P) -> Result<(), ProcessError>
+    where
+        R: Read + Seek,
+        P: AsRef<Path>,
+    {
+        self.checkpointed(reader, path.as_ref(), None)
+    }
+
+    /// Continue processing the records contained in the `reader` in CSV format
+    /// from the checkpoint at the `path`, see [`PaymentEngine::process_checkpointed`].
+    ///
+    /// The engine is restored from the checkpoint first, after which the records
+    /// following the checkpointed offset are processed, and further checkpoints
+    /// are taken the same way. The `reader` is expected to be the same input the
+    /// checkpoint has been taken for. Note that the report only covers the records
+    /// processed after resuming, and the lines reported for them, if any, are
+    /// counted as if the input started with the headers followed by these records.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn resume_checkpointed<R, P>(&mut self, reader: R, path: P) -> Result<(), ProcessError>
+    where
+        R: Read + Seek,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let (progress, snapshot) = crate::checkpoint::read(path)?;
+        self.restore(snapshot)?;
+        self.rows = progress.rows;
+        self.checkpointed(reader, path, Some(progress))
+    }
+
+    fn checkpointed<R>(
+        &mut self,
+        mut reader: R,
+        path: &Path,
+        resumed: Option<Progress>,
+    ) -> Result<(), ProcessError>
+    where
+        R: Read + Seek,
+    {
+        // the records already processed are cut out of the input, while the
+        // headers are kept for the records to be read as they would be
+        let (headers, skipped) = match resumed {
+            Some(progress) => {
+                let mut headers = vec![0; progress.header_end as usize];
+                reader.read_exact(&mut headers)?;
+                reader.seek(SeekFrom::Start(progress.offset))?;
+                (headers, progress.offset - progress.header_end)
+            }
+            None => (Vec::new(), 0),
+        };
+        let policy = self.options.checkpoint;
+        self.checkpointer = Some(Checkpointer::new(path.to_path_buf(), policy, skipped));
+        let result = self.process(headers.as_slice().chain(reader));
+        self.checkpointer = None;
+        result
+    }
+
+    /// Take a checkpoint at the `offset` in the input being read, if checkpointing.
+    fn checkpoint(&mut self, header_end: u64, offset: u64) -> Result<(), ProcessError> {
+        let Some(checkpointer) = &self.checkpointer else {
+            return Ok(());
+        };
+        let progress = Progress {
+            header_end,
+            offset: offset + checkpointer.skipped,
+            rows: self.rows,
+        };
+        crate::checkpoint::write(&checkpointer.path, progress, |writer| self.snapshot(writer))?;
+        if let Some(checkpointer) = &mut self.checkpointer {
+            checkpointer.reset();
+        }
+        Ok(())
+    }
+
+    /// Process the records contained in the `readers` in CSV format, merged in
+    /// the chronological order of their `timestamp` column.
+    ///
+    /// The timestamps are unsigned integers, e.g. the Unix time in seconds or
+    /// milliseconds, as long as they are alike across the inputs. Each input is
+    /// expected to be ordered by the timestamps already, and a record without a
+    /// timestamp is considered to have the one of the record preceding it in its
+    /// input, while on a tie the record from the earlier input comes first. A
+    /// record with neither (e.g. of an input without the `timestamp` column) fails
+    /// with [`ProcessError::InvalidTimestamp`], as does one with a malformed
+    /// timestamp, once the records of the other inputs preceding it have been
+    /// applied. The
+    /// inputs are not required to have the same set or order of columns, and the
+    /// lines reported for the records (if any) are the ones in their own input.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn process_merged<R>(&mut self, readers: Vec<R>) -> Result<(), ProcessError>
+    where
+        R: Read,
+    {
+        let mut headers = csv::StringRecord::new();
+        let mut inputs = Vec::with_capacity(readers.len());
+        for reader in readers {
+            let mut rdr = self.csv_reader(reader);
+            let own = self.csv_headers(&mut rdr)?;
+            let columns = own
+                .iter()
+                .map(
+                    |header| match headers.iter().position(|known| known == header) {
+                        Some(idx) => idx,
+                        None => {
+                            headers.push_field(header);
+                            headers.len() - 1
+                        }
+                    },
+                )
+                .collect();
+            let timestamp_idx = own.iter().position(|header| header == "timestamp");
+            let consumed = rdr.position().byte();
+            inputs.push(MergedInput {
+                rdr,
+                columns,
+                timestamp_idx,
+                timestamp: None,
+                next: None,
+                consumed,
+            });
+        }
+        for input in &mut inputs {
+            input.advance(headers.len());
+        }
+        let width = headers.len();
+        let header_end = inputs.iter().map(|input| input.consumed).sum();
+        let mut reading = Reading::new(headers, header_end);
+        let mut visit =
+            |engine: &mut Self, _: &csv::StringRecord, record| engine.apply_record(record);
+        while let Some(input) = inputs
+            .iter_mut()
+            .filter(|input| input.next.is_some())
+            .min_by_key(|input| input.timestamp)
+        {
+            let mut raw = input.next.take().expect("filtered by the next record")?;
+            // the progress is of the bytes of all the inputs taken so far
+            let consumed = input.rdr.position().byte();
+            reading.consumed += consumed - input.consumed;
+            input.consumed = consumed;
+            input.advance(width);
+            if !self.admit()? {
+                break;
+            }
+            self.read_record(&mut reading, &mut raw, &mut visit)?;
+        }
+        self.finish_reading(reading, visit)
+    }
+
+    /// Process the records contained in the `reader` in CSV format, writing
+    /// the accounts to the `writer` in CSV format as soon as they are final.
+    ///
+    /// This expects the records to be grouped by client: once a record for
+    /// another client is encountered, all the accounts known so far are written
+    /// out and dropped, so that the engine only ever holds the accounts of one
+    /// client. The records for a client whose accounts have already been written
+    /// are skipped with [`SkipReason::AccountFlushed`], which also goes for the
+    /// dispute resolution records deferred with [`ProcessOptions::two_pass`].
+    /// The output has got a currency column if the input has got one, and in
+    /// case of an input in JSON Lines format, if its first record names a
+    /// currency. The output has only got a `closed` column if enabled with
+    /// [`ProcessOptions::closed_column`]. Note that the transactions are kept,
+    /// see [`PaymentEngine::clear_finalized_transactions`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn process_streaming<R, W>(&mut self, reader: R, writer: W) -> Result<(), ProcessError>
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut wrt = RowWriter::new(writer, &self.options);
+        let mut current = None;
+        let mut with_currency = false;
+        let (format, reader) = Self::input(self.options.input_format, reader)?;
+        self.read_as(format, reader, |engine, headers, record| {
+            let client = record.client();
+            if engine.flushed.contains(&client) {
+                engine.report.records += 1;
+                let reason = SkipReason::AccountFlushed;
+                return engine.skip(client, record.tx(), reason).map(drop);
+            }
+            if current.is_none() {
+                // a JSON record has got all the columns, if only empty ones
+                with_currency = if format == InputFormat::Csv {
+                    headers.iter().any(|header| header == "currency")
+                } else {
+                    !record.currency().is_implicit()
+                };
+            }
+            if current.is_some_and(|current| current != client) {
+                engine.flush_accounts(&mut wrt, with_currency)?;
+            }
+            current = Some(client);
+            engine.apply_record(record)
+        })?;
+        self.flush_accounts(&mut wrt, with_currency)?;
+        wrt.finish()
+    }
+
+    /// Write out and drop all the accounts known so far.
+    fn flush_accounts<W>(
+        &mut self,
+        wrt: &mut RowWriter<W>,
+        with_currency: bool,
+    ) -> Result<(), ProcessError>
+    where
+        W: Write,
+    {
+        let mut accounts = self.accounts.drain();
+        accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+        for account in accounts {
+            let row = AccountRow::new(&account, with_currency, false, &self.options);
+            wrt.write(&row)?;
+            self.flushed.insert(account.client);
+        }
+        Ok(())
+    }
+
+    /// Read the records contained in the `reader` without applying them, handing
+    /// each of them over to the `visit` callback along with its row and line,
+    /// see [`PaymentEngine::apply_at`].
+    pub(crate) fn parse<R, F>(&mut self, reader: R, mut visit: F) -> Result<(), ProcessError>
+    where
+        R: Read,
+        F: FnMut(u64, Option<u64>, Record) -> Result<(), ProcessError>,
+    {
+        self.read(reader, |engine, _, record| {
+            visit(engine.rows, engine.line, record)
+        })
+    }
+
+    /// Apply the `record` found at the `row` and `line` of the input by another
+    /// engine, telling what became of it, see [`PaymentEngine::parse`].
+    pub(crate) fn apply_at(
+        &mut self,
+        row: u64,
+        line: Option<u64>,
+        record: Record,
+    ) -> Result<TxnEvent, ProcessError> {
+        self.rows = row;
+        self.line = line;
+        self.apply_outcome(record)
+    }
+
+    /// Take the transaction identifier `tx` as taken by a record applied by
+    /// another engine, for the records creating it to be skipped as duplicates,
+    /// see [`ConcurrentEngine`](crate::ConcurrentEngine).
+    pub(crate) fn reserve_taken(&mut self, tx: TxnID) {
+        self.reserved.insert(tx);
+    }
+
+    /// Remove the `client`'s account in the `currency`, e.g. for another engine
+    /// to apply a transfer to it.
+    pub(crate) fn remove_account(
+        &mut self,
+        client: ClientID,
+        currency: Currency,
+    ) -> Option<Account> {
+        self.accounts.remove(client, currency)
+    }
+
+    /// Insert the `account`, e.g. one [removed](PaymentEngine::remove_account) from
+    /// another engine.
+    pub(crate) fn insert_account(&mut self, account: Account) {
+        self.accounts.insert(account);
+    }
+
+    /// Read the records contained in the `reader` in CSV format, handing each
+    /// of them over to the `visit` callback along with the headers.
+    ///
+    /// The records are read in JSON Lines format instead, if so configured, see
+    /// [`ProcessOptions::input_format`].
+    fn read<R, F>(&mut self, reader: R, visit: F) -> Result<(), ProcessError>
+    where
+        R: Read,
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        let (format, reader) = Self::input(self.options.input_format, reader)?;
+        self.read_as(format, reader, visit)
+    }
+
+    /// Format of the input in the `reader` as per the `format` configured, see [`ProcessOptions::input_format`],
+    /// along with the input to be read, see [`PaymentEngine::read_as`].
+    ///
+    /// The format is never [`InputFormat::Auto`], which is told from the input
+    /// itself, see [`crate::json::sniff`].
+    fn input<R>(format: InputFormat, reader: R) -> Result<(InputFormat, impl BufRead), ProcessError>
+    where
+        R: Read,
+    {
+        let reader = BufReader::new(reader);
+        match format {
+            #[cfg(feature = "json")]
+            InputFormat::Auto => {
+                let mut reader = reader;
+                let (json, start) = crate::json::sniff(&mut reader)?;
+                let format = if json {
+                    InputFormat::JsonLines
+                } else {
+                    InputFormat::Csv
+                };
+                Ok((format, std::io::Cursor::new(start).chain(reader)))
+            }
+            format => Ok((format, std::io::Cursor::new(Vec::new()).chain(reader))),
+        }
+    }
+
+    /// Read the records contained in the `reader` in the `format`, see [`PaymentEngine::read`].
+    fn read_as<R, F>(
+        &mut self,
+        format: InputFormat,
+        reader: R,
+        visit: F,
+    ) -> Result<(), ProcessError>
+    where
+        R: BufRead,
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        match format {
+            #[cfg(feature = "json")]
+            InputFormat::JsonLines | InputFormat::Auto => self.read_json(reader, visit),
+            InputFormat::Csv => self.read_csv(reader, visit),
+        }
+    }
+
+    /// Read the records contained in the `reader` in CSV format, see [`PaymentEngine::read`].
+    fn read_csv<R, F>(&mut self, reader: R, mut visit: F) -> Result<(), ProcessError>
+    where
+        R: Read,
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        let mut rdr = self.csv_reader(reader);
+        let headers = self.csv_headers(&mut rdr)?;
+        let mut reading = Reading::new(headers, rdr.position().byte());
+        let mut raw = csv::StringRecord::new();
+        while rdr.read_record(&mut raw)? {
+            if !self.admit()? {
+                break;
+            }
+            reading.consumed = rdr.position().byte();
+            let result = self.read_record(&mut reading, &mut raw, &mut visit);
+            self.collect_issue(result)?;
+        }
+        self.finish_reading(reading, visit)
+    }
+
+    /// CSV reader of the `reader` configured as per the options.
+    fn csv_reader<R>(&self, reader: R) -> csv::Reader<R>
+    where
+        R: Read,
+    {
+        crate::reader_builder()
+            .delimiter(self.options.delimiter)
+            .quote(self.options.quote)
+            .has_headers(self.options.has_headers)
+            .comment(self.options.comment)
+            .from_reader(reader)
+    }
+
+    /// Headers of the input renamed as per the [`ProcessOptions::column_map`],
+    /// or the [`COLUMNS`](crate::COLUMNS) if there are none, see [`ProcessOptions::has_headers`].
+    fn csv_headers<R>(&self, rdr: &mut csv::Reader<R>) -> Result<csv::StringRecord, ProcessError>
+    where
+        R: Read,
+    {
+        match self.options.has_headers {
+            true => Ok(self.options.column_map.apply(rdr.headers()?)),
+            false => Ok(crate::COLUMNS.into_iter().collect()),
+        }
+    }
+
+    /// Read the records contained in the `reader` in JSON Lines format, see
+    /// [`PaymentEngine::read`].
+    #[cfg(feature = "json")]
+    fn read_json<R, F>(&mut self, mut reader: R, mut visit: F) -> Result<(), ProcessError>
+    where
+        R: BufRead,
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        // the fields are renamed as they are read, see `json::read_line`
+        let mut reading = Reading::new(crate::COLUMNS.into_iter().collect(), 0);
+        let mut line = String::new();
+        let mut raw = csv::StringRecord::new();
+        let mut position = csv::Position::new();
+        let mut line_no = 0;
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            line_no += 1;
+            reading.consumed += read as u64;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if !self.admit()? {
+                break;
+            }
+            self.line = Some(line_no);
+            let result = match crate::json::read_line(
+                &line,
+                self.rows,
+                Some(line_no),
+                &self.options.column_map,
+                &mut raw,
+            ) {
+                Ok(()) => {
+                    position.set_line(line_no);
+                    raw.set_position(Some(position.clone()));
+                    self.read_record(&mut reading, &mut raw, &mut visit)
+                }
+                // none of the fields is known, but the reason is
+                Err(err) => Err(self.reject_raw(&csv::StringRecord::new(), &reading.headers, err)),
+            };
+            self.collect_issue(result)?;
+        }
+        self.finish_reading(reading, visit)
+    }
+
+    /// Collect the issue with the current record if the `result` of reading it
+    /// is a record error, and the engine is [validating](PaymentEngine::validate),
+    /// or a parse error, and the engine is [lenient](ProcessOptions::lenient).
+    pub(crate) fn collect_issue(
+        &mut self,
+        result: Result<(), ProcessError>,
+    ) -> Result<(), ProcessError> {
+        let err = match result {
+            Err(err) if self.issues.is_some() && err.is_record_error() => err,
+            Err(err) if self.options.lenient && err.is_parse_error() => err,
+            result => return result,
+        };
+        let kind = match &err {
+            ProcessError::InvalidAmount(_)
+            | ProcessError::AmountRejected { .. }
+            | ProcessError::PrecisionLoss { .. } => IssueKind::InvalidAmount,
+            ProcessError::Rejected { reason, .. } => {
+                IssueKind::of_skipped(*reason).unwrap_or(IssueKind::Rejected)
+            }
+            _ => IssueKind::Malformed,
+        };
+        let issue = Issue {
+            row: self.rows,
+            line: self.line,
+            kind,
+            message: err.to_string(),
+        };
+        match &mut self.issues {
+            Some(issues) => issues.push(issue),
+            None => {
+                emit!(warn, row = self.rows, line = self.line, %err, "malformed record skipped");
+                self.report.malformed.push(issue);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand the `raw` record read over to the `visit` callback, unless deferred,
+    /// see [`PaymentEngine::read`].
+    fn read_record<F>(
+        &mut self,
+        reading: &mut Reading,
+        raw: &mut csv::StringRecord,
+        visit: &mut F,
+    ) -> Result<(), ProcessError>
+    where
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        self.report_progress(reading.consumed, false);
+        self.line = raw.position().map(|position| position.line());
+        crate::pad_record(raw, &reading.headers);
+        let record = match self.parse_record(reading, raw) {
+            Ok(record) => record,
+            Err(err) => return Err(self.reject_raw(raw, &reading.headers, err)),
+        };
+        let headers = &reading.headers;
+        if self.options.two_pass && matches!(record.inner, RecordInner::DisputeRecord(_)) {
+            reading.deferred.push((self.rows, self.line, record));
+            return Ok(());
+        }
+        if let Some(window) = self.options.reorder_window {
+            reading.timestamp = record.timestamp.or(reading.timestamp);
+            reading.held.push(Reverse(Held {
+                timestamp: reading.timestamp.unwrap_or_default(),
+                routed: (self.rows, self.line, record),
+            }));
+            if reading.held.len() > window
+                && let Some(Reverse(held)) = reading.held.pop()
+            {
+                self.visit_at(held.routed, &reading.headers, visit)?;
+            }
+            return Ok(());
+        }
+        visit(self, headers, record)?;
+        if !self.options.two_pass && self.checkpointer.as_mut().is_some_and(|c| c.due()) {
+            self.checkpoint(reading.header_end, reading.consumed)?;
+        }
+        Ok(())
+    }
+
+    /// Deserialize the `raw` record, warning about (or rejecting) the precision
+    /// of its amount being lost.
+    fn parse_record(
+        &mut self,
+        reading: &Reading,
+        raw: &csv::StringRecord,
+    ) -> Result<Record, ProcessError> {
+        let record = crate::deserialize_record(
+            raw,
+            &reading.headers,
+            self.rows,
+            self.options.precision,
+            self.options.rounding,
+        )?;
+        if let RecordInner::TxnRecord(txn) = &record.inner
+            && let Some(amount) = reading.amount_idx.and_then(|idx| raw.get(idx))
+            && Amount::exceeds_places(amount, self.options.precision)
+        {
+            if self.options.reject_precision_loss {
+                return Err(ProcessError::PrecisionLoss {
+                    row: self.rows,
+                    line: self.line,
+                    tx: txn.tx,
+                    amount: amount.to_string(),
+                });
+            }
+            emit!(warn, row = self.rows, tx = txn.tx, amount, "precision loss");
+            self.report.warnings.push(Warning {
+                row: self.rows,
+                kind: WarningKind::PrecisionLoss {
+                    tx: txn.tx,
+                    amount: amount.to_string(),
+                },
+            });
+        }
+        Ok(record)
+    }
+
+    /// Write the `raw` record that cannot be read out to the rejects (if
+    /// any), along with the `err`, which is handed back, unless the writing
+    /// fails.
+    fn reject_raw(
+        &mut self,
+        raw: &csv::StringRecord,
+        headers: &csv::StringRecord,
+        err: ProcessError,
+    ) -> ProcessError {
+        if let Some(rejects) = &mut self.rejects
+            && let Err(failed) = rejects.write(self.rows, Rejects::raw_row(raw, headers), &err)
+        {
+            return failed;
+        }
+        err
+    }
+
+    /// Apply the deferred records once the input is exhausted, see [`PaymentEngine::read`].
+    fn finish_reading<F>(&mut self, reading: Reading, mut visit: F) -> Result<(), ProcessError>
+    where
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        let mut held = reading.held;
+        while let Some(Reverse(record)) = held.pop() {
+            self.visit_at(record.routed, &reading.headers, &mut visit)?;
+        }
+        for routed in reading.deferred {
+            self.visit_at(routed, &reading.headers, &mut visit)?;
+        }
+        self.txns.flush()?;
+        self.checkpoint(reading.header_end, reading.consumed)?;
+        self.report_progress(reading.consumed, true);
+        self.bytes_read += reading.consumed;
+        Ok(())
+    }
+
+    /// Hand the `routed` record over to the `visit` callback.
+    ///
+    /// The record is applied as if it was at its original row, for the row to
+    /// be reported correctly.
+    fn visit_at<F>(
+        &mut self,
+        (row, line, record): Routed,
+        headers: &csv::StringRecord,
+        visit: &mut F,
+    ) -> Result<(), ProcessError>
+    where
+        F: FnMut(&mut Self, &csv::StringRecord, Record) -> Result<(), ProcessError>,
+    {
+        let (rows, current) = (self.rows, self.line);
+        self.rows = row;
+        self.line = line;
+        let result = visit(self, headers, record);
+        self.rows = rows;
+        self.line = current;
+        result
+    }
+
+    /// Write the accounts to the `writer` in CSV format, or else as per
+    /// [`ProcessOptions::output_format`].
+    ///
+    /// By default, accounts are written ordered by client identifier (and
+    /// currency, if any), so that the output is reproducible, while with
+    /// [`ProcessOptions::sorted_output`] disabled, they are written in no
+    /// particular order. The `closed` column is only written if some account
+    /// has been closed, see [`ProcessOptions::closed_column`].
+    pub fn write<W>(&self, writer: W) -> Result<(), ProcessError>
+    where
+        W: Write,
+    {
+        // the currency column is only there if the input had one, so that
+        // the single currency users are not affected
+        let with_currency = self
+            .accounts
+            .iter()
+            .any(|account| !account.currency.is_implicit());
+        // and likewise the closed column, see `ProcessOptions::closed_column`
+        let with_closed = self.accounts.iter().any(|account| account.closed);
+        // the same client can hold accounts in several currencies, hence
+        // the tie-break on the currency code
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        if !self.options.sorted_output {
+            return self.write_accounts(writer, accounts, with_currency, with_closed);
+        }
+        accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+        debug_assert!(
+            accounts.windows(2).all(|pair| {
+                (pair[0].client, pair[0].currency) != (pair[1].client, pair[1].currency)
+            }),
+            "each account to be written at most once"
+        );
+        self.write_accounts(writer, accounts, with_currency, with_closed)
+    }
+
+    /// Write the accounts to the file at the `path`, see [`PaymentEngine::write`].
+    ///
+    /// The accounts are written to a temporary file next to the `path` first,
+    /// which then replaces the file, so that the previous file is left intact
+    /// should the writing fail or the process crash.
+    pub fn write_file<P>(&self, path: P) -> Result<(), ProcessError>
+    where
+        P: AsRef<Path>,
+    {
+        crate::checkpoint::replace(path.as_ref(), |file| self.write(file))
+    }
+
+    fn write_accounts<W>(
+        &self,
+        writer: W,
+        accounts: Vec<&Account>,
+        with_currency: bool,
+        with_closed: bool,
+    ) -> Result<(), ProcessError>
+    where
+        W: Write,
+    {
+        let mut wrt = RowWriter::new(writer, &self.options);
+        for account in accounts {
+            let row = AccountRow::new(account, with_currency, with_closed, &self.options);
+            wrt.write(&row)?;
+        }
+        wrt.finish()
+    }
+
+    /// Accounts known to this engine, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.iter()
+    }
+
+    /// The `client`'s account, if any.
+    ///
+    /// This is the account in the implicit currency, i.e. the one used for the
+    /// records not specifying a currency, see [`PaymentEngine::accounts`] otherwise.
+    pub fn account(&self, client: ClientID) -> Option<&Account> {
+        self.accounts.get(client, Currency::default())
+    }
+
+    /// The `client`'s accounts in all the currencies, ordered by currency.
+    pub fn balances(&self, client: ClientID) -> Vec<&Account> {
+        let mut accounts: Vec<_> = self
+            .accounts
+            .iter()
+            .filter(|account| account.client == client)
+            .collect();
+        accounts.sort_unstable_by_key(|account| account.currency);
+        accounts
+    }
+
+    /// The transaction `tx` along with its dispute state, if retained.
+    ///
+    /// Only the deposits, withdrawals and transfers are retained, and for as
+    /// long as they can be disputed, see [`PaymentEngine::clear_finalized_transactions`].
+    /// An error is returned if the store fails to read the transaction.
+    pub fn transaction(&self, tx: TxnID) -> Result<Option<TxnRecord>, ProcessError> {
+        self.txns.get(tx)
+    }
+
+    /// Lift the lock of the `client`'s account in the `currency`, returning
+    /// whether it was locked.
+    ///
+    /// Unlike the `unlock` records, this is not subject to [`ProcessOptions::allow_unlock`],
+    /// since it is up to the caller to unlock an account only after it has been
+    /// reviewed. A closed account stays as is. The charge backs are no longer
+    /// counted towards locking the account again, see [`Account::unlock`].
+    ///
+    /// The unlock is counted in [`ProcessReport::unlocks`], written to the audit
+    /// log (if any), see [`PaymentEngine::write_audit_to`], which is the only way
+    /// for it to fail, and reported to the observers, see [`EventObserver::on_account_unlocked`].
+    pub fn unlock(&mut self, client: ClientID, currency: Currency) -> Result<bool, ProcessError> {
+        let pending = self
+            .audit
+            .as_ref()
+            .map(|audit| audit.before_operation("unlock", client, &*self.accounts));
+        match self.accounts.get_mut(client, currency) {
+            Some(account) if account.locked && !account.closed => {
+                emit!(info, client, currency = %currency, "account unlocked");
+                account.unlock();
+            }
+            _ => return Ok(false),
+        }
+        self.report.unlocks += 1;
+        if let (Some(audit), Some(pending)) = (&mut self.audit, pending) {
+            audit.write(self.rows, pending, &*self.accounts)?;
+        }
+        let event = TxnEvent {
+            row: self.rows,
+            line: None,
+            client,
+            tx: None,
+            amount: None,
+            outcome: TxnOutcome::Applied,
+        };
+        let account = self
+            .accounts
+            .get(client, currency)
+            .expect("account unlocked");
+        for observer in &mut self.observers {
+            observer.on_account_unlocked(&event, account);
+        }
+        Ok(true)
+    }
+
+    /// Consume the engine, returning the accounts in no particular order.
+    pub fn into_accounts(mut self) -> impl Iterator<Item = Account> {
+        self.accounts.drain().into_iter()
+    }
+
+    /// Check the records contained in the `reader`, reporting the issues found
+    /// with them rather than failing at the first one.
+    ///
+    /// The records are processed as with [`PaymentEngine::process`], while the
+    /// records that do not conform to the schema or have an invalid amount, and
+    /// the ones skipped for a duplicate transaction or an invalid reference (e.g.
+    /// a dispute of an unknown transaction) are reported along with their lines.
+    /// In the strict mode, the records rejected are reported likewise. The
+    /// processing still fails on an input that cannot be read.
+    pub fn validate<R>(&mut self, reader: R) -> Result<ValidationReport, ProcessError>
+    where
+        R: Read,
+    {
+        let rows = self.rows;
+        self.issues = Some(Vec::new());
+        let mut skipped = Vec::new();
+        let result = self.process_with_events(reader, |event| {
+            let reason = match event.outcome {
+                TxnOutcome::Skipped(reason) => reason,
+                TxnOutcome::UnknownTransaction => SkipReason::UnknownTransaction,
+                TxnOutcome::Applied | TxnOutcome::Ignored => return,
+            };
+            if let Some(kind) = IssueKind::of_skipped(reason) {
+                let location = crate::error::location(event.row, event.line);
+                skipped.push(Issue {
+                    row: event.row,
+                    line: event.line,
+                    kind,
+                    message: format!(
+                        "{location}: record of client {} skipped: {reason}",
+                        event.client
+                    ),
+                });
+            }
+        });
+        let mut issues = self.issues.take().expect("validating");
+        result?;
+        issues.extend(skipped);
+        issues.sort_by_key(|issue| issue.row);
+        Ok(ValidationReport {
+            records: self.rows - rows,
+            issues,
+        })
+    }
+
+    /// Overview of the accounts, e.g. for an operator to sanity check the input
+    /// before the accounts are written out.
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary {
+            records: self.report.records,
+            accounts: 0,
+            locked: 0,
+            held: BTreeMap::new(),
+            places: self.options.precision,
+        };
+        for account in self.accounts.iter() {
+            summary.accounts += 1;
+            summary.locked += usize::from(account.locked);
+            let held = summary.held.entry(account.currency).or_default();
+            *held = held.saturating_add(account.held);
+        }
+        summary
+    }
+
+    /// Compare the accounts with the expected ones read from the `reader`,
+    /// e.g. the output of another engine the input has been processed with.
+    ///
+    /// The expected accounts are in the output CSV format (with the columns in
+    /// any order, and those other than the `client`, the `currency`, the funds
+    /// and the `locked` flag ignored), at the same [`ProcessOptions::precision`].
+    /// The `closed` flag is not compared, since another engine need not close
+    /// the accounts, and closing one changes neither its funds nor its lock
+    /// state. An account listed more than once is [`ProcessError::Malformed`].
+    /// The differences are returned ordered by client, see [`Discrepancy`].
+    pub fn compare_balances<R>(&self, reader: R) -> Result<Vec<Discrepancy>, ProcessError>
+    where
+        R: Read,
+    {
+        let expected = crate::balances::read(reader, &self.options)?;
+        Ok(crate::balances::compare(&expected, self.accounts.iter()))
+    }
+
+    /// Verify the accounting invariants.
+    ///
+    /// For each account, its total funds should be the available plus held ones,
+    /// and the held funds should not be negative, while they (along with the
+    /// [`Account::shortfall`]) should match the amounts of its transactions
+    /// currently under dispute (or the ones transferred to it). This catches
+    /// accounting drift before the output is trusted.
+    pub fn reconcile(&self) -> ReconcileReport {
+        let mut disputed: HashMap<(ClientID, Currency), Amount> = HashMap::new();
+        self.txns.for_each(&mut |txn| {
+            if txn.state == TxnState::Disputed {
+                // the funds of a disputed transfer are held by its recipient
+                let client = txn.to_client.unwrap_or(txn.client);
+                // a sum out of range cannot be held, and so is reported as is
+                let sum = disputed.entry((client, txn.currency)).or_default();
+                *sum = sum.saturating_add(txn.disputed);
+            }
+        });
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_unstable_by_key(|account| (account.client, account.currency));
+        let mut report = ReconcileReport::default();
+        for account in accounts {
+            report.violations.extend(Violation::of_account(account));
+            let disputed = disputed
+                .remove(&(account.client, account.currency))
+                .unwrap_or_default();
+            if account.held.checked_add(account.shortfall) != Some(disputed) {
+                report.violations.push(Violation::HeldMismatch {
+                    client: account.client,
+                    currency: account.currency,
+                    held: account.held,
+                    disputed,
+                });
+            }
+        }
+        // disputed transactions of the clients who do not have an account
+        let mut orphans: Vec<_> = disputed.into_iter().collect();
+        orphans.sort_unstable_by_key(|(key, _)| *key);
+        for ((client, currency), disputed) in orphans {
+            report.violations.push(Violation::HeldMismatch {
+                client,
+                currency,
+                held: Amount::default(),
+                disputed,
+            });
+        }
+        report
+    }
+
+    #[cfg(test)]
+    pub(crate) fn account_mut(&mut self, client: ClientID) -> Option<&mut Account> {
+        self.accounts.get_mut(client, Currency::default())
+    }
+
+    /// Combine the `other` engine into this one.
+    ///
+    /// Transactions are moved over as is, while the accounts of the clients
+    /// present in both engines get their balances summed up. The latter is only
+    /// sound if neither of the two accounts is locked: had both shards been
+    /// processed by one engine, the operations from one shard could have been
+    /// rejected due to a charge back from the other shard.
+    ///
+    /// The operation is atomic: if an error is returned, this engine is left
+    /// intact. Note that the rows in the `other` engine's report and client spans
+    /// are kept as is, i.e. relative to the input the `other` engine processed.
+    pub fn merge(&mut self, mut other: PaymentEngine) -> Result<(), MergeError> {
+        let mut txs = Vec::with_capacity(other.txns.len());
+        other.txns.for_each(&mut |txn| txs.push(txn.tx));
+        for tx in txs {
+            if self.txns.contains(tx).map_err(Box::new)? {
+                return Err(MergeError::TxnCollision(tx));
+            }
+        }
+        let mut combined = Vec::new();
+        for account in other.accounts.iter() {
+            if let Some(existing) = self.accounts.get(account.client, account.currency) {
+                if existing.locked || account.locked {
+                    return Err(MergeError::ClientConflict(account.client));
+                }
+                let account = existing
+                    .combined(account)
+                    .map_err(|_| MergeError::Overflow(account.client))?;
+                combined.push(account);
+            }
+        }
+        other
+            .txns
+            .for_each(&mut |txn| self.txns.insert(txn.clone()));
+        for (client, txns) in other.history {
+            self.history.entry(client).or_default().extend(txns);
+        }
+        self.recorded.extend(other.recorded);
+        self.flushed.extend(other.flushed);
+        self.latest = self.latest.max(other.latest);
+        self.applied.extend(other.applied);
+        self.reserved.extend(other.reserved);
+        for (client, span) in other.spans {
+            self.spans
+                .entry(client)
+                .and_modify(|existing| {
+                    existing.first_row = existing.first_row.min(span.first_row);
+                    existing.last_row = existing.last_row.max(span.last_row);
+                })
+                .or_insert(span);
+        }
+        self.report.warnings.extend(other.report.warnings);
+        self.report.skipped.extend(other.report.skipped);
+        self.report.malformed.extend(other.report.malformed);
+        self.report.truncated |= other.report.truncated;
+        self.report.records += other.report.records;
+        self.report.deposits += other.report.deposits;
+        self.report.withdrawals += other.report.withdrawals;
+        self.report.transfers += other.report.transfers;
+        self.report.fees_collected = self
+            .report
+            .fees_collected
+            .saturating_add(other.report.fees_collected);
+        self.report.disputes += other.report.disputes;
+        self.report.ignored_disputes += other.report.ignored_disputes;
+        self.report.locked_skips += other.report.locked_skips;
+        self.report.resets += other.report.resets;
+        self.report.unlocks += other.report.unlocks;
+        for account in other.accounts.drain() {
+            if self
+                .accounts
+                .get(account.client, account.currency)
+                .is_none()
+            {
+                self.accounts.insert(account);
+            }
+        }
+        for account in combined {
+            self.accounts.insert(account);
+        }
+        Ok(())
+    }
+
+    /// Drop the transactions that can no longer be referenced by a dispute.
+    ///
+    /// This is meant for a long-running engine to bound its memory. Reversed
+    /// transactions are always dropped, while the undisputed ones only if they
+    /// are older than [`ProcessOptions::undisputed_window`]. Transactions under
+    /// dispute are always kept. Note that a dropped transaction is no longer
+    /// recognized as a duplicate, unless [`ProcessOptions::idempotent`] is enabled,
+    /// and the further dispute resolution records referencing it are ignored.
+    pub fn clear_finalized_transactions(&mut self) {
+        let rows = self.rows;
+        let window = self.options.undisputed_window;
+        let recorded = &self.recorded;
+        let mut dropped = HashSet::new();
+        self.txns.retain(&mut |txn| {
+            let keep = match txn.state {
+                TxnState::Disputed => true,
+                TxnState::Reversed => false,
+                TxnState::Undisputed => window.is_none_or(|window| {
+                    recorded
+                        .get(&txn.tx)
+                        .is_none_or(|row| rows.saturating_sub(*row) <= window)
+                }),
+            };
+            if !keep {
+                dropped.insert(txn.tx);
+            }
+            keep
+        });
+        self.recorded.retain(|tx, _| !dropped.contains(tx));
+        self.history.retain(|_, entries| {
+            entries.retain(|entry| !dropped.contains(&entry.tx()));
+            !entries.is_empty()
+        });
+    }
+
+    /// Zero out the balances of the `client`'s accounts and unlock them.
+    ///
+    /// This is an administrative operation, e.g. for after a manual reconciliation.
+    /// The transactions still under dispute whose funds the client holds (i.e.
+    /// its own ones, and the transfers it has been credited with) are considered
+    /// reversed, since the funds held for them are gone, and so they cannot be
+    /// resolved or charged back later on. The disputes of the transfers the client
+    /// has sent are resolved instead, releasing the funds their recipients hold.
+    /// The charge backs are no longer counted towards locking the
+    /// accounts again, see [`ProcessOptions::lock_on_chargeback`]. A closed account
+    /// stays closed.
+    ///
+    /// The reset is counted in [`ProcessReport::resets`], written to the audit
+    /// log (if any), see [`PaymentEngine::write_audit_to`], which is the only way
+    /// for it to fail, and reported to the observers, see [`EventObserver::on_account_reset`].
+    ///
+    /// Returns `false` if the client has no accounts.
+    pub fn reset_account(&mut self, client: ClientID) -> Result<bool, ProcessError> {
+        let pending = self
+            .audit
+            .as_ref()
+            .map(|audit| audit.before_operation("reset", client, &*self.accounts));
+        if !self.accounts.iter().any(|account| account.client == client) {
+            return Ok(false);
+        }
+        let (mut held, mut sent) = (Vec::new(), Vec::new());
+        self.txns.for_each(&mut |txn| {
+            if txn.state != TxnState::Disputed {
+                return;
+            }
+            // the funds of a disputed transfer are held by its recipient
+            if txn.to_client.unwrap_or(txn.client) == client {
+                held.push(txn.tx);
+            } else if txn.client == client {
+                sent.push(txn.clone());
+            }
+        });
+        // the recipients are released before anything changes, for the reset
+        // to be applied in full or not at all
+        let mut released = Vec::with_capacity(sent.len());
+        for mut txn in sent {
+            let recipient = txn
+                .to_client
+                .and_then(|to_client| self.accounts.get(to_client, txn.currency))
+                .map(|recipient| {
+                    let mut recipient = recipient.clone();
+                    recipient
+                        .resolve_covered(txn.disputed, txn.shortfall)
+                        .map(|()| recipient)
+                })
+                .transpose()?;
+            txn.disputed = Amount::default();
+            txn.shortfall = Amount::default();
+            txn.state = TxnState::Undisputed;
+            released.push((txn, recipient));
+        }
+        for (txn, recipient) in released {
+            if let Some(recipient) = recipient {
+                self.accounts.insert(recipient);
+            }
+            self.txns.update(txn);
+        }
+        // the currencies of the accounts unlocked by the reset
+        let mut unlocked = Vec::new();
+        for account in self
+            .accounts
+            .iter_mut()
+            .filter(|account| account.client == client)
+        {
+            if account.locked {
+                unlocked.push(account.currency);
+            }
+            account.available = Amount::default();
+            account.held = Amount::default();
+            account.total = Amount::default();
+            account.shortfall = Amount::default();
+            account.chargebacks = 0;
+            account.charged_back = Amount::default();
+            account.locked = false;
+        }
+        emit!(info, client, "account reset");
+        for tx in held {
+            self.txns.set_state(tx, TxnState::Reversed);
+        }
+        self.report.resets += 1;
+        if let (Some(audit), Some(pending)) = (&mut self.audit, pending) {
+            audit.write(self.rows, pending, &*self.accounts)?;
+        }
+        let event = TxnEvent {
+            row: self.rows,
+            line: None,
+            client,
+            tx: None,
+            amount: None,
+            outcome: TxnOutcome::Applied,
+        };
+        for account in self
+            .accounts
+            .iter()
+            .filter(|account| account.client == client)
+        {
+            for observer in &mut self.observers {
+                observer.on_account_reset(&event, account);
+                if unlocked.contains(&account.currency) {
+                    observer.on_account_unlocked(&event, account);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the transaction `tx` has already been applied (or attempted),
+    /// see [`ProcessOptions::idempotent`].
+    pub fn is_applied(&self, tx: TxnID) -> bool {
+        self.applied.contains(&IdempotencyKey::Txn(tx))
+    }
+
+    /// Span of the input rows that referenced the `client`.
+    ///
+    /// Only available if enabled with [`ProcessOptions::client_spans`]. A row is
+    /// the ordinal number (starting from 1) of the record among all the records
+    /// fed to this engine, same as in the [`ProcessReport`], which - for a single
+    /// input - is the CSV record index (the header not being counted).
+    pub fn client_span(&self, client: ClientID) -> Option<ClientSpan> {
+        self.spans.get(&client).copied()
+    }
+
+    /// Transactions of the `client`, including the transfers it has been credited
+    /// with, in the order they have been applied, along with their current state
+    /// (e.g. [`TxnState::Disputed`]).
+    ///
+    /// Only available if enabled with [`ProcessOptions::history`]. The transactions
+    /// are read from the store, and so the ones it has not retained are left out,
+    /// while an error is returned if it fails to read them.
+    pub fn history(&self, client: ClientID) -> Result<Vec<TxnRecord>, ProcessError> {
+        let entries = self.indexed(client)?.into_iter();
+        let txns = entries.filter(|(entry, _)| matches!(entry, HistoryEntry::Txn(_)));
+        Ok(txns.map(|(_, txn)| txn).collect())
+    }
+
+    /// Statement of the `client`'s account, with its transactions timestamped
+    /// within `from` and `to` (both inclusive, if given) and the running balance.
+    ///
+    /// Same as [`PaymentEngine::history`], only available if enabled with
+    /// [`ProcessOptions::history`].
+    pub fn statement(
+        &self,
+        client: ClientID,
+        from: Option<Timestamp>,
+        to: Option<Timestamp>,
+    ) -> Result<Statement, ProcessError> {
+        let entries = self.indexed(client)?;
+        Ok(Statement::new(
+            client,
+            from,
+            to,
+            entries.into_iter(),
+            &self.options,
+        ))
+    }
+
+    /// Entries of the history of the `client`, along with their transactions
+    /// as read from the store, see [`PaymentEngine::history`].
+    fn indexed(&self, client: ClientID) -> Result<Vec<(HistoryEntry, TxnRecord)>, ProcessError> {
+        let entries = self.history.get(&client).into_iter().flatten();
+        // a transaction evicted from the store may have been taken over by
+        // another client's one of the same identifier
+        let party = |txn: &TxnRecord| txn.client == client || txn.to_client == Some(client);
+        entries
+            .filter_map(|&entry| {
+                let txn = self.txns.get(entry.tx()).transpose()?;
+                Some(txn.map(|txn| (entry, txn)))
+            })
+            .filter(|indexed| {
+                indexed.as_ref().is_err() || indexed.as_ref().is_ok_and(|(_, txn)| party(txn))
+            })
+            .collect()
+    }
+
+    /// Add the `txn` to the history of its parties, see [`PaymentEngine::history`].
+    fn index(&mut self, txn: &TxnRecord) {
+        self.index_entry(txn, HistoryEntry::Txn(txn.tx));
+    }
+
+    /// Add the chargeback of the `amount` of the `txn`, at the `timestamp`, to
+    /// the history of its parties, see [`PaymentEngine::statement`].
+    fn index_chargeback(&mut self, txn: &TxnRecord, amount: Amount, timestamp: Option<Timestamp>) {
+        let tx = txn.tx;
+        self.index_entry(
+            txn,
+            HistoryEntry::ChargeBack {
+                tx,
+                amount,
+                timestamp,
+            },
+        );
+    }
+
+    /// Add the `entry` about the `txn` to the history of its parties.
+    ///
+    /// The transactions the store has evicted since are dropped from the history
+    /// of a party every so often, i.e. whenever it doubles in length.
+    fn index_entry(&mut self, txn: &TxnRecord, entry: HistoryEntry) {
+        if !self.options.history {
+            return;
+        }
+        for client in std::iter::once(txn.client).chain(txn.to_client) {
+            let entries = self.history.entry(client).or_default();
+            entries.push(entry);
+            if entries.len() >= PRUNE_HISTORY_AT && entries.len().is_power_of_two() {
+                // the one being indexed is yet to be stored, and the ones the
+                // store fails to tell about are kept, for the failure to be
+                // reported when reading the history
+                let txns = &self.txns;
+                entries.retain(|entry| match txns.get(entry.tx()) {
+                    _ if entry.tx() == txn.tx => true,
+                    Ok(Some(txn)) => txn.client == client || txn.to_client == Some(client),
+                    Ok(None) => false,
+                    Err(_) => true,
+                });
+            }
+        }
+    }
+
+    /// Apply a single record, e.g. one obtained with [`crate::records`].
+    ///
+    /// Note that unlike [`PaymentEngine::process`], the precision loss cannot
+    /// be detected here, since the record's amount has already been parsed.
+    pub fn apply(&mut self, record: Record) -> Result<(), ProcessError> {
+        if !self.admit()? {
+            return Ok(());
+        }
+        self.line = None;
+        self.apply_record(record)
+    }
+
+    /// The options the engine was created with.
+    pub(crate) fn options(&self) -> &ProcessOptions {
+        &self.options
+    }
+
+    /// Apply the `raw` record of the [`COLUMNS`](crate::COLUMNS), e.g. one
+    /// received over the network, telling what became of it.
+    ///
+    /// Returns `None` if the record was not applied since the limit on the number
+    /// of records is reached, see [`ProcessOptions::truncate_at_max_records`].
+    #[cfg(any(feature = "grpc", feature = "kafka"))]
+    pub(crate) fn apply_raw(
+        &mut self,
+        raw: &mut csv::StringRecord,
+    ) -> Result<Option<TxnEvent>, ProcessError> {
+        if !self.admit()? {
+            return Ok(None);
+        }
+        self.apply_admitted(raw).map(Some)
+    }
+
+    /// Apply the `raw` record once counted, see [`PaymentEngine::apply_raw`].
+    #[cfg(any(feature = "grpc", feature = "kafka"))]
+    fn apply_admitted(&mut self, raw: &mut csv::StringRecord) -> Result<TxnEvent, ProcessError> {
+        self.line = None;
+        let headers = crate::COLUMNS.into_iter().collect();
+        crate::pad_record(raw, &headers);
+        let record = crate::deserialize_record(
+            raw,
+            &headers,
+            self.rows,
+            self.options.precision,
+            self.options.rounding,
+        )
+        .map_err(|err| self.reject_raw(raw, &headers, err))?;
+        self.apply_outcome(record)
+    }
+
+    /// Apply the record in the `payload` of a message, either a CSV row of the
+    /// [`COLUMNS`](crate::COLUMNS) or a JSON object, as per [`ProcessOptions::input_format`],
+    /// telling what became of it, see [`PaymentEngine::apply_raw`].
+    #[cfg(feature = "kafka")]
+    pub(crate) fn apply_payload(
+        &mut self,
+        payload: &[u8],
+    ) -> Result<Option<TxnEvent>, ProcessError> {
+        let json = match self.options.input_format {
+            InputFormat::Csv => false,
+            InputFormat::JsonLines => true,
+            InputFormat::Auto => payload.trim_ascii_start().starts_with(b"{"),
+        };
+        if !self.admit()? {
+            return Ok(None);
+        }
+        let mut raw = csv::StringRecord::new();
+        let row = self.rows;
+        let read = if json {
+            std::str::from_utf8(payload)
+                .map_err(|err| ProcessError::Malformed {
+                    row,
+                    line: None,
+                    record: String::from_utf8_lossy(payload).into_owned(),
+                    reason: err.to_string(),
+                })
+                .and_then(|line| {
+                    crate::json::read_line(line, row, None, &self.options.column_map, &mut raw)
+                })
+        } else {
+            crate::reader_builder()
+                .has_headers(false)
+                .delimiter(self.options.delimiter)
+                .from_reader(payload)
+                .read_record(&mut raw)
+                .map(drop)
+                .map_err(ProcessError::from)
+        };
+        if let Err(err) = read {
+            // none of the fields is known, but the reason is
+            let headers = crate::COLUMNS.into_iter().collect();
+            return Err(self.reject_raw(&csv::StringRecord::new(), &headers, err));
+        }
+        self.apply_admitted(&mut raw).map(Some)
+    }
+
+    /// Account for the next record.
+    ///
+    /// Returns `false` if the record should not be applied, since the limit
+    /// on the number of records is reached, see [`ProcessOptions::max_records`].
+    /// The limit applies to all the records fed to the engine so far, and not
+    /// only to the ones of the current call.
+    fn admit(&mut self) -> Result<bool, ProcessError> {
+        if let Some(limit) = self.options.max_records
+            && self.rows - self.replayed >= limit as u64
+        {
+            if self.options.truncate_at_max_records {
+                self.report.truncated = true;
+                return Ok(false);
+            }
+            return Err(ProcessError::TooManyRecords { limit });
+        }
+        self.rows += 1;
+        Ok(true)
+    }
+
+    fn apply_record(&mut self, record: Record) -> Result<(), ProcessError> {
+        if self.rejects.is_some() || self.audit.is_some() || !self.observers.is_empty() {
+            return self.apply_outcome(record).map(drop);
+        }
+        self.apply_counted(record).map(drop)
+    }
+
+    /// Apply the `record`, keeping count of the dispute resolution records
+    /// that did not take effect, and telling what became of it.
+    fn apply_counted(&mut self, record: Record) -> Result<TxnOutcome, ProcessError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "record",
+            row = self.rows,
+            line = self.line,
+            client = record.client(),
+            tx = record.tx()
+        )
+        .entered();
+        let dispute = matches!(record.inner, RecordInner::DisputeRecord(_));
+        let verified = if self.options.verify_invariants {
+            Some((self.parties(&record)?, record.currency()))
+        } else {
+            None
+        };
+        self.report.records += 1;
+        // the record is logged once applied, for the rejected ones to be left out,
+        // as are the replays and the duplicates, which would be skipped all the
+        // same when recovering
+        let logged = self.wal.as_ref().map(|wal| wal.fields(&record));
+        let outcome = self.apply_inner(record)?;
+        if let (Some(wal), Some(fields)) = (&mut self.wal, logged)
+            && !matches!(
+                outcome,
+                TxnOutcome::Skipped(SkipReason::Replayed | SkipReason::DuplicateTransaction)
+            )
+        {
+            wal.append(fields)?;
+        }
+        if let Some((clients, currency)) = verified {
+            self.verify_invariants(&clients, currency)?;
+        }
+        // there are quite a few reasons for a dispute resolution record to not
+        // take effect, and not all of them are worth a skip of its own, while
+        // the ones that are have been counted already
+        if dispute
+            && matches!(
+                outcome,
+                TxnOutcome::Ignored | TxnOutcome::UnknownTransaction
+            )
+        {
+            emit!(debug, "dispute resolution record ignored");
+            self.report.ignored_disputes += 1;
+        }
+        Ok(outcome)
+    }
+
+    /// Apply the `record`, telling what became of it, and notifying the
+    /// observers (if any).
+    fn apply_outcome(&mut self, record: Record) -> Result<TxnEvent, ProcessError> {
+        let (client, tx) = (record.client(), record.tx());
+        let (operation, amount, currency) = match &record.inner {
+            RecordInner::TxnRecord(r) => (Operation::Txn(r.kind), Some(r.amount), r.currency),
+            RecordInner::DisputeRecord(r) => (Operation::Dispute(r.kind), r.amount, r.currency),
+            RecordInner::AccountRecord(r) => (Operation::Account, None, r.currency),
+        };
+        let row = self.rejects.as_ref().map(|rejects| rejects.row(&record));
+        let clients = if self.audit.is_some() || !self.observers.is_empty() {
+            self.parties(&record)?
+        } else {
+            Vec::new()
+        };
+        // along with whether their accounts were locked before the record
+        let parties: Vec<_> = clients
+            .iter()
+            .map(|client| {
+                let account = self.accounts.get(*client, currency);
+                (*client, account.is_some_and(|account| account.locked))
+            })
+            .collect();
+        let pending = (self.audit.as_ref())
+            .map(|audit| audit.before(&record, &clients, currency, &*self.accounts));
+        let start = Instant::now();
+        let outcome = match self.apply_counted(record) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                match (&mut self.rejects, row, &err) {
+                    (Some(rejects), Some(row), ProcessError::Rejected { reason, .. }) => {
+                        rejects.write(self.rows, row, reason)?;
+                    }
+                    // e.g. a transfer to its own client
+                    (Some(rejects), Some(row), err) if err.is_parse_error() => {
+                        rejects.write(self.rows, row, err)?;
+                    }
+                    _ => {}
+                }
+                return Err(err);
+            }
+        };
+        let reason = match outcome {
+            TxnOutcome::Skipped(reason) => Some(reason),
+            TxnOutcome::UnknownTransaction => Some(SkipReason::UnknownTransaction),
+            TxnOutcome::Applied | TxnOutcome::Ignored => None,
+        };
+        if let (Some(rejects), Some(row), Some(reason)) = (&mut self.rejects, row, reason) {
+            rejects.write(self.rows, row, reason)?;
+        }
+        if let (Some(audit), Some(pending), TxnOutcome::Applied) =
+            (&mut self.audit, pending, outcome)
+        {
+            audit.write(self.rows, pending, &*self.accounts)?;
+        }
+        let event = TxnEvent {
+            row: self.rows,
+            line: self.line,
+            client,
+            tx,
+            amount,
+            outcome,
+        };
+        if !self.observers.is_empty() {
+            self.notify(&event, start.elapsed(), operation, currency, &parties);
+        }
+        Ok(event)
+    }
+
+    /// Verify the invariants of the `clients`' accounts in the `currency` right
+    /// after the current record has been applied, see [`ProcessOptions::verify_invariants`].
+    fn verify_invariants(
+        &self,
+        clients: &[ClientID],
+        currency: Currency,
+    ) -> Result<(), ProcessError> {
+        let violation = clients
+            .iter()
+            .filter_map(|&client| self.accounts.get(client, currency))
+            .find_map(Violation::of_account);
+        match violation {
+            Some(violation) => Err(ProcessError::InvariantViolated {
+                row: self.rows,
+                line: self.line,
+                violation,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Clients whose accounts the `record` may change, i.e. its client, along
+    /// with the parties to the transfer it makes or references, if any.
+    fn parties(&self, record: &Record) -> Result<Vec<ClientID>, ProcessError> {
+        let mut clients = vec![record.client()];
+        let txn = match &record.inner {
+            RecordInner::TxnRecord(r) => Some((r.client, r.to_client)),
+            RecordInner::DisputeRecord(r) => {
+                (self.txns.get(r.tx)?).map(|t| (t.client, t.to_client))
+            }
+            RecordInner::AccountRecord(_) => None,
+        };
+        if let Some((client, to_client)) = txn {
+            clients.extend([Some(client), to_client].into_iter().flatten());
+        }
+        clients.sort_unstable();
+        clients.dedup();
+        Ok(clients)
+    }
+
+    /// Call the observers back with the `event` of the record of the `operation`,
+    /// applied in the `elapsed` time, in the `currency`, given the `parties` to
+    /// it along with whether their accounts were locked before it.
+    ///
+    /// Each account the record changed is passed to the observers, e.g. both
+    /// the sender's and the recipient's one of a transfer.
+    fn notify(
+        &mut self,
+        event: &TxnEvent,
+        elapsed: Duration,
+        operation: Operation,
+        currency: Currency,
+        parties: &[(ClientID, bool)],
+    ) {
+        let accounts: Vec<_> = match event.outcome {
+            TxnOutcome::Applied => parties
+                .iter()
+                .filter_map(|(client, locked)| {
+                    let account = self.accounts.get(*client, currency)?;
+                    Some((account, *locked))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        // the funds of a disputed transfer are held by its recipient, while
+        // its sender takes part in a charge back only
+        let transfer = parties.len() > 1;
+        for observer in &mut self.observers {
+            observer.on_record(event);
+            observer.on_processed(event, elapsed);
+            if let TxnOutcome::Skipped(reason) = event.outcome {
+                if operation == Operation::Txn(TxnRecordKind::Withdrawal) {
+                    observer.on_withdrawal_rejected(event, reason);
+                }
+                observer.on_skipped(event, reason);
+                continue;
+            }
+            for (account, locked) in &accounts {
+                let (account, locked) = (*account, *locked);
+                let sender = transfer && account.client == event.client;
+                match operation {
+                    Operation::Txn(TxnRecordKind::Deposit) => observer.on_deposit(event, account),
+                    Operation::Txn(TxnRecordKind::Withdrawal) => {
+                        observer.on_withdrawal(event, account)
+                    }
+                    Operation::Txn(TxnRecordKind::Transfer) if sender => {
+                        observer.on_transfer(event, account)
+                    }
+                    Operation::Txn(TxnRecordKind::Transfer) => {
+                        observer.on_transfer_received(event, account)
+                    }
+                    Operation::Dispute(DisputeRecordKind::Dispute) if !sender => {
+                        observer.on_dispute(event, account)
+                    }
+                    Operation::Dispute(DisputeRecordKind::Resolve) if !sender => {
+                        observer.on_resolve(event, account)
+                    }
+                    Operation::Dispute(DisputeRecordKind::ChargeBack) => {
+                        observer.on_chargeback(event, account)
+                    }
+                    Operation::Dispute(_) | Operation::Account => {}
+                }
+                if account.locked && !locked {
+                    observer.on_account_locked(event, account);
+                } else if !account.locked && locked {
+                    observer.on_account_unlocked(event, account);
+                }
+            }
+        }
+    }
+
+    /// Apply the `record`, telling what became of it.
+    fn apply_inner(&mut self, record: Record) -> Result<TxnOutcome, ProcessError> {
+        if self.options.client_spans {
+            let client = record.client();
+            let row = self.rows;
+            self.spans
+                .entry(client)
+                .and_modify(|span| {
+                    // rows can come out of order, see `ProcessOptions::two_pass`
+                    span.first_row = span.first_row.min(row);
+                    span.last_row = span.last_row.max(row);
+                })
+                .or_insert(ClientSpan {
+                    first_row: row,
+                    last_row: row,
+                });
+        }
+        if let Some(timestamp) = record.timestamp
+            && !self.in_order(&record, timestamp)
+        {
+            return self.skip(record.client(), record.tx(), SkipReason::OutOfOrder);
+        }
+        let client = record.client();
+        let dispute = matches!(record.inner, RecordInner::DisputeRecord(_));
+        let timestamp = record.timestamp;
+        self.latest = self.latest.max(timestamp);
+        let outcome = self.apply_ordered(record)?;
+        // only the records taking effect move the clock on, for e.g. a rejected
+        // withdrawal not to get the records following it skipped as out of order
+        if let Some(timestamp) = timestamp
+            && outcome == TxnOutcome::Applied
+        {
+            self.advance_clock(client, dispute, timestamp);
+        }
+        Ok(outcome)
+    }
+
+    /// Apply the `record` found to be in order, see [`PaymentEngine::apply_inner`].
+    fn apply_ordered(&mut self, record: Record) -> Result<TxnOutcome, ProcessError> {
+        if self.options.idempotent {
+            let key = match &record.inner {
+                RecordInner::TxnRecord(r) => Some((r.client, r.tx, IdempotencyKey::Txn(r.tx))),
+                RecordInner::DisputeRecord(r) => {
+                    Some((r.client, r.tx, IdempotencyKey::Dispute(r.tx, r.kind)))
+                }
+                // replaying an account operation is a no-op on its own
+                RecordInner::AccountRecord(_) => None,
+            };
+            // we are remembering the operation no matter its outcome, since
+            // e.g. a withdrawal rejected due to insufficient funds could succeed
+            // when replayed, which would not make the replay a no-op
+            if let Some((client, tx, key)) = key
+                && !self.applied.insert(key)
+            {
+                return self.skip(client, Some(tx), SkipReason::Replayed);
+            }
+        }
+        let timestamp = record.timestamp;
+        match record.inner {
+            RecordInner::TxnRecord(mut record) => {
+                if self.txns.contains(record.tx)? || self.reserved.contains(&record.tx) {
+                    // transaction identifiers are globally unique, and so we
+                    // are not applying the balance effects of the same transaction
+                    // twice, no matter the state the original one is currently in
+                    let reason = SkipReason::DuplicateTransaction;
+                    return match self.options.on_duplicate {
+                        DuplicatePolicy::Skip if !self.options.strict => {
+                            Ok(TxnOutcome::Skipped(reason))
+                        }
+                        DuplicatePolicy::Reject => {
+                            Err(self.rejected(record.client, Some(record.tx), reason))
+                        }
+                        _ => self.skip(record.client, Some(record.tx), reason),
+                    };
+                }
+                let key = (record.client, record.currency);
+                let Some(fee) = self.options.fees.fee(record.kind, record.amount) else {
+                    return self.skip(record.client, Some(record.tx), SkipReason::Overflow);
+                };
+                match record.kind {
+                    TxnRecordKind::Deposit => {
+                        if let Some(account) = self.accounts.get_mut(key.0, key.1) {
+                            if account.closed {
+                                self.reserve(record.tx);
+                                return self.skip(
+                                    record.client,
+                                    Some(record.tx),
+                                    SkipReason::AccountClosed,
+                                );
+                            }
+                            if account.locked
+                                && self.options.on_locked != LockedAccountPolicy::AllowDeposits
+                            {
+                                // we assume they cannot credit a locked account,
+                                // unless told otherwise
+                                self.reserve(record.tx);
+                                return self.skip_locked(record.client, Some(record.tx));
+                            }
+                            if credit(account, record.amount, self.options.stats).is_err() {
+                                return self.skip(
+                                    record.client,
+                                    Some(record.tx),
+                                    SkipReason::Overflow,
+                                );
+                            }
+                        } else {
+                            let mut account = Account::new(record.client, record.currency);
+                            // a sole deposit cannot overflow an empty account
+                            credit(&mut account, record.amount, self.options.stats)
+                                .expect("empty account");
+                            self.accounts.insert(account);
+                        }
+                    }
+                    TxnRecordKind::Withdrawal => {
+                        if let Some(account) = self.accounts.get_mut(key.0, key.1) {
+                            if account.closed {
+                                self.reserve(record.tx);
+                                return self.skip(
+                                    record.client,
+                                    Some(record.tx),
+                                    SkipReason::AccountClosed,
+                                );
+                            }
+                            if account.locked {
+                                // we assume they cannot debit a locked account
+                                // (similar to the credit operation above)
+                                self.reserve(record.tx);
+                                return self.skip_locked(record.client, Some(record.tx));
+                            }
+                            let Ok(ok) = debit(
+                                account,
+                                record.amount,
+                                fee,
+                                self.options.allow_withdraw_held,
+                                self.options.stats,
+                            ) else {
+                                return self.skip(
+                                    record.client,
+                                    Some(record.tx),
+                                    SkipReason::Overflow,
+                                );
+                            };
+                            if !ok {
+                                // we are not storing the transaction either, since
+                                // there are no funds movements to dispute
+                                return self.skip(
+                                    record.client,
+                                    Some(record.tx),
+                                    SkipReason::InsufficientFunds,
+                                );
+                            }
+                        } else {
+                            // the account was not there in the first place, and there
+                            // is no sense in creating an empty one just to fail the
+                            // withdrawal, since it would then show up in the output
+                            return self.skip(
+                                record.client,
+                                Some(record.tx),
+                                SkipReason::UnknownAccount,
+                            );
+                        }
+                    }
+                    TxnRecordKind::Transfer => {
+                        // the records read are validated the same way, see `deserialize_record`
+                        let Some(to_client) = record
+                            .to_client
+                            .filter(|to_client| *to_client != record.client)
+                        else {
+                            return Err(ProcessError::InvalidTransfer {
+                                row: self.rows,
+                                line: self.line,
+                            });
+                        };
+                        match self.transfer(&record, to_client, fee) {
+                            Ok(()) => {}
+                            Err(SkipReason::AccountLocked) => {
+                                self.reserve(record.tx);
+                                return self.skip_locked(record.client, Some(record.tx));
+                            }
+                            Err(SkipReason::AccountClosed) => {
+                                self.reserve(record.tx);
+                                let reason = SkipReason::AccountClosed;
+                                return self.skip(record.client, Some(record.tx), reason);
+                            }
+                            Err(reason) => {
+                                return self.skip(record.client, Some(record.tx), reason);
+                            }
+                        }
+                    }
+                }
+                emit!(debug, kind = ?record.kind, amount = %record.amount, fee = %fee, "transaction applied");
+                self.report.fees_collected = self.report.fees_collected.saturating_add(fee);
+                match record.kind {
+                    TxnRecordKind::Deposit => self.report.deposits += 1,
+                    TxnRecordKind::Withdrawal => self.report.withdrawals += 1,
+                    TxnRecordKind::Transfer => self.report.transfers += 1,
+                }
+                // this record may be referenced by one of the further dispute
+                // resolution records (if any) so let's store it; note that the
+                // state is not expected in the input, but we are making sure
+                // a fresh transaction never starts off as disputed
+                record.state = TxnState::Undisputed;
+                record.timestamp = timestamp;
+                if self.options.disputes_present {
+                    if self.options.undisputed_window.is_some() {
+                        self.recorded.insert(record.tx, self.rows);
+                    }
+                    self.index(&record);
+                    self.txns.insert(record);
+                }
+            }
+            RecordInner::DisputeRecord(record) => {
+                if !self.options.disputes_present {
+                    // we have not been storing the transactions, and so there
+                    // is no way for us to settle this dispute correctly
+                    return Err(ProcessError::UnexpectedDispute {
+                        row: self.rows,
+                        line: self.line,
+                        tx: record.tx,
+                    });
+                }
+                let key = (record.client, record.currency);
+                if self
+                    .accounts
+                    .get(key.0, key.1)
+                    .is_some_and(|account| account.closed)
+                {
+                    // disputes can no longer be settled on a closed account,
+                    // and so whatever is held there, stays there
+                    return self.skip(record.client, Some(record.tx), SkipReason::AccountClosed);
+                }
+                if self
+                    .accounts
+                    .get(key.0, key.1)
+                    .is_some_and(|account| account.locked)
+                {
+                    // the account has been locked due to a charge back, and we are
+                    // not letting further disputes move its funds around - neither
+                    // the ones against other transactions of this client, nor
+                    // resolutions of the ones already under way
+                    return self.skip(record.client, Some(record.tx), SkipReason::AccountLocked);
+                }
+                let txn = self.txns.get(record.tx)?;
+                if txn.as_ref().is_some_and(|txn| txn.client != record.client) {
+                    // transaction identifiers are globally unique, and so this
+                    // record is referencing a transaction of another client, whose
+                    // account we are not going to touch
+                    return self.skip(record.client, Some(record.tx), SkipReason::ClientMismatch);
+                }
+                if txn
+                    .as_ref()
+                    .is_some_and(|txn| txn.currency != record.currency)
+                {
+                    // the dispute should be settled in the currency of the
+                    // original transaction
+                    return self.skip(record.client, Some(record.tx), SkipReason::CurrencyMismatch);
+                }
+                if record.kind == DisputeRecordKind::Dispute
+                    && txn
+                        .as_ref()
+                        .is_some_and(|txn| !self.disputable(txn, timestamp))
+                {
+                    // the transaction is too old to be disputed, while the
+                    // disputes already under way can still be settled
+                    return self.skip(
+                        record.client,
+                        Some(record.tx),
+                        SkipReason::DisputeWindowElapsed,
+                    );
+                }
+                if let Some(txn) = &txn
+                    && matches!(txn.kind, TxnRecordKind::Transfer)
+                {
+                    return match self.settle_transfer(txn, record.kind, record.amount) {
+                        Ok(true) => {
+                            if record.kind == DisputeRecordKind::ChargeBack {
+                                self.index_chargeback(txn, txn.disputed, timestamp);
+                            }
+                            emit!(debug, kind = ?record.kind, "dispute resolution record applied");
+                            self.report.disputes += 1;
+                            Ok(TxnOutcome::Applied)
+                        }
+                        Ok(false) => Ok(TxnOutcome::Ignored),
+                        Err(reason) => self.skip(record.client, Some(record.tx), reason),
+                    };
+                }
+                let (Some(mut txn), Some(account)) = (txn, self.accounts.get_mut(key.0, key.1))
+                else {
+                    if self.txns.contains(record.tx)? {
+                        // the account of the client who owns this transaction is not
+                        // there (any longer), e.g. since it has been written out
+                        return self.skip(
+                            record.client,
+                            Some(record.tx),
+                            SkipReason::UnknownAccount,
+                        );
+                    }
+                    // the `DisputeRecord` record is referencing a transaction which we
+                    // never encountered before; there is not much we can do about
+                    // it, so we just move on, unless told to flag it
+                    if self.options.strict_ordering {
+                        // with all the transactions applied beforehand, this one
+                        // is not further down the input either
+                        let reason = if self.options.two_pass {
+                            SkipReason::UnknownTransaction
+                        } else {
+                            SkipReason::DisputeBeforeTransaction
+                        };
+                        return self.skip(record.client, Some(record.tx), reason);
+                    }
+                    if self.options.strict {
+                        return self.skip(
+                            record.client,
+                            Some(record.tx),
+                            SkipReason::UnknownTransaction,
+                        );
+                    }
+                    return Ok(TxnOutcome::UnknownTransaction);
+                };
+                match record.kind {
+                    DisputeRecordKind::Dispute => {
+                        let max_cycles = self.options.max_dispute_cycles;
+                        let amount = match dispute(&mut txn, record.amount, max_cycles) {
+                            Ok(Some(amount)) => amount,
+                            // this transaction has already been disputed in full or
+                            // even reversed, and so to guarantee idempotency, we
+                            // simply move on to the next record
+                            Ok(None) => return Ok(TxnOutcome::Ignored),
+                            Err(reason) => {
+                                return self.skip(record.client, Some(record.tx), reason);
+                            }
+                        };
+                        // available can temporarily become negative in this case
+                        // which we consider ok by default, since the `DisputeRecordKind::Resolve`
+                        // can restore the available funds and so we are not locking
+                        // their account (we do only in a change back occurs)
+                        let policy = self.options.on_negative_available;
+                        let result = match txn.kind {
+                            TxnRecordKind::Deposit => hold(account, amount, policy),
+                            TxnRecordKind::Withdrawal => {
+                                account.hold_withdrawal(amount).map(|()| Amount::default())
+                            }
+                            TxnRecordKind::Transfer => unreachable!("settled above"),
+                        };
+                        let shortfall = result.and_then(|shortfall| {
+                            (txn.shortfall.checked_add(shortfall)).ok_or(AmountError::Overflow)
+                        });
+                        match shortfall {
+                            Ok(shortfall) => txn.shortfall = shortfall,
+                            Err(_) => {
+                                return self.skip(
+                                    record.client,
+                                    Some(record.tx),
+                                    SkipReason::Overflow,
+                                );
+                            }
+                        }
+                        self.txns.update(txn);
+                        emit!(debug, kind = ?record.kind, "dispute resolution record applied");
+                        self.report.disputes += 1;
+                    }
+                    DisputeRecordKind::Resolve => {
+                        if txn.state != TxnState::Disputed {
+                            // this transaction has never been disputed in the
+                            // first place or has already been reversed, and so
+                            // we are moving on to the next record
+                            return Ok(TxnOutcome::Ignored);
+                        }
+                        let result = match txn.kind {
+                            TxnRecordKind::Deposit => {
+                                account.resolve_covered(txn.disputed, txn.shortfall)
+                            }
+                            TxnRecordKind::Withdrawal => account.resolve_withdrawal(txn.disputed),
+                            TxnRecordKind::Transfer => unreachable!("settled above"),
+                        };
+                        if result.is_err() {
+                            return self.skip(record.client, Some(record.tx), SkipReason::Overflow);
+                        }
+                        txn.disputed = Amount::default();
+                        txn.shortfall = Amount::default();
+                        txn.state = TxnState::Undisputed;
+                        self.txns.update(txn);
+                        emit!(debug, kind = ?record.kind, "dispute resolution record applied");
+                        self.report.disputes += 1;
+                    }
+                    DisputeRecordKind::ChargeBack => {
+                        if txn.state != TxnState::Disputed {
+                            // similar to `DisputeRecordKind::Resolve`, we can
+                            // only act here if the transaction is under dipute
+                            return Ok(TxnOutcome::Ignored);
+                        }
+                        let result = match txn.kind {
+                            TxnRecordKind::Deposit => {
+                                account.charge_back_covered(txn.disputed, txn.shortfall)
+                            }
+                            TxnRecordKind::Withdrawal => {
+                                account.charge_back_withdrawal(txn.disputed)
+                            }
+                            TxnRecordKind::Transfer => unreachable!("settled above"),
+                        };
+                        if result.is_err() {
+                            return self.skip(record.client, Some(record.tx), SkipReason::Overflow);
+                        }
+                        if self.options.lock_on_chargeback.should_lock(account) {
+                            emit!(info, "account locked");
+                            account.lock();
+                        }
+                        let amount = txn.disputed;
+                        charged_back(&mut txn);
+                        self.index_chargeback(&txn, amount, timestamp);
+                        self.txns.update(txn);
+                        emit!(debug, kind = ?record.kind, "dispute resolution record applied");
+                        self.report.disputes += 1;
+                    }
+                }
+            }
+            RecordInner::AccountRecord(record) => match record.kind {
+                AccountRecordKind::Close => {
+                    let key = (record.client, record.currency);
+                    let Some(account) = self.accounts.get_mut(key.0, key.1) else {
+                        return self.skip(record.client, None, SkipReason::UnknownAccount);
+                    };
+                    if account.closed {
+                        return self.skip(record.client, None, SkipReason::AccountClosed);
+                    }
+                    emit!(info, "account closed");
+                    account.close();
+                }
+                AccountRecordKind::Unlock => {
+                    if !self.options.allow_unlock {
+                        return self.skip(record.client, None, SkipReason::UnlockNotAllowed);
+                    }
+                    let key = (record.client, record.currency);
+                    let Some(account) = self.accounts.get_mut(key.0, key.1) else {
+                        return self.skip(record.client, None, SkipReason::UnknownAccount);
+                    };
+                    if account.closed {
+                        return self.skip(record.client, None, SkipReason::AccountClosed);
+                    }
+                    // unlocking an account which is not locked is a no-op
+                    emit!(info, "account unlocked");
+                    account.unlock();
+                }
+            },
+        }
+        Ok(TxnOutcome::Applied)
+    }
+
+    /// Debit the sender's account (with the `fee` on top) and credit the
+    /// account of the recipient, `to_client`, with the transfer `record`, either
+    /// both or none of them.
+    ///
+    /// Much like a deposit, a transfer opens the recipient's account if need be,
+    /// but unlike one, it credits no locked account, no matter the [`ProcessOptions::on_locked`].
+    fn transfer(
+        &mut self,
+        record: &TxnRecord,
+        to_client: ClientID,
+        fee: Amount,
+    ) -> Result<(), SkipReason> {
+        let Some(sender) = self.accounts.get(record.client, record.currency) else {
+            // similar to a withdrawal, there are no funds to transfer
+            return Err(SkipReason::UnknownAccount);
+        };
+        if sender.closed {
+            return Err(SkipReason::AccountClosed);
+        }
+        if sender.locked {
+            return Err(SkipReason::AccountLocked);
+        }
+        // the accounts are updated on copies, for a failure to credit the
+        // recipient not to leave the sender debited
+        let mut sender = sender.clone();
+        let debited = debit(
+            &mut sender,
+            record.amount,
+            fee,
+            self.options.allow_withdraw_held,
+            self.options.stats,
+        );
+        match debited {
+            Ok(true) => {}
+            Ok(false) => return Err(SkipReason::InsufficientFunds),
+            Err(_) => return Err(SkipReason::Overflow),
+        }
+        let mut recipient = match self.accounts.get(to_client, record.currency) {
+            Some(account) if account.closed => return Err(SkipReason::AccountClosed),
+            Some(account) if account.locked => return Err(SkipReason::AccountLocked),
+            Some(account) => account.clone(),
+            None => Account::new(to_client, record.currency),
+        };
+        credit(&mut recipient, record.amount, self.options.stats)
+            .map_err(|_| SkipReason::Overflow)?;
+        self.accounts.insert(sender);
+        self.accounts.insert(recipient);
+        Ok(())
+    }
+
+    /// Apply the dispute resolution record of the `kind` to the transfer `txn`,
+    /// returning whether it took effect.
+    ///
+    /// The funds of a disputed transfer are held by its recipient, and so
+    /// a charge back reverses both legs of it: the recipient gives up the held
+    /// funds, while the sender is credited with them (and possibly locked, as
+    /// with any other charge back).
+    fn settle_transfer(
+        &mut self,
+        txn: &TxnRecord,
+        kind: DisputeRecordKind,
+        requested: Option<Amount>,
+    ) -> Result<bool, SkipReason> {
+        let recipient = txn
+            .to_client
+            .and_then(|to_client| self.accounts.get(to_client, txn.currency));
+        let (Some(sender), Some(recipient)) =
+            (self.accounts.get(txn.client, txn.currency), recipient)
+        else {
+            return Err(SkipReason::UnknownAccount);
+        };
+        if recipient.closed {
+            return Err(SkipReason::AccountClosed);
+        }
+        if recipient.locked {
+            return Err(SkipReason::AccountLocked);
+        }
+        let (mut sender, mut recipient) = (sender.clone(), recipient.clone());
+        let mut txn = txn.clone();
+        // same as for the other transactions, see `PaymentEngine::apply_inner`
+        let result = match kind {
+            DisputeRecordKind::Dispute => {
+                let max_cycles = self.options.max_dispute_cycles;
+                let Some(amount) = dispute(&mut txn, requested, max_cycles)? else {
+                    return Ok(false);
+                };
+                hold(&mut recipient, amount, self.options.on_negative_available)
+                    .and_then(|shortfall| {
+                        (txn.shortfall.checked_add(shortfall)).ok_or(AmountError::Overflow)
+                    })
+                    .map(|shortfall| txn.shortfall = shortfall)
+            }
+            _ if txn.state != TxnState::Disputed => return Ok(false),
+            DisputeRecordKind::Resolve => {
+                let amount = std::mem::take(&mut txn.disputed);
+                let shortfall = std::mem::take(&mut txn.shortfall);
+                txn.state = TxnState::Undisputed;
+                recipient.resolve_covered(amount, shortfall)
+            }
+            DisputeRecordKind::ChargeBack => {
+                let amount = txn.disputed;
+                let shortfall = std::mem::take(&mut txn.shortfall);
+                charged_back(&mut txn);
+                recipient
+                    .forfeit_covered(amount, shortfall)
+                    .and_then(|()| sender.charge_back_transfer(amount))
+            }
+        };
+        result.map_err(|_| SkipReason::Overflow)?;
+        if kind == DisputeRecordKind::ChargeBack
+            && self.options.lock_on_chargeback.should_lock(&sender)
+        {
+            emit!(info, client = sender.client, "account locked");
+            sender.lock();
+        }
+        self.accounts.insert(sender);
+        self.accounts.insert(recipient);
+        self.txns.update(txn);
+        Ok(true)
+    }
+
+    /// Error rejecting the current record for the `reason`.
+    fn rejected(&self, client: ClientID, tx: Option<TxnID>, reason: SkipReason) -> ProcessError {
+        emit!(error, %reason, "record rejected");
+        ProcessError::Rejected {
+            row: self.rows,
+            line: self.line,
+            client,
+            tx,
+            reason,
+        }
+    }
+
+    /// Whether the `txn` can be disputed by a record issued at the `timestamp`,
+    /// see [`ProcessOptions::dispute_window`].
+    ///
+    /// A record without a timestamp is taken as issued at the latest one seen,
+    /// for it not to escape the window, and the transaction has one to be
+    /// checked in the first place.
+    fn disputable(&self, txn: &TxnRecord, timestamp: Option<Timestamp>) -> bool {
+        let (Some(window), Some(issued)) = (self.options.dispute_window, txn.timestamp) else {
+            return true;
+        };
+        self.options
+            .clock
+            .now(timestamp.or(self.latest))
+            .is_none_or(|now| now.saturating_sub(issued) <= window)
+    }
+
+    /// Whether the `record` issued at the `timestamp` is in order, see
+    /// [`ProcessOptions::timestamp_order`].
+    fn in_order(&self, record: &Record, timestamp: Timestamp) -> bool {
+        // the deferred disputes are out of order by design, see `ProcessOptions::two_pass`
+        if self.options.two_pass && matches!(record.inner, RecordInner::DisputeRecord(_)) {
+            return true;
+        }
+        let last = match self.options.timestamp_order {
+            TimestampOrder::Unchecked => None,
+            TimestampOrder::Global => self.timestamp,
+            TimestampOrder::PerClient => self.timestamps.get(&record.client()).copied(),
+        };
+        last.is_none_or(|last| last <= timestamp)
+    }
+
+    /// Remember the `timestamp` of the record of the `client` which took effect,
+    /// for the further records to be checked against, see [`PaymentEngine::in_order`].
+    fn advance_clock(&mut self, client: ClientID, dispute: bool, timestamp: Timestamp) {
+        if self.options.two_pass && dispute {
+            return;
+        }
+        match self.options.timestamp_order {
+            TimestampOrder::Unchecked => {}
+            TimestampOrder::Global => self.timestamp = Some(timestamp),
+            TimestampOrder::PerClient => {
+                self.timestamps.insert(client, timestamp);
+            }
+        }
+    }
+
+    /// Take the identifier of the transaction `tx` skipped due to the state of
+    /// its account, for the transaction not to be applied once the account
+    /// gets unlocked, e.g. when submitted again.
+    fn reserve(&mut self, tx: TxnID) {
+        // the duplicates are only recognized with the transactions stored
+        if self.options.disputes_present {
+            self.reserved.insert(tx);
+        }
+    }
+
+    /// Skip the current transaction record due to the account being locked, as
+    /// per [`ProcessOptions::on_locked`].
+    fn skip_locked(
+        &mut self,
+        client: ClientID,
+        tx: Option<TxnID>,
+    ) -> Result<TxnOutcome, ProcessError> {
+        let reason = SkipReason::AccountLocked;
+        match self.options.on_locked {
+            LockedAccountPolicy::SkipSilently => {
+                emit!(debug, %reason, "record skipped silently");
+                self.report.locked_skips += 1;
+                Ok(TxnOutcome::Skipped(reason))
+            }
+            LockedAccountPolicy::Error => Err(self.rejected(client, tx, reason)),
+            _ => self.skip(client, tx, reason),
+        }
+    }
+
+    /// Record the current record as skipped for the `reason`.
+    ///
+    /// Fails instead if the `reason` is a referential one (or an overflow, or
+    /// an ordering one) and the engine is in the strict mode, see [`ProcessOptions::strict`].
+    fn skip(
+        &mut self,
+        client: ClientID,
+        tx: Option<TxnID>,
+        reason: SkipReason,
+    ) -> Result<TxnOutcome, ProcessError> {
+        if self.options.strict
+            && (reason.is_referential()
+                || matches!(reason, SkipReason::Overflow | SkipReason::OutOfOrder))
+        {
+            return Err(self.rejected(client, tx, reason));
+        }
+        emit!(warn, %reason, "record skipped");
+        if reason == SkipReason::AccountLocked {
+            self.report.locked_skips += 1;
+        }
+        self.report.skipped.push(Skipped {
+            row: self.rows,
+            client,
+            tx,
+            reason,
+        });
+        Ok(TxnOutcome::Skipped(reason))
+    }
+}
+
+/// Put the `requested` part of the `txn` (or all of what is left of it) under
+/// dispute, returning the amount to hold, or `None` if there is nothing left
+/// to dispute.
+///
+/// A transaction can be disputed in parts, for as long as they add up to no
+/// more than its amount, e.g. with the ones charged back. Disputing it once
+/// resolved starts a new cycle, up to the `max_cycles` (if any).
+fn dispute(
+    txn: &mut TxnRecord,
+    requested: Option<Amount>,
+    max_cycles: Option<u32>,
+) -> Result<Option<Amount>, SkipReason> {
+    if txn.state == TxnState::Reversed {
+        return Ok(None);
+    }
+    let undisputed = txn.undisputed();
+    let amount = requested.unwrap_or(undisputed);
+    if amount > undisputed {
+        return Err(SkipReason::DisputeExceedsAmount);
+    }
+    if amount == Amount::default() {
+        return Ok(None);
+    }
+    if txn.state == TxnState::Undisputed {
+        if max_cycles.is_some_and(|max| txn.cycles >= max) {
+            return Err(SkipReason::DisputeCyclesExceeded);
+        }
+        txn.cycles += 1;
+    }
+    txn.disputed = txn
+        .disputed
+        .checked_add(amount)
+        .ok_or(SkipReason::Overflow)?;
+    txn.state = TxnState::Disputed;
+    Ok(Some(amount))
+}
+
+/// Hold the disputed `amount` on the `account`, as per the `policy` on the
+/// available funds going negative, returning the part that could not be held.
+fn hold(
+    account: &mut Account,
+    amount: Amount,
+    policy: NegativeAvailablePolicy,
+) -> Result<Amount, AmountError> {
+    match policy {
+        NegativeAvailablePolicy::Allow => account.hold(amount).map(|()| Amount::default()),
+        NegativeAvailablePolicy::Clamp => account.hold_covered(amount),
+        NegativeAvailablePolicy::Lock => {
+            account.hold(amount)?;
+            if account.available < Amount::default() {
+                emit!(
+                    info,
+                    client = account.client,
+                    "account locked pending review"
+                );
+                account.lock();
+            }
+            Ok(Amount::default())
+        }
+    }
+}
+
+/// Charge back the part of the `txn` under dispute, reversing the transaction
+/// once all of it has been charged back.
+fn charged_back(txn: &mut TxnRecord) {
+    txn.reversed = txn.reversed.saturating_add(txn.disputed);
+    txn.disputed = Amount::default();
+    txn.state = if txn.undisputed() == Amount::default() {
+        TxnState::Reversed
+    } else {
+        TxnState::Undisputed
+    };
+}
+
+/// Credit the `account`, keeping track of the gross volume if it is a `stats` one.
+fn credit(account: &mut Account, amount: Amount, stats: bool) -> Result<(), AmountError> {
+    let gross = account.gross_deposits.checked_add(amount);
+    let gross = if stats {
+        gross.ok_or(AmountError::Overflow)?
+    } else {
+        account.gross_deposits
+    };
+    account.deposit(amount)?;
+    account.gross_deposits = gross;
+    Ok(())
+}
+
+/// Debit the `account` with the `amount` plus the `fee`, possibly using the held
+/// funds if `including_held`, while keeping track of the gross volume (the fee
+/// not counted) if it is a `stats` one.
+fn debit(
+    account: &mut Account,
+    amount: Amount,
+    fee: Amount,
+    including_held: bool,
+    stats: bool,
+) -> Result<bool, AmountError> {
+    let gross = account.gross_withdrawals.checked_add(amount);
+    let gross = if stats {
+        gross.ok_or(AmountError::Overflow)?
+    } else {
+        account.gross_withdrawals
+    };
+    let fees = account.fees.checked_add(fee).ok_or(AmountError::Overflow)?;
+    let charged = amount.checked_add(fee).ok_or(AmountError::Overflow)?;
+    let ok = if including_held {
+        account.withdraw_including_held(charged)?
+    } else {
+        account.withdraw(charged)?
+    };
+    if ok {
+        account.gross_withdrawals = gross;
+        account.fees = fees;
+    }
+    Ok(ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::{NonZeroU64, NonZeroUsize};
+
+    use super::{HistoryEntry, MergeError, PRUNE_HISTORY_AT, PaymentEngine};
+    use crate::EventObserver;
+    use crate::domain::{
+        Account, Amount, ClientID, Currency, Record, TxnID, TxnRecord, TxnRecordKind, TxnState,
+    };
+    use crate::{
+        AmountError, CheckpointPolicy, ClientSpan, Clock, ColumnMap, DuplicatePolicy, Fee,
+        FeeSchedule, IssueKind, LockPolicy, LockedAccountPolicy, NegativeAvailablePolicy,
+        ProcessError, ProcessOptions, Retention, Rounding, SkipReason, Skipped, TimestampOrder,
+        TxnEvent, TxnOutcome, Violation, Warning, WarningKind,
+    };
+    use crate::{MemoryAccountStore, TxnStore};
+
+    fn engine(input: &[&str]) -> PaymentEngine {
+        let mut engine = PaymentEngine::new();
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        engine
+    }
+
+    fn sorted_accounts(engine: &PaymentEngine) -> Vec<&Account> {
+        let mut accounts: Vec<_> = engine.accounts().collect();
+        accounts.sort_by_key(|account| account.client);
+        accounts
+    }
+
+    #[test]
+    fn merges_disjoint_engines() {
+        let mut left = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    3,       3,      5.0",
+            "dispute,    3,       3,",
+        ]);
+        let right = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    2,       2,      20.0",
+            "withdrawal, 2,       4,      7.5",
+        ]);
+        left.merge(right).unwrap();
+
+        let accounts = sorted_accounts(&left);
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].client, 1);
+        assert_eq!(accounts[0].total, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(accounts[1].client, 2);
+        assert_eq!(accounts[1].available, Amount::try_from_f64(12.5).unwrap());
+        assert_eq!(accounts[1].total, Amount::try_from_f64(12.5).unwrap());
+        assert_eq!(accounts[2].client, 3);
+        assert_eq!(accounts[2].held, Amount::try_from_f64(5.).unwrap());
+
+        // transactions got moved over as well, so that the dispute
+        // started in `left` can be resolved after the merge ...
+        left.process("type, client, tx\nresolve, 3, 3".as_bytes())
+            .unwrap();
+        let accounts = sorted_accounts(&left);
+        assert_eq!(accounts[2].available, Amount::try_from_f64(5.).unwrap());
+        assert_eq!(accounts[2].held, Amount::try_from_f64(0.).unwrap());
+
+        // ... and the ones from `right` can be disputed
+        left.process("type, client, tx\ndispute, 2, 2".as_bytes())
+            .unwrap();
+        let accounts = sorted_accounts(&left);
+        assert_eq!(accounts[1].held, Amount::try_from_f64(20.).unwrap());
+    }
+
+    #[test]
+    fn refuses_to_merge_on_txn_collision() {
+        let mut left = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+        ]);
+        let right = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    2,       1,      20.0",
+        ]);
+        let err = left.merge(right).unwrap_err();
+        assert!(matches!(err, MergeError::TxnCollision(1)));
+        // the engine is left intact
+        let accounts = sorted_accounts(&left);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].total, Amount::try_from_f64(10.).unwrap());
+    }
+
+    #[test]
+    fn refuses_to_merge_locked_account() {
+        let mut left = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+        ]);
+        let right = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       2,      20.0",
+        ]);
+        let err = left.merge(right).unwrap_err();
+        assert!(matches!(err, MergeError::ClientConflict(1)));
+    }
+
+    #[test]
+    fn warns_on_precision_loss() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      5.00009999", // lossy
+            "deposit,    1,       2,      5.00000",    // trailing zeros only
+            "withdrawal, 1,       3,      1.0000001",  // lossy
+            "deposit,    1,       4,      5",
+        ];
+        let engine = engine(&input);
+        assert_eq!(
+            engine.report().warnings,
+            vec![
+                Warning {
+                    row: 1,
+                    kind: WarningKind::PrecisionLoss {
+                        tx: 1,
+                        amount: "5.00009999".to_string()
+                    }
+                },
+                Warning {
+                    row: 3,
+                    kind: WarningKind::PrecisionLoss {
+                        tx: 3,
+                        amount: "1.0000001".to_string()
+                    }
+                },
+            ]
+        );
+        // the extra places are still truncated and the records applied
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(14.).unwrap());
+    }
+
+    #[test]
+    fn rejects_precision_loss_if_configured() {
+        let options = ProcessOptions::new().reject_precision_loss(true);
+
+        let mut engine = PaymentEngine::with_options(options.clone());
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 5.00000\n";
+        engine.process(input.as_bytes()).unwrap();
+        assert!(engine.report().warnings.is_empty());
+
+        let mut engine = PaymentEngine::with_options(options);
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 5.00001\n";
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::PrecisionLoss { row: 1, tx: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_precision_loss_in_scientific_notation() {
+        let options = ProcessOptions::new().reject_precision_loss(true);
+        let mut engine = PaymentEngine::with_options(options.clone());
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.00000e0\n";
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total, 1.);
+
+        let mut engine = PaymentEngine::with_options(options);
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1e-5\n";
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::PrecisionLoss { row: 1, tx: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn rounds_amounts_if_configured() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.00015\ndeposit, 1, 2, 0.0003";
+        let mut engine = PaymentEngine::new();
+        engine.process(input.as_bytes()).unwrap();
+        // the amounts are read exactly, so that `0.0003` is not off by one unit
+        assert_eq!(engine.account(1).unwrap().total.minor_units(), 10004);
+
+        let options = ProcessOptions::new().rounding(Rounding::Bankers);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total.minor_units(), 10005);
+        assert_eq!(engine.report().warnings.len(), 1);
+    }
+
+    #[test]
+    fn skips_overflowing_records() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      900000000000000",
+            "deposit,    1,       2,      22337203685477.5807", // the maximum
+            "deposit,    1,       3,      0.0001",              // overflow
+            "withdrawal, 1,       4,      1",
+            "deposit,    1,       5,      1",
+            "dispute,    1,       4,", // overflow
+        ];
+        let engine = engine(&input);
+        let skipped: Vec<_> = engine
+            .report()
+            .skipped
+            .iter()
+            .map(|s| (s.row, s.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            [(3, SkipReason::Overflow), (6, SkipReason::Overflow)]
+        );
+        // the account is left intact
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available.minor_units(), i64::MAX);
+        assert_eq!(account.held, 0.);
+        assert_eq!(account.total.minor_units(), i64::MAX);
+
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().strict(true));
+        let err = engine.process(input.join("\n").as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::Rejected {
+                row: 3,
+                reason: SkipReason::Overflow,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn ignores_activity_on_closed_account() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "close,      1,,",
+            "deposit,    1,       3,      100.0",
+            "withdrawal, 1,       4,      1.0",
+            "dispute,    1,       2,",
+            "close,      1,,",
+            "close,      2,,",
+        ];
+        let engine = engine(&input);
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(engine.accounts().count(), 1);
+        assert_eq!(account.total, Amount::try_from_f64(15.).unwrap());
+        assert_eq!(account.available, Amount::try_from_f64(15.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(0.).unwrap());
+        assert!(account.closed);
+        assert!(!account.locked);
+
+        let skipped = |row, client, tx, reason| Skipped {
+            row,
+            client,
+            tx,
+            reason,
+        };
+        assert_eq!(
+            engine.report().skipped,
+            vec![
+                skipped(4, 1, Some(3), SkipReason::AccountClosed),
+                skipped(5, 1, Some(4), SkipReason::AccountClosed),
+                skipped(6, 1, Some(2), SkipReason::AccountClosed),
+                skipped(7, 1, None, SkipReason::AccountClosed),
+                skipped(8, 2, None, SkipReason::UnknownAccount),
+            ]
+        );
+
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,closed\n1,15.0000,0.0000,15.0000,false,true\n"
+        );
+    }
+
+    #[test]
+    fn writes_closed_column_if_configured() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\nclose,2,,";
+        let options = ProcessOptions::new().closed_column(true);
+        let mut engine = PaymentEngine::with_options(options.clone());
+        engine
+            .process("type,client,tx,amount\ndeposit,1,1,1.0".as_bytes())
+            .unwrap();
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,closed\n1,1.0000,0.0000,1.0000,false,false\n"
+        );
+
+        // the accounts streamed before any is closed
+        let streamed = |options| {
+            let mut engine = PaymentEngine::with_options(options);
+            let mut output = Vec::new();
+            engine
+                .process_streaming(input.as_bytes(), &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            streamed(options),
+            "client,available,held,total,locked,closed\n\
+             1,1.0000,0.0000,1.0000,false,false\n\
+             2,2.0000,0.0000,2.0000,false,true\n"
+        );
+        assert_eq!(
+            streamed(ProcessOptions::new()),
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n\
+             2,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn does_not_reapply_duplicate_transaction() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,",
+            "deposit,    1,       1,      25.0", // duplicate (skip)
+        ];
+        let mut engine = engine(&input);
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.available, Amount::try_from_f64(0.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(
+            engine.report().skipped,
+            vec![Skipped {
+                row: 3,
+                client: 1,
+                tx: Some(1),
+                reason: SkipReason::DuplicateTransaction
+            }]
+        );
+
+        // the original transaction is still under dispute and resolving it
+        // releases exactly what was held
+        engine
+            .process("type, client, tx\nresolve, 1, 1".as_bytes())
+            .unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.available, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(0.).unwrap());
+    }
+
+    #[test]
+    fn reserves_txn_skipped_on_locked_account() {
+        let mut engine = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+            "deposit,    1,       2,      5.0", // locked (skip)
+            "deposit,    2,       3,      5.0",
+            "close,      2,,",
+            "withdrawal, 2,       4,      1.0", // closed (skip)
+        ]);
+        assert!(engine.unlock(1, Currency::default()).unwrap());
+        let input = "type, client, tx, amount\ndeposit, 1, 2, 5.0\ndeposit, 1, 4, 1.0";
+        engine.process(input.as_bytes()).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(0.).unwrap());
+        let skipped: Vec<_> = engine
+            .report()
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.tx, skipped.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                (Some(2), SkipReason::AccountLocked),
+                (Some(4), SkipReason::AccountClosed),
+                (Some(2), SkipReason::DuplicateTransaction),
+                (Some(4), SkipReason::DuplicateTransaction),
+            ]
+        );
+
+        // and so does a snapshot
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = PaymentEngine::new();
+        restored.restore(snapshot.as_slice()).unwrap();
+        restored.process(input.as_bytes()).unwrap();
+        assert_eq!(restored.account(1).unwrap().total, 0.);
+        assert_eq!(restored.report().skipped.len(), 2);
+    }
+
+    #[test]
+    fn fails_on_dispute_if_declared_absent() {
+        let options = ProcessOptions::new().disputes_present(false);
+
+        let mut engine = PaymentEngine::with_options(options.clone());
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      2.5",
+        ];
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(7.5).unwrap());
+
+        let mut engine = PaymentEngine::with_options(options);
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,",
+        ];
+        let err = engine.process(input.join("\n").as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::UnexpectedDispute {
+                row: 2,
+                line: Some(3),
+                tx: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn ignores_disputes_on_locked_account() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "deposit,    1,       3,      1.0",
+            "dispute,    1,       3,",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",          // account gets locked
+            "dispute,    1,       2,",          // skip
+            "resolve,    1,       3,",          // skip
+            "deposit,    1,       4,      1.0", // skip
+        ];
+        let engine = engine(&input);
+        let account = engine.accounts().next().unwrap();
+        assert!(account.locked);
+        assert_eq!(account.total, Amount::try_from_f64(6.).unwrap());
+        assert_eq!(account.available, Amount::try_from_f64(5.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(1.).unwrap());
+        let skipped: Vec<_> = engine
+            .report()
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.row, skipped.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (7, SkipReason::AccountLocked),
+                (8, SkipReason::AccountLocked),
+                (9, SkipReason::AccountLocked),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_minor_units_if_configured() {
+        #[derive(Debug, Deserialize)]
+        struct Row {
+            client: ClientID,
+            available: Amount,
+            held: Amount,
+            total: Amount,
+            available_units: i64,
+            held_units: i64,
+            total_units: i64,
+        }
+
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.1234",
+            "deposit,    2,       2,      0.0001",
+            "deposit,    2,       3,      7.5",
+            "dispute,    2,       3,",
+        ];
+        let options = ProcessOptions::new().minor_units(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+
+        let mut rows = csv::Reader::from_reader(output.as_slice())
+            .deserialize()
+            .collect::<Result<Vec<Row>, _>>()
+            .unwrap();
+        rows.sort_by_key(|row| row.client);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].total_units, 101234);
+        assert_eq!(rows[1].available_units, 1);
+        assert_eq!(rows[1].held_units, 75000);
+        for row in rows {
+            assert_eq!(row.available_units, row.available.minor_units());
+            assert_eq!(row.held_units, row.held.minor_units());
+            assert_eq!(row.total_units, row.total.minor_units());
+        }
+
+        // the columns are not there by default
+        let mut output = Vec::new();
+        self::engine(&input).write(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("client,available,held,total,locked\n"));
+    }
+
+    #[test]
+    fn stops_at_max_records() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,",
+            "resolve,    1,       1,",
+            "deposit,    1,       2,      10.0",
+        ];
+
+        let options = ProcessOptions::new().max_records(Some(3));
+        let mut engine = PaymentEngine::with_options(options.clone());
+        engine.process(input[..4].join("\n").as_bytes()).unwrap();
+        assert!(!engine.report().truncated);
+        // the limit is on the lifetime of the engine, and not per call
+        let err = engine.process(input.join("\n").as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::TooManyRecords { limit: 3 }));
+
+        let mut engine = PaymentEngine::with_options(options.truncate_at_max_records(true));
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert!(engine.report().truncated);
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.available, Amount::try_from_f64(10.).unwrap());
+    }
+
+    #[test]
+    fn keeps_balances_per_currency() {
+        let input = [
+            "type,       client,  tx,     amount,  currency",
+            "deposit,    1,       1,      10.0,    USD",
+            "deposit,    1,       2,      20.0,    EUR",
+            "withdrawal, 1,       3,      15.0,    USD", // insufficient funds in USD
+            "withdrawal, 1,       4,      15.0,    EUR",
+            "dispute,    1,       1,,              EUR", // currency mismatch (skip)
+            "dispute,    1,       1,,              USD",
+        ];
+        let engine = engine(&input);
+        let mut accounts: Vec<_> = engine.accounts().collect();
+        accounts.sort_by_key(|account| account.currency);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].currency, Currency::new("EUR").unwrap());
+        assert_eq!(accounts[0].total, Amount::try_from_f64(5.).unwrap());
+        assert_eq!(accounts[0].held, Amount::try_from_f64(0.).unwrap());
+        assert_eq!(accounts[1].currency, Currency::new("USD").unwrap());
+        assert_eq!(accounts[1].total, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(accounts[1].held, Amount::try_from_f64(10.).unwrap());
+        let currencies: Vec<_> = engine
+            .balances(1)
+            .iter()
+            .map(|account| account.currency.as_str())
+            .collect();
+        assert_eq!(currencies, ["EUR", "USD"]);
+        assert!(engine.balances(2).is_empty());
+        assert_eq!(
+            engine.report().skipped,
+            vec![
+                Skipped {
+                    row: 3,
+                    client: 1,
+                    tx: Some(3),
+                    reason: SkipReason::InsufficientFunds
+                },
+                Skipped {
+                    row: 5,
+                    client: 1,
+                    tx: Some(1),
+                    reason: SkipReason::CurrencyMismatch
+                }
+            ]
+        );
+
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "1,EUR,5.0000,0.0000,5.0000,false",
+                "1,USD,0.0000,10.0000,10.0000,false",
+                "client,currency,available,held,total,locked",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_implicit_currency_apart_from_named_ones() {
+        let balances = engine(&[
+            "type,       client,  tx,     amount,  currency",
+            "deposit,    2,       1,      10.0,    USD",
+            "deposit,    2,       2,      3.0,",
+            "deposit,    1,       3,      1.0,     EUR",
+            "deposit,    2,       4,      7.0,     EUR",
+            "withdrawal, 2,       5,      5.0,", // insufficient funds (skip)
+            "withdrawal, 2,       6,      5.0,     USD",
+        ]);
+        // the implicit currency goes first
+        let accounts = balances.balances(2);
+        let currencies: Vec<_> = accounts
+            .iter()
+            .map(|account| account.currency.as_str())
+            .collect();
+        assert_eq!(currencies, ["", "EUR", "USD"]);
+        assert_eq!(accounts[0].total, 3.);
+        assert_eq!(accounts[1].total, 7.);
+        assert_eq!(accounts[2].total, 5.);
+        assert_eq!(balances.account(2).unwrap().total, 3.);
+        assert!(balances.account(1).is_none());
+        assert_eq!(balances.report().skipped.len(), 1);
+
+        let mut output = Vec::new();
+        balances.write(&mut output).unwrap();
+        let mut lines: Vec<_> = std::str::from_utf8(&output).unwrap().lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "1,EUR,1.0000,0.0000,1.0000,false",
+                "2,,3.0000,0.0000,3.0000,false",
+                "2,EUR,7.0000,0.0000,7.0000,false",
+                "2,USD,5.0000,0.0000,5.0000,false",
+                "client,currency,available,held,total,locked",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_one_row_per_merged_client() {
+        let mut left = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    2,       1,      10.0",
+            "deposit,    1,       2,      1.0",
+        ]);
+        let right = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    3,       3,      3.0",
+            "deposit,    2,       4,      20.0",
+            "withdrawal, 2,       5,      5.0",
+        ]);
+        left.merge(right).unwrap();
+        let mut output = Vec::new();
+        left.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+                client,available,held,total,locked\n\
+                1,1.0000,0.0000,1.0000,false\n\
+                2,25.0000,0.0000,25.0000,false\n\
+                3,3.0000,0.0000,3.0000,false\n\
+            "
+        );
+    }
+
+    #[test]
+    fn reports_reading_progress() {
+        let updates = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::new();
+        let reported = updates.clone();
+        engine.on_progress(NonZeroU64::new(2).unwrap(), move |progress| {
+            reported
+                .lock()
+                .unwrap()
+                .push((progress.records, progress.bytes));
+        });
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\ndeposit,1,3,1.0\n";
+        engine.process(input.as_bytes()).unwrap();
+        engine.process(input.as_bytes()).unwrap();
+        let len = input.len() as u64;
+        // the second input has been read up to its first record by the 4th record
+        assert_eq!(
+            *updates.lock().unwrap(),
+            vec![(2, 54), (3, len), (4, len + 38), (6, 2 * len)]
+        );
+    }
+
+    #[test]
+    fn reports_progress_of_merged_inputs() {
+        let updates = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::new();
+        let reported = updates.clone();
+        engine.on_progress(NonZeroU64::new(1).unwrap(), move |progress| {
+            reported
+                .lock()
+                .unwrap()
+                .push((progress.records, progress.bytes));
+        });
+        let first = "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,100\n";
+        let second = "type,client,tx,amount,timestamp\ndeposit,1,2,1.0,50\n";
+        engine
+            .process_merged(vec![first.as_bytes(), second.as_bytes()])
+            .unwrap();
+        let len = (first.len() + second.len()) as u64;
+        // the headers of both inputs are read before any of the records
+        assert_eq!(*updates.lock().unwrap(), vec![(1, 83), (2, len)]);
+    }
+
+    #[test]
+    fn summarizes_accounts() {
+        let engine = engine(&[
+            "type,       client,  tx,     amount,  currency",
+            "deposit,    1,       1,      10.0,    USD",
+            "deposit,    1,       2,      5.0,     EUR",
+            "dispute,    1,       2,,              EUR",
+            "deposit,    2,       3,      7.0,     USD",
+            "dispute,    2,       3,,              USD",
+            "chargeback, 2,       3,,              USD",
+        ]);
+        let summary = engine.summary();
+        assert_eq!(summary.records, 6);
+        assert_eq!(summary.accounts, 3);
+        assert_eq!(summary.locked, 1);
+        assert_eq!(
+            summary.to_string(),
+            "records read: 6\n\
+             accounts touched: 3\n\
+             locked accounts: 1\n\
+             total held (EUR): 5.0000\n\
+             total held (USD): 0.0000\n"
+        );
+    }
+
+    #[test]
+    fn compares_balances() {
+        let engine = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      5.0",
+            "dispute,    2,       2,",
+            "chargeback, 2,       2,",
+            "deposit,    3,       3,      1.0",
+        ]);
+        let expected = [
+            "client, available, held, total, locked, closed",
+            "1,      9.5,       0.0,  9.5,   false,  false",
+            "2,      0.0,       0.0,  0.0,   false,  false",
+            "4,      1.0,       0.0,  1.0,   false,  false",
+        ];
+        let discrepancies = engine
+            .compare_balances(expected.join("\n").as_bytes())
+            .unwrap();
+        let discrepancies: Vec<_> = discrepancies.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            discrepancies,
+            [
+                "client 1: available 10.0000 instead of 9.5000 (delta 0.5000)",
+                "client 1: total 10.0000 instead of 9.5000 (delta 0.5000)",
+                "client 2: locked instead of unlocked",
+                "client 3: account not expected",
+                "client 4: account missing",
+            ]
+        );
+
+        // the output of the engine is what it expects
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(engine.compare_balances(output.as_slice()).unwrap(), []);
+
+        let missing = "client,available,total,locked\n1,10.0,10.0,false";
+        assert!(matches!(
+            engine.compare_balances(missing.as_bytes()),
+            Err(ProcessError::Malformed { reason, .. }) if reason == "missing column \"held\""
+        ));
+    }
+
+    #[test]
+    fn compares_balances_at_precision() {
+        let options = ProcessOptions::new().precision(2).unwrap();
+        let mut engine = PaymentEngine::with_options(options);
+        let input = "type,client,tx,amount\ndeposit,1,1,10.5\nwithdrawal,1,2,1.5\n";
+        engine.process(input.as_bytes()).unwrap();
+
+        let expected = "client,available,held,total,locked\n1,9.10,0.00,9.10,false\n";
+        let discrepancies = engine.compare_balances(expected.as_bytes()).unwrap();
+        let displayed: Vec<_> = discrepancies
+            .iter()
+            .map(|d| d.display(2).to_string())
+            .collect();
+        assert_eq!(
+            displayed,
+            [
+                "client 1: available 9.00 instead of 9.10 (delta -0.10)",
+                "client 1: total 9.00 instead of 9.10 (delta -0.10)",
+            ]
+        );
+
+        // an account listed twice is not silently taken for the last one
+        let duplicate = "client,available,held,total,locked\n\
+            1,10.00,0.00,10.00,false\n\
+            1,9.00,0.00,9.00,false\n";
+        assert!(matches!(
+            engine.compare_balances(duplicate.as_bytes()),
+            Err(ProcessError::Malformed { row: 2, line: Some(3), reason, .. })
+                if reason == "duplicate account of client 1"
+        ));
+    }
+
+    #[test]
+    fn verifies_invariants_if_configured() {
+        let options = ProcessOptions::new().verify_invariants(true);
+        let mut engine = PaymentEngine::with_options(options);
+        let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n";
+        engine.process(input.as_bytes()).unwrap();
+
+        let amount = |value| Amount::try_from_f64(value).unwrap();
+        engine.account_mut(1).unwrap().total = amount(11.);
+        // the accounts are only verified once a record changes them
+        let input = "type,client,tx,amount\ndeposit,2,3,1.0\nwithdrawal,1,4,1.0\n";
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::InvariantViolated {
+                line: Some(3),
+                violation: Violation::TotalMismatch { client: 1, .. },
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "line 3: invariant violated, client 1: total 10.0000 is not available 9.0000 plus held 0.0000"
+        );
+
+        let account = engine.account_mut(2).unwrap();
+        account.held = -amount(1.);
+        account.available = amount(7.);
+        let input = "type,client,tx,amount\ndeposit,2,5,1.0\n";
+        assert!(matches!(
+            engine.process(input.as_bytes()),
+            Err(ProcessError::InvariantViolated {
+                violation: Violation::NegativeHeld { client: 2, .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn verifies_invariants_of_restored_accounts() {
+        let input = [
+            "type,       client,  tx,     amount,  to_client",
+            "deposit,    1,       1,      10.0,",
+            "transfer,   1,       2,      4.0,     2",
+            "dispute,    1,       2,,",
+            "resolve,    1,       2,,",
+            "deposit,    2,       3,      1.0,",
+            "dispute,    2,       3,,",
+            "chargeback, 2,       3,,",
+        ]
+        .join("\n");
+        let options = || ProcessOptions::new().verify_invariants(true);
+        let mut engine = PaymentEngine::with_options(options());
+        engine.process(input.as_bytes()).unwrap();
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+
+        // a snapshot tampered with so that the total of the recipient is off
+        let snapshot = String::from_utf8(snapshot).unwrap();
+        let tampered: Vec<_> = snapshot
+            .lines()
+            .map(|line| {
+                let mut fields: Vec<_> = line.split(',').collect();
+                if fields[0] == "account" && fields[1] == "2" {
+                    fields[9] = "90000";
+                }
+                fields.join(",")
+            })
+            .collect();
+        let mut restored = PaymentEngine::with_options(options());
+        restored.restore(tampered.join("\n").as_bytes()).unwrap();
+        // only the accounts a record changes are verified, the recipient's among them
+        let input = "type,client,tx,amount,to_client\ndeposit,1,4,1.0,\n";
+        restored.process(input.as_bytes()).unwrap();
+        let input = "type,client,tx,amount,to_client\ntransfer,1,5,1.0,2\n";
+        assert!(matches!(
+            restored.process(input.as_bytes()),
+            Err(ProcessError::InvariantViolated {
+                violation: Violation::TotalMismatch { client: 2, .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reconciles_balances() {
+        let mut engine = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "dispute,    1,       2,",
+            "deposit,    2,       3,      7.0",
+            "deposit,    3,       4,      1.0",
+            "dispute,    3,       4,",
+            "chargeback, 3,       4,",
+        ]);
+        assert!(engine.reconcile().is_ok());
+
+        let amount = |value| Amount::try_from_f64(value).unwrap();
+        engine.account_mut(1).unwrap().held = amount(4.);
+        engine.account_mut(2).unwrap().total = amount(8.);
+        let report = engine.reconcile();
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::TotalMismatch {
+                    client: 1,
+                    currency: Currency::default(),
+                    available: amount(10.),
+                    held: amount(4.),
+                    total: amount(15.),
+                },
+                Violation::HeldMismatch {
+                    client: 1,
+                    currency: Currency::default(),
+                    held: amount(4.),
+                    disputed: amount(5.),
+                },
+                Violation::TotalMismatch {
+                    client: 2,
+                    currency: Currency::default(),
+                    available: amount(7.),
+                    held: amount(0.),
+                    total: amount(8.),
+                },
+            ]
+        );
+        assert_eq!(
+            report.violations[2].to_string(),
+            "client 2: total 8.0000 is not available 7.0000 plus held 0.0000"
+        );
+    }
+
+    #[test]
+    fn reconciles_funds_out_of_range() {
+        let mut reconciled = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+        ]);
+        let greatest = Amount::from_minor_units(i64::MAX);
+        let account = reconciled.account_mut(1).unwrap();
+        account.available = greatest;
+        account.held = greatest;
+        account.shortfall = greatest;
+        let report = reconciled.reconcile();
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::TotalMismatch {
+                    client: 1,
+                    currency: Currency::default(),
+                    available: greatest,
+                    held: greatest,
+                    total: Amount::try_from_f64(10.).unwrap(),
+                },
+                Violation::HeldMismatch {
+                    client: 1,
+                    currency: Currency::default(),
+                    held: greatest,
+                    disputed: Amount::default(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_column_names() {
+        let input = [
+            "txn_type,   account,  reference,  value",
+            "deposit,    1,        1,          10.123456",
+            "dispute,    1,        1,",
+        ];
+        let columns = ColumnMap::new()
+            .column("type", "txn_type")
+            .column("client", "account")
+            .column("tx", "reference")
+            .column("amount", "value");
+
+        let mut engine = PaymentEngine::new();
+        assert!(engine.process(input.join("\n").as_bytes()).is_err());
+
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().column_map(columns));
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.client, 1);
+        assert_eq!(account.held, Amount::try_from_f64(10.1234).unwrap());
+        assert_eq!(account.total, Amount::try_from_f64(10.1234).unwrap());
+        // the raw amount is looked up by the mapped name as well
+        assert_eq!(engine.report().warnings.len(), 1);
+    }
+
+    #[test]
+    fn ignores_replayed_input() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      50.0", // insufficient funds
+            "deposit,    1,       3,      50.0",
+            "dispute,    1,       1,",
+            "resolve,    1,       1,",
+        ]
+        .join("\n");
+        let options = ProcessOptions::new().idempotent(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.as_bytes()).unwrap();
+        assert!(engine.is_applied(2));
+        assert!(!engine.is_applied(4));
+        let balances = |engine: &PaymentEngine| {
+            let account = engine.accounts().next().unwrap();
+            (account.available, account.held, account.total)
+        };
+        let before = balances(&engine);
+        assert_eq!(before.2, Amount::try_from_f64(60.).unwrap());
+        assert_eq!(engine.report().skipped.len(), 1);
+        assert_eq!(
+            engine.report().skipped[0].reason,
+            SkipReason::InsufficientFunds
+        );
+
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(balances(&engine), before);
+        let skipped = &engine.report().skipped[1..];
+        assert_eq!(skipped.len(), 5);
+        assert!(
+            skipped
+                .iter()
+                .all(|skipped| skipped.reason == SkipReason::Replayed)
+        );
+
+        // the operations applied carry over a snapshot
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = PaymentEngine::with_options(ProcessOptions::new().idempotent(true));
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert!(restored.is_applied(2));
+        restored.process(input.as_bytes()).unwrap();
+        assert_eq!(balances(&restored), before);
+        assert_eq!(restored.report().skipped.len(), 5);
+
+        // while new operations on the same transactions still apply
+        engine
+            .process("type, client, tx\ndispute, 1, 3".as_bytes())
+            .unwrap();
+        assert_eq!(balances(&engine).1, Amount::try_from_f64(50.).unwrap());
+    }
+
+    #[test]
+    fn locks_on_chargeback_per_policy() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      20.0",
+            "deposit,    1,       3,      30.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+            "dispute,    1,       2,",
+            "chargeback, 1,       2,",
+            "dispute,    1,       3,",
+            "chargeback, 1,       3,",
+        ];
+        let amount = |value| Amount::try_from_f64(value).unwrap();
+        // records whether the account is locked and how many charge backs took
+        // place after the first, the second and the third charge back attempt;
+        // once an account is locked, further disputes are not applied
+        let cases = [
+            (LockPolicy::Always, [(true, 1), (true, 1), (true, 1)]),
+            (LockPolicy::Never, [(false, 1), (false, 2), (false, 3)]),
+            (
+                LockPolicy::AfterCount(2),
+                [(false, 1), (true, 2), (true, 2)],
+            ),
+            (
+                LockPolicy::AfterAmount(amount(30.)),
+                [(false, 1), (true, 2), (true, 2)],
+            ),
+            (
+                LockPolicy::AfterAmount(amount(30.0001)),
+                [(false, 1), (false, 2), (true, 3)],
+            ),
+        ];
+        for (policy, expected) in cases {
+            for (attempts, (locked, chargebacks)) in (1..=3).zip(expected) {
+                let options = ProcessOptions::new().lock_on_chargeback(policy);
+                let mut engine = PaymentEngine::with_options(options);
+                let rows = 4 + 2 * attempts;
+                engine.process(input[..rows].join("\n").as_bytes()).unwrap();
+                let account = engine.accounts().next().unwrap();
+                assert_eq!(
+                    account.chargebacks, chargebacks,
+                    "{policy:?} after {attempts}"
+                );
+                assert_eq!(account.locked, locked, "{policy:?} after {attempts}");
+            }
+        }
+    }
+
+    #[test]
+    fn tracks_client_spans() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      10.0",
+            "withdrawal, 1,       3,      5.0",
+            "dispute,    2,       2,",
+            "dispute,    3,       4,", // unknown tx, but the client is still referenced
+            "resolve,    2,       2,",
+            "deposit,    1,       5,      1.0",
+        ];
+        let options = ProcessOptions::new().client_spans(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let span = |first_row, last_row| ClientSpan {
+            first_row,
+            last_row,
+        };
+        assert_eq!(engine.client_span(1), Some(span(1, 7)));
+        assert_eq!(engine.client_span(2), Some(span(2, 6)));
+        assert_eq!(engine.client_span(3), Some(span(5, 5)));
+        assert_eq!(engine.client_span(4), None);
+
+        // row numbers keep counting across batches
+        engine
+            .process("type, client, tx\nchargeback, 3, 4".as_bytes())
+            .unwrap();
+        assert_eq!(engine.client_span(3), Some(span(5, 8)));
+
+        // and are not tracked by default
+        assert_eq!(self::engine(&input).client_span(1), None);
+    }
+
+    #[test]
+    fn tracks_transaction_history() {
+        let input = [
+            "type,       client,  tx,     amount,  to_client",
+            "deposit,    1,       1,      10.0,",
+            "deposit,    2,       2,      10.0,",
+            "transfer,   2,       3,      4.0,     1",
+            "withdrawal, 1,       4,      5.0,",
+            "dispute,    1,       1,,",
+            "withdrawal, 1,       5,      50.0,", // insufficient funds
+        ];
+        let options = ProcessOptions::new().history(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let history = |engine: &PaymentEngine, client| {
+            engine
+                .history(client)
+                .unwrap()
+                .into_iter()
+                .map(|txn| (txn.tx, txn.state))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            history(&engine, 1),
+            [
+                (1, TxnState::Disputed),
+                (3, TxnState::Undisputed),
+                (4, TxnState::Undisputed)
+            ]
+        );
+        assert_eq!(
+            history(&engine, 2),
+            [(2, TxnState::Undisputed), (3, TxnState::Undisputed)]
+        );
+        assert_eq!(history(&engine, 3), []);
+
+        // the history is carried over to a restored engine
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = PaymentEngine::with_options(ProcessOptions::new().history(true));
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert_eq!(history(&restored, 2).len(), 2);
+
+        // and is not tracked by default
+        assert_eq!(history(&self::engine(&input), 1), []);
+    }
+
+    #[test]
+    fn keeps_history_in_order_applied() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       5,      10.0",
+            "deposit,    1,       2,      10.0",
+            "withdrawal, 1,       3,      5.0",
+        ];
+        let options = ProcessOptions::new().history(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let history = |engine: &PaymentEngine, client| {
+            let txns = engine.history(client).unwrap().into_iter();
+            txns.map(|txn| (txn.tx, txn.kind, txn.amount))
+                .collect::<Vec<_>>()
+        };
+        let applied = [
+            (
+                5,
+                TxnRecordKind::Deposit,
+                Amount::try_from_f64(10.).unwrap(),
+            ),
+            (
+                2,
+                TxnRecordKind::Deposit,
+                Amount::try_from_f64(10.).unwrap(),
+            ),
+            (
+                3,
+                TxnRecordKind::Withdrawal,
+                Amount::try_from_f64(5.).unwrap(),
+            ),
+        ];
+        assert_eq!(history(&engine, 1), applied);
+
+        // rather than in the order of the transactions once restored
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = PaymentEngine::with_options(ProcessOptions::new().history(true));
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert_eq!(history(&restored, 1), applied);
+    }
+
+    #[test]
+    fn leaves_evicted_transactions_out_of_history() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      20.0", // evicts the first one
+            "deposit,    2,       1,      30.0",
+        ];
+        let options = ProcessOptions::new()
+            .history(true)
+            .retention(Retention::LastPerClient(1));
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let history = |engine: &PaymentEngine, client| {
+            let txns = engine.history(client).unwrap().into_iter();
+            txns.map(|txn| (txn.client, txn.tx, txn.amount))
+                .collect::<Vec<_>>()
+        };
+        // the identifier taken over by another client is not in the history
+        assert_eq!(
+            history(&engine, 1),
+            [(1, 2, Amount::try_from_f64(20.).unwrap())]
+        );
+        assert_eq!(
+            history(&engine, 2),
+            [(2, 1, Amount::try_from_f64(30.).unwrap())]
+        );
+    }
+
+    #[test]
+    fn prunes_history_of_dropped_transactions() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      20.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+        ];
+        let options = ProcessOptions::new().history(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let txs = |engine: &PaymentEngine| {
+            let entries = engine.history[&1].iter();
+            entries.map(HistoryEntry::tx).collect::<Vec<_>>()
+        };
+        // the chargeback is an entry of its own
+        assert_eq!(txs(&engine), [1, 2, 1]);
+        engine.clear_finalized_transactions();
+        assert_eq!(engine.history[&1], [HistoryEntry::Txn(2)]);
+        let history = engine.history(1).unwrap();
+        assert_eq!(history.iter().map(|txn| txn.tx).collect::<Vec<_>>(), [2]);
+
+        // and prunes the history of the evicted ones as it grows
+        let options = ProcessOptions::new()
+            .history(true)
+            .retention(Retention::LastPerClient(1));
+        let mut engine = PaymentEngine::with_options(options);
+        let deposits = (1..=PRUNE_HISTORY_AT).map(|tx| format!("deposit,1,{tx},1.0"));
+        let input = std::iter::once("type,client,tx,amount".to_string()).chain(deposits);
+        engine
+            .process(input.collect::<Vec<_>>().join("\n").as_bytes())
+            .unwrap();
+        let last = PRUNE_HISTORY_AT as TxnID;
+        assert_eq!(
+            engine.history[&1],
+            [HistoryEntry::Txn(last - 1), HistoryEntry::Txn(last)]
+        );
+        let history = engine.history(1).unwrap();
+        assert_eq!(history.iter().map(|txn| txn.tx).collect::<Vec<_>>(), [last]);
+    }
+
+    #[test]
+    fn does_not_create_account_on_withdrawal() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "withdrawal, 1,       1,      0.0",
+            "dispute,    1,       1,",
+        ];
+        let engine = engine(&input);
+        assert_eq!(engine.accounts().count(), 0);
+        assert_eq!(
+            engine.report().skipped,
+            vec![Skipped {
+                row: 1,
+                client: 1,
+                tx: Some(1),
+                reason: SkipReason::UnknownAccount
+            }]
+        );
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn withdraws_held_funds_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "dispute,    1,       1,",
+            "withdrawal, 1,       3,      12.0",
+        ];
+
+        let engine = engine(&input);
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.available, Amount::try_from_f64(5.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.total, Amount::try_from_f64(15.).unwrap());
+        assert_eq!(
+            engine.report().skipped,
+            vec![Skipped {
+                row: 4,
+                client: 1,
+                tx: Some(3),
+                reason: SkipReason::InsufficientFunds
+            }]
+        );
+
+        let options = ProcessOptions::new().allow_withdraw_held(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.available, Amount::try_from_f64(-7.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.total, Amount::try_from_f64(3.).unwrap());
+        assert!(engine.report().skipped.is_empty());
+
+        // still, the held funds are the limit
+        engine
+            .process("type, client, tx, amount\nwithdrawal, 1, 4, 3.0001".as_bytes())
+            .unwrap();
+        assert_eq!(
+            engine.report().skipped[0].reason,
+            SkipReason::InsufficientFunds
+        );
+    }
+
+    #[test]
+    fn keeps_disputed_transactions_across_batches() {
+        let options = ProcessOptions::new()
+            .undisputed_window(Some(2))
+            .lock_on_chargeback(LockPolicy::Never);
+        let mut engine = PaymentEngine::with_options(options);
+        let batch = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      20.0",
+            "deposit,    1,       3,      30.0",
+            "dispute,    1,       1,",
+            "dispute,    1,       2,",
+            "chargeback, 1,       2,",
+        ];
+        engine.process(batch.join("\n").as_bytes()).unwrap();
+        engine.clear_finalized_transactions();
+        // the disputed one is kept no matter how old, while the reversed one
+        // and the undisputed one recorded out of the window are gone
+        let mut kept = Vec::new();
+        engine.txns.for_each(&mut |txn| kept.push(txn.tx));
+        kept.sort_unstable();
+        assert_eq!(kept, vec![1]);
+
+        let batch = [
+            "type,       client,  tx,     amount",
+            "resolve,    1,       1,",
+            "deposit,    1,       4,      5.0",
+        ];
+        engine.process(batch.join("\n").as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.available, Amount::try_from_f64(45.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(0.).unwrap());
+        assert_eq!(account.total, Amount::try_from_f64(45.).unwrap());
+        assert!(engine.reconcile().is_ok());
+
+        // nothing is final without the window but the reversed transactions
+        let mut engine = PaymentEngine::new();
+        engine.process(batch.join("\n").as_bytes()).unwrap();
+        engine.clear_finalized_transactions();
+        assert!(engine.txns.contains(4).unwrap());
+    }
+
+    #[test]
+    fn retains_transactions_per_policy() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      20.0",
+            "withdrawal, 1,       3,      5.0",
+            "dispute,    1,       1,", // evicted, ignored
+            "dispute,    1,       2,",
+            "dispute,    1,       3,",
+        ];
+        let options = ProcessOptions::new().retention(Retention::LastPerClient(2));
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert_eq!(engine.txns.len(), 2);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 5.);
+        assert_eq!(account.held, 25.);
+        assert!(engine.reconcile().is_ok());
+        // restoring under the same policy keeps all of the retained ones
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let options = ProcessOptions::new().retention(Retention::LastPerClient(2));
+        let mut restored = PaymentEngine::with_options(options);
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert!(restored.txns.contains(2).unwrap() && restored.txns.contains(3).unwrap());
+
+        let options = ProcessOptions::new().retention(Retention::DepositsOnly);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert_eq!(engine.txns.len(), 2);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, -5.);
+        assert_eq!(account.held, 30.);
+    }
+
+    /// Store keeping the transactions in a vector, for the engine to be run
+    /// against something other than the default one.
+    #[derive(Debug, Default)]
+    struct VecTxnStore(Vec<TxnRecord>);
+
+    impl TxnStore for VecTxnStore {
+        fn get(&self, tx: TxnID) -> Result<Option<TxnRecord>, ProcessError> {
+            Ok(self.0.iter().find(|txn| txn.tx == tx).cloned())
+        }
+
+        fn insert(&mut self, txn: TxnRecord) {
+            self.0.push(txn);
+        }
+
+        fn set_state(&mut self, tx: TxnID, state: TxnState) {
+            self.0
+                .iter_mut()
+                .filter(|txn| txn.tx == tx)
+                .for_each(|txn| txn.state = state);
+        }
+
+        fn update(&mut self, txn: TxnRecord) {
+            if let Some(retained) = self.0.iter_mut().find(|retained| retained.tx == txn.tx) {
+                *retained = txn;
+            }
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(&TxnRecord) -> bool) {
+            self.0.retain(|txn| keep(txn));
+        }
+
+        fn for_each(&self, visit: &mut dyn FnMut(&TxnRecord)) {
+            self.0.iter().for_each(visit);
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn runs_against_pluggable_stores() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       1,      10.0", // duplicate
+            "deposit,    2,       2,      20.0",
+            "dispute,    2,       2,",
+        ];
+        let mut engine = PaymentEngine::with_stores(
+            ProcessOptions::new(),
+            VecTxnStore::default(),
+            MemoryAccountStore::default(),
+        );
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert_eq!(engine.txns.len(), 2);
+        assert_eq!(engine.report().skipped.len(), 1);
+        assert_eq!(engine.account(2).unwrap().held, 20.);
+        assert!(engine.reconcile().is_ok());
+    }
+
+    /// Writer remembering what it got on each flush.
+    #[derive(Default)]
+    struct FlushRecorder {
+        pending: Vec<u8>,
+        flushed: Vec<Vec<u8>>,
+    }
+
+    impl std::io::Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if !self.pending.is_empty() {
+                self.flushed.push(std::mem::take(&mut self.pending));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn restores_from_snapshot() {
+        let engine = engine(&[
+            "type,       client,  tx,     amount, currency",
+            "deposit,    1,       1,      10.0,",
+            "deposit,    1,       2,      0.1234, BTC",
+            "deposit,    2,       3,      20.0,",
+            "dispute,    2,       3,,",
+            "deposit,    3,       4,      5.0,",
+            "dispute,    3,       4,,",
+            "chargeback, 3,       4,,",
+        ]);
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+
+        let options = ProcessOptions::new()
+            .idempotent(true)
+            .client_spans(true)
+            .timestamp_order(TimestampOrder::Global);
+        let mut restored = PaymentEngine::with_options(options);
+        let input = "type, client, tx, amount, timestamp\ndeposit, 9, 9, 1.0, 100";
+        restored.process(input.as_bytes()).unwrap();
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert!(restored.account(9).is_none());
+        // nothing is left of the records processed before
+        assert!(restored.applied.is_empty());
+        assert!(restored.client_span(9).is_none());
+        assert_eq!(restored.timestamp, None);
+        assert!(restored.timestamps.is_empty());
+        let mut again = Vec::new();
+        restored.snapshot(&mut again).unwrap();
+        assert_eq!(again, snapshot);
+
+        // the open dispute and the older transactions carry over
+        let batch = [
+            "type,       client,  tx,     amount",
+            "resolve,    2,       3,",
+            "dispute,    1,       1,",
+            "dispute,    3,       4,",
+        ];
+        restored.process(batch.join("\n").as_bytes()).unwrap();
+        assert_eq!(restored.account(1).unwrap().held, 10.);
+        let btc = Currency::new("BTC").unwrap();
+        assert_eq!(restored.accounts.get(1, btc).unwrap().available, 0.1234);
+        assert_eq!(restored.account(2).unwrap().available, 20.);
+        assert_eq!(restored.account(2).unwrap().held, 0.);
+        let account = restored.account(3).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.chargebacks, 1);
+        assert_eq!(account.total, 0.);
+        assert!(restored.reconcile().is_ok());
+
+        // a malformed snapshot leaves the engine as is
+        let snapshot = String::from_utf8(snapshot).unwrap();
+        let malformed = snapshot.replace(",disputed,,", ",pending,,");
+        let err = restored.restore(malformed.as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::InvalidSnapshot { row: 7 }));
+        assert!(
+            restored
+                .accounts()
+                .all(|account| account.client != 2 || account.held == 0.)
+        );
+        let err = restored.restore("kind,client\n".as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::InvalidSnapshot { row: 0 }));
+        // as does the one of another version of the format
+        assert!(snapshot.contains("\nversion,"));
+        let versioned = snapshot.replacen(",3\n", ",4\n", 1);
+        let err = restored.restore(versioned.as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::InvalidSnapshot { row: 0 }));
+
+        // as does the one taken at a higher precision
+        let mut precise = PaymentEngine::with_options(ProcessOptions::new().precision(2).unwrap());
+        let err = precise.restore(snapshot.as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::InvalidSnapshot { row: 2 }));
+        assert_eq!(precise.accounts().count(), 0);
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let path = std::env::temp_dir().join(format!("{}-checkpoint.csv", std::process::id()));
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      20.0",
+            "dispute,    1,       1,",
+            "withdrawal, 2,       3,      5.0",
+            "deposit,    1,       4,      oops",
+            "resolve,    1,       1,",
+            "deposit,    2,       5,      1.0",
+        ]
+        .join("\n");
+        let options = ProcessOptions::new().checkpoint(CheckpointPolicy::Every(2));
+        let mut interrupted = PaymentEngine::with_options(options.clone());
+        let reader = std::io::Cursor::new(input.as_bytes());
+        assert!(interrupted.process_checkpointed(reader, &path).is_err());
+
+        // the malformed record is fixed, and the processing goes on from the
+        // last checkpoint, i.e. after the withdrawal
+        let input = input.replace("oops", "1.0");
+        let mut resumed = PaymentEngine::with_options(options);
+        let reader = std::io::Cursor::new(input.as_bytes());
+        resumed.resume_checkpointed(reader, &path).unwrap();
+        assert_eq!(resumed.report().records, 3);
+        let expected = engine(&[&input]);
+        let mut output = Vec::new();
+        resumed.write(&mut output).unwrap();
+        let mut expected_output = Vec::new();
+        expected.write(&mut expected_output).unwrap();
+        assert_eq!(output, expected_output);
+        assert_eq!(resumed.account(1).unwrap().available, 11.);
+
+        // the final checkpoint covers the whole input
+        let mut finished = PaymentEngine::new();
+        let reader = std::io::Cursor::new(input.as_bytes());
+        finished.resume_checkpointed(reader, &path).unwrap();
+        assert_eq!(finished.report().records, 0);
+        assert_eq!(finished.account(2).unwrap().total, 16.);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn processes_shards_in_parallel() {
+        let mut input = vec!["type, client, tx, amount".to_string()];
+        for tx in 1..=500 {
+            let client = tx % 7;
+            input.push(match tx % 10 {
+                3 => format!("withdrawal, {client}, {tx}, 2.5"),
+                5 => format!("dispute, {}, {},", (tx - 4) % 7, tx - 4),
+                7 => format!("resolve, {}, {},", (tx - 6) % 7, tx - 6),
+                9 => format!("chargeback, {}, {},", (tx - 8) % 7, tx - 8),
+                _ => format!("deposit, {client}, {tx}, 1.2345"),
+            });
+        }
+        let input = input.join("\n");
+        let mut expected = PaymentEngine::new();
+        expected.process(input.as_bytes()).unwrap();
+        let mut expected_output = Vec::new();
+        expected.write(&mut expected_output).unwrap();
+
+        for workers in [1, 3, 8] {
+            let mut engine = PaymentEngine::new();
+            let workers = NonZeroUsize::new(workers).unwrap();
+            engine.process_parallel(input.as_bytes(), workers).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            assert_eq!(output, expected_output);
+            assert_eq!(engine.report().records, 500);
+            assert_eq!(
+                engine.report().skipped.len(),
+                expected.report().skipped.len()
+            );
+        }
+
+        // a failed worker fails the processing
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    2,       7,",
+        ];
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().strict(true));
+        let workers = NonZeroUsize::new(2).unwrap();
+        let err = engine
+            .process_parallel(input.join("\n").as_bytes(), workers)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::Rejected {
+                row: 2,
+                client: 2,
+                ..
+            }
+        ));
+
+        // and an engine that has processed records already is not sharded
+        let mut engine = PaymentEngine::new();
+        engine.process(input[..2].join("\n").as_bytes()).unwrap();
+        let err = engine
+            .process_parallel(input.join("\n").as_bytes(), workers)
+            .unwrap_err();
+        assert!(matches!(err, ProcessError::NotEmpty));
+    }
+
+    #[test]
+    fn processes_shards_as_sequentially() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      20.0",
+            // reusing the transaction identifier of another client
+            "deposit,    2,       1,      5.0",
+            "withdrawal, 3,       2,      1.0",
+            // disputing the transaction of another client
+            "dispute,    2,       1,",
+            "dispute,    1,       1,",
+            "resolve,    2,       1,",
+            "chargeback, 1,       1,",
+            "deposit,    1,       3,      1.0",
+            "dispute,    3,       4,",
+            "withdrawal, 2,       4,      2.5",
+            "dispute,    2,       4,",
+            "deposit,    3,       5,      1.0",
+        ];
+        let input = input.join("\n");
+        let mut expected = PaymentEngine::new();
+        expected.process(input.as_bytes()).unwrap();
+        let mut expected_output = Vec::new();
+        expected.write(&mut expected_output).unwrap();
+        let skipped = |engine: &PaymentEngine| {
+            engine
+                .report()
+                .skipped
+                .iter()
+                .map(|skipped| (skipped.row, skipped.tx, skipped.reason))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            skipped(&expected),
+            [
+                (3, Some(1), SkipReason::DuplicateTransaction),
+                (4, Some(2), SkipReason::DuplicateTransaction),
+                (5, Some(1), SkipReason::ClientMismatch),
+                (7, Some(1), SkipReason::ClientMismatch),
+                (9, Some(3), SkipReason::AccountLocked),
+            ]
+        );
+
+        for workers in [1, 2, 3, 4] {
+            let mut engine = PaymentEngine::new();
+            let workers = NonZeroUsize::new(workers).unwrap();
+            engine.process_parallel(input.as_bytes(), workers).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            assert_eq!(output, expected_output, "{workers}");
+            assert_eq!(skipped(&engine), skipped(&expected), "{workers}");
+        }
+    }
+
+    #[test]
+    fn processes_transfers_across_shards_as_sequentially() {
+        let input = [
+            "type,       client,  tx,     amount,  to_client",
+            "deposit,    1,       1,      10.0,",
+            "transfer,   1,       2,      5.0,     2",
+            // the recipient spending the funds transferred
+            "withdrawal, 2,       3,      4.0,",
+            "deposit,    4,       4,      1.0,",
+            "dispute,    4,       4,,",
+            "chargeback, 4,       4,,",
+            // the recipient locked
+            "transfer,   1,       5,      1.0,     4",
+            "deposit,    2,       6,      2.0,",
+            "dispute,    1,       2,,",
+            "resolve,    1,       2,,",
+            "dispute,    1,       2,,",
+            "chargeback, 1,       2,,",
+        ];
+        let mut input = input.join("\n");
+        // plenty of transfers back and forth, for the workers to lend the
+        // accounts to one another while busy with their own records
+        for tx in 7..500 {
+            let (client, to_client) = (tx % 5 + 5, (tx * 3) % 5 + 5);
+            input += &match tx % 4 {
+                0 => format!("\ndeposit, {client}, {tx}, 3.0,"),
+                1 if client != to_client => format!("\ntransfer, {client}, {tx}, 1.5, {to_client}"),
+                2 => format!("\ndispute, {}, {},,", (tx - 1) % 5 + 5, tx - 1),
+                _ => format!("\nwithdrawal, {client}, {tx}, 1.0,"),
+            };
+        }
+        let mut expected = PaymentEngine::new();
+        expected.process(input.as_bytes()).unwrap();
+        let mut expected_output = Vec::new();
+        expected.write(&mut expected_output).unwrap();
+        // the withdrawal of the funds transferred applied
+        assert_eq!(expected.account(2).unwrap().total, -2.);
+        assert_eq!(
+            expected.report().skipped[0].reason,
+            SkipReason::AccountLocked
+        );
+
+        for workers in [2, 3, 8] {
+            let mut engine = PaymentEngine::new();
+            let workers = NonZeroUsize::new(workers).unwrap();
+            engine.process_parallel(input.as_bytes(), workers).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            assert_eq!(output, expected_output, "{workers}");
+            assert_eq!(engine.report().skipped, expected.report().skipped);
+            assert!(engine.reconcile().is_ok());
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn processes_async_input() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.00005",
+            "deposit,    2,       2,      20.0",
+            "dispute,    1,       1,",
+            "withdrawal, 2,       3,      5.0",
+            "dispute,    2,       9,",
+            "chargeback, 1,       1,",
+        ]
+        .join("\n");
+        let expected = engine(&[&input]);
+        let mut expected_output = Vec::new();
+        expected.write(&mut expected_output).unwrap();
+
+        let mut engine = PaymentEngine::new();
+        let mut output = Vec::new();
+        engine
+            .process_async(input.as_bytes(), &mut output)
+            .await
+            .unwrap();
+        assert_eq!(output, expected_output);
+        assert_eq!(engine.report().warnings, expected.report().warnings);
+        assert_eq!(engine.report().skipped, expected.report().skipped);
+
+        let mut engine = PaymentEngine::new();
+        let input = "type, client, tx, amount\nblocking, 1, 1, 1.0";
+        let err = engine
+            .process_async(input.as_bytes(), Vec::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProcessError::Malformed { line: Some(2), .. }));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn reads_json_lines_if_configured() {
+        use crate::InputFormat;
+
+        let csv = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.12345",
+            "deposit,    2,       2,      20.0",
+            "dispute,    1,       1,",
+            "withdrawal, 2,       3,      5.0",
+        ]
+        .join("\n");
+        let json = [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.12345}"#,
+            r#"{"type": "deposit", "client": 2, "tx": 2, "amount": "20.0", "note": "ignored"}"#,
+            "",
+            r#"{"type": "dispute", "client": 1, "tx": 1, "amount": null}"#,
+            r#"{"type": "withdrawal", "client": 2, "tx": 3, "amount": 5}"#,
+        ]
+        .join("\n");
+        let expected = engine(&[&csv]);
+        let mut expected_output = Vec::new();
+        expected.write(&mut expected_output).unwrap();
+
+        for format in [InputFormat::JsonLines, InputFormat::Auto] {
+            let options = ProcessOptions::new().input_format(format);
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(json.as_bytes()).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            assert_eq!(output, expected_output);
+            assert_eq!(engine.report().warnings, expected.report().warnings);
+        }
+
+        // the CSV input is still recognized as such
+        let options = ProcessOptions::new().input_format(InputFormat::Auto);
+        let mut engine = PaymentEngine::with_options(options.clone());
+        engine.process(csv.as_bytes()).unwrap();
+        assert_eq!(engine.account(2).unwrap().available, 15.);
+
+        // the currency column is streamed out only if the first record names one
+        for (currency, header) in [("", "client,available"), ("BTC", "client,currency")] {
+            let json = format!(
+                r#"{{"type": "deposit", "client": 1, "tx": 1, "amount": 1, "currency": "{currency}"}}"#
+            );
+            let mut engine = PaymentEngine::with_options(options.clone());
+            let mut output = Vec::new();
+            engine
+                .process_streaming(json.as_bytes(), &mut output)
+                .unwrap();
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.starts_with(header), "{output}");
+        }
+
+        // the fields are renamed, and the malformed lines are reported
+        let columns = ColumnMap::new().column("client", "account");
+        let options = ProcessOptions::new()
+            .input_format(InputFormat::JsonLines)
+            .column_map(columns)
+            .strict(true);
+        let mut engine = PaymentEngine::with_options(options.clone());
+        let json = r#"{"type": "deposit", "account": 7, "tx": 1, "amount": "1.5"}"#;
+        engine.process(json.as_bytes()).unwrap();
+        assert_eq!(engine.account(7).unwrap().total, 1.5);
+        let json = "\n{\"type\": \"resolve\", \"account\": 7, \"tx\": 9}\n{";
+        let err = engine.process(json.as_bytes()).unwrap_err();
+        assert!(
+            err.to_string().starts_with("line 2: record of client 7"),
+            "{err}"
+        );
+        let mut engine = PaymentEngine::with_options(options);
+        let err = engine.process("\n{".as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::Json { row: 1, .. }));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn writes_json_if_configured() {
+        use crate::OutputFormat;
+
+        let input = [
+            "type,       client,  tx,     amount, currency",
+            "deposit,    2,       1,      0.1,",
+            "deposit,    1,       2,      10.5,   BTC",
+            "dispute,    1,       2,,             BTC",
+            "chargeback, 1,       2,,             BTC",
+        ]
+        .join("\n");
+        let write = |format| {
+            let options = ProcessOptions::new().output_format(format);
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(input.as_bytes()).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let btc = r#"{"client":1,"currency":"BTC","available":"0.0000","held":"0.0000","total":"0.0000","locked":true}"#;
+        let implicit =
+            r#"{"client":2,"available":"0.1000","held":"0.0000","total":"0.1000","locked":false}"#;
+        assert_eq!(write(OutputFormat::Json), format!("[{btc},{implicit}]"));
+        assert_eq!(
+            write(OutputFormat::JsonLines),
+            format!("{btc}\n{implicit}\n")
+        );
+
+        let mut output = Vec::new();
+        let options = ProcessOptions::new().output_format(OutputFormat::Json);
+        PaymentEngine::with_options(options)
+            .write(&mut output)
+            .unwrap();
+        assert_eq!(output, b"[]");
+    }
+
+    #[test]
+    fn merges_inputs_by_timestamp() {
+        let monday = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      10.0,   100",
+            "withdrawal, 1,       3,      8.0,    300",
+            "dispute,    1,       3,,",
+        ]
+        .join("\n");
+        let tuesday = [
+            "timestamp, type,       client,  tx,     amount",
+            "200,       withdrawal, 1,       2,      5.0",
+            "300,       deposit,    2,       4,      1.0",
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::new();
+        engine
+            .process_merged(vec![monday.as_bytes(), tuesday.as_bytes()])
+            .unwrap();
+        // the second withdrawal fails, since the first one comes in between
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 5.);
+        assert_eq!(account.held, 0.);
+        assert_eq!(engine.report().records, 5);
+        assert_eq!(engine.report().skipped.len(), 1);
+        // on a tie, the earlier input comes first
+        let failed = &engine.report().skipped[0];
+        assert_eq!((failed.row, failed.tx), (3, Some(3)));
+        assert_eq!(engine.account(2).unwrap().available, 1.);
+
+        // the records preceding the malformed one are applied nevertheless
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    2,       5,      1.0,    150",
+            "deposit,    2,       6,      1.0,    yesterday",
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::new();
+        let err = engine
+            .process_merged(vec![monday.as_bytes(), input.as_bytes()])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::InvalidTimestamp { line: Some(3), .. }
+        ));
+        assert_eq!(engine.report().records, 2);
+        assert_eq!(engine.account(2).unwrap().available, 1.);
+
+        // while a record without a timestamp to go by is not merged in first
+        let input = "type, client, tx, amount\ndeposit, 2, 5, 1.0";
+        let mut engine = PaymentEngine::new();
+        let err = engine
+            .process_merged(vec![monday.as_bytes(), input.as_bytes()])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::InvalidTimestamp { row: 1, line: Some(2), ref value } if value.is_empty()
+        ));
+        assert!(engine.account(2).is_none());
+    }
+
+    #[test]
+    fn validates_timestamp_order_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      10.0,   100",
+            "deposit,    2,       2,      5.0,    300",
+            "withdrawal, 1,       3,      8.0,    200",
+            "deposit,    1,       4,      1.0,",
+            "deposit,    2,       5,      1.0,    300",
+        ]
+        .join("\n");
+        let process = |options: ProcessOptions| {
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(input.as_bytes()).map(|()| engine)
+        };
+        let skipped = |engine: &PaymentEngine| -> Vec<_> {
+            let skipped = &engine.report().skipped;
+            skipped.iter().map(|s| (s.row, s.reason)).collect()
+        };
+
+        let engine = process(ProcessOptions::new()).unwrap();
+        assert!(engine.report().skipped.is_empty());
+        assert_eq!(engine.account(1).unwrap().available, 3.);
+
+        let options = ProcessOptions::new().timestamp_order(TimestampOrder::Global);
+        let engine = process(options).unwrap();
+        assert_eq!(skipped(&engine), [(3, SkipReason::OutOfOrder)]);
+        assert_eq!(engine.account(1).unwrap().available, 11.);
+
+        let options = ProcessOptions::new().timestamp_order(TimestampOrder::PerClient);
+        let engine = process(options).unwrap();
+        assert!(engine.report().skipped.is_empty());
+
+        // the withdrawal gets applied ahead of the deposit preceding it
+        let options = ProcessOptions::new()
+            .timestamp_order(TimestampOrder::Global)
+            .reorder_window(Some(1));
+        let engine = process(options).unwrap();
+        assert!(engine.report().skipped.is_empty());
+        assert_eq!(engine.account(1).unwrap().available, 3.);
+        assert_eq!(engine.account(2).unwrap().available, 6.);
+
+        let options = ProcessOptions::new()
+            .timestamp_order(TimestampOrder::Global)
+            .strict(true);
+        let err = process(options).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::Rejected {
+                reason: SkipReason::OutOfOrder,
+                ..
+            }
+        ));
+
+        // only the records taking effect move the clock on
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      1.0,    100",
+            "withdrawal, 1,       2,      5.0,    300",
+            "deposit,    1,       3,      1.0,    200",
+        ]
+        .join("\n");
+        let options = ProcessOptions::new().timestamp_order(TimestampOrder::Global);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(skipped(&engine), [(2, SkipReason::InsufficientFunds)]);
+        assert_eq!(engine.account(1).unwrap().available, 2.);
+
+        let input = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0, yesterday";
+        let err = PaymentEngine::new().process(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::InvalidTimestamp { line: Some(2), .. }
+        ));
+    }
+
+    #[test]
+    fn transfers_between_clients() {
+        let mut engine = engine(&[
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    1,       1,      10.0,",
+            "transfer,   1,       2,      4.0,    2",
+            "transfer,   1,       3,      100.0,  2",
+            "transfer,   3,       4,      1.0,    1",
+            "dispute,    1,       2,,",
+        ]);
+        let skipped: Vec<_> = engine
+            .report()
+            .skipped
+            .iter()
+            .map(|s| (s.row, s.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                (3, SkipReason::InsufficientFunds),
+                (4, SkipReason::UnknownAccount)
+            ]
+        );
+        assert_eq!(engine.report().transfers, 1);
+        assert_eq!(engine.account(1).unwrap().available, 6.);
+        // the funds under dispute are held by the recipient
+        let recipient = engine.account(2).unwrap();
+        assert_eq!(recipient.available, 0.);
+        assert_eq!(recipient.held, 4.);
+        assert!(engine.reconcile().is_ok());
+
+        // the charge back reverses both legs of the transfer
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "chargeback, 1,       2,,",
+            "deposit,    2,       5,      1.0,",
+            "transfer,   2,       6,      1.0,    1",
+        ]
+        .join("\n");
+        engine.process(input.as_bytes()).unwrap();
+        let sender = engine.account(1).unwrap();
+        assert_eq!(sender.available, 10.);
+        assert_eq!(sender.total, 10.);
+        assert!(sender.locked);
+        let recipient = engine.account(2).unwrap();
+        assert_eq!(recipient.available, 1.);
+        assert_eq!(recipient.held, 0.);
+        assert!(!recipient.locked);
+        let locked = engine.report().skipped.last().unwrap();
+        assert_eq!(
+            (locked.tx, locked.reason),
+            (Some(6), SkipReason::AccountLocked)
+        );
+        assert!(engine.reconcile().is_ok());
+
+        let input = "type, client, tx, amount, to_client\ntransfer, 1, 7, 1.0, 1";
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::InvalidTransfer { .. }));
+        // the same goes for the records applied directly
+        let one = Amount::try_from_f64(1.).unwrap();
+        let err = engine.apply(Record::transfer(2, 7, one, 2)).unwrap_err();
+        assert!(matches!(err, ProcessError::InvalidTransfer { .. }));
+
+        // the locked recipient is not credited, even if deposits to it are
+        let options = ProcessOptions::new().on_locked(LockedAccountPolicy::AllowDeposits);
+        let mut engine = PaymentEngine::with_options(options);
+        let input = [
+            "type,       client,  tx,     amount, to_client, from_client",
+            "deposit,    1,       1,      10.0,,",
+            "dispute,    1,       1,,,",
+            "chargeback, 1,       1,,,",
+            "deposit,    1,       2,      1.0,,",
+            "deposit,    2,       3,      5.0,,",
+            "transfer,   ,        4,      2.0,    1,         2",
+            "transfer,   2,       5,      2.0,    3,         2",
+        ]
+        .join("\n");
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, 1.);
+        assert_eq!(engine.account(3).unwrap().available, 2.);
+        let locked = &engine.report().skipped;
+        assert_eq!(locked.len(), 1);
+        assert_eq!(
+            (locked[0].tx, locked[0].reason),
+            (Some(4), SkipReason::AccountLocked)
+        );
+        let input = "type, client, tx, amount, to_client, from_client\ntransfer, 2, 6, 1.0, 3, 1";
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::Malformed { .. }));
+    }
+
+    #[test]
+    fn unlocks_accounts_if_allowed() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "dispute,    1,       2,",
+            "chargeback, 1,       2,",
+            "unlock,     1,,",
+            "withdrawal, 1,       3,      1.0",
+            "unlock,     2,,",
+        ]
+        .join("\n");
+        let process = |options| {
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(input.as_bytes()).unwrap();
+            engine
+        };
+        let locked = process(ProcessOptions::new());
+        assert!(locked.account(1).unwrap().locked);
+        let skipped = &locked.report().skipped[0];
+        assert_eq!(
+            (skipped.row, skipped.reason),
+            (5, SkipReason::UnlockNotAllowed)
+        );
+
+        let unlocked = process(ProcessOptions::new().allow_unlock(true));
+        let account = unlocked.account(1).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.available, 9.);
+        assert_eq!(account.chargebacks, 0);
+        let skipped = &unlocked.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(
+            (skipped[0].row, skipped[0].reason),
+            (7, SkipReason::UnknownAccount)
+        );
+
+        // the API is there regardless of the option
+        let mut engine = engine(&[
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+        ]);
+        let path = std::env::temp_dir().join(format!("{}-unlock-audit.csv", std::process::id()));
+        engine
+            .write_audit_to(std::fs::File::create(&path).unwrap())
+            .unwrap();
+        let recorder = Recorder::default();
+        engine.add_observer(recorder.clone());
+        assert!(engine.unlock(1, Currency::default()).unwrap());
+        let account = engine.account(1).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.chargebacks, 0);
+        assert_eq!(account.charged_back, 0.);
+        assert!(!engine.unlock(1, Currency::default()).unwrap());
+        assert!(!engine.unlock(2, Currency::default()).unwrap());
+        assert_eq!(engine.report().unlocks, 1);
+        assert_eq!(*recorder.0.lock().unwrap(), [("account_unlocked", 3)]);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().nth(1),
+            Some("3,unlock,,,1,,0.0000,0.0000,0.0000,0.0000,0.0000,0.0000,false,false")
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // the charge backs reviewed do not lock the account again
+        let options = ProcessOptions::new().lock_on_chargeback(LockPolicy::AfterCount(2));
+        let mut engine = PaymentEngine::with_options(options);
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      10.0",
+            "deposit,    1,       3,      10.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+            "dispute,    1,       2,",
+            "chargeback, 1,       2,",
+        ];
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert!(engine.unlock(1, Currency::default()).unwrap());
+        let input = "type, client, tx, amount\ndispute, 1, 3,\nchargeback, 1, 3,";
+        engine.process(input.as_bytes()).unwrap();
+        let account = engine.account(1).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.chargebacks, 1);
+    }
+
+    #[test]
+    fn applies_locked_account_policy() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      2.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+            "deposit,    1,       3,      3.0",
+            "withdrawal, 1,       4,      1.0",
+        ]
+        .join("\n");
+        let process = |policy| {
+            let options = ProcessOptions::new().on_locked(policy);
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(input.as_bytes()).map(|()| engine)
+        };
+
+        let engine = process(LockedAccountPolicy::SkipWithWarning).unwrap();
+        assert_eq!(engine.report().skipped.len(), 2);
+        assert_eq!(engine.report().locked_skips, 2);
+        assert_eq!(engine.account(1).unwrap().total, 2.);
+
+        let engine = process(LockedAccountPolicy::SkipSilently).unwrap();
+        assert!(engine.report().skipped.is_empty());
+        assert_eq!(engine.report().locked_skips, 2);
+        assert_eq!(engine.account(1).unwrap().total, 2.);
+
+        let engine = process(LockedAccountPolicy::AllowDeposits).unwrap();
+        let skipped = &engine.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(
+            (skipped[0].row, skipped[0].reason),
+            (6, SkipReason::AccountLocked)
+        );
+        assert_eq!(engine.account(1).unwrap().total, 5.);
+
+        let err = process(LockedAccountPolicy::Error).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::Rejected {
+                row: 5,
+                reason: SkipReason::AccountLocked,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn applies_negative_available_policy() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      8.0",
+            "dispute,    1,       1,",
+        ];
+        let process = |policy, extra: &[&str]| {
+            let options = ProcessOptions::new().on_negative_available(policy);
+            let mut engine = PaymentEngine::with_options(options);
+            let input = [&input[..], extra].concat().join("\n");
+            engine.process(input.as_bytes()).unwrap();
+            engine
+        };
+        // available, held, total, and shortfall
+        let funds = |engine: &PaymentEngine| {
+            let account = engine.account(1).unwrap();
+            [
+                account.available,
+                account.held,
+                account.total,
+                account.shortfall,
+            ]
+            .map(|amount| amount.as_f64())
+        };
+
+        let engine = process(NegativeAvailablePolicy::Allow, &[]);
+        assert_eq!(funds(&engine), [-8., 10., 2., 0.]);
+        assert!(!engine.account(1).unwrap().locked);
+
+        let engine = process(NegativeAvailablePolicy::Clamp, &[]);
+        assert_eq!(funds(&engine), [0., 2., 2., 8.]);
+        assert!(engine.reconcile().is_ok());
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,shortfall\n1,0.0000,2.0000,2.0000,false,8.0000\n"
+        );
+        let engine = process(NegativeAvailablePolicy::Clamp, &["resolve, 1, 1,"]);
+        assert_eq!(funds(&engine), [2., 0., 2., 0.]);
+        let engine = process(NegativeAvailablePolicy::Clamp, &["chargeback, 1, 1,"]);
+        assert_eq!(funds(&engine), [0., 0., 0., 0.]);
+        assert!(engine.account(1).unwrap().locked);
+        assert!(engine.reconcile().is_ok());
+
+        let engine = process(NegativeAvailablePolicy::Lock, &["deposit, 1, 3, 1.0"]);
+        assert_eq!(funds(&engine), [-8., 10., 2., 0.]);
+        assert!(engine.account(1).unwrap().locked);
+        assert_eq!(engine.report().skipped[0].reason, SkipReason::AccountLocked);
+    }
+
+    #[test]
+    fn settles_shortfalls_of_their_own_transactions() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      3.0",
+            "withdrawal, 1,       3,      8.0",
+            "dispute,    1,       2,",
+            "dispute,    1,       1,",
+        ];
+        let options =
+            || ProcessOptions::new().on_negative_available(NegativeAvailablePolicy::Clamp);
+        let mut engine = PaymentEngine::with_options(options());
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        // available, held, total, and shortfall
+        let funds = |engine: &PaymentEngine| {
+            let account = engine.account(1).unwrap();
+            [
+                account.available,
+                account.held,
+                account.total,
+                account.shortfall,
+            ]
+            .map(|amount| amount.as_f64())
+        };
+        assert_eq!(funds(&engine), [0., 5., 5., 8.]);
+        assert_eq!(engine.transaction(1).unwrap().unwrap().shortfall, 8.);
+        assert_eq!(engine.transaction(2).unwrap().unwrap().shortfall, 0.);
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+
+        // the transaction held in full settles none of the other one's shortfall
+        let mut restored = PaymentEngine::with_options(options());
+        restored.restore(snapshot.as_slice()).unwrap();
+        for engine in [&mut engine, &mut restored] {
+            engine
+                .process("type,client,tx\nresolve,1,2".as_bytes())
+                .unwrap();
+            assert_eq!(funds(engine), [3., 2., 5., 8.]);
+            assert!(engine.reconcile().is_ok());
+        }
+        engine
+            .process("type,client,tx\nresolve,1,1".as_bytes())
+            .unwrap();
+        assert_eq!(funds(&engine), [5., 0., 5., 0.]);
+        restored
+            .process("type,client,tx\nchargeback,1,1".as_bytes())
+            .unwrap();
+        assert_eq!(funds(&restored), [3., 0., 3., 0.]);
+        assert_eq!(restored.account(1).unwrap().charged_back, 10.);
+        assert!(restored.reconcile().is_ok());
+    }
+
+    #[test]
+    fn charges_fees_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    1,       1,      10.0,",
+            "withdrawal, 1,       2,      4.0,",
+            "withdrawal, 1,       3,      5.5,",
+            "transfer,   1,       4,      2.0,    2",
+        ]
+        .join("\n");
+        let fees = FeeSchedule::new()
+            .withdrawal(Fee::Flat(Amount::try_from_f64(0.5).unwrap()))
+            .unwrap()
+            .transfer(Fee::BasisPoints(100))
+            .unwrap();
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().fees(fees));
+        engine.process(input.as_bytes()).unwrap();
+        // the fee is on top of the amount, and so the second withdrawal fails
+        let skipped = &engine.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(
+            (skipped[0].row, skipped[0].reason),
+            (3, SkipReason::InsufficientFunds)
+        );
+        assert_eq!(engine.report().fees_collected, 0.52);
+        assert_eq!(engine.account(1).unwrap().fees, 0.52);
+        assert!(engine.reconcile().is_ok());
+
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked,fees_collected\n\
+             1,3.4800,0.0000,3.4800,false,0.5200\n\
+             2,2.0000,0.0000,2.0000,false,0.0000\n"
+        );
+
+        // a negative fee would credit the account instead
+        let rebate = Fee::Flat(Amount::try_from_f64(-0.5).unwrap());
+        let err = FeeSchedule::new().transfer(rebate).unwrap_err();
+        assert_eq!(err, AmountError::Negative);
+    }
+
+    #[test]
+    fn disputes_transactions_in_parts() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,      3.0",
+            "dispute,    1,       1,      4.0",
+            "dispute,    1,       1,      5.0", // exceeds what is left (skip)
+            "resolve,    1,       1,",
+            "dispute,    1,       1,      2.0",
+            "chargeback, 1,       1,",
+            "dispute,    1,       1,      -1.0",
+        ]
+        .join("\n");
+        let options = ProcessOptions::new().lock_on_chargeback(LockPolicy::Never);
+        let mut engine = PaymentEngine::with_options(options);
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::AmountRejected { row: 8, .. }));
+        let skipped = &engine.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].row, 4);
+        assert_eq!(skipped[0].reason, SkipReason::DisputeExceedsAmount);
+        // only the part disputed last has been charged back
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 8.);
+        assert_eq!(account.held, 0.);
+        assert_eq!(account.total, 8.);
+        assert_eq!(account.charged_back, 2.);
+        let txn = engine.txns.get(1).unwrap().unwrap();
+        assert_eq!(txn.state, TxnState::Undisputed);
+        assert_eq!(txn.reversed, 2.);
+
+        // the rest can still be disputed, all of it by default
+        let input = "type, client, tx, amount\ndispute, 1, 1, 1.0\ndispute, 1, 1,\ndispute, 1, 1,";
+        engine.process(input.as_bytes()).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 0.);
+        assert_eq!(account.held, 8.);
+        assert_eq!(engine.txns.get(1).unwrap().unwrap().disputed, 8.);
+        assert!(engine.reconcile().is_ok());
+        engine
+            .process("type, client, tx\nchargeback, 1, 1".as_bytes())
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().total, 0.);
+        assert_eq!(
+            engine.txns.get(1).unwrap().unwrap().state,
+            TxnState::Reversed
+        );
+    }
+
+    #[test]
+    fn limits_dispute_cycles_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       1,      4.0",
+            "dispute,    1,       1,      6.0", // same cycle
+            "resolve,    1,       1,",
+            "dispute,    1,       1,",
+            "resolve,    1,       1,",
+            "dispute,    1,       1,", // third cycle (skip)
+        ]
+        .join("\n");
+        let options = ProcessOptions::new().max_dispute_cycles(Some(2));
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.as_bytes()).unwrap();
+        let skipped = &engine.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].row, 7);
+        assert_eq!(skipped[0].reason, SkipReason::DisputeCyclesExceeded);
+        assert_eq!(engine.txns.get(1).unwrap().unwrap().cycles, 2);
+        assert_eq!(engine.account(1).unwrap().held, 0.);
+
+        // unlimited by default
+        let mut engine = PaymentEngine::new();
+        engine.process(input.as_bytes()).unwrap();
+        assert!(engine.report().skipped.is_empty());
+        assert_eq!(engine.txns.get(1).unwrap().unwrap().cycles, 3);
+        assert_eq!(engine.account(1).unwrap().held, 10.);
+    }
+
+    #[test]
+    fn enforces_dispute_window_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      10.0,   100",
+            "deposit,    1,       2,      5.0,    150",
+            "deposit,    1,       3,      1.0,",
+            "dispute,    1,       1,,             200", // too late (skip)
+            "dispute,    1,       2,,             200",
+            "dispute,    1,       3,,             200", // no timestamp to tell
+        ]
+        .join("\n");
+        let options = ProcessOptions::new().dispute_window(Some(50));
+        let mut engine = PaymentEngine::with_options(options.clone());
+        engine.process(input.as_bytes()).unwrap();
+        let skipped = &engine.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!((skipped[0].row, skipped[0].tx), (4, Some(1)));
+        assert_eq!(skipped[0].reason, SkipReason::DisputeWindowElapsed);
+        assert_eq!(engine.account(1).unwrap().held, 6.);
+        assert_eq!(engine.txns.get(1).unwrap().unwrap().timestamp, Some(100));
+
+        // the disputes already under way can be settled past the window
+        let input = "type, client, tx, amount, timestamp\nresolve, 1, 2,, 1000";
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().held, 1.);
+
+        // a dispute without a timestamp is taken as issued at the latest one
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      10.0,   100",
+            "deposit,    1,       2,      5.0,    120",
+            "dispute,    1,       2,,", // as of 120
+            "deposit,    1,       3,      1.0,    200",
+            "dispute,    1,       1,,", // as of 200 (skip)
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::with_options(options.clone());
+        engine.process(input.as_bytes()).unwrap();
+        let skipped = &engine.report().skipped;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!((skipped[0].row, skipped[0].tx), (5, Some(1)));
+        assert_eq!(skipped[0].reason, SkipReason::DisputeWindowElapsed);
+        assert_eq!(engine.account(1).unwrap().held, 5.);
+
+        // the time can come from elsewhere than the records
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      10.0,   100",
+            "deposit,    1,       2,      5.0,    150",
+            "dispute,    1,       1,,",
+            "dispute,    1,       2,,",
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::with_options(options.clock(Clock::Fixed(180)));
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.report().skipped[0].tx, Some(1));
+        assert_eq!(engine.account(1).unwrap().held, 5.);
+    }
+
+    #[test]
+    fn reports_outcome_of_each_record() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      20.0",
+            "deposit,    1,       1,      10.0",
+            "dispute,    1,       3,",
+            "resolve,    1,       1,",
+            "dispute,    1,       1,",
+            "close,      1,,",
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::new();
+        let mut events = Vec::new();
+        engine
+            .process_with_events(input.as_bytes(), |event| events.push(event))
+            .unwrap();
+        let outcomes: Vec<_> = events.iter().map(|event| event.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [
+                TxnOutcome::Applied,
+                TxnOutcome::Skipped(SkipReason::InsufficientFunds),
+                TxnOutcome::Skipped(SkipReason::DuplicateTransaction),
+                TxnOutcome::UnknownTransaction,
+                TxnOutcome::Ignored,
+                TxnOutcome::Applied,
+                TxnOutcome::Applied,
+            ]
+        );
+        let event = events[1];
+        assert_eq!((event.row, event.line), (2, Some(3)));
+        assert_eq!((event.client, event.tx), (1, Some(2)));
+        assert_eq!(engine.account(1).unwrap().held, 10.);
+    }
+
+    #[test]
+    fn writes_rejected_records() {
+        let input = [
+            "type,       client,  tx,     amount, timestamp",
+            "deposit,    1,       1,      10.0,   1",
+            "withdrawal, 1,       2,      20.5,   2",
+            "dispute,    1,       3,,             3",
+            "dispute,    1,       1,      4.0,    4",
+            "dispute,    2,       1,,",
+        ]
+        .join("\n");
+        let path = std::env::temp_dir().join(format!("{}-engine-rejects.csv", std::process::id()));
+        let rejects = |options: ProcessOptions| {
+            let mut engine = PaymentEngine::with_options(options);
+            let file = std::fs::File::create(&path).unwrap();
+            engine.write_rejects_to(file).unwrap();
+            let result = engine.process(input.as_bytes());
+            drop(engine);
+            (result, std::fs::read_to_string(&path).unwrap())
+        };
+        let (result, written) = rejects(ProcessOptions::new());
+        assert!(result.is_ok());
+        assert_eq!(
+            written,
+            "type,client,tx,amount,currency,to_client,timestamp,reason\n\
+             withdrawal,1,2,20.5000,,,2,insufficient funds\n\
+             dispute,1,3,,,,3,transaction does not exist\n\
+             dispute,2,1,,,,,transaction of another client\n"
+        );
+        // the record failing the processing is written out as well
+        let (result, written) = rejects(ProcessOptions::new().strict(true));
+        assert!(matches!(result, Err(ProcessError::Rejected { row: 3, .. })));
+        assert!(written.ends_with("dispute,1,3,,,,3,transaction does not exist\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_malformed_and_parallel_rejects() {
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    1,       1,      10.0,",
+            "deposit,    2,       2,      ten,",
+            "withdrawal, 1,       3,      20.0,",
+            "transfer,   1,       4,      1.0,    1",
+            "withdrawal, 2,       5,      1.0,",
+        ]
+        .join("\n");
+        let path = std::env::temp_dir().join(format!(
+            "{}-engine-parallel-rejects.csv",
+            std::process::id()
+        ));
+        let rejects = |parallel: bool| {
+            let mut engine = PaymentEngine::with_options(ProcessOptions::new().lenient(true));
+            let file = std::fs::File::create(&path).unwrap();
+            engine.write_rejects_to(file).unwrap();
+            let result = match parallel {
+                true => engine.process_parallel(input.as_bytes(), NonZeroUsize::new(2).unwrap()),
+                false => engine.process(input.as_bytes()),
+            };
+            assert!(result.is_ok());
+            assert_eq!(engine.report().malformed.len(), 2);
+            drop(engine);
+            std::fs::read_to_string(&path).unwrap()
+        };
+        let expected = "type,client,tx,amount,currency,to_client,timestamp,reason\n\
+             deposit,2,2,ten,,,,\"line 3: invalid amount \"\"ten\"\" for deposit tx 2: not a decimal number\"\n\
+             withdrawal,1,3,20.0000,,,,insufficient funds\n\
+             transfer,1,4,1.0,,1,,line 5: transfer expected to have a recipient other than its client\n\
+             withdrawal,2,5,1.0000,,,,account does not exist\n";
+        assert_eq!(rejects(false), expected);
+        // the rejects of the workers are written out in the input order
+        assert_eq!(rejects(true), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_audit_log() {
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    1,       1,      10.0,",
+            "withdrawal, 1,       2,      20.0,",
+            "transfer,   1,       3,      4.0,    2",
+            "dispute,    1,       3,,",
+            "chargeback, 1,       3,,",
+        ]
+        .join("\n");
+        let path = std::env::temp_dir().join(format!("{}-engine-audit.csv", std::process::id()));
+        let mut engine = PaymentEngine::new();
+        let file = std::fs::File::create(&path).unwrap();
+        engine.write_audit_to(file).unwrap();
+        engine.process(input.as_bytes()).unwrap();
+        drop(engine);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "row,type,tx,amount,client,currency,available_before,held_before,total_before,\
+             available_after,held_after,total_after,locked,closed\n\
+             1,deposit,1,10.0000,1,,0.0000,0.0000,0.0000,10.0000,0.0000,10.0000,false,false\n\
+             3,transfer,3,4.0000,1,,10.0000,0.0000,10.0000,6.0000,0.0000,6.0000,false,false\n\
+             3,transfer,3,4.0000,2,,0.0000,0.0000,0.0000,4.0000,0.0000,4.0000,false,false\n\
+             4,dispute,3,,2,,4.0000,0.0000,4.0000,0.0000,4.0000,4.0000,false,false\n\
+             5,chargeback,3,,1,,6.0000,0.0000,6.0000,10.0000,0.0000,10.0000,true,false\n\
+             5,chargeback,3,,2,,0.0000,4.0000,4.0000,0.0000,0.0000,0.0000,false,false\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Observer recording the names of its callbacks along with the rows.
+    #[derive(Debug, Default, Clone)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>);
+
+    impl Recorder {
+        fn record(&self, callback: &'static str, event: &TxnEvent) {
+            self.0.lock().unwrap().push((callback, event.row));
+        }
+    }
+
+    impl EventObserver for Recorder {
+        fn on_deposit(&mut self, event: &TxnEvent, _: &Account) {
+            self.record("deposit", event);
+        }
+
+        fn on_withdrawal_rejected(&mut self, event: &TxnEvent, _: SkipReason) {
+            self.record("withdrawal_rejected", event);
+        }
+
+        fn on_chargeback(&mut self, event: &TxnEvent, account: &Account) {
+            assert_eq!(account.chargebacks, 1);
+            self.record("chargeback", event);
+        }
+
+        fn on_account_locked(&mut self, event: &TxnEvent, account: &Account) {
+            assert!(account.locked);
+            self.record("account_locked", event);
+        }
+
+        fn on_account_unlocked(&mut self, event: &TxnEvent, account: &Account) {
+            assert!(!account.locked);
+            self.record("account_unlocked", event);
+        }
+
+        fn on_skipped(&mut self, event: &TxnEvent, _: SkipReason) {
+            self.record("skipped", event);
+        }
+    }
+
+    #[test]
+    fn notifies_observers_in_record_order() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      20.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+            "deposit,    1,       3,      1.0",
+        ]
+        .join("\n");
+        let recorder = Recorder::default();
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(recorder.clone());
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                ("deposit", 1),
+                ("withdrawal_rejected", 2),
+                ("skipped", 2),
+                ("chargeback", 4),
+                ("account_locked", 4),
+                ("skipped", 5),
+            ]
+        );
+    }
+
+    /// Observer of the clients whose accounts the records change.
+    #[derive(Debug, Clone, Default)]
+    struct Parties(std::sync::Arc<std::sync::Mutex<Vec<(&'static str, ClientID)>>>);
+
+    impl Parties {
+        fn record(&self, callback: &'static str, account: &Account) {
+            self.0.lock().unwrap().push((callback, account.client));
+        }
+    }
+
+    impl EventObserver for Parties {
+        fn on_deposit(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("deposit", account);
+        }
+
+        fn on_transfer(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("transfer", account);
+        }
+
+        fn on_transfer_received(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("transfer_received", account);
+        }
+
+        fn on_dispute(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("dispute", account);
+        }
+
+        fn on_resolve(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("resolve", account);
+        }
+
+        fn on_chargeback(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("chargeback", account);
+        }
+
+        fn on_account_locked(&mut self, _: &TxnEvent, account: &Account) {
+            self.record("account_locked", account);
+        }
+    }
+
+    #[test]
+    fn notifies_observers_of_both_parties_to_transfers() {
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    2,       1,      10.0,",
+            "transfer,   2,       2,      4.0,    1",
+            "dispute,    2,       2,,",
+            "resolve,    2,       2,,",
+            "dispute,    2,       2,,",
+            "chargeback, 2,       2,,",
+        ]
+        .join("\n");
+        let parties = Parties::default();
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(parties.clone());
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(
+            *parties.0.lock().unwrap(),
+            [
+                ("deposit", 2),
+                ("transfer_received", 1),
+                ("transfer", 2),
+                // the funds of the transfer are held by its recipient
+                ("dispute", 1),
+                ("resolve", 1),
+                ("dispute", 1),
+                ("chargeback", 1),
+                ("chargeback", 2),
+                ("account_locked", 2),
+            ]
+        );
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn updates_metrics() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      10.0",
+            "dispute,    1,       1,      4.0",
+            "dispute,    1,       1,      4.0",
+            "dispute,    2,       2,",
+            "chargeback, 2,       2,",
+            "resolve,    1,       3,",
+            "withdrawal, 2,       4,      1.0",
+        ]
+        .join("\n");
+        let metrics = crate::Metrics::new();
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(metrics.clone());
+        metrics.time(|| engine.process(input.as_bytes())).unwrap();
+        let encoded = metrics.encode();
+        for line in [
+            "payment_engine_records_total{outcome=\"applied\"} 6",
+            "payment_engine_records_total{outcome=\"skipped\"} 1",
+            "payment_engine_records_total{outcome=\"unknown_transaction\"} 1",
+            "payment_engine_active_disputes 1",
+            "payment_engine_locked_accounts 1",
+            "payment_engine_processing_seconds_count 1",
+        ] {
+            assert!(encoded.lines().any(|l| l == line), "{line} in {encoded}");
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn keeps_metrics_gauges_in_step() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      10.0",
+            "dispute,    1,       1,",
+            "dispute,    2,       2,",
+            "chargeback, 2,       2,",
+        ]
+        .join("\n");
+        let metrics = crate::Metrics::new();
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(metrics.clone());
+        engine.process(input.as_bytes()).unwrap();
+        let gauges = |metrics: &crate::Metrics| {
+            let encoded = metrics.encode();
+            let value = |name: &str| {
+                let line = encoded.lines().find(|line| line.starts_with(name)).unwrap();
+                line[name.len()..].trim().to_string()
+            };
+            (
+                value("payment_engine_active_disputes"),
+                value("payment_engine_locked_accounts"),
+                value("payment_engine_record_seconds_count"),
+            )
+        };
+        assert_eq!(gauges(&metrics), ("1".into(), "1".into(), "5".into()));
+        // unlocking and resetting the accounts is reflected as well
+        assert!(engine.unlock(2, Currency::default()).unwrap());
+        assert_eq!(gauges(&metrics), ("1".into(), "0".into(), "5".into()));
+        engine
+            .process("type,client,tx,amount\ndispute,2,2,".as_bytes())
+            .unwrap();
+        assert!(engine.reset_account(1).unwrap());
+        assert_eq!(gauges(&metrics), ("0".into(), "0".into(), "6".into()));
+
+        // the clones share the transactions under dispute
+        let metrics = crate::Metrics::new();
+        let (mut disputes, mut settles) = (metrics.clone(), metrics.clone());
+        let event = TxnEvent {
+            row: 1,
+            line: None,
+            client: 1,
+            tx: Some(1),
+            amount: None,
+            outcome: TxnOutcome::Applied,
+        };
+        let account = Account::new(1, Currency::default());
+        disputes.on_dispute(&event, &account);
+        settles.on_resolve(&event, &account);
+        assert_eq!(gauges(&metrics).0, "0");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn traces_records() {
+        use std::sync::{Arc, Mutex};
+
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record as Values};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Fields of a span or an event, as `name=value` pairs.
+        #[derive(Default)]
+        struct Fields(Vec<String>);
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.push(format!("{}={value:?}", field.name()));
+            }
+        }
+
+        /// Subscriber keeping the events along with the spans they occur in.
+        #[derive(Clone, Default)]
+        struct Collector {
+            spans: Arc<Mutex<Vec<String>>>,
+            entered: Arc<Mutex<Vec<u64>>>,
+            events: Arc<Mutex<Vec<(String, String)>>>,
+        }
+        impl Subscriber for Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(format!("{} {}", span.metadata().name(), fields.0.join(" ")));
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Values<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                let span = match self.entered.lock().unwrap().last() {
+                    Some(id) => self.spans.lock().unwrap()[*id as usize - 1].clone(),
+                    None => String::new(),
+                };
+                self.events.lock().unwrap().push((span, fields.0.join(" ")));
+            }
+            fn enter(&self, span: &Id) {
+                self.entered.lock().unwrap().push(span.into_u64());
+            }
+            fn exit(&self, _: &Id) {
+                self.entered.lock().unwrap().pop();
+            }
+        }
+
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      50.0", // insufficient funds (skip)
+        ]
+        .join("\n");
+        let collector = Collector::default();
+        let mut traced = PaymentEngine::new();
+        tracing::subscriber::with_default(collector.clone(), || {
+            traced.process(input.as_bytes()).unwrap();
+        });
+        let spans = collector.spans.lock().unwrap();
+        assert_eq!(spans[0].trim(), "process");
+        let events = collector.events.lock().unwrap();
+        let within = |span: &str, message: &str| {
+            events
+                .iter()
+                .any(|(within, event)| within == span && event.contains(message))
+        };
+        assert!(
+            within("record row=1 line=2 client=1 tx=1", "transaction applied"),
+            "{events:?}"
+        );
+        assert!(
+            within("record row=2 line=3 client=1 tx=2", "record skipped"),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn replaces_output_file() {
+        let path = std::env::temp_dir().join(format!("{}-accounts.csv", std::process::id()));
+        std::fs::write(&path, "previous").unwrap();
+        let mut engine = PaymentEngine::new();
+        engine
+            .process("type,client,tx,amount\ndeposit,1,1,1.0".as_bytes())
+            .unwrap();
+        engine.write_file(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+        // failing to write leaves the previous file intact
+        let missing = std::env::temp_dir().join("definitely-missing-dir/accounts.csv");
+        assert!(engine.write_file(&missing).is_err());
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .starts_with("client")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cleans_up_after_failing_to_replace_output_file() {
+        let dir = std::env::temp_dir().join(format!("{}-replaced", std::process::id()));
+        // the temporary file is written, but cannot be moved over a directory
+        let path = dir.join("accounts.csv");
+        std::fs::create_dir_all(&path).unwrap();
+        let mut engine = PaymentEngine::new();
+        engine
+            .process("type,client,tx,amount\ndeposit,1,1,1.0".as_bytes())
+            .unwrap();
+        assert!(engine.write_file(&path).is_err());
+        let entries: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["accounts.csv"]);
+        assert!(path.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn audits_administrative_operations() {
+        let path = std::env::temp_dir().join(format!("{}-admin-audit.csv", std::process::id()));
+        let mut engine = PaymentEngine::new();
+        engine
+            .write_audit_to(std::fs::File::create(&path).unwrap())
+            .unwrap();
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      4.0",
+            "dispute,    2,       2,",
+            "chargeback, 2,       2,",
+        ];
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert!(engine.reset_account(1).unwrap());
+        assert!(engine.unlock(2, Currency::default()).unwrap());
+        let audit = std::fs::read_to_string(&path).unwrap();
+        let admin: Vec<_> = audit
+            .lines()
+            .filter(|line| line.contains(",reset,") || line.contains(",unlock,"))
+            .collect();
+        assert_eq!(
+            admin,
+            [
+                "4,reset,,,1,,10.0000,0.0000,10.0000,0.0000,0.0000,0.0000,false,false",
+                "4,unlock,,,2,,0.0000,0.0000,0.0000,0.0000,0.0000,0.0000,false,false",
+            ]
+        );
+        // the operations follow the records applied before them
+        assert_eq!(audit.lines().count(), 1 + 4 + 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn validates_records() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "blocking,   1,       2,      1.0",
+            "deposit,    1,       1,      5.0",
+            "withdrawal, 1,       3,",
+            "dispute,    1,       7,",
+            "deposit,    2,       4,      -1.0",
+            "dispute,    2,       1,",
+            "withdrawal, 1,       5,      20.0",
+            "deposit,    2,       6,      1.0",
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::new();
+        let report = engine.validate(input.as_bytes()).unwrap();
+        assert_eq!(report.records, 9);
+        let issues: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.line, issue.kind))
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                (Some(3), IssueKind::Malformed),
+                (Some(4), IssueKind::DuplicateTransaction),
+                (Some(5), IssueKind::Malformed),
+                (Some(6), IssueKind::InvalidReference),
+                (Some(7), IssueKind::InvalidAmount),
+                (Some(8), IssueKind::InvalidReference),
+            ]
+        );
+        assert_eq!(
+            report.issues[3].to_string(),
+            "line 6: record of client 1 skipped: transaction does not exist"
+        );
+        assert_eq!(engine.account(2).unwrap().available, 1.0);
+
+        // the rejected records are reported likewise
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().strict(true));
+        let report = engine.validate(input.as_bytes()).unwrap();
+        assert_eq!(report.issues.len(), 6);
+        assert_eq!(report.issues[1].kind, IssueKind::DuplicateTransaction);
+        assert!(engine.process(input.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn skips_malformed_records_if_lenient() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "blocking,   1,       2,      1.0",
+            "withdrawal, 1,       3,",
+            "deposit,    1,       4,      5.x",
+            "withdrawal, 1,       5,      2.5",
+        ]
+        .join("\n");
+        assert!(matches!(
+            PaymentEngine::new().process(input.as_bytes()),
+            Err(ProcessError::Malformed { line: Some(3), .. })
+        ));
+
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().lenient(true));
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, 7.5);
+        let malformed: Vec<_> = engine
+            .report()
+            .malformed
+            .iter()
+            .map(|issue| (issue.line, issue.kind))
+            .collect();
+        assert_eq!(
+            malformed,
+            [
+                (Some(3), IssueKind::Malformed),
+                (Some(4), IssueKind::Malformed),
+                (Some(5), IssueKind::InvalidAmount),
+            ]
+        );
+        assert_eq!(
+            engine.report().malformed[0].to_string(),
+            "line 3: unknown type \"blocking\" in record \"blocking,1,2,1.0\""
+        );
+
+        // the records rejected for the state of the accounts still fail the processing
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,1,1.0\n";
+        let options = ProcessOptions::new().lenient(true).strict(true);
+        let mut engine = PaymentEngine::with_options(options);
+        assert!(engine.process(input.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn flushes_complete_rows_only() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      1.0",
+            "deposit,    2,       2,      22.0",
+            "deposit,    3,       3,      333.0",
+            "deposit,    4,       4,      4444.0",
+            "deposit,    5,       5,      55555.0",
+        ];
+        for (capacity, every) in [(8 * 1024, Some(2)), (16, None), (0, Some(1))] {
+            let options = ProcessOptions::new()
+                .buffer_capacity(capacity)
+                .flush_every(every);
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(input.join("\n").as_bytes()).unwrap();
+            let mut recorder = FlushRecorder::default();
+            engine.write(&mut recorder).unwrap();
+            assert!(recorder.pending.is_empty());
+            if let Some(every) = every {
+                assert_eq!(recorder.flushed.len(), 5usize.div_ceil(every));
+            }
+            for chunk in &recorder.flushed {
+                assert_eq!(chunk.last(), Some(&b'\n'));
+                let mut rdr = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(chunk.as_slice());
+                for record in rdr.records() {
+                    assert_eq!(record.unwrap().len(), 5);
+                }
+            }
+            let output = recorder.flushed.concat();
+            let mut expected = Vec::new();
+            self::engine(&input).write(&mut expected).unwrap();
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn flags_dispute_before_transaction_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "dispute,    1,       1,",
+            "deposit,    1,       1,      10.0",
+        ];
+        assert!(engine(&input).report().skipped.is_empty());
+
+        let options = ProcessOptions::new().strict_ordering(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert_eq!(
+            engine.report().skipped,
+            vec![Skipped {
+                row: 1,
+                client: 1,
+                tx: Some(1),
+                reason: SkipReason::DisputeBeforeTransaction
+            }]
+        );
+        // the deposit itself is applied as usual
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.available, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(0.).unwrap());
+    }
+
+    #[test]
+    fn resolves_forward_disputes_in_two_pass_mode() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "dispute,    1,       1,",
+            "dispute,    1,       3,", // never existed
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       1,      20.0", // duplicate (skip)
+            "deposit,    1,       2,      5.0",
+        ]
+        .join("\n");
+        let held = |engine: &PaymentEngine| engine.accounts().next().unwrap().held;
+
+        let mut engine = PaymentEngine::new();
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(held(&engine), Amount::try_from_f64(0.).unwrap());
+
+        let options = ProcessOptions::new().two_pass(true).strict_ordering(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.held, Amount::try_from_f64(10.).unwrap());
+        assert_eq!(account.available, Amount::try_from_f64(5.).unwrap());
+        assert_eq!(
+            engine.report().skipped,
+            vec![
+                Skipped {
+                    row: 4,
+                    client: 1,
+                    tx: Some(1),
+                    reason: SkipReason::DuplicateTransaction
+                },
+                Skipped {
+                    row: 2,
+                    client: 1,
+                    tx: Some(3),
+                    reason: SkipReason::UnknownTransaction
+                }
+            ]
+        );
+        assert!(engine.reconcile().is_ok());
+    }
+
+    #[test]
+    fn quotes_output_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      20.0",
+        ];
+        let options = ProcessOptions::new()
+            .quote_style(csv::QuoteStyle::Always)
+            .terminator(csv::Terminator::CRLF);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("\r\n"));
+        let lines: Vec<_> = output.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        for line in lines {
+            assert!(line.split(',').all(|field| {
+                field.len() >= 2 && field.starts_with('"') && field.ends_with('"')
+            }));
+        }
+    }
+
+    #[test]
+    fn counts_amounts_at_configured_precision() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.123456",
+            "withdrawal, 1,       2,      0.1",
+        ];
+        let output = |options: ProcessOptions| {
+            let mut engine = PaymentEngine::with_options(options.minor_units(true));
+            engine.process(input.join("\n").as_bytes()).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            (
+                engine.report().warnings.len(),
+                String::from_utf8(output).unwrap(),
+            )
+        };
+        assert_eq!(
+            output(ProcessOptions::new().precision(2).unwrap()),
+            (
+                1,
+                "client,available,held,total,locked,available_units,held_units,total_units\n\
+                 1,10.02,0.00,10.02,false,1002,0,1002\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            output(ProcessOptions::new().precision(6).unwrap()),
+            (
+                0,
+                "client,available,held,total,locked,available_units,held_units,total_units\n\
+                 1,10.023456,0.000000,10.023456,false,10023456,0,10023456\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            ProcessOptions::new()
+                .precision(Amount::MAX_PRECISION + 1)
+                .unwrap_err(),
+            AmountError::UnsupportedPrecision
+        );
+    }
+
+    #[test]
+    fn accumulates_gross_volumes_if_configured() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "withdrawal, 1,       3,      3.0",
+            "withdrawal, 1,       4,      100.0", // insufficient funds
+            "withdrawal, 1,       5,      2.0",
+        ];
+        let gross_deposits = engine(&input).accounts().next().unwrap().gross_deposits;
+        assert_eq!(gross_deposits, 0.);
+
+        let options = ProcessOptions::new().stats(true);
+        let mut engine = PaymentEngine::with_options(options);
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.gross_deposits, 15.);
+        assert_eq!(account.gross_withdrawals, 5.);
+        assert_eq!(account.total, 10.);
+
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client,available,held,total,locked,gross_deposits,gross_withdrawals\n\
+             1,10.0000,0.0000,10.0000,false,15.0000,5.0000\n"
+        );
+    }
+
+    #[test]
+    fn resets_account() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      5.0",
+            "deposit,    1,       3,      7.0",
+            "dispute,    1,       3,",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+        ];
+        let path = std::env::temp_dir().join(format!("{}-reset-audit.csv", std::process::id()));
+        let mut engine = engine(&input);
+        engine
+            .write_audit_to(std::fs::File::create(&path).unwrap())
+            .unwrap();
+        assert!(!engine.reset_account(2).unwrap());
+        assert!(engine.reset_account(1).unwrap());
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.available, 0.);
+        assert_eq!(account.held, 0.);
+        assert_eq!(account.total, 0.);
+        assert!(!account.locked);
+        assert_eq!(account.chargebacks, 0);
+        assert!(engine.reconcile().is_ok());
+        assert_eq!(engine.report().resets, 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap().lines().nth(1),
+            Some("6,reset,,,1,,5.0000,7.0000,12.0000,0.0000,0.0000,0.0000,false,false")
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // the dispute under way is gone, while the account is active again
+        let input = [
+            "type,       client,  tx,     amount",
+            "resolve,    1,       3,",
+            "deposit,    1,       4,      1.0",
+        ];
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.available, 1.);
+        assert_eq!(account.held, 0.);
+        assert!(engine.reconcile().is_ok());
+    }
+
+    #[test]
+    fn resets_accounts_holding_disputed_transfers() {
+        let input = [
+            "type,       client,  tx,     amount,  to_client",
+            "deposit,    1,       1,      10.0,",
+            "transfer,   1,       2,      5.0,     2",
+            "dispute,    1,       2,,",
+        ];
+        // the recipient holding the funds of the transfer
+        let mut reset = engine(&input);
+        assert!(reset.reset_account(2).unwrap());
+        reset
+            .process("type,client,tx,amount\nresolve,1,2,".as_bytes())
+            .unwrap();
+        let recipient = reset.account(2).unwrap();
+        assert_eq!(recipient.available, 0.);
+        assert_eq!(recipient.held, 0.);
+        assert_eq!(
+            reset.transaction(2).unwrap().unwrap().state,
+            TxnState::Reversed
+        );
+        assert!(reset.reconcile().is_ok());
+
+        // the sender, whose dispute no longer holds the recipient's funds
+        let mut reset = engine(&input);
+        assert!(reset.reset_account(1).unwrap());
+        let recipient = reset.account(2).unwrap();
+        assert_eq!(recipient.available, 5.);
+        assert_eq!(recipient.held, 0.);
+        assert_eq!(
+            reset.transaction(2).unwrap().unwrap().state,
+            TxnState::Undisputed
+        );
+        assert!(reset.reconcile().is_ok());
+    }
+
+    #[test]
+    fn streams_accounts_of_grouped_clients() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      5.0",
+            "deposit,    2,       3,      20.0",
+            "dispute,    2,       3,",
+            "deposit,    3,       4,      30.0",
+            "dispute,    1,       1,", // client 1 is already written out (skip)
+        ];
+        let options = ProcessOptions::new().flush_every(Some(1));
+        let mut engine = PaymentEngine::with_options(options);
+        let mut output = Vec::new();
+        engine
+            .process_streaming(input.join("\n").as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(engine.accounts().count(), 0);
+        assert_eq!(
+            engine.report().skipped,
+            vec![Skipped {
+                row: 6,
+                client: 1,
+                tx: Some(1),
+                reason: SkipReason::AccountFlushed
+            }]
+        );
+        let mut expected = Vec::new();
+        self::engine(&input[..input.len() - 1])
+            .write(&mut expected)
+            .unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn writes_accounts_sorted_by_default() {
+        let mut input = vec!["type, client, tx, amount".to_string()];
+        for tx in 1..=100u32 {
+            let client = (tx * 37) % 101;
+            input.push(format!("deposit, {client}, {tx}, 1.0"));
+        }
+        let input = input.join("\n");
+        let clients = |options: ProcessOptions| {
+            let mut engine = PaymentEngine::with_options(options);
+            engine.process(input.as_bytes()).unwrap();
+            let mut output = Vec::new();
+            engine.write(&mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            output
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().parse::<ClientID>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let sorted = clients(ProcessOptions::new());
+        assert_eq!(sorted.len(), 100);
+        assert!(sorted.is_sorted());
+        assert_eq!(clients(ProcessOptions::new()), sorted);
+
+        let mut unsorted = clients(ProcessOptions::new().sorted_output(false));
+        unsorted.sort_unstable();
+        assert_eq!(unsorted, sorted);
+    }
+
+    #[test]
+    fn disputes_withdrawals() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      4.0",
+            "dispute,    1,       2,",
+        ];
+        let balances = |engine: &PaymentEngine| {
+            let account = engine.account(1).unwrap();
+            (
+                account.available,
+                account.held,
+                account.total,
+                account.locked,
+            )
+        };
+        let mut engine = engine(&input);
+        // the funds have already left, so that nothing is taken from available
+        let amount = |value| Amount::try_from_f64(value).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (amount(6.), amount(4.), amount(10.), false)
+        );
+        assert!(engine.reconcile().is_ok());
+
+        let mut resolved = self::engine(&input);
+        resolved
+            .process("type, client, tx\nresolve, 1, 2".as_bytes())
+            .unwrap();
+        assert_eq!(
+            balances(&resolved),
+            (amount(6.), amount(0.), amount(6.), false)
+        );
+        assert!(resolved.reconcile().is_ok());
+
+        engine
+            .process("type, client, tx\nchargeback, 1, 2".as_bytes())
+            .unwrap();
+        // the withdrawn funds are credited back
+        assert_eq!(
+            balances(&engine),
+            (amount(10.), amount(0.), amount(10.), true)
+        );
+        assert!(engine.reconcile().is_ok());
+    }
+
+    #[test]
+    fn reads_headerless_input_if_configured() {
+        let input = [
+            "# exported by the partner",
+            "deposit;1;1;'10.0'",
+            "deposit;2;2;'1;5'",
+            "# withdrawals follow",
+            "withdrawal; 1;       3;      2.5",
+            "deposit;    2;       4;      1.0; BTC",
+        ];
+        let options = ProcessOptions::new()
+            .delimiter(b';')
+            .quote(b'\'')
+            .comment(Some(b'#'))
+            .has_headers(false);
+        let mut engine = PaymentEngine::with_options(options.clone());
+        // the delimiter is quoted, and so it ends up in the amount
+        let err = engine.process(input.join("\n").as_bytes()).unwrap_err();
+        assert!(matches!(err, ProcessError::AmountRejected { ref amount, .. } if amount == "1;5"));
+        assert_eq!(engine.account(1).unwrap().total, 10.);
+
+        let input = input.join("\n").replace("1;5", "1.5");
+        // the columns are not renamed, there being no headers to rename
+        let columns = ColumnMap::new().column("amount", "tx");
+        let mut engine = PaymentEngine::with_options(options.column_map(columns));
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.report().records, 4);
+        assert_eq!(engine.account(1).unwrap().available, 7.5);
+        assert_eq!(engine.account(2).unwrap().available, 1.5);
+        let btc = Currency::new("BTC").unwrap();
+        assert_eq!(engine.accounts.get(2, btc).unwrap().available, 1.);
+    }
+
+    #[test]
+    fn applies_delimiter_and_locked_deposits_if_configured() {
+        let input = [
+            "type;       client;  tx;     amount",
+            "deposit;    1;       1;      10.0",
+            "dispute;    1;       1;",
+            "chargeback; 1;       1;",
+            "deposit;    1;       2;      3.0",
+        ];
+        let options = ProcessOptions::new().delimiter(b';');
+        let mut engine = PaymentEngine::with_options(options.clone());
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total, 0.);
+        assert_eq!(engine.report().locked_skips, 1);
+        let mut output = Vec::new();
+        engine.write(&mut output).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "client;available;held;total;locked\n1;0.0000;0.0000;0.0000;true\n"
+        );
+
+        let mut engine = PaymentEngine::with_options(options.locked_deposits(true));
+        engine.process(input.join("\n").as_bytes()).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total, 3.);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn rejects_referentially_invalid_records_in_strict_mode() {
+        let cases = [
+            ("dispute,    1,       2,", SkipReason::UnknownTransaction),
+            (
+                "withdrawal, 2,       2,      1.0",
+                SkipReason::UnknownAccount,
+            ),
+            (
+                "deposit,    1,       1,      1.0",
+                SkipReason::DuplicateTransaction,
+            ),
+        ];
+        for (record, reason) in cases {
+            let input = [
+                "type,       client,  tx,     amount",
+                "deposit,    1,       1,      10.0",
+                "withdrawal, 1,       3,      50.0", // insufficient funds (skip)
+                record,
+            ]
+            .join("\n");
+            assert!(!engine(&[&input]).report().skipped.is_empty());
+
+            let options = ProcessOptions::new().strict(true);
+            let mut engine = PaymentEngine::with_options(options);
+            let err = engine.process(input.as_bytes()).unwrap_err();
+            let ProcessError::Rejected {
+                row,
+                line,
+                reason: rejected,
+                ..
+            } = err
+            else {
+                panic!("unexpected error: {err}");
+            };
+            // the insufficient funds are not a reason to fail though
+            assert_eq!((row, line, rejected), (3, Some(4), reason));
+            assert!(err.to_string().starts_with("line 4: record of client"));
+        }
+    }
+
+    #[test]
+    fn handles_duplicates_per_policy() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       1,      20.0",
+            "dispute,    1,       1,",
+        ]
+        .join("\n");
+        let cases = [
+            (DuplicatePolicy::Report, Some(1)),
+            (DuplicatePolicy::Skip, Some(0)),
+            (DuplicatePolicy::Reject, None),
+        ];
+        for (policy, skipped) in cases {
+            let options = ProcessOptions::new().on_duplicate(policy);
+            let mut engine = PaymentEngine::with_options(options);
+            let result = engine.process(input.as_bytes());
+            let Some(skipped) = skipped else {
+                assert!(matches!(
+                    result,
+                    Err(ProcessError::Rejected {
+                        row: 2,
+                        reason: SkipReason::DuplicateTransaction,
+                        ..
+                    })
+                ));
+                continue;
+            };
+            result.unwrap();
+            assert_eq!(engine.report().skipped.len(), skipped, "{policy:?}");
+            // the original transaction is the one disputed
+            let account = engine.account(1).unwrap();
+            assert_eq!(account.held, 10.);
+            assert_eq!(account.total, 10.);
+        }
+    }
+
+    #[test]
+    fn ignores_dispute_of_another_clients_transaction() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      5.0",
+            "dispute,    2,       1,",
+            "dispute,    3,       1,", // client never transacted
+        ];
+        let engine = engine(&input);
+        assert_eq!(engine.account(1).unwrap().held, 0.);
+        assert_eq!(engine.account(2).unwrap().held, 0.);
+        assert_eq!(engine.account(2).unwrap().available, 5.);
+        assert!(engine.account(3).is_none());
+        let skipped: Vec<_> = engine
+            .report()
+            .skipped
+            .iter()
+            .map(|skipped| (skipped.row, skipped.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (3, SkipReason::ClientMismatch),
+                (4, SkipReason::ClientMismatch)
+            ]
+        );
+
+        let options = ProcessOptions::new().strict(true);
+        let mut engine = PaymentEngine::with_options(options);
+        let err = engine.process(input.join("\n").as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            ProcessError::Rejected {
+                row: 3,
+                reason: SkipReason::ClientMismatch,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn skips_dispute_if_account_is_gone() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      5.0",
+        ]
+        .join("\n");
+        let mut engine = PaymentEngine::new();
+        engine.process(input.as_bytes()).unwrap();
+        engine.accounts.remove(1, Currency::default());
+        engine
+            .process("type, client, tx\ndispute, 1, 1".as_bytes())
+            .unwrap();
+        assert_eq!(
+            engine.report().skipped,
+            vec![Skipped {
+                row: 3,
+                client: 1,
+                tx: Some(1),
+                reason: SkipReason::UnknownAccount
+            }]
+        );
+    }
+}
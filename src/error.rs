@@ -0,0 +1,269 @@
+use crate::domain::{ClientID, TxnID};
+use crate::{MergeError, SkipReason, Violation};
+
+/// Error processing the records.
+///
+/// The optional features bring in variants of their own (e.g. `AsyncCsv` with
+/// the `tokio` one), and so the error is not to be matched exhaustively.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ProcessError {
+    /// Failed to read or deserialize a record.
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
+    /// Failed to read or deserialize a record from an async reader, see
+    /// [`PaymentEngine::process_async`](crate::PaymentEngine::process_async).
+    #[cfg(feature = "tokio")]
+    #[error(transparent)]
+    AsyncCsv(#[from] csv_async::Error),
+
+    /// Failed to consume the messages of a topic, see [`KafkaConsumer`](crate::KafkaConsumer).
+    #[cfg(feature = "kafka")]
+    #[error(transparent)]
+    Kafka(#[from] kafka::Error),
+
+    /// Failed to read a JSON record, see [`InputFormat::JsonLines`](crate::InputFormat::JsonLines).
+    #[cfg(feature = "json")]
+    #[error("{}: {source}", location(*row, *line))]
+    Json {
+        row: u64,
+        line: Option<u64>,
+        source: serde_json::Error,
+    },
+
+    /// The record cannot be read, e.g. its type is unknown or its client is not
+    /// a number.
+    ///
+    /// The `record` holds its fields, comma separated, and the `line` is where
+    /// it starts in the input, if known.
+    #[error("{}: {reason} in record \"{record}\"", location(*row, *line))]
+    Malformed {
+        row: u64,
+        line: Option<u64>,
+        record: String,
+        reason: String,
+    },
+
+    /// Failed to serialize an account to the output.
+    #[error("failed to serialize account: {0}")]
+    Serialize(#[source] csv::Error),
+
+    /// Failed to write to or flush the underlying writer.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// A deposit or a withdrawal record (of the `kind`) is missing its amount.
+    #[error("{}: amount is missing{}", location(*row, *line), subject(kind, *tx))]
+    MissingAmount {
+        row: u64,
+        line: Option<u64>,
+        kind: String,
+        tx: Option<TxnID>,
+    },
+
+    /// The value cannot be represented as an [`Amount`](crate::Amount).
+    #[error("invalid amount: {0}")]
+    InvalidAmount(#[from] AmountError),
+
+    /// The `amount` of a record (of the `kind`) is not valid, e.g. of a deposit.
+    #[error(
+        "{}: invalid amount \"{amount}\"{}: {source}",
+        location(*row, *line),
+        subject(kind, *tx)
+    )]
+    AmountRejected {
+        row: u64,
+        line: Option<u64>,
+        kind: String,
+        tx: Option<TxnID>,
+        amount: String,
+        source: AmountError,
+    },
+
+    /// The amount has more significant decimal places than the engine can
+    /// store, and the engine has been configured to reject such records, see
+    /// [`ProcessOptions::reject_precision_loss`](crate::ProcessOptions::reject_precision_loss).
+    #[error(
+        "{}: amount {amount} of transaction {tx} exceeds supported precision",
+        location(*row, *line)
+    )]
+    PrecisionLoss {
+        row: u64,
+        line: Option<u64>,
+        tx: TxnID,
+        amount: String,
+    },
+
+    /// A dispute resolution record has been encountered even though the engine
+    /// has been told there are none in the input, see
+    /// [`ProcessOptions::disputes_present`](crate::ProcessOptions::disputes_present).
+    #[error(
+        "{}: unexpected dispute resolution record for transaction {tx}",
+        location(*row, *line)
+    )]
+    UnexpectedDispute {
+        row: u64,
+        line: Option<u64>,
+        tx: TxnID,
+    },
+
+    /// The record is referentially invalid, and the engine has been configured
+    /// to reject such records, see [`ProcessOptions::strict`](crate::ProcessOptions::strict).
+    ///
+    /// The `line` is where the record starts in the input, if known.
+    #[error("{}: record of client {client} rejected: {reason}", location(*row, *line))]
+    Rejected {
+        row: u64,
+        line: Option<u64>,
+        client: ClientID,
+        tx: Option<TxnID>,
+        reason: SkipReason,
+    },
+
+    /// The snapshot being restored is malformed, or has been taken at a higher
+    /// precision, see [`PaymentEngine::restore`](crate::PaymentEngine::restore).
+    ///
+    /// The `row` is zero if the header (or the version of the format following
+    /// it) is not the expected one.
+    #[error("row {row}: invalid snapshot entry")]
+    InvalidSnapshot { row: u64 },
+
+    /// Failed to combine the shards processed in parallel, see
+    /// [`PaymentEngine::process_parallel`](crate::PaymentEngine::process_parallel).
+    #[error(transparent)]
+    Merge(#[from] MergeError),
+
+    /// The transfer does not name a recipient other than its client, see
+    /// [`TxnRecord::to_client`](crate::TxnRecord::to_client).
+    #[error(
+        "{}: transfer expected to have a recipient other than its client",
+        location(*row, *line)
+    )]
+    InvalidTransfer { row: u64, line: Option<u64> },
+
+    /// The timestamp of a record is not an unsigned integer, see
+    /// [`Timestamp`](crate::Timestamp).
+    ///
+    /// The `line` is where the record starts in the input, if known.
+    #[error("{}: invalid timestamp \"{value}\"", location(*row, *line))]
+    InvalidTimestamp {
+        row: u64,
+        line: Option<u64>,
+        value: String,
+    },
+
+    /// The engine is expected to not have processed anything yet, see
+    /// [`PaymentEngine::process_parallel`](crate::PaymentEngine::process_parallel).
+    #[error("engine expected to be empty")]
+    NotEmpty,
+
+    /// The engine has been fed more records than allowed, see
+    /// [`ProcessOptions::max_records`](crate::ProcessOptions::max_records).
+    #[error("number of records exceeds the limit of {limit}")]
+    TooManyRecords { limit: usize },
+
+    /// Applying the record broke the accounting invariants of an account, see
+    /// [`ProcessOptions::verify_invariants`](crate::ProcessOptions::verify_invariants).
+    #[error("{}: invariant violated, {violation}", location(*row, *line))]
+    InvariantViolated {
+        row: u64,
+        line: Option<u64>,
+        violation: Violation,
+    },
+}
+
+impl ProcessError {
+    /// Whether the error is about a single record (e.g. a malformed one), as
+    /// opposed to the input or the output failing, and so the records that follow
+    /// could still be read.
+    pub(crate) fn is_record_error(&self) -> bool {
+        match self {
+            ProcessError::Csv(err) => !err.is_io_error(),
+            #[cfg(feature = "tokio")]
+            ProcessError::AsyncCsv(_) => false,
+            #[cfg(feature = "kafka")]
+            ProcessError::Kafka(_) => false,
+            #[cfg(feature = "json")]
+            ProcessError::Json { .. } => true,
+            ProcessError::Malformed { .. }
+            | ProcessError::MissingAmount { .. }
+            | ProcessError::InvalidAmount(_)
+            | ProcessError::AmountRejected { .. }
+            | ProcessError::PrecisionLoss { .. }
+            | ProcessError::UnexpectedDispute { .. }
+            | ProcessError::Rejected { .. }
+            | ProcessError::InvalidTransfer { .. }
+            | ProcessError::InvalidTimestamp { .. } => true,
+            ProcessError::Serialize(_)
+            | ProcessError::Io(_)
+            | ProcessError::InvalidSnapshot { .. }
+            | ProcessError::Merge(_)
+            | ProcessError::NotEmpty
+            | ProcessError::TooManyRecords { .. }
+            | ProcessError::InvariantViolated { .. } => false,
+        }
+    }
+
+    /// Whether this is an error of a record that cannot be parsed, and so can
+    /// be skipped, see [`ProcessOptions::lenient`](crate::ProcessOptions::lenient).
+    pub(crate) fn is_parse_error(&self) -> bool {
+        match self {
+            ProcessError::Csv(err) => !err.is_io_error(),
+            #[cfg(feature = "json")]
+            ProcessError::Json { .. } => true,
+            ProcessError::Malformed { .. }
+            | ProcessError::MissingAmount { .. }
+            | ProcessError::InvalidAmount(_)
+            | ProcessError::AmountRejected { .. }
+            | ProcessError::InvalidTransfer { .. }
+            | ProcessError::InvalidTimestamp { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Reason for a value to not be a valid [`Amount`](crate::Amount).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum AmountError {
+    #[error("not a number")]
+    NotANumber,
+
+    #[error("infinite")]
+    Infinite,
+
+    /// Only the amounts of the input transactions are rejected for this.
+    #[error("negative")]
+    Negative,
+
+    #[error("out of range")]
+    Overflow,
+
+    #[error("not a decimal number")]
+    Malformed,
+
+    /// The number of places past the decimal point exceeds
+    /// [`Amount::MAX_PRECISION`](crate::Amount::MAX_PRECISION).
+    #[error("unsupported precision")]
+    UnsupportedPrecision,
+}
+
+pub(crate) fn location(row: u64, line: Option<u64>) -> String {
+    match line {
+        Some(line) => format!("line {line}"),
+        None => format!("row {row}"),
+    }
+}
+
+/// The record of the `kind` about the `tx`, as far as known, e.g. ` for deposit tx 99`.
+fn subject(kind: &str, tx: Option<TxnID>) -> String {
+    match (kind, tx) {
+        ("", _) => String::new(),
+        (kind, None) => format!(" for {kind}"),
+        (kind, Some(tx)) => format!(" for {kind} tx {tx}"),
+    }
+}
+
+/// Former name of the [`ProcessError`].
+#[deprecated(note = "renamed to `ProcessError`")]
+pub type PaymentError = ProcessError;
@@ -0,0 +1,466 @@
+//! gRPC service of the engine, for other services to push the records with
+//! backpressure instead of shipping CSV files around.
+//!
+//! The service is described by `proto/payment_engine.proto`, and mirrored by
+//! the messages of this module, so that it can be built without `protoc`, which
+//! the tests check against the file.
+
+use std::sync::{Arc, Mutex};
+
+use tonic::codegen::tokio_stream::StreamExt;
+use tonic::codegen::{Body, BoxFuture, BoxStream, Context, Poll, Service, StdError, http};
+use tonic::server::{Grpc, NamedService, StreamingService, UnaryService};
+use tonic::{Request, Response, Status, Streaming};
+use tonic_prost::ProstCodec;
+
+use crate::domain::{ClientID, Currency, format_amount};
+use crate::{PaymentEngine, ProcessError, TxnOutcome};
+
+/// A record, with the same fields as a row of the CSV input.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Transaction {
+    /// Kind of the record, e.g. `deposit`.
+    #[prost(string, tag = "1")]
+    pub r#type: String,
+    #[prost(uint32, tag = "2")]
+    pub client: u32,
+    #[prost(uint32, optional, tag = "3")]
+    pub tx: Option<u32>,
+    /// Decimal text of the amount, empty if not applicable.
+    #[prost(string, tag = "4")]
+    pub amount: String,
+    /// Currency code, empty for the implicit currency.
+    #[prost(string, tag = "5")]
+    pub currency: String,
+    #[prost(uint64, optional, tag = "6")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "7")]
+    pub to_client: Option<u32>,
+}
+
+/// What became of a [`Transaction`], see [`TxnEvent`](crate::TxnEvent).
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TransactionOutcome {
+    #[prost(uint64, tag = "1")]
+    pub row: u64,
+    #[prost(uint32, tag = "2")]
+    pub client: u32,
+    #[prost(uint32, optional, tag = "3")]
+    pub tx: Option<u32>,
+    /// `applied`, `skipped`, `unknown_transaction` or `ignored`.
+    #[prost(string, tag = "4")]
+    pub outcome: String,
+    /// Why the record was skipped, if it was.
+    #[prost(string, tag = "5")]
+    pub reason: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetAccountRequest {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    /// Currency code, empty for the implicit currency.
+    #[prost(string, tag = "2")]
+    pub currency: String,
+}
+
+/// A client's account in a currency, see [`crate::Account`].
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Account {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    #[prost(string, tag = "2")]
+    pub currency: String,
+    /// Decimal text of the available funds, and likewise for the other ones.
+    #[prost(string, tag = "3")]
+    pub available: String,
+    #[prost(string, tag = "4")]
+    pub held: String,
+    #[prost(string, tag = "5")]
+    pub total: String,
+    #[prost(bool, tag = "6")]
+    pub locked: bool,
+}
+
+/// The `PaymentEngine` gRPC service on top of a [`PaymentEngine`].
+///
+/// The transactions of a `SubmitTransactions` stream are applied one at a time,
+/// as the outcomes are consumed, so that a client sending faster than the engine
+/// applies them is held back by the flow control of the connection. The stream
+/// ends with the status of the first record failing the processing, if any, e.g.
+/// `INVALID_ARGUMENT` for a malformed one.
+///
+/// The engine is called on the blocking threads of the runtime rather than its
+/// workers. A call failing due to a panic (e.g. of an observer) ends with the
+/// `INTERNAL` status, as do the later ones, the engine being left poisoned.
+#[derive(Debug, Clone)]
+pub struct GrpcService {
+    engine: Arc<Mutex<PaymentEngine>>,
+}
+
+impl GrpcService {
+    /// Serve the `engine`, e.g. one [restored](PaymentEngine::restore) from a snapshot.
+    pub fn new(engine: PaymentEngine) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+        }
+    }
+
+    /// Accept the connections on the `listener` until the server fails.
+    pub async fn serve(
+        self,
+        listener: tokio::net::TcpListener,
+    ) -> Result<(), tonic::transport::Error> {
+        let incoming = tonic::transport::server::TcpIncoming::from(listener);
+        tonic::transport::Server::builder()
+            .add_service(self)
+            .serve_with_incoming(incoming)
+            .await
+    }
+}
+
+impl NamedService for GrpcService {
+    const NAME: &'static str = "payment_engine.PaymentEngine";
+}
+
+impl<B> Service<http::Request<B>> for GrpcService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let engine = self.engine.clone();
+        match req.uri().path() {
+            "/payment_engine.PaymentEngine/SubmitTransactions" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.streaming(Submit(engine), req).await)
+            }),
+            "/payment_engine.PaymentEngine/GetAccount" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(GetAccount(engine), req).await)
+            }),
+            path => {
+                let status = Status::unimplemented(format!("no method {path}"));
+                Box::pin(async move { Ok(status.into_http()) })
+            }
+        }
+    }
+}
+
+/// The `SubmitTransactions` method.
+struct Submit(Arc<Mutex<PaymentEngine>>);
+
+impl StreamingService<Transaction> for Submit {
+    type Response = TransactionOutcome;
+    type ResponseStream = BoxStream<TransactionOutcome>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<Streaming<Transaction>>) -> Self::Future {
+        let engine = self.0.clone();
+        let outcomes = request.into_inner().then(move |txn| {
+            let engine = engine.clone();
+            async move { blocking(move || submit(&engine, txn?)).await }
+        });
+        Box::pin(async move { Ok(Response::new(Box::pin(outcomes) as Self::ResponseStream)) })
+    }
+}
+
+/// Call `f` on a thread of its own, since it locks the engine for as long as
+/// it takes, failing with the `INTERNAL` status if it panicked.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) => Err(Status::internal(err.to_string())),
+    }
+}
+
+/// Apply the `txn`, telling what became of it.
+fn submit(engine: &Mutex<PaymentEngine>, txn: Transaction) -> Result<TransactionOutcome, Status> {
+    let optional = |value: Option<u64>| value.map_or(String::new(), |value| value.to_string());
+    let mut raw = csv::StringRecord::from(vec![
+        txn.r#type,
+        txn.client.to_string(),
+        optional(txn.tx.map(u64::from)),
+        txn.amount,
+        txn.currency,
+        optional(txn.timestamp),
+        optional(txn.to_client.map(u64::from)),
+    ]);
+    // the fields get trimmed when read from the CSV input
+    raw.trim();
+    let mut engine = engine.lock().expect("engine not poisoned");
+    let event = engine
+        .apply_raw(&mut raw)
+        .map_err(status)?
+        .ok_or_else(|| Status::resource_exhausted("limit on the number of records reached"))?;
+    let (outcome, reason) = match event.outcome {
+        TxnOutcome::Applied => ("applied", String::new()),
+        TxnOutcome::Skipped(reason) => ("skipped", reason.to_string()),
+        TxnOutcome::UnknownTransaction => ("unknown_transaction", String::new()),
+        TxnOutcome::Ignored => ("ignored", String::new()),
+    };
+    Ok(TransactionOutcome {
+        row: event.row,
+        client: event.client.into(),
+        tx: event.tx,
+        outcome: outcome.to_string(),
+        reason,
+    })
+}
+
+/// Status of the response to a record failed with the `err`.
+fn status(err: ProcessError) -> Status {
+    match err {
+        ProcessError::Io(_)
+        | ProcessError::Serialize(_)
+        | ProcessError::InvariantViolated { .. } => Status::internal(err.to_string()),
+        ProcessError::Csv(ref inner) if inner.is_io_error() => Status::internal(err.to_string()),
+        ProcessError::Rejected { .. } | ProcessError::TooManyRecords { .. } => {
+            Status::failed_precondition(err.to_string())
+        }
+        _ => Status::invalid_argument(err.to_string()),
+    }
+}
+
+/// The `GetAccount` method.
+struct GetAccount(Arc<Mutex<PaymentEngine>>);
+
+impl UnaryService<GetAccountRequest> for GetAccount {
+    type Response = Account;
+    type Future = BoxFuture<Response<Account>, Status>;
+
+    fn call(&mut self, request: Request<GetAccountRequest>) -> Self::Future {
+        let engine = self.0.clone();
+        let request = request.into_inner();
+        Box::pin(async move {
+            blocking(move || get_account(&engine, request))
+                .await
+                .map(Response::new)
+        })
+    }
+}
+
+fn get_account(
+    engine: &Mutex<PaymentEngine>,
+    request: GetAccountRequest,
+) -> Result<Account, Status> {
+    let client = ClientID::try_from(request.client)
+        .map_err(|_| Status::invalid_argument(format!("invalid client {}", request.client)))?;
+    let currency = Currency::new(&request.currency).ok_or_else(|| {
+        Status::invalid_argument(format!("invalid currency {}", request.currency))
+    })?;
+    let engine = engine.lock().expect("engine not poisoned");
+    let places = engine.options().precision;
+    let account = engine
+        .accounts()
+        .find(|account| account.client == client && account.currency == currency)
+        .ok_or_else(|| Status::not_found(format!("no account of client {client}")))?;
+    Ok(Account {
+        client: account.client.into(),
+        currency: account.currency.as_str().to_string(),
+        available: format_amount(account.available, places),
+        held: format_amount(account.held, places),
+        total: format_amount(account.total, places),
+        locked: account.locked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::encoding::WireType;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::codegen::tokio_stream::{self, StreamExt};
+    use tonic::{Code, Request};
+    use tonic_prost::ProstCodec;
+
+    use super::{Account, GetAccountRequest, GrpcService, Transaction, TransactionOutcome};
+    use crate::PaymentEngine;
+
+    fn txn(kind: &str, tx: u32, amount: &str) -> Transaction {
+        Transaction {
+            r#type: kind.to_string(),
+            client: 1,
+            tx: Some(tx),
+            amount: amount.to_string(),
+            ..Transaction::default()
+        }
+    }
+
+    /// Fields of the `message` as declared by the proto file, i.e. their names,
+    /// tags, wire types and whether they are optional.
+    fn declared(message: &str) -> Vec<(String, u32, WireType, bool)> {
+        let proto = include_str!("../proto/payment_engine.proto");
+        let start = format!("message {message} {{");
+        let lines = proto.lines().skip_while(|line| *line != start).skip(1);
+        lines
+            .take_while(|line| *line != "}")
+            .map(str::trim)
+            .filter(|line| !line.starts_with("//"))
+            .map(|line| {
+                let (optional, line) = match line.strip_prefix("optional ") {
+                    Some(line) => (true, line),
+                    None => (false, line),
+                };
+                let fields: Vec<_> = line.trim_end_matches(';').split_whitespace().collect();
+                let wire_type = match fields[0] {
+                    "string" => WireType::LengthDelimited,
+                    _ => WireType::Varint,
+                };
+                (
+                    fields[1].to_string(),
+                    fields[3].parse().unwrap(),
+                    wire_type,
+                    optional,
+                )
+            })
+            .collect()
+    }
+
+    /// Fields of the `message`, which is expected to have all of them set,
+    /// same as [`declared`].
+    fn mirrored<M>(message: &M) -> Vec<(String, u32, WireType, bool)>
+    where
+        M: prost::Message + std::fmt::Debug,
+    {
+        let debug = format!("{message:?}");
+        let fields = &debug[debug.find('{').unwrap() + 1..debug.rfind('}').unwrap()];
+        let encoded = message.encode_to_vec();
+        let mut buf = encoded.as_slice();
+        let mut keys = Vec::new();
+        while !buf.is_empty() {
+            let (tag, wire_type) = prost::encoding::decode_key(&mut buf).unwrap();
+            prost::encoding::skip_field(wire_type, tag, &mut buf, Default::default()).unwrap();
+            keys.push((tag, wire_type));
+        }
+        let fields: Vec<_> = fields.split(", ").collect();
+        assert_eq!(fields.len(), keys.len(), "all fields of {debug} set");
+        fields
+            .into_iter()
+            .zip(keys)
+            .map(|(field, (tag, wire_type))| {
+                let (name, value) = field.trim().split_once(": ").unwrap();
+                let name = name.trim_start_matches("r#").to_string();
+                (name, tag, wire_type, value.starts_with("Some("))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mirrors_proto_messages() {
+        let txn = Transaction {
+            r#type: "deposit".to_string(),
+            client: 1,
+            tx: Some(2),
+            amount: "1.0".to_string(),
+            currency: "USD".to_string(),
+            timestamp: Some(3),
+            to_client: Some(4),
+        };
+        assert_eq!(mirrored(&txn), declared("Transaction"));
+        let outcome = TransactionOutcome {
+            row: 1,
+            client: 2,
+            tx: Some(3),
+            outcome: "skipped".to_string(),
+            reason: "why".to_string(),
+        };
+        assert_eq!(mirrored(&outcome), declared("TransactionOutcome"));
+        let request = GetAccountRequest {
+            client: 1,
+            currency: "USD".to_string(),
+        };
+        assert_eq!(mirrored(&request), declared("GetAccountRequest"));
+        let account = Account {
+            client: 1,
+            currency: "USD".to_string(),
+            available: "1".to_string(),
+            held: "2".to_string(),
+            total: "3".to_string(),
+            locked: true,
+        };
+        assert_eq!(mirrored(&account), declared("Account"));
+    }
+
+    #[tokio::test]
+    async fn serves_the_engine() {
+        let mut client = tonic::client::Grpc::new(GrpcService::new(PaymentEngine::new()));
+        let submit = PathAndQuery::from_static("/payment_engine.PaymentEngine/SubmitTransactions");
+        let get_account = PathAndQuery::from_static("/payment_engine.PaymentEngine/GetAccount");
+
+        let txns = [
+            txn("deposit", 1, "10.0"),
+            txn("withdrawal", 2, "20"),
+            txn("dispute", 1, ""),
+        ];
+        let request = Request::new(tokio_stream::iter(txns));
+        let response = client.streaming(request, submit.clone(), ProstCodec::default());
+        let outcomes: Vec<TransactionOutcome> = response
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let outcomes: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| {
+                (
+                    outcome.row,
+                    outcome.outcome.as_str(),
+                    outcome.reason.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (1, "applied", ""),
+                (2, "skipped", "insufficient funds"),
+                (3, "applied", "")
+            ]
+        );
+
+        let request = Request::new(GetAccountRequest {
+            client: 1,
+            currency: String::new(),
+        });
+        let response = client.unary(request, get_account.clone(), ProstCodec::default());
+        let account: Account = response.await.unwrap().into_inner();
+        assert_eq!(
+            (account.available.as_str(), account.held.as_str()),
+            ("0.0000", "10.0000")
+        );
+
+        let request = Request::new(GetAccountRequest {
+            client: 2,
+            currency: String::new(),
+        });
+        let response = client.unary::<_, Account, _>(request, get_account, ProstCodec::default());
+        assert_eq!(response.await.unwrap_err().code(), Code::NotFound);
+
+        let request = Request::new(tokio_stream::iter([
+            txn("deposit", 3, "1.0"),
+            txn("what", 4, ""),
+        ]));
+        let response = client.streaming(request, submit, ProstCodec::default());
+        let mut outcomes = response.await.unwrap().into_inner();
+        let outcome: TransactionOutcome = outcomes.next().await.unwrap().unwrap();
+        assert_eq!(outcome.row, 4);
+        assert_eq!(
+            outcomes.next().await.unwrap().unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use serde_json::value::RawValue;
+
+use crate::{COLUMNS, ColumnMap, ProcessError};
+
+/// Byte order mark the input may start with.
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Tell whether the input of the `reader` is in JSON Lines format, that is it
+/// starts with `{`, see [`InputFormat::Auto`](crate::InputFormat::Auto).
+///
+/// The leading whitespace (and the byte order mark) can span several reads,
+/// e.g. of a pipe, and so the bytes read to tell are returned as well, for them
+/// to be read again.
+pub(crate) fn sniff<R>(reader: &mut R) -> std::io::Result<(bool, Vec<u8>)>
+where
+    R: BufRead,
+{
+    let mut start = Vec::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok((false, start));
+        }
+        start.extend_from_slice(buf);
+        let read = buf.len();
+        reader.consume(read);
+        if start.len() < BOM.len() && BOM.starts_with(&start) {
+            continue;
+        }
+        let text = start.strip_prefix(BOM).unwrap_or(&start);
+        if let Some(first) = text.trim_ascii_start().first() {
+            return Ok((*first == b'{', start));
+        }
+    }
+}
+
+/// Read the JSON object on the `line` into the `raw` record with the [`COLUMNS`](crate::COLUMNS),
+/// for it to be deserialized the same way as a CSV one.
+///
+/// The record is at the `row`, and on the `line_no` of the input, if known.
+///
+/// The fields are renamed as per the `columns`, and the fields not in the
+/// [`COLUMNS`](crate::COLUMNS) are ignored. The numbers are kept in their textual form, for
+/// the amounts to be read exactly, while `null` stands for an empty field.
+pub(crate) fn read_line(
+    line: &str,
+    row: u64,
+    line_no: Option<u64>,
+    columns: &ColumnMap,
+    raw: &mut csv::StringRecord,
+) -> Result<(), ProcessError> {
+    let json = |source| ProcessError::Json {
+        row,
+        line: line_no,
+        source,
+    };
+    let fields: HashMap<&str, &RawValue> = serde_json::from_str(line).map_err(json)?;
+    let mut values = [const { Cow::Borrowed("") }; COLUMNS.len()];
+    for (name, value) in fields {
+        let name = columns.expected(name);
+        let Some(idx) = COLUMNS.iter().position(|header| *header == name) else {
+            continue;
+        };
+        values[idx] = match value.get() {
+            "null" => Cow::Borrowed(""),
+            text if text.starts_with('"') => serde_json::from_str(text).map_err(json)?,
+            text => Cow::Borrowed(text),
+        };
+    }
+    raw.clear();
+    values.iter().for_each(|value| raw.push_field(value.trim()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+
+    #[test]
+    fn sniffs_across_reads() {
+        for (input, json) in [
+            ("\u{feff}  \n {\"type\": \"deposit\"}", true),
+            ("   \n\ntype,client", false),
+            ("\u{feff}", false),
+            ("", false),
+        ] {
+            // a byte at a time, as a pipe may hand it over
+            let mut reader = BufReader::with_capacity(1, input.as_bytes());
+            let (sniffed, start) = super::sniff(&mut reader).unwrap();
+            assert_eq!(sniffed, json, "{input:?}");
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            assert_eq!(String::from_utf8(start).unwrap() + &rest, input);
+        }
+    }
+}
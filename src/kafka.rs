@@ -0,0 +1,273 @@
+use std::fmt;
+
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+
+use crate::{PaymentEngine, ProcessError};
+
+/// Source of the messages to consume, see [`KafkaConsumer`].
+trait Source {
+    /// Payloads of the next batch of messages, which get marked as consumed.
+    fn poll(&mut self) -> Result<Vec<Vec<u8>>, ProcessError>;
+
+    /// Commit the offsets of the messages consumed so far.
+    fn commit(&mut self) -> Result<(), ProcessError>;
+}
+
+impl Source for Consumer {
+    fn poll(&mut self) -> Result<Vec<Vec<u8>>, ProcessError> {
+        let sets = Consumer::poll(self)?;
+        let mut payloads = Vec::new();
+        for set in sets.iter() {
+            payloads.extend(set.messages().iter().map(|message| message.value.to_vec()));
+            self.consume_messageset(set)?;
+        }
+        Ok(payloads)
+    }
+
+    fn commit(&mut self) -> Result<(), ProcessError> {
+        Ok(self.commit_consumed()?)
+    }
+}
+
+/// Consumer of the records in the messages of a Kafka topic, one record per
+/// message, in either CSV or JSON.
+///
+/// The records are applied a batch of messages at a time, and the offsets of
+/// the batch are committed only once the engine's write-ahead log has been synced,
+/// see [`PaymentEngine::sync_wal`]. And so, for each record to be applied exactly
+/// once, the engine is to be [opened with a log](PaymentEngine::open_with_wal)
+/// and be [idempotent](crate::ProcessOptions::idempotent): after a crash the log
+/// brings the engine back to where it was, while the records that were applied
+/// but not committed get delivered again, and are recognized as such.
+///
+/// A CSV message is a single row in the order of a headerless input, see
+/// [`ProcessOptions::has_headers`](crate::ProcessOptions::has_headers), while a JSON one is
+/// an object, as per [`ProcessOptions::input_format`](crate::ProcessOptions::input_format).
+/// Empty messages are ignored.
+///
+/// A message whose record cannot be applied on its own (e.g. a malformed one,
+/// or one rejected in the [strict](crate::ProcessOptions::strict) mode) is
+/// skipped rather than failing its batch over and over again, and so its
+/// offset gets committed along with the others. Such messages are dead-lettered
+/// to the rejects of the engine, if written, see [`PaymentEngine::write_rejects_to`].
+/// With the engine being [lenient](crate::ProcessOptions::lenient), the messages
+/// that cannot be parsed are reported as malformed too, see [`PaymentEngine::report`].
+pub struct KafkaConsumer {
+    consumer: Consumer,
+}
+
+impl fmt::Debug for KafkaConsumer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KafkaConsumer")
+            .field("group", &self.consumer.group())
+            .finish_non_exhaustive()
+    }
+}
+
+impl KafkaConsumer {
+    /// Consume the `topic` from the brokers at the `hosts` (e.g. `localhost:9092`)
+    /// as a member of the consumer `group`, starting from the offsets the group
+    /// has committed, or from the earliest ones.
+    pub fn new(hosts: Vec<String>, topic: &str, group: &str) -> Result<Self, ProcessError> {
+        let consumer = Consumer::from_hosts(hosts)
+            .with_topic(topic.to_string())
+            .with_group(group.to_string())
+            .with_fallback_offset(FetchOffset::Earliest)
+            .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+            .create()?;
+        Ok(Self::from_consumer(consumer))
+    }
+
+    /// Consume with the `consumer` configured otherwise, which is expected to
+    /// have a group to commit the offsets with.
+    pub fn from_consumer(consumer: Consumer) -> Self {
+        Self { consumer }
+    }
+
+    /// Apply the records of the next batch of messages to the `engine`, returning
+    /// the number of the messages.
+    ///
+    /// Should the processing fail other than due to a record (e.g. the log
+    /// failing to sync), the batch does not get committed, and so the consumer
+    /// is not to be used any further.
+    pub fn consume_batch(&mut self, engine: &mut PaymentEngine) -> Result<usize, ProcessError> {
+        consume_batch(&mut self.consumer, engine)
+    }
+
+    /// Apply the records of the messages to the `engine` as they come, until
+    /// the processing fails, see [`KafkaConsumer::consume_batch`].
+    pub fn consume(&mut self, engine: &mut PaymentEngine) -> Result<(), ProcessError> {
+        loop {
+            self.consume_batch(engine)?;
+        }
+    }
+}
+
+fn consume_batch<S>(source: &mut S, engine: &mut PaymentEngine) -> Result<usize, ProcessError>
+where
+    S: Source,
+{
+    let payloads = source.poll()?;
+    for payload in &payloads {
+        if payload.trim_ascii().is_empty() {
+            continue;
+        }
+        let result = engine.apply_payload(payload).map(drop);
+        match engine.collect_issue(result) {
+            Ok(()) => {}
+            Err(_err) if _err.is_record_error() => {
+                emit!(warn, error = %_err, "message skipped");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    engine.sync_wal()?;
+    source.commit()?;
+    Ok(payloads.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Source, consume_batch};
+    use crate::{InputFormat, PaymentEngine, ProcessError, ProcessOptions};
+
+    #[derive(Default)]
+    struct Batches<T> {
+        batches: Vec<Vec<T>>,
+        committed: usize,
+    }
+
+    impl<T> Source for Batches<T>
+    where
+        T: Into<Vec<u8>>,
+    {
+        fn poll(&mut self) -> Result<Vec<Vec<u8>>, ProcessError> {
+            let batch = self.batches.remove(0);
+            Ok(batch.into_iter().map(|payload| payload.into()).collect())
+        }
+
+        fn commit(&mut self) -> Result<(), ProcessError> {
+            self.committed += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commits_batches_once_logged() {
+        let path = std::env::temp_dir().join(format!("{}-kafka.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = || {
+            ProcessOptions::new()
+                .input_format(InputFormat::Auto)
+                .idempotent(true)
+        };
+        let mut source = Batches {
+            batches: vec![
+                vec![
+                    "deposit, 1, 1, 2.5",
+                    r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "1"}"#,
+                    "",
+                ],
+                vec!["withdrawal, 1, 3, 0.5", "what, 1, 4,"],
+            ],
+            ..Batches::default()
+        };
+        let mut engine = PaymentEngine::open_with_wal(&path, options()).unwrap();
+        assert_eq!(consume_batch(&mut source, &mut engine).unwrap(), 3);
+        assert_eq!(source.committed, 1);
+        // the malformed record is skipped
+        assert_eq!(consume_batch(&mut source, &mut engine).unwrap(), 2);
+        assert_eq!(source.committed, 2);
+        drop(engine);
+
+        // the batch is delivered again, e.g. having failed to commit
+        let mut source = Batches {
+            batches: vec![vec!["withdrawal, 1, 3, 0.5", "dispute, 1, 1,"]],
+            ..Batches::default()
+        };
+        let mut engine = PaymentEngine::open_with_wal(&path, options()).unwrap();
+        consume_batch(&mut source, &mut engine).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 0.5);
+        assert_eq!(account.held, 2.5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dead_letters_poison_messages() {
+        let id = std::process::id();
+        let wal = std::env::temp_dir().join(format!("{id}-kafka-poison.wal"));
+        let rejects = std::env::temp_dir().join(format!("{id}-kafka-rejects.csv"));
+        let _ = std::fs::remove_file(&wal);
+        let options = ProcessOptions::new()
+            .input_format(InputFormat::Auto)
+            .idempotent(true);
+        let mut engine = PaymentEngine::open_with_wal(&wal, options).unwrap();
+        let file = std::fs::File::create(&rejects).unwrap();
+        engine.write_rejects_to(file).unwrap();
+        let mut source = Batches {
+            batches: vec![vec![
+                b"deposit, 1, 1, 2.5".as_slice(),
+                b"what, 1, 2,",
+                b"deposit, 1, 3, \xff",
+                b"{\"type\": \"deposit\", \"client\": \xff}",
+                b"{\"type\": \"deposit\"",
+                b"deposit, 1, 1, 2.5",
+            ]],
+            ..Batches::default()
+        };
+        assert_eq!(consume_batch(&mut source, &mut engine).unwrap(), 6);
+        assert_eq!(source.committed, 1);
+        assert_eq!(engine.account(1).unwrap().available, 2.5);
+        drop(engine);
+        // the replayed deposit is not logged again
+        assert_eq!(
+            std::fs::read_to_string(&wal).unwrap(),
+            "type,client,tx,amount,currency,to_client\ndeposit,1,1,2.5000,,\n"
+        );
+        // the fields of the messages that cannot be read as records are unknown
+        let mut written = csv::Reader::from_path(&rejects).unwrap();
+        let rejected: Vec<_> = written
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (record[0].to_string(), record[7].to_string())
+            })
+            .collect();
+        assert_eq!(rejected.len(), 5);
+        assert_eq!(rejected[0].0, "what");
+        assert!(rejected[1].1.contains("invalid utf-8"));
+        assert!(rejected[2].1.contains("invalid utf-8"));
+        assert!(rejected[3].1.contains("EOF while parsing"));
+        assert_eq!(
+            rejected[4],
+            ("deposit".into(), "operation already applied".into())
+        );
+        std::fs::remove_file(&wal).unwrap();
+        std::fs::remove_file(&rejects).unwrap();
+    }
+
+    #[test]
+    fn reports_malformed_messages_if_lenient() {
+        let options = ProcessOptions::new()
+            .input_format(InputFormat::Auto)
+            .lenient(true);
+        let mut engine = PaymentEngine::with_options(options);
+        let mut source = Batches {
+            batches: vec![vec![
+                "deposit, 1, 1, 2.5",
+                "what, 1, 2,",
+                r#"{"type": "deposit""#,
+                "deposit, 1, 3, 1.0",
+            ]],
+            ..Batches::default()
+        };
+        assert_eq!(consume_batch(&mut source, &mut engine).unwrap(), 4);
+        assert_eq!(engine.account(1).unwrap().available, 3.5);
+        let malformed = &engine.report().malformed;
+        assert_eq!(malformed.len(), 2);
+        assert_eq!(malformed[0].row, 2);
+        assert!(malformed[0].message.contains("what"));
+        assert_eq!(malformed[1].row, 3);
+    }
+}
@@ -1,18 +1,141 @@
 #[macro_use]
 extern crate serde;
 
-use std::{
-    collections::HashMap,
-    error::Error,
-    io::{Read, Write},
-};
+use std::io::{Read, Write};
+
+/// Emit a `tracing` event at the given level, if built with the `tracing` feature.
+macro_rules! emit {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    };
+}
 
+mod audit;
+mod balances;
+mod checkpoint;
+mod concurrent;
+mod config;
 mod domain;
+mod engine;
+mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod observer;
+mod options;
+mod output;
+mod rejects;
+mod report;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "sled")]
+mod sled_store;
+mod snapshot;
+mod statement;
+mod store;
+mod wal;
+#[cfg(feature = "webhook")]
+mod webhook;
 
-use domain::{
-    Account, ClientID, DisputeRecordKind, Record, RecordInner, TxnID, TxnRecord, TxnRecordKind,
-    TxnState,
+pub use concurrent::ConcurrentEngine;
+pub use config::{Config, ConfigError, FeeConfig, FeesConfig, ServerConfig};
+pub use domain::{
+    Account, AccountRecord, AccountRecordKind, Amount, ClientID, Currency, DisputeRecord,
+    DisputeRecordKind, Record, RecordInner, Timestamp, TxnID, TxnRecord, TxnRecordKind, TxnState,
+};
+pub use engine::{MergeError, PaymentEngine};
+#[allow(deprecated)]
+pub use error::PaymentError;
+pub use error::{AmountError, ProcessError};
+#[cfg(feature = "kafka")]
+pub use kafka::KafkaConsumer;
+#[cfg(feature = "prometheus")]
+pub use metrics::Metrics;
+pub use observer::EventObserver;
+pub use options::{
+    CheckpointPolicy, Clock, ColumnMap, DuplicatePolicy, Fee, FeeSchedule, FsyncPolicy,
+    InputFormat, LockPolicy, LockedAccountPolicy, NegativeAvailablePolicy, OutputFormat,
+    ProcessOptions, Retention, Rounding, TimestampOrder,
 };
+pub use report::{
+    ClientSpan, Discrepancy, Issue, IssueKind, ProcessReport, ReadProgress, ReconcileReport,
+    SkipReason, Skipped, Summary, TxnEvent, TxnOutcome, ValidationReport, Violation, Warning,
+    WarningKind,
+};
+#[cfg(feature = "server")]
+pub use server::Server;
+#[cfg(feature = "sled")]
+pub use sled_store::SledTxnStore;
+pub use statement::{Statement, StatementEntry, StatementFormat};
+pub use store::{AccountStore, MemoryAccountStore, MemoryTxnStore, TxnStore};
+#[cfg(feature = "webhook")]
+pub use webhook::Webhook;
+
+/// Stateful engine to be driven record by record, e.g. from an event source
+/// of one's own, with the balances inspected in between the records.
+///
+/// This is the gist of the [`PaymentEngine`], which the engine can be turned
+/// into (and back from) for the rest of what it offers, e.g. processing a CSV
+/// input with [`PaymentEngine::process`], see [`Engine::into_inner`].
+#[derive(Debug, Default)]
+pub struct Engine {
+    inner: PaymentEngine,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: ProcessOptions) -> Self {
+        Engine {
+            inner: PaymentEngine::with_options(options),
+        }
+    }
+
+    /// Apply a single record, see [`PaymentEngine::apply`].
+    pub fn apply(&mut self, record: Record) -> Result<(), ProcessError> {
+        self.inner.apply(record)
+    }
+
+    /// The `client`'s account in the implicit currency, if any, see
+    /// [`PaymentEngine::account`].
+    pub fn account(&self, client: ClientID) -> Option<&Account> {
+        self.inner.account(client)
+    }
+
+    /// The accounts, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.inner.accounts()
+    }
+
+    /// What became of the records applied so far, see [`ProcessReport`].
+    pub fn report(&self) -> &ProcessReport {
+        self.inner.report()
+    }
+
+    /// Consume the engine, returning the accounts in no particular order.
+    pub fn into_accounts(self) -> impl Iterator<Item = Account> {
+        self.inner.into_accounts()
+    }
+
+    /// Consume the engine, returning the [`PaymentEngine`] it wraps.
+    pub fn into_inner(self) -> PaymentEngine {
+        self.inner
+    }
+}
+
+impl From<PaymentEngine> for Engine {
+    fn from(inner: PaymentEngine) -> Self {
+        Engine { inner }
+    }
+}
 
 /// Process the records contained in the `reader` in CSV format.
 ///
@@ -24,139 +147,363 @@ use domain::{
 /// Whitespaces and decimal precisions are accepted. Internally, whitespaces
 /// get trimmed both in headers and in fields. As for the decimals, only the
 /// integer part and the first four places after the demial point are taken
-/// into account (pun intended).
-// TODO: once our trace-bullet implementation is ready, consider intoducing
-// our own enumerated error using `thiserror` and `anyhow`
-pub fn process<R, W>(reader: R, writer: W) -> Result<(), Box<dyn Error>>
+/// into account (pun intended), see [`ProcessOptions::reject_precision_loss`]
+/// and [`ProcessOptions::rounding`].
+///
+/// Rows may be of varying length. The fields beyond the header are ignored,
+/// while the fields missing at the end of a row are treated as empty. The amount
+/// is required for deposits, withdrawals and transfers (and so both an empty and
+/// a missing one is an error), while it is optional for disputes, see [`DisputeRecord::amount`],
+/// and ignored for the other dispute resolution operations.
+/// A transfer moves the amount from the `client` to the client in the `to_client`
+/// column, see [`TxnRecordKind::Transfer`]. Its sender can be named in the
+/// `from_client` column instead, which stands in for an empty (or missing) `client`.
+///
+/// Returns the [`ProcessReport`] with the records that were not applied along
+/// with the counters of the applied ones.
+pub fn process<R, W>(reader: R, writer: W) -> Result<ProcessReport, ProcessError>
 where
     R: Read,
     W: Write,
 {
-    // TODO: in case we decide tp use this logic on the server, we will
-    // want to use a concurrent hash map and also make it available either
-    // via the app's state, or globally
-    let mut txns: HashMap<TxnID, TxnRecord> = HashMap::new();
-    let mut accounts: HashMap<ClientID, Account> = HashMap::new();
-
-    for result in csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_reader(reader)
-        .deserialize()
-    {
-        let record: Record = result?;
-        match record.inner {
-            RecordInner::TxnRecord(record) => {
-                match record.kind {
-                    TxnRecordKind::Deposit => {
-                        if let Some(account) = accounts.get_mut(&record.client) {
-                            if account.locked {
-                                // we assume they cannot credit a locked account
-                                continue;
-                            }
-                            account.deposit(record.amount);
-                        } else {
-                            let mut account = Account::new(record.client);
-                            account.deposit(record.amount);
-                            accounts.insert(record.client, account);
-                        }
-                    }
-                    TxnRecordKind::Withdrawal => {
-                        if let Some(account) = accounts.get_mut(&record.client) {
-                            if account.locked {
-                                // we assume they cannot debit a locked account
-                                // (similar to the credit operation above)
-                                continue;
-                            }
-                            // this operation is "fallible", but we are currently
-                            // just moving on; we can consider emitting a warn event
-                            // or collect such cases and reporting back to the caller
-                            let _ok = account.withdraw(record.amount);
-                        } else {
-                            // the account was not there in the first place, and so we
-                            // create one and continue; there is probably no sense in
-                            // trying to withdraw from the newly created account (unless
-                            // we withdraw `0.0`?)
-                            let account = Account::new(record.client);
-                            accounts.insert(record.client, account);
-                        }
-                    }
-                }
-                // this record may be referenced by one of the further dispute
-                // resolution records (if any) so let's store it
-                txns.insert(record.tx, record);
-            }
-            RecordInner::DisputeRecord(record) => {
-                let Some(txn) = txns.get_mut(&record.tx) else {
-                    // the `DisputeRecord` record is referencing a transaction which we
-                    // never encountered before; there is not much we can do about
-                    // it (we can consider emitting a warning), so we just move on;
-                    //
-                    // further down this branch, we know by this time that we actually
-                    // processed and stored the referenced transaction, hence we
-                    // can `.expect` it as our invariant
-                    continue;
-                };
-                match record.kind {
-                    DisputeRecordKind::Dispute => {
-                        if txn.state != TxnState::Undisputed {
-                            // this transaction has already been disputed or even
-                            // reversed, and so to guarantee idempotency, we simply
-                            // move on to the next record
-                            continue;
-                        }
-                        let account = accounts
-                            .get_mut(&record.client)
-                            .expect("account to have been created earlier for this client");
-                        // available can temporarily become negative in this case
-                        // which we consider ok, since the `DisputeRecordKind::Resolve`
-                        // can restore the available funds and so we are not locking
-                        // their account (we do only in a change back occurs)
-                        account.hold(txn.amount);
-                        txn.state = TxnState::Disputed;
-                    }
-                    DisputeRecordKind::Resolve => {
-                        if txn.state != TxnState::Disputed {
-                            // this transaction has never been disputed in the
-                            // first place or has already been reversed, and so
-                            // we are moving on to the next record
-                            continue;
-                        }
-                        let account = accounts
-                            .get_mut(&record.client)
-                            .expect("account to have been created earlier for this client");
-                        account.resolve(txn.amount);
-                        txn.state = TxnState::Undisputed;
-                    }
-                    DisputeRecordKind::ChargeBack => {
-                        if txn.state != TxnState::Disputed {
-                            // similar to `DisputeRecordKind::Resolve`, we can
-                            // only act here if the transaction is under dipute
-                            continue;
-                        }
-                        let account = accounts
-                            .get_mut(&record.client)
-                            .expect("account to have been created earlier for this client");
-                        account.charge_back(txn.amount);
-                        account.lock();
-                        txn.state = TxnState::Reversed;
-                    }
-                }
+    process_with(reader, writer, ProcessOptions::default())
+}
+
+/// Process the records contained in the `reader` in CSV format, tuning the
+/// behavior with the `options`.
+///
+/// Same as [`process`], which uses the default [`ProcessOptions`].
+pub fn process_with<R, W>(
+    reader: R,
+    writer: W,
+    options: ProcessOptions,
+) -> Result<ProcessReport, ProcessError>
+where
+    R: Read,
+    W: Write,
+{
+    let mut engine = PaymentEngine::with_options(options);
+    engine.process(reader)?;
+    engine.write(writer)?;
+    Ok(engine.into_report())
+}
+
+/// Process the records contained in the `reader` in CSV format, writing the
+/// accounts to each of the `writers`.
+///
+/// Same as [`process`], but each writer receives identical output. Writing stops
+/// on the first error from any of the writers, which is returned; by that time, the
+/// writers preceding the failed one may have received more rows than the rest,
+/// but never a partially written row, unless failed mid-row themselves.
+pub fn process_tee<R>(reader: R, writers: &mut [&mut dyn Write]) -> Result<(), ProcessError>
+where
+    R: Read,
+{
+    let mut engine = PaymentEngine::new();
+    engine.process(reader)?;
+    engine.write(output::Tee::new(writers))
+}
+
+/// Compare the accounts written out to the `old` and the `new` readers in CSV
+/// format, e.g. by two versions of the engine processing the same input.
+///
+/// The accounts are matched by client (and currency), whatever the order of
+/// their rows, while the funds are compared by value, so that `1.5` and `1.5000`
+/// are alike. The differences are returned ordered by client, the `old` accounts
+/// being the expected ones, see [`Discrepancy`]. An account listed more than
+/// once in either of them is [`ProcessError::Malformed`]. Of the `options`, only
+/// the [`ProcessOptions::delimiter`] and the [`ProcessOptions::precision`] apply.
+pub fn diff_accounts<R1, R2>(
+    old: R1,
+    new: R2,
+    options: ProcessOptions,
+) -> Result<Vec<Discrepancy>, ProcessError>
+where
+    R1: Read,
+    R2: Read,
+{
+    let old = balances::read(old, &options)?;
+    let new = balances::read(new, &options)?;
+    Ok(balances::compare(&old, &new))
+}
+
+/// Lazily read the records contained in the `reader` in CSV format.
+///
+/// Unlike [`process`], which fails on the first malformed record, this yields
+/// a result per record, so that each `Ok` record can be fed to a [`PaymentEngine`]
+/// with [`PaymentEngine::apply`], while each `Err` can be handled according to
+/// the caller's own policy.
+pub fn records<R>(reader: R) -> impl Iterator<Item = Result<Record, ProcessError>>
+where
+    R: Read,
+{
+    let mut rdr = reader_builder().from_reader(reader);
+    let (headers, failed) = match rdr.headers() {
+        Ok(headers) => (headers.clone(), None),
+        Err(err) => (csv::StringRecord::new(), Some(ProcessError::from(err))),
+    };
+    // the reader would not get past the header anyway, so that only its error
+    // is reported back in this case
+    let proceed = failed.is_none();
+    failed.map(Err).into_iter().chain(
+        rdr.into_records()
+            .take_while(move |_| proceed)
+            .zip(1..)
+            .map(move |(raw, row)| {
+                let mut raw = raw?;
+                pad_record(&mut raw, &headers);
+                deserialize_record(&raw, &headers, row, Amount::PRECISION, Rounding::default())
+            }),
+    )
+}
+
+/// Columns of the input, in the order assumed for a headerless one, see
+/// [`ProcessOptions::has_headers`].
+const COLUMNS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "currency",
+    "timestamp",
+    "to_client",
+    "from_client",
+];
+
+/// Reader configured for the input format described in [`process`].
+fn reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.trim(csv::Trim::All).flexible(true);
+    builder
+}
+
+/// Treat the fields missing at the end of the `raw` record as empty.
+///
+/// The rows are allowed to be of varying length, but a row shorter than the
+/// `headers` would otherwise fail to deserialize, even when the missing fields
+/// are optional for the row's record kind. The fields beyond the `headers` are
+/// ignored when deserializing.
+fn pad_record(raw: &mut csv::StringRecord, headers: &csv::StringRecord) {
+    while raw.len() < headers.len() {
+        raw.push_field("");
+    }
+}
+
+/// Deserialize the `raw` record found at the `row`.
+///
+/// Tells a deposit or withdrawal missing its amount (or with an invalid one)
+/// apart from the other malformed records, see [`ProcessError::MissingAmount`]
+/// and [`ProcessError::Malformed`]. The amount is read from its decimal text
+/// at the number of `places`, applying the `rounding`, see [`Amount::parse_at`].
+fn deserialize_record(
+    raw: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    row: u64,
+    places: u32,
+    rounding: Rounding,
+) -> Result<Record, ProcessError> {
+    let aliased = sender_headers(raw, headers, row)?;
+    let headers = aliased.as_ref().unwrap_or(headers);
+    let field = |name| {
+        let idx = headers.iter().position(|header| header == name)?;
+        raw.get(idx)
+    };
+    let line = raw.position().map(|position| position.line());
+    let kind = field("type").unwrap_or_default();
+    let tx = field("tx").and_then(|tx| tx.parse().ok());
+    let rejected = |amount: &str, source| ProcessError::AmountRejected {
+        row,
+        line,
+        kind: kind.to_string(),
+        tx,
+        amount: amount.to_string(),
+        source,
+    };
+    let parse = |amount: &str| {
+        Amount::parse_at(amount, places, rounding).map_err(|err| match err {
+            ProcessError::InvalidAmount(source) => rejected(amount, source),
+            err => err,
+        })
+    };
+    let expects_amount = matches!(kind, "deposit" | "withdrawal" | "transfer");
+    // the amount of a transaction is read from its text, as it would otherwise
+    // be deserialized as a float, with the record deserialized around it
+    let amount = match field("amount") {
+        Some(amount) if expects_amount && !amount.is_empty() => {
+            let parsed = parse(amount)?;
+            if parsed < Amount::default() {
+                return Err(rejected(amount, AmountError::Negative));
             }
+            Some(parsed)
         }
+        _ => None,
+    };
+    let masked = amount.map(|_| {
+        let idx = headers.iter().position(|header| header == "amount");
+        let mut masked: csv::StringRecord = raw
+            .iter()
+            .enumerate()
+            .map(|(i, value)| if Some(i) == idx { "0" } else { value })
+            .collect();
+        masked.set_position(raw.position().cloned());
+        masked
+    });
+    let deserialized = masked.as_ref().unwrap_or(raw).deserialize(Some(headers));
+    let mut record: Record = deserialized.map_err(|err| {
+        if expects_amount && amount.is_none() {
+            let kind = kind.to_string();
+            return ProcessError::MissingAmount {
+                row,
+                line,
+                kind,
+                tx,
+            };
+        }
+        ProcessError::Malformed {
+            row,
+            line,
+            record: raw.iter().collect::<Vec<_>>().join(","),
+            reason: malformed_reason(raw, headers, &err),
+        }
+    })?;
+    if let RecordInner::TxnRecord(txn) = &mut record.inner
+        && let Some(amount) = amount
+    {
+        txn.amount = amount;
+    }
+    if let RecordInner::DisputeRecord(dispute) = &mut record.inner
+        && dispute.kind == DisputeRecordKind::Dispute
+        && let Some(amount) = field("amount")
+        && !amount.is_empty()
+    {
+        let parsed = parse(amount)?;
+        if parsed < Amount::default() {
+            return Err(rejected(amount, AmountError::Negative));
+        }
+        dispute.amount = Some(parsed);
+    }
+    if let RecordInner::TxnRecord(txn) = &record.inner
+        && matches!(txn.kind, TxnRecordKind::Transfer)
+        && txn.to_client.is_none_or(|client| client == txn.client)
+    {
+        return Err(ProcessError::InvalidTransfer { row, line });
+    }
+    if let Some(value) = field("timestamp")
+        && !value.is_empty()
+    {
+        let timestamp = value.parse().map_err(|_| ProcessError::InvalidTimestamp {
+            row,
+            line,
+            value: value.to_string(),
+        })?;
+        record.timestamp = Some(timestamp);
     }
-    let mut wrt = csv::Writer::from_writer(writer);
-    for account in accounts.values() {
-        wrt.serialize(account)?;
+    Ok(record)
+}
+
+/// The `headers` to read the `raw` record found at the `row` with, if its
+/// client is in the `from_client` column, see [`process`].
+///
+/// The `from_client` takes the place of the `client` then. Either way, the two
+/// are not to disagree.
+fn sender_headers(
+    raw: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    row: u64,
+) -> Result<Option<csv::StringRecord>, ProcessError> {
+    let position = |name| headers.iter().position(|header| header == name);
+    let Some(from) = position("from_client") else {
+        return Ok(None);
+    };
+    let client = position("client");
+    let sender = raw.get(from).unwrap_or_default();
+    let given = client.and_then(|idx| raw.get(idx)).unwrap_or_default();
+    if sender.is_empty() || sender == given {
+        return Ok(None);
     }
-    wrt.flush()?;
-    Ok(())
+    if !given.is_empty() {
+        return Err(ProcessError::Malformed {
+            row,
+            line: raw.position().map(|position| position.line()),
+            record: raw.iter().collect::<Vec<_>>().join(","),
+            reason: format!("client \"{given}\" differs from from_client \"{sender}\""),
+        });
+    }
+    let renamed = headers.iter().enumerate().map(|(idx, header)| match idx {
+        _ if idx == from => "client",
+        _ if Some(idx) == client => "",
+        _ => header,
+    });
+    Ok(Some(renamed.collect()))
+}
+
+/// Record types, as in the `type` column.
+const TYPES: [&str; 8] = [
+    "deposit",
+    "withdrawal",
+    "transfer",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "close",
+    "unlock",
+];
+
+/// Why the `raw` record failed to be deserialized with the `err`, pointing
+/// at the offending field if possible.
+fn malformed_reason(
+    raw: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    err: &csv::Error,
+) -> String {
+    let field = |name: &str| {
+        let idx = headers.iter().position(|header| header == name)?;
+        raw.get(idx)
+    };
+    let invalid = |name: &str, valid: fn(&str) -> bool| {
+        field(name)
+            .filter(|value| !valid(value))
+            .map(|value| format!("invalid {name} \"{value}\""))
+    };
+    let reason = match field("type") {
+        None | Some("") => Some(String::from("type is missing")),
+        Some(kind) if !TYPES.contains(&kind) => Some(format!("unknown type \"{kind}\"")),
+        Some(_) => invalid("client", |value| value.parse::<ClientID>().is_ok())
+            .or_else(|| {
+                invalid("tx", |value| {
+                    value.is_empty() || value.parse::<TxnID>().is_ok()
+                })
+            })
+            .or_else(|| {
+                invalid("to_client", |value| {
+                    value.is_empty() || value.parse::<ClientID>().is_ok()
+                })
+            }),
+    };
+    reason.unwrap_or_else(|| match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        _ => err.to_string(),
+    })
+}
+
+/// Process the records contained in the `input` in CSV format.
+///
+/// Same as [`process`], but returns the accounts info in CSV format
+/// directly as a `String`.
+pub fn process_str(input: &str) -> Result<String, ProcessError> {
+    let mut output = Vec::new();
+    process(input.as_bytes(), &mut output)?;
+    // we are only writing out numbers and booleans, which are valid UTF-8
+    Ok(String::from_utf8(output).expect("CSV output to be valid UTF-8"))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::{Account, Amount};
-    use crate::process;
+    use crate::domain::{Account, Amount, Record, RecordInner};
+    use crate::{
+        AmountError, Discrepancy, Engine, PaymentEngine, ProcessError, ProcessOptions, SkipReason,
+        diff_accounts, process, process_str, process_tee, records,
+    };
 
     #[test]
     fn handles_malformed_input() {
@@ -167,34 +514,38 @@ mod tests {
                     wrong,      column,  names, provided\n\
                     deposit,    1,       1,     5.9999\n\
                 ",
-                "wrong column names",
+                "type is missing in record \"deposit,1,1,5.9999\"",
             ),
             (
                 "\
                     type,      client,  tx,     amount\n\
                     blocking,  1,       1,      5.9999\n\
                 ",
-                "wrong variant for record type",
+                "unknown type \"blocking\" in record \"blocking,1,1,5.9999\"",
             ),
             (
                 "\
                     type,      client,  tx,     amount\n\
                     deposit,   1.0,     1,      5.9999\n\
                 ",
-                "wrong data type for client",
+                "invalid client \"1.0\" in record \"deposit,1.0,1,5.9999\"",
             ),
             (
                 "\
                     type,      client,  tx,     amount\n\
                     deposit,   1,       1.0,    5.9999\n\
                 ",
-                "wrong data type for tx",
+                "invalid tx \"1.0\" in record \"deposit,1,1.0,5.9999\"",
             ),
         ];
         for (case, msg) in cases {
             let writer = Vec::new();
-            let result = process(case.as_bytes(), writer);
-            assert!(result.is_err(), "{msg}");
+            let err = process(case.as_bytes(), writer).unwrap_err();
+            assert!(
+                matches!(err, ProcessError::Malformed { row: 1, .. }),
+                "{msg}"
+            );
+            assert_eq!(err.to_string(), format!("line 2: {msg}"));
         }
     }
 
@@ -246,12 +597,10 @@ mod tests {
             ),
         ];
         for (case, msg) in cases {
-            let mut writer = Vec::new();
-            let result = process(case.as_bytes(), &mut writer);
-            assert!(result.is_ok(), "{msg}");
+            let result = process_str(case);
             assert_eq!(
-                String::from_utf8(writer).unwrap(),
-                "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n",
+                result.unwrap(),
+                "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n",
                 "{msg}"
             )
         }
@@ -267,6 +616,20 @@ mod tests {
                 ",
                 "amount field is empty but preceeded by comma",
             ),
+            (
+                "\
+                    type,      client,  tx,     amount\n\
+                    resolve,   1,       1\n\
+                ",
+                "amount field is empty and _not_ preceeded by comma",
+            ),
+            (
+                "\
+                    type,      client,  tx,     amount\n\
+                    dispute,   1,       1,,,\n\
+                ",
+                "extra trailing commas",
+            ),
             // this case is also considered fine, since we are just ingoring
             // the amount field for dispute resolution operations
             (
@@ -288,22 +651,21 @@ mod tests {
         }
 
         let cases = [
-            // amount is not required for dispute resolution operations, but formatting
-            // should still be valid
+            // amout is a required field for deposits, whether empty ...
             (
                 "\
                     type,      client,  tx,     amount\n\
-                    resolve,   1,       1\n\
+                    deposit,   1,       1,\n\
                 ",
-                "amount field is empty and _not_ preceeded by comma",
+                "amount field empty for deposit",
             ),
-            // amout is a required field for deposits ...
+            // ... or not there at all ...
             (
                 "\
                     type,      client,  tx,     amount\n\
-                    deposit,   1,       1,\n\
+                    deposit,   1,       1\n\
                 ",
-                "amount not provided for deposit",
+                "amount field not there for deposit",
             ),
             // ... as well as for withdrawals
             (
@@ -311,13 +673,90 @@ mod tests {
                     type,      client,  tx,     amount\n\
                     withdrawal,   1,       1,\n\
                 ",
-                "amount not provided for withdrawal",
+                "amount field empty for withdrawal",
+            ),
+            (
+                "\
+                    type,      client,  tx,     amount\n\
+                    withdrawal,   1,       1\n\
+                ",
+                "amount field not there for withdrawal",
             ),
         ];
         for (case, msg) in cases {
             let writer = Vec::new();
             let result = process(case.as_bytes(), writer);
-            assert!(result.is_err(), "{msg}");
+            assert!(
+                matches!(
+                    result,
+                    Err(ProcessError::MissingAmount {
+                        row: 1,
+                        line: Some(2),
+                        ..
+                    })
+                ),
+                "{msg}"
+            );
+            let result = records(case.as_bytes()).next().unwrap();
+            assert!(
+                matches!(
+                    result,
+                    Err(ProcessError::MissingAmount {
+                        row: 1,
+                        line: Some(2),
+                        ..
+                    })
+                ),
+                "{msg}"
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_ragged_rows() {
+        let input = [
+            "type,       client,  tx,     amount,  currency",
+            "deposit,    1,       1,      10.0", // no currency field
+            "deposit,    1,       2,      5.0,,,", // extra trailing commas
+            "withdrawal, 1,       3,      2.0,     ,   ignored", // extra field
+            "dispute,    1,       2",            // no amount field
+            "resolve,    1,       2,,,,",
+            "dispute,    1,       1,",
+        ];
+        let accounts = &process_valid_input(input.join("\n").as_bytes());
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, 3.);
+        assert_eq!(accounts[0].held, 10.);
+        assert_eq!(accounts[0].total, 13.);
+
+        // while the amount of a transaction is still required, whatever follows
+        for (row, msg) in [
+            (
+                "deposit,    1,       4,,,",
+                "empty amount followed by extra commas",
+            ),
+            (
+                "withdrawal, 1,       4",
+                "no amount field, nor the ones after it",
+            ),
+            (
+                "transfer,   1,       4,       ,   , 2",
+                "empty amount followed by fields",
+            ),
+        ] {
+            let input = [input[0], input[1], row].join("\n");
+            let result = process(input.as_bytes(), std::io::sink());
+            assert!(
+                matches!(
+                    result,
+                    Err(ProcessError::MissingAmount {
+                        row: 2,
+                        line: Some(3),
+                        ..
+                    })
+                ),
+                "{msg}"
+            );
         }
     }
 
@@ -398,6 +837,231 @@ mod tests {
         assert!(account.locked); // NB
     }
 
+    #[test]
+    fn process_str_matches_process() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      5.9999",
+            "deposit,    2,       2,      200.0",
+            "withdrawal, 2,       3,      150.0",
+            "deposit,    3,       4,      100",
+            "dispute,    3,       4,",
+        ]
+        .join("\n");
+        let mut writer = Vec::new();
+        process(input.as_bytes(), &mut writer).unwrap();
+        let output = process_str(&input).unwrap();
+        assert_eq!(output.lines().count(), 4);
+        assert_eq!(output, String::from_utf8(writer).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        let cases = [
+            ("NaN", AmountError::NotANumber),
+            ("inf", AmountError::Infinite),
+            ("-infinity", AmountError::Infinite),
+            ("-1.0", AmountError::Negative),
+            ("1e300", AmountError::Overflow),
+        ];
+        for (amount, expected) in cases {
+            let input = format!("type, client, tx, amount\ndeposit, 1, 1, {amount}");
+            let err = process(input.as_bytes(), std::io::sink()).unwrap_err();
+            assert!(
+                matches!(err, ProcessError::AmountRejected { row: 1, source, .. } if source == expected),
+                "{amount}: {err}"
+            );
+        }
+        let input = "type, client, tx, amount\ndeposit, 1, 99, 5.x";
+        let err = process(input.as_bytes(), std::io::sink()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: invalid amount \"5.x\" for deposit tx 99: not a decimal number"
+        );
+    }
+
+    #[test]
+    fn reads_amounts_beyond_float_precision() {
+        let greatest = "922337203685477.5807";
+        let input = format!("type, client, tx, amount\ndeposit, 1, 1, {greatest}");
+        let record = records(input.as_bytes()).next().unwrap().unwrap();
+        let RecordInner::TxnRecord(txn) = record.inner else {
+            panic!("expected a transaction record");
+        };
+        assert_eq!(txn.amount, Amount::from_minor_units(i64::MAX));
+
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 922337203685478";
+        let err = process(input.as_bytes(), std::io::sink()).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ProcessError::AmountRejected {
+                    row: 1,
+                    source: AmountError::Overflow,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        let input = [
+            "type, client, tx, amount, timestamp",
+            "deposit, 1, 1, 1.0, 100",
+            "deposit, 1, 2, 1.0, yesterday",
+        ]
+        .join("\n");
+        let err = process(input.as_bytes(), std::io::sink()).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ProcessError::InvalidTimestamp { row: 2, line: Some(3), ref value } if value == "yesterday"
+            ),
+            "{err}"
+        );
+        assert_eq!(err.to_string(), "line 3: invalid timestamp \"yesterday\"");
+    }
+
+    #[test]
+    fn tells_error_classes_apart() {
+        let header = "type, client, tx, amount\n";
+        let process = |record: &[u8]| {
+            let input = [header.as_bytes(), record].concat();
+            process(input.as_slice(), std::io::sink()).unwrap_err()
+        };
+        // the input itself cannot be read
+        let err = process(b"deposit, 1, 1, \xff\n");
+        assert!(matches!(err, ProcessError::Csv(_)), "{err}");
+        // while the records are read, but are not valid ones
+        let err = process(b"deposit, 1, 1,\n");
+        assert!(
+            matches!(err, ProcessError::MissingAmount { row: 1, tx: Some(1), ref kind, .. } if kind == "deposit"),
+            "{err}"
+        );
+        let err = process(b"deposit, 1, 1, -1.0\n");
+        assert!(
+            matches!(
+                err,
+                ProcessError::AmountRejected {
+                    source: AmountError::Negative,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        let err = process(b"deposit, x, 1, 1.0\n");
+        assert!(
+            matches!(err, ProcessError::Malformed { row: 1, .. }),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn reports_processing_summary() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      5.0",
+            "withdrawal, 1,       3,      50.0", // insufficient funds (skip)
+            "withdrawal, 1,       4,      1.0",
+            "dispute,    1,       5,", // unknown transaction
+            "dispute,    2,       2,",
+            "chargeback, 2,       2,",
+            "deposit,    2,       6,      1.0", // account locked (skip)
+            "resolve,    2,       2,",          // account locked (skip)
+        ]
+        .join("\n");
+        let report = process(input.as_bytes(), std::io::sink()).unwrap();
+        assert_eq!(report.records, 9);
+        assert_eq!(report.deposits, 2);
+        assert_eq!(report.withdrawals, 1);
+        assert_eq!(report.disputes, 2);
+        // the resolve on the locked account is counted as skipped only
+        assert_eq!(report.ignored_disputes, 1);
+        assert_eq!(report.locked_skips, 2);
+        let reasons: Vec<_> = report.skipped.iter().map(|s| s.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                SkipReason::InsufficientFunds,
+                SkipReason::AccountLocked,
+                SkipReason::AccountLocked
+            ]
+        );
+    }
+
+    #[test]
+    fn tees_output_to_each_writer() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      5.9999",
+            "deposit,    2,       2,      200.0",
+            "withdrawal, 2,       3,      150.0",
+        ]
+        .join("\n");
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        process_tee(input.as_bytes(), &mut [&mut first, &mut second]).unwrap();
+        let expected = process_str(&input).unwrap();
+        assert_eq!(String::from_utf8(first).unwrap(), expected);
+        assert_eq!(String::from_utf8(second).unwrap(), expected);
+
+        /// Writer failing on any write.
+        struct Broken;
+        impl std::io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut first = Vec::new();
+        let err = process_tee(
+            input.as_bytes(),
+            &mut [&mut first, &mut Broken as &mut dyn std::io::Write],
+        )
+        .unwrap_err();
+        assert!(matches!(err, ProcessError::Io(_)));
+        // whatever got through is made up of complete rows
+        assert!(expected.starts_with(std::str::from_utf8(&first).unwrap()));
+        assert!(first.is_empty() || first.ends_with(b"\n"));
+    }
+
+    #[test]
+    fn reads_records_lazily() {
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    1,       2,      ten", // invalid amount
+            "blocking,   1,       3,      1.0", // invalid record type
+            "withdrawal, 1,       4,      2.5",
+            "dispute,    1,       1,",
+        ]
+        .join("\n");
+        let results: Vec<_> = records(input.as_bytes()).collect();
+        assert_eq!(results.len(), 5);
+        assert_eq!(
+            results.iter().map(Result::is_ok).collect::<Vec<_>>(),
+            [true, false, false, true, true]
+        );
+        assert!(matches!(
+            &results[4],
+            Ok(record) if matches!(&record.inner, RecordInner::DisputeRecord(r) if r.tx == 1)
+        ));
+
+        // the caller decides what to do about the errors, e.g. just moves on
+        let mut engine = PaymentEngine::new();
+        for record in records(input.as_bytes()).flatten() {
+            engine.apply(record).unwrap();
+        }
+        let account = engine.accounts().next().unwrap();
+        assert_eq!(account.total, Amount::try_from_f64(7.5).unwrap());
+        assert_eq!(account.held, Amount::try_from_f64(10.).unwrap());
+    }
+
     fn process_valid_input(input: &[u8]) -> Vec<Account> {
         let mut writer = Vec::new();
         let result = process(input, &mut writer);
@@ -407,4 +1071,123 @@ mod tests {
             .collect::<Result<Vec<Account>, _>>()
             .unwrap()
     }
+
+    #[test]
+    fn drives_engine_record_by_record() {
+        let deposit = |tx, amount| Record::deposit(1, tx, Amount::try_from_f64(amount).unwrap());
+        let mut engine = Engine::new();
+        assert!(engine.account(1).is_none());
+        engine.apply(deposit(1, 10.)).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, 10.);
+        engine.apply(deposit(2, 5.)).unwrap();
+        engine.apply(Record::dispute(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 5.);
+        assert_eq!(account.held, 10.);
+        assert_eq!(engine.report().deposits, 2);
+        assert_eq!(engine.accounts().count(), 1);
+        let accounts: Vec<Account> = engine.into_accounts().collect();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].total, 15.);
+
+        // the engine is the one behind the rest of the API
+        let mut engine = Engine::from(PaymentEngine::new());
+        engine.apply(deposit(1, 10.)).unwrap();
+        let mut engine = engine.into_inner();
+        engine
+            .process("type,client,tx\ndispute,1,1".as_bytes())
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().held, 10.);
+    }
+
+    #[test]
+    fn diffs_accounts() {
+        let old = "client,available,held,total,locked,closed\n\
+                   1,1.5,0.0,1.5,false,false\n\
+                   2,2.0,0.0,2.0,false,false\n\
+                   3,0.0,0.0,0.0,true,false\n";
+        // the rows in another order, and the amounts at another precision
+        let new = "client,available,held,total,locked,closed\n\
+                   3,0.0000,0.0000,0.0000,true,false\n\
+                   2,2.0000,0.0000,2.0000,false,false\n\
+                   1,1.5000,0.0000,1.5000,false,false\n";
+        let options = ProcessOptions::new();
+        let diff = diff_accounts(old.as_bytes(), new.as_bytes(), options.clone()).unwrap();
+        assert_eq!(diff, []);
+
+        let new = "client,available,held,total,locked,closed\n\
+                   4,1.0,0.0,1.0,false,false\n\
+                   1,1.0,0.5,1.5,false,false\n\
+                   3,0.0,0.0,0.0,false,false\n";
+        let diff = diff_accounts(old.as_bytes(), new.as_bytes(), options).unwrap();
+        let amount = |text: &str| text.parse::<Amount>().unwrap();
+        assert_eq!(
+            diff,
+            [
+                Discrepancy::Funds {
+                    client: 1,
+                    currency: Default::default(),
+                    funds: "available",
+                    expected: amount("1.5"),
+                    actual: amount("1.0"),
+                },
+                Discrepancy::Funds {
+                    client: 1,
+                    currency: Default::default(),
+                    funds: "held",
+                    expected: amount("0.0"),
+                    actual: amount("0.5"),
+                },
+                Discrepancy::Missing {
+                    client: 2,
+                    currency: Default::default(),
+                },
+                Discrepancy::Locked {
+                    client: 3,
+                    currency: Default::default(),
+                    expected: true,
+                },
+                Discrepancy::Unexpected {
+                    client: 4,
+                    currency: Default::default(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_accounts_listed_twice_in_diff() {
+        // the accounts of a client in different currencies are not duplicates
+        let old = "client,currency,available,held,total,locked\n\
+                   1,USD,1.0,0.0,1.0,false\n\
+                   1,EUR,2.0,0.0,2.0,false\n";
+        let new = "client,currency,available,held,total,locked\n\
+                   1,USD,1.0,0.0,1.0,false\n\
+                   1,EUR,2.0,0.0,2.0,false\n\
+                   1,USD,3.0,0.0,3.0,false\n";
+        let options = ProcessOptions::new();
+        let diff = diff_accounts(old.as_bytes(), old.as_bytes(), options.clone()).unwrap();
+        assert_eq!(diff, []);
+        for (old, new) in [(old, new), (new, old)] {
+            assert!(matches!(
+                diff_accounts(old.as_bytes(), new.as_bytes(), options.clone()),
+                Err(ProcessError::Malformed { row: 3, line: Some(4), reason, .. })
+                    if reason == "duplicate account of client 1 in USD"
+            ));
+        }
+    }
+
+    #[test]
+    fn displays_discrepancies_of_funds_too_far_apart() {
+        let old = "client,available,held,total,locked\n\
+                   1,900000000000000.0000,0.0,900000000000000.0000,false\n";
+        let new = "client,available,held,total,locked\n\
+                   1,-900000000000000.0000,0.0,-900000000000000.0000,false\n";
+        let diff = diff_accounts(old.as_bytes(), new.as_bytes(), ProcessOptions::new()).unwrap();
+        assert_eq!(
+            diff[0].display(4).to_string(),
+            "client 1: available -900000000000000.0000 instead of \
+             900000000000000.0000 (delta overflow)"
+        );
+    }
 }
@@ -1,28 +1,1101 @@
-const USAGE_HINT: &str = r#"
-    Usage:
+use std::io::Read;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
 
-    $cargo run -- transactions.csv > accounts.csv
-"#;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use payment_engine::{
+    Amount, CheckpointPolicy, Config, ConfigError, Discrepancy, InputFormat, IssueKind,
+    OutputFormat, PaymentEngine, ProcessError, ProcessOptions, StatementFormat, ValidationReport,
+};
 
-fn main() {
-    // TODO: consider using `clap` if we are going to support
-    // extra arguments/flags (e.g. configurable custom separator in the csv file,
-    // or "invalid" transactions handling mode, i.e. whether to silently skip vs fail
-    let mut args = std::env::args();
-    let _binname = args.next();
-    let Some(filename) = args.next() else {
-        eprintln!("CSV filename expected.\n{USAGE_HINT}",);
-        std::process::exit(1);
+const EXIT_CODES: &str = r#"Exit codes:
+    0    Success.
+    1    Invalid command line arguments or configuration.
+    2    Failed to open, read, or write a file (the configuration included).
+    3    Failed to parse the transactions.
+    4    The transactions were rejected (e.g. precision loss in strict mode),
+         or the balances validation failed."#;
+
+const CHECKPOINT_EVERY: &str = "100000";
+
+/// Number of records between the updates of the progress bar, see `--progress`.
+const PROGRESS_EVERY: NonZeroU64 = NonZeroU64::new(10_000).unwrap();
+
+/// Configuration file read from the working directory, if there is one and
+/// no other has been given with `--config`.
+const CONFIG: &str = "payment-engine.toml";
+
+/// File argument standing for the standard input.
+const STDIN: &str = "-";
+
+#[cfg(any(feature = "server", feature = "grpc"))]
+const LISTEN: &str = "127.0.0.1:8080";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_IO: i32 = 2;
+const EXIT_PARSE: i32 = 3;
+const EXIT_REJECTED: i32 = 4;
+
+/// Process the payment transactions, writing out the state of the client accounts.
+///
+/// Without a subcommand, the transactions are processed as with `process`,
+/// e.g. `payment-engine transactions.csv > accounts.csv`, or
+/// `zcat transactions.csv.gz | payment-engine > accounts.csv`.
+///
+/// The options are read from `payment-engine.toml` (or the file given with
+/// `--config`), overridden by the environment variables, which are in turn
+/// overridden by the flags.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    after_help = EXIT_CODES
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Process the transactions, writing out the accounts.
+    Process(ProcessArgs),
+
+    /// Check the transactions, reporting the malformed records, the duplicate
+    /// transactions, the invalid amounts and references (e.g. disputes of unknown
+    /// transactions) along with their lines, without writing out the accounts.
+    ///
+    /// The accounting invariants are verified as well. Exits with `3` if any
+    /// record is malformed, and with `4` if there are other issues.
+    Validate(ValidateArgs),
+
+    /// Bring the accounts back from a write-ahead log, writing them out.
+    Replay(ReplayArgs),
+
+    /// Process the transactions, and compare the accounts with the expected
+    /// ones, e.g. the output of the legacy engine, reporting the missing and
+    /// the unexpected accounts, the funds differing and the lock states.
+    ///
+    /// Exits with `4` if any of the accounts differ.
+    Reconcile(ReconcileArgs),
+
+    /// Compare two files of accounts, e.g. written out by two versions of the
+    /// engine, whatever the order of the rows and the precision of the amounts.
+    ///
+    /// The differences of the new accounts from the old ones are reported the
+    /// same as by `reconcile`. Exits with `4` if any of the accounts differ.
+    AccountsDiff(AccountsDiffArgs),
+
+    /// Process the transactions, writing out the statement of a client's account
+    /// instead, with the running balance per transaction.
+    Statement(StatementArgs),
+
+    /// Accept the transactions over HTTP, or gRPC, until the server fails.
+    ///
+    /// The records are submitted with `POST /transactions`, while the accounts are
+    /// served with `GET /accounts` and `GET /accounts/{client}`, and the transactions
+    /// with `GET /transactions/{tx}`. `GET /accounts/{client}/updates` pushes the
+    /// account over a WebSocket whenever it changes.
+    #[cfg(any(feature = "server", feature = "grpc"))]
+    Serve(ServeArgs),
+
+    /// Apply the records in the messages of a Kafka topic, a CSV row or a JSON
+    /// object per message, until the processing fails.
+    ///
+    /// The state is kept in the write-ahead log, and the offsets are committed
+    /// only once the log is synced. The messages whose records cannot be applied
+    /// (e.g. malformed ones) are skipped.
+    #[cfg(feature = "kafka")]
+    Consume(ConsumeArgs),
+}
+
+/// How to apply the records, whatever their source.
+#[derive(Debug, Args)]
+struct EngineArgs {
+    /// Read the options from the TOML file, `payment-engine.toml` if there is one
+    /// in the working directory, see `payment_engine::Config`.
+    #[arg(long, env = "PAYMENT_ENGINE_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Fail on a referentially invalid record (e.g. a dispute referencing an
+    /// unknown transaction), instead of skipping it.
+    #[arg(
+        long,
+        env = "PAYMENT_ENGINE_STRICT",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    strict: Option<bool>,
+
+    /// Apply the `unlock` records, lifting the lock of an account (e.g. after
+    /// a charge back has been reviewed).
+    #[arg(
+        long,
+        env = "PAYMENT_ENGINE_ALLOW_UNLOCK",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    allow_unlock: Option<bool>,
+
+    /// Skip the records that cannot be parsed (e.g. of an unknown type, or with
+    /// a malformed amount), reporting them on the standard error, instead of
+    /// failing on the first one.
+    #[arg(
+        long,
+        env = "PAYMENT_ENGINE_LENIENT",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    lenient: Option<bool>,
+}
+
+impl EngineArgs {
+    /// The configuration, exiting if it is invalid.
+    fn config(&self) -> Config {
+        let path = match &self.config {
+            Some(path) => path.as_path(),
+            None if Path::new(CONFIG).is_file() => Path::new(CONFIG),
+            None => return Config::default(),
+        };
+        Config::load(path).unwrap_or_else(|err| config_error(err))
+    }
+
+    /// Number of places past the decimal point the amounts are counted in, as
+    /// configured, see `ProcessOptions::precision`.
+    fn places(&self) -> u32 {
+        self.config().precision.unwrap_or(Amount::PRECISION)
+    }
+
+    /// The options as configured, overridden by the flags.
+    fn options(&self) -> ProcessOptions {
+        let mut options = self
+            .config()
+            .options()
+            .unwrap_or_else(|err| config_error(err));
+        if let Some(strict) = self.strict {
+            options = options.strict(strict);
+        }
+        if let Some(allow_unlock) = self.allow_unlock {
+            options = options.allow_unlock(allow_unlock);
+        }
+        if let Some(lenient) = self.lenient {
+            options = options.lenient(lenient);
+        }
+        options
+    }
+}
+
+/// The transaction files along with their dialect.
+#[derive(Debug, Args)]
+struct InputArgs {
+    /// Transaction files, processed in the given order, one after another, while
+    /// a pattern (e.g. `transactions-*.csv`) stands for the matching files in
+    /// alphabetical order, and `-` (or no file at all) for the standard input.
+    #[arg(value_name = "FILE")]
+    files: Vec<String>,
+
+    /// Field delimiter of the input and the output, `,` by default, while `\t`
+    /// stands for a tab.
+    #[arg(long, env = "PAYMENT_ENGINE_DELIMITER", value_name = "CHAR", value_parser = single_byte)]
+    delimiter: Option<u8>,
+
+    /// Quote character of the input, `"` by default.
+    #[arg(long, value_name = "CHAR", value_parser = single_byte)]
+    quote: Option<u8>,
+
+    /// Ignore the input lines starting with the character.
+    #[arg(long, value_name = "CHAR", value_parser = single_byte)]
+    comment: Option<u8>,
+
+    /// The input does not start with the headers, and so the columns are expected
+    /// in the `type, client, tx, amount` order, optionally followed by `currency`,
+    /// `timestamp`, `to_client` and `from_client`.
+    #[arg(long)]
+    no_headers: bool,
+
+    /// Format of the input.
+    #[arg(long, value_enum, default_value_t = InputFormatArg::Csv)]
+    input_format: InputFormatArg,
+
+    /// Merge the records of the files in the chronological order of their `timestamp`
+    /// column, instead of processing the files one after another.
+    #[arg(long)]
+    merge_by_timestamp: bool,
+
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+impl InputArgs {
+    fn options(&self) -> ProcessOptions {
+        let mut options = self
+            .engine
+            .options()
+            .has_headers(!self.no_headers)
+            .input_format(self.input_format.into())
+            .comment(self.comment);
+        if let Some(delimiter) = self.delimiter {
+            options = options.delimiter(delimiter);
+        }
+        if let Some(quote) = self.quote {
+            options = options.quote(quote);
+        }
+        options
+    }
+
+    /// The files of the input, with the patterns expanded.
+    fn filenames(&self) -> Vec<String> {
+        if self.files.is_empty() {
+            return vec![STDIN.to_string()];
+        }
+        self.files.iter().flat_map(|arg| expand(arg)).collect()
+    }
+}
+
+/// Where and how to write the accounts.
+#[derive(Debug, Args)]
+struct OutputArgs {
+    /// Write the accounts to the file rather than to the standard output, leaving
+    /// the previous file intact unless all of them are written.
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Format of the accounts.
+    #[arg(long, value_enum, default_value_t = OutputFormatArg::Csv)]
+    format: OutputFormatArg,
+}
+
+impl OutputArgs {
+    /// Write the accounts of the `engine` out, replacing the output file only
+    /// once all of them are written.
+    fn write(&self, engine: &PaymentEngine) -> Result<(), ProcessError> {
+        match &self.output {
+            Some(path) => engine.write_file(path),
+            None => engine.write(std::io::BufWriter::new(std::io::stdout())),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct ProcessArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Verify the accounting invariants after processing, and exit with an error
+    /// instead of writing the accounts, if any of them is violated.
+    #[arg(long)]
+    validate_balances: bool,
+
+    /// Verify the accounting invariants of the accounts each record changes
+    /// right after applying it, and exit with an error pointing at the first
+    /// record violating them, e.g. for a verification run.
+    #[arg(long)]
+    verify_invariants: bool,
+
+    /// Process the transactions, but print a summary of the accounts (the accounts
+    /// touched, the locked ones, and the funds held) to the standard error instead
+    /// of writing them out.
+    #[arg(long, conflicts_with = "output")]
+    dry_run: bool,
+
+    /// Show the progress of reading the transactions (the bytes and the records
+    /// per second, along with the time left for the files) on the standard error,
+    /// a line at a time unless it is a terminal.
+    #[arg(long)]
+    progress: bool,
+
+    /// Periodically save the progress along with the state to the file, replacing
+    /// the previous checkpoint.
+    #[arg(long, value_name = "FILE", conflicts_with = "merge_by_timestamp")]
+    checkpoint: Option<String>,
+
+    /// Number of records between the checkpoints.
+    #[arg(long, value_name = "RECORDS", default_value = CHECKPOINT_EVERY)]
+    checkpoint_every: NonZeroUsize,
+
+    /// Continue from the checkpoint in the file specified with `--checkpoint`,
+    /// rather than from the start of the input.
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Write the records that have not been applied (e.g. due to insufficient
+    /// funds) to the file, with a `reason` column.
+    #[arg(long, value_name = "FILE")]
+    rejects_file: Option<String>,
+
+    /// Write each change to the accounts (e.g. a deposit crediting one) to the
+    /// file, along with the balances before and after it.
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<String>,
+
+    /// Print the transactions of the client (along with their dispute state)
+    /// to the standard error, in the order they have been applied.
+    #[arg(long, value_name = "CLIENT")]
+    history: Option<u16>,
+}
+
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Format of the report.
+    #[arg(long, value_enum, default_value_t = ReportFormatArg::Text)]
+    format: ReportFormatArg,
+}
+
+#[derive(Debug, Args)]
+struct ReconcileArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Accounts expected, in the output CSV format.
+    #[arg(long, value_name = "FILE")]
+    expected: String,
+
+    /// Format of the report.
+    #[arg(long, value_enum, default_value_t = ReportFormatArg::Text)]
+    format: ReportFormatArg,
+}
+
+#[derive(Debug, Args)]
+struct AccountsDiffArgs {
+    /// Accounts to compare against.
+    #[arg(value_name = "OLD")]
+    old: String,
+
+    /// Accounts to compare.
+    #[arg(value_name = "NEW")]
+    new: String,
+
+    /// Format of the report.
+    #[arg(long, value_enum, default_value_t = ReportFormatArg::Text)]
+    format: ReportFormatArg,
+
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+#[derive(Debug, Args)]
+struct StatementArgs {
+    #[command(flatten)]
+    input: InputArgs,
+
+    /// Client whose statement to write out.
+    #[arg(long)]
+    client: u16,
+
+    /// Leave out the transactions timestamped before.
+    #[arg(long, value_name = "TIMESTAMP")]
+    from: Option<u64>,
+
+    /// Leave out the transactions timestamped after.
+    #[arg(long, value_name = "TIMESTAMP")]
+    to: Option<u64>,
+
+    /// Format of the statement.
+    #[arg(long, value_enum, default_value_t = StatementFormatArg::Text)]
+    format: StatementFormatArg,
+}
+
+#[derive(Debug, Args)]
+struct ReplayArgs {
+    /// Write-ahead log to replay, see `PaymentEngine::open_with_wal`.
+    #[arg(value_name = "WAL")]
+    wal: PathBuf,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+#[cfg(any(feature = "server", feature = "grpc"))]
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Address to listen on, `127.0.0.1:8080` unless configured otherwise.
+    #[arg(long, env = "PAYMENT_ENGINE_LISTEN", value_name = "ADDRESS")]
+    listen: Option<String>,
+
+    /// Serve the `PaymentEngine` gRPC service instead, see `proto/payment_engine.proto`.
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc: bool,
+
+    /// Respond to the submissions retried within the window (by the `Idempotency-Key`
+    /// header or the transaction) with their original outcomes instead of applying
+    /// them again.
+    #[cfg(feature = "server")]
+    #[arg(long, value_name = "SECONDS")]
+    replay_window: Option<u64>,
+
+    #[cfg(feature = "webhook")]
+    #[command(flatten)]
+    webhook: WebhookArgs,
+
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+#[cfg(feature = "kafka")]
+#[derive(Debug, Args)]
+struct ConsumeArgs {
+    /// Comma separated brokers, e.g. `localhost:9092`.
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',', required = true)]
+    brokers: Vec<String>,
+
+    /// Topic to consume.
+    #[arg(long)]
+    topic: String,
+
+    /// Consumer group to commit the offsets with.
+    #[arg(long)]
+    group: String,
+
+    /// Write-ahead log to keep the state in, replayed on start.
+    #[arg(long, value_name = "FILE")]
+    wal: PathBuf,
+
+    #[cfg(feature = "webhook")]
+    #[command(flatten)]
+    webhook: WebhookArgs,
+
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+/// Where to notify of the charge backs, in the long-running modes.
+#[cfg(all(
+    feature = "webhook",
+    any(feature = "server", feature = "grpc", feature = "kafka")
+))]
+#[derive(Debug, Args)]
+struct WebhookArgs {
+    /// `POST` a JSON object to the URL whenever a charge back locks an account,
+    /// sending it again with a growing delay until it succeeds, unless configured
+    /// otherwise.
+    #[arg(long, env = "PAYMENT_ENGINE_CHARGEBACK_WEBHOOK", value_name = "URL")]
+    chargeback_webhook: Option<String>,
+}
+
+#[cfg(all(
+    feature = "webhook",
+    any(feature = "server", feature = "grpc", feature = "kafka")
+))]
+impl WebhookArgs {
+    /// The webhook to notify, if any, either given or configured, writing the
+    /// amounts out at the configured precision.
+    fn webhook(&self, engine: &EngineArgs) -> Option<payment_engine::Webhook> {
+        let places = engine.places();
+        self.chargeback_webhook
+            .clone()
+            .or_else(|| engine.config().server.chargeback_webhook)
+            .map(|url| payment_engine::Webhook::new(url).precision(places))
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ReportFormatArg {
+    /// An issue per line, followed by a summary.
+    Text,
+
+    /// A JSON object with the number of the records and the issues.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum StatementFormatArg {
+    /// A transaction per line, with the running balance.
+    Text,
+
+    /// CSV with the headers.
+    Csv,
+
+    /// A JSON object with the transactions.
+    Json,
+}
+
+impl From<StatementFormatArg> for StatementFormat {
+    fn from(format: StatementFormatArg) -> Self {
+        match format {
+            StatementFormatArg::Text => StatementFormat::Text,
+            StatementFormatArg::Csv => StatementFormat::Csv,
+            StatementFormatArg::Json => StatementFormat::Json,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum InputFormatArg {
+    /// CSV, with the headers unless `--no-headers`.
+    Csv,
+
+    /// Newline-delimited JSON objects with the same fields as the CSV columns.
+    Ndjson,
+
+    /// Tell the format from the first bytes of the input.
+    Auto,
+}
+
+impl From<InputFormatArg> for InputFormat {
+    fn from(format: InputFormatArg) -> Self {
+        match format {
+            InputFormatArg::Csv => InputFormat::Csv,
+            InputFormatArg::Ndjson => InputFormat::JsonLines,
+            InputFormatArg::Auto => InputFormat::Auto,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormatArg {
+    /// CSV with the headers.
+    Csv,
+
+    /// A JSON array of the accounts.
+    Json,
+
+    /// A JSON object per account, each on its own line.
+    Ndjson,
+}
+
+impl From<OutputFormatArg> for OutputFormat {
+    fn from(format: OutputFormatArg) -> Self {
+        match format {
+            OutputFormatArg::Csv => OutputFormat::Csv,
+            OutputFormatArg::Json => OutputFormat::Json,
+            OutputFormatArg::Ndjson => OutputFormat::JsonLines,
+        }
+    }
+}
+
+fn exit_code(err: &ProcessError) -> i32 {
+    match err {
+        ProcessError::Io(_) => EXIT_IO,
+        ProcessError::Csv(err) if err.is_io_error() => EXIT_IO,
+        #[cfg(feature = "tokio")]
+        ProcessError::AsyncCsv(err) if matches!(err.kind(), csv_async::ErrorKind::Io(_)) => EXIT_IO,
+        #[cfg(feature = "tokio")]
+        ProcessError::AsyncCsv(_) => EXIT_PARSE,
+        #[cfg(feature = "kafka")]
+        ProcessError::Kafka(_) => EXIT_IO,
+        ProcessError::Csv(_)
+        | ProcessError::Malformed { .. }
+        | ProcessError::MissingAmount { .. }
+        | ProcessError::Json { .. }
+        | ProcessError::InvalidTimestamp { .. }
+        | ProcessError::InvalidTransfer { .. }
+        | ProcessError::InvalidSnapshot { .. } => EXIT_PARSE,
+        ProcessError::Serialize(_) => EXIT_IO,
+        ProcessError::InvalidAmount(_)
+        | ProcessError::AmountRejected { .. }
+        | ProcessError::PrecisionLoss { .. }
+        | ProcessError::UnexpectedDispute { .. }
+        | ProcessError::TooManyRecords { .. }
+        | ProcessError::InvariantViolated { .. }
+        | ProcessError::Merge(_)
+        | ProcessError::NotEmpty
+        | ProcessError::Rejected { .. } => EXIT_REJECTED,
+        _ => EXIT_IO,
+    }
+}
+
+/// Report the processing `err` and exit with its code.
+fn fail(err: ProcessError) -> ! {
+    eprintln!("Processing error: {}", err);
+    std::process::exit(exit_code(&err));
+}
+
+/// Report the invalid command line arguments and exit.
+fn usage_error(message: impl std::fmt::Display) -> ! {
+    let err = Cli::command().error(clap::error::ErrorKind::ValueValidation, message);
+    let _ = err.print();
+    std::process::exit(EXIT_USAGE);
+}
+
+/// Report the invalid configuration and exit.
+fn config_error(err: ConfigError) -> ! {
+    eprintln!("Configuration error: {err}");
+    let code = match err {
+        ConfigError::Read { .. } => EXIT_IO,
+        _ => EXIT_USAGE,
+    };
+    std::process::exit(code);
+}
+
+/// The byte of a single ASCII character argument, with `\t` standing for a tab.
+fn single_byte(arg: &str) -> Result<u8, String> {
+    match arg.as_bytes() {
+        b"\\t" => Ok(b'\t'),
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(String::from("a single ASCII character expected")),
+    }
+}
+
+/// The files matching the `arg` if it is a pattern, or the `arg` itself.
+fn expand(arg: &str) -> Vec<String> {
+    if arg == STDIN || !arg.contains(['*', '?', '[']) {
+        return vec![arg.to_string()];
+    }
+    let paths = match glob::glob(arg) {
+        Ok(paths) => paths,
+        Err(err) => usage_error(format!("invalid pattern \"{arg}\": {err}")),
+    };
+    let filenames: Vec<_> = paths
+        .filter_map(Result::ok)
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    if filenames.is_empty() {
+        eprintln!("No files match \"{arg}\".");
+        std::process::exit(EXIT_IO);
+    }
+    filenames
+}
+
+/// Open the files, with `-` standing for the standard input, exiting if any of
+/// them cannot be.
+fn open(filenames: &[String]) -> Vec<Box<dyn Read>> {
+    filenames
+        .iter()
+        .map(|filename| -> Box<dyn Read> {
+            match filename.as_str() {
+                STDIN => Box::new(std::io::stdin().lock()),
+                _ => Box::new(open_file(filename)),
+            }
+        })
+        .collect()
+}
+
+/// Open the file, exiting if it cannot be.
+fn open_file(filename: &str) -> std::io::BufReader<std::fs::File> {
+    let Ok(file) = std::fs::File::open(filename) else {
+        eprintln!("Please make sure file \"{filename}\" exists.");
+        std::process::exit(EXIT_IO);
+    };
+    std::io::BufReader::new(file)
+}
+
+/// Apply the records of the files to the `engine`, one file after another,
+/// or else merged by their timestamps.
+fn process_files(
+    engine: &mut PaymentEngine,
+    filenames: &[String],
+    merge: bool,
+) -> Result<(), ProcessError> {
+    let readers = open(filenames);
+    if merge {
+        engine.process_merged(readers)
+    } else {
+        readers
+            .into_iter()
+            .try_for_each(|reader| engine.process(reader))
+    }
+}
+
+/// Show the progress of the `engine` reading the files on a bar, sized after
+/// the files, unless any of them is the standard input.
+fn show_progress(engine: &mut PaymentEngine, filenames: &[String]) -> ProgressBar {
+    let size: Option<u64> = filenames
+        .iter()
+        .map(|filename| match filename.as_str() {
+            STDIN => None,
+            _ => std::fs::metadata(filename)
+                .ok()
+                .map(|metadata| metadata.len()),
+        })
+        .sum();
+    let (bar, template) = match size {
+        Some(size) => (
+            ProgressBar::new(size),
+            "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta}) {msg}",
+        ),
+        None => (
+            ProgressBar::no_length(),
+            "{spinner} [{elapsed_precise}] {bytes} ({bytes_per_sec}) {msg}",
+        ),
+    };
+    bar.set_style(ProgressStyle::with_template(template).expect("valid progress template"));
+    let started = std::time::Instant::now();
+    let shown = bar.clone();
+    engine.on_progress(PROGRESS_EVERY, move |progress| {
+        let rate = progress.records as f64 / started.elapsed().as_secs_f64().max(f64::EPSILON);
+        // the bar is not drawn unless to a terminal, and so the progress is
+        // logged line by line instead
+        if shown.is_hidden() {
+            eprintln!(
+                "Read {} bytes, {} records ({rate:.0}/s)",
+                progress.bytes, progress.records
+            );
+            return;
+        }
+        shown.set_position(progress.bytes);
+        shown.set_message(format!("{} records ({rate:.0}/s)", progress.records));
+    });
+    bar
+}
+
+/// Report the records the `engine` skipped for being malformed, see `--lenient`.
+fn report_malformed(engine: &PaymentEngine) {
+    let malformed = &engine.report().malformed;
+    if malformed.is_empty() {
+        return;
+    }
+    for issue in malformed {
+        eprintln!("Skipped malformed record: {issue}");
+    }
+    eprintln!("{} malformed records skipped", malformed.len());
+}
+
+/// Print the transactions of the `client` to the standard error, see `--history`.
+fn print_history(engine: &PaymentEngine, client: u16) {
+    let txns = match engine.history(client) {
+        Ok(txns) => txns,
+        Err(err) => fail(err),
     };
-    let Ok(file) = std::fs::File::open(&filename) else {
-        eprintln!("Please make sure file \"{filename}\" exists.\n{USAGE_HINT}");
-        std::process::exit(1);
+    eprintln!("History of client {client}:");
+    for txn in txns {
+        let currency = match txn.currency.is_implicit() {
+            true => String::new(),
+            false => format!(" {}", txn.currency),
+        };
+        let party = match txn.to_client {
+            Some(to) if to == client => format!(" from client {}", txn.client),
+            Some(to) => format!(" to client {to}"),
+            None => String::new(),
+        };
+        eprintln!(
+            "  {} tx {}: {}{currency}{party}, {}",
+            txn.kind,
+            txn.tx,
+            txn.amount,
+            txn.state()
+        );
+    }
+}
+
+/// Exit with an error if the `engine`'s balances violate the accounting invariants.
+fn validate_balances(engine: &PaymentEngine) {
+    let report = engine.reconcile();
+    if !report.is_ok() {
+        for violation in &report.violations {
+            eprintln!("Balance violation: {violation}");
+        }
+        std::process::exit(EXIT_REJECTED);
+    }
+}
+
+fn process(args: ProcessArgs) {
+    let filenames = args.input.filenames();
+    if args.checkpoint.is_some() && filenames.len() > 1 {
+        usage_error("checkpoints are only supported for a single file");
+    }
+    let options = args
+        .input
+        .options()
+        .output_format(args.output.format.into())
+        .checkpoint(CheckpointPolicy::Every(args.checkpoint_every.get()))
+        .verify_invariants(args.verify_invariants)
+        .history(args.history.is_some());
+    let mut engine = PaymentEngine::with_options(options);
+    if let Some(path) = &args.rejects_file {
+        let result = std::fs::File::create(path)
+            .map_err(ProcessError::from)
+            .and_then(|file| engine.write_rejects_to(std::io::BufWriter::new(file)));
+        if let Err(err) = result {
+            eprintln!("Failed to create rejects file \"{path}\": {err}.");
+            std::process::exit(EXIT_IO);
+        }
+    }
+    if let Some(path) = &args.audit_log {
+        let result = std::fs::File::create(path)
+            .map_err(ProcessError::from)
+            .and_then(|file| engine.write_audit_to(std::io::BufWriter::new(file)));
+        if let Err(err) = result {
+            eprintln!("Failed to create audit log \"{path}\": {err}.");
+            std::process::exit(EXIT_IO);
+        }
+    }
+    let bar = args
+        .progress
+        .then(|| show_progress(&mut engine, &filenames));
+    let result = match &args.checkpoint {
+        Some(path) => {
+            if filenames[0] == STDIN {
+                usage_error("checkpoints are not supported for the standard input");
+            }
+            let reader = open_file(&filenames[0]);
+            if args.resume {
+                engine.resume_checkpointed(reader, path)
+            } else {
+                engine.process_checkpointed(reader, path)
+            }
+        }
+        None => process_files(&mut engine, &filenames, args.input.merge_by_timestamp),
     };
+    if let Some(bar) = bar {
+        bar.finish();
+    }
+    if let Err(err) = result {
+        fail(err);
+    }
+    report_malformed(&engine);
+    if let Some(client) = args.history {
+        print_history(&engine, client);
+    }
+    if args.validate_balances {
+        validate_balances(&engine);
+    }
+    if args.dry_run {
+        eprint!("{}", engine.summary());
+        return;
+    }
+    if let Err(err) = args.output.write(&engine) {
+        fail(err);
+    }
+}
 
-    let reader = std::io::BufReader::new(file);
+fn validate(args: ValidateArgs) {
+    if args.input.merge_by_timestamp {
+        usage_error("the files are validated one after another, not merged");
+    }
+    let mut engine = PaymentEngine::with_options(args.input.options());
+    let mut report = ValidationReport::default();
+    for reader in open(&args.input.filenames()) {
+        match engine.validate(reader) {
+            Ok(validated) => {
+                report.records += validated.records;
+                report.issues.extend(validated.issues);
+            }
+            Err(err) => fail(err),
+        }
+    }
+    match args.format {
+        ReportFormatArg::Text => {
+            for issue in &report.issues {
+                println!("{issue}");
+            }
+            let (records, issues) = (report.records, report.issues.len());
+            println!("{records} records, {issues} issues");
+        }
+        ReportFormatArg::Json => {
+            let json = serde_json::to_string_pretty(&report).expect("report encoded as JSON");
+            println!("{json}");
+        }
+    }
+    validate_balances(&engine);
+    if report
+        .issues
+        .iter()
+        .any(|issue| issue.kind == IssueKind::Malformed)
+    {
+        std::process::exit(EXIT_PARSE);
+    }
+    if !report.is_ok() {
+        std::process::exit(EXIT_REJECTED);
+    }
+}
+
+fn reconcile(args: ReconcileArgs) {
+    let mut engine = PaymentEngine::with_options(args.input.options());
+    let filenames = args.input.filenames();
+    if let Err(err) = process_files(&mut engine, &filenames, args.input.merge_by_timestamp) {
+        fail(err);
+    }
+    report_malformed(&engine);
+    let discrepancies = engine
+        .compare_balances(open_file(&args.expected))
+        .unwrap_or_else(|err| fail(err));
+    let places = args.input.engine.places();
+    report_discrepancies(&discrepancies, args.format, places);
+}
+
+fn accounts_diff(args: AccountsDiffArgs) {
+    let (old, new) = (open_file(&args.old), open_file(&args.new));
+    let discrepancies = payment_engine::diff_accounts(old, new, args.engine.options())
+        .unwrap_or_else(|err| fail(err));
+    report_discrepancies(&discrepancies, args.format, args.engine.places());
+}
+
+/// Print the `discrepancies` with the amounts at the given number of `places`
+/// in the `format`, exiting with an error if there are any.
+fn report_discrepancies(discrepancies: &[Discrepancy], format: ReportFormatArg, places: u32) {
+    match format {
+        ReportFormatArg::Text => {
+            for discrepancy in discrepancies {
+                println!("{}", discrepancy.display(places));
+            }
+            println!("{} discrepancies", discrepancies.len());
+        }
+        ReportFormatArg::Json => {
+            let discrepancies: Vec<_> = discrepancies
+                .iter()
+                .map(|discrepancy| discrepancy.serialize_at(places))
+                .collect();
+            let json =
+                serde_json::to_string_pretty(&discrepancies).expect("report encoded as JSON");
+            println!("{json}");
+        }
+    }
+    if !discrepancies.is_empty() {
+        std::process::exit(EXIT_REJECTED);
+    }
+}
+
+fn statement(args: StatementArgs) {
+    let options = args.input.options().history(true);
+    let mut engine = PaymentEngine::with_options(options);
+    let filenames = args.input.filenames();
+    if let Err(err) = process_files(&mut engine, &filenames, args.input.merge_by_timestamp) {
+        fail(err);
+    }
+    report_malformed(&engine);
     let writer = std::io::BufWriter::new(std::io::stdout());
-    if let Err(err) = payment_engine::process(reader, writer) {
-        eprintln!("Processing error: {}", err);
-        std::process::exit(1);
+    let result = engine
+        .statement(args.client, args.from, args.to)
+        .and_then(|statement| statement.write(writer, args.format.into()));
+    if let Err(err) = result {
+        fail(err);
+    }
+}
+
+fn replay(args: ReplayArgs) {
+    let options = args
+        .engine
+        .options()
+        .output_format(args.output.format.into());
+    let result = PaymentEngine::open_with_wal(&args.wal, options)
+        .and_then(|engine| args.output.write(&engine));
+    if let Err(err) = result {
+        fail(err);
+    }
+}
+
+/// Run the server on the `listen` address until it fails, see the `serve` subcommand.
+#[cfg(any(feature = "server", feature = "grpc"))]
+fn run<F, E>(listen: &str, serve: impl FnOnce(tokio::net::TcpListener) -> F) -> !
+where
+    F: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|err| {
+        eprintln!("Failed to start the server: {err}.");
+        std::process::exit(EXIT_IO);
+    });
+    let result = runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .map_err(|err| err.to_string())?;
+        serve(listener).await.map_err(|err| err.to_string())
+    });
+    if let Err(err) = result {
+        eprintln!("Server error on \"{listen}\": {err}.");
+    }
+    std::process::exit(EXIT_IO);
+}
+
+/// Serve the engine over HTTP, or gRPC, until the server fails.
+#[cfg(any(feature = "server", feature = "grpc"))]
+fn serve(args: ServeArgs) -> ! {
+    let options = args.engine.options();
+    let listen = args
+        .listen
+        .or_else(|| args.engine.config().server.listen)
+        .unwrap_or_else(|| LISTEN.to_string());
+    #[cfg(feature = "webhook")]
+    let webhook = args.webhook.webhook(&args.engine);
+    #[cfg(feature = "grpc")]
+    if args.grpc {
+        #[allow(unused_mut)]
+        let mut engine = PaymentEngine::with_options(options);
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = webhook {
+            engine.add_observer(webhook);
+        }
+        let service = payment_engine::grpc::GrpcService::new(engine);
+        run(&listen, |listener| service.serve(listener));
+    }
+    #[cfg(feature = "server")]
+    {
+        // the records of different clients are applied in parallel
+        let shards = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+        #[allow(unused_mut)]
+        let mut engine = payment_engine::ConcurrentEngine::new(shards, options);
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = webhook {
+            engine.add_observer(webhook);
+        }
+        let mut server = payment_engine::Server::new(engine);
+        if let Some(seconds) = args.replay_window {
+            server = server.idempotency(std::time::Duration::from_secs(seconds));
+        }
+        #[cfg(feature = "prometheus")]
+        let server = server.metrics(payment_engine::Metrics::new());
+        run(&listen, |listener| server.serve(listener));
+    }
+    #[cfg(not(feature = "server"))]
+    usage_error("built without the `server` feature, see `--grpc`");
+}
+
+/// Consume the records of a Kafka topic until the processing fails.
+///
+/// The engine is dropped before failing, for the chargeback notifications still
+/// pending to be sent, see `Webhook`.
+#[cfg(feature = "kafka")]
+fn consume(args: ConsumeArgs) {
+    // the records delivered again after a restart are to be recognized
+    let options = args
+        .engine
+        .options()
+        .idempotent(true)
+        .input_format(InputFormat::Auto);
+    let result = PaymentEngine::open_with_wal(&args.wal, options).and_then(|mut engine| {
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = args.webhook.webhook(&args.engine) {
+            engine.add_observer(webhook);
+        }
+        payment_engine::KafkaConsumer::new(args.brokers, &args.topic, &args.group)?
+            .consume(&mut engine)
+    });
+    if let Err(err) = result {
+        fail(err);
+    }
+}
+
+fn main() {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(err) if err.use_stderr() => {
+            let _ = err.print();
+            std::process::exit(EXIT_USAGE);
+        }
+        // the help or the version
+        Err(err) => err.exit(),
+    };
+    match cli.command {
+        None => process(cli.process),
+        Some(Command::Process(args)) => process(args),
+        Some(Command::Validate(args)) => validate(args),
+        Some(Command::Replay(args)) => replay(args),
+        Some(Command::Reconcile(args)) => reconcile(args),
+        Some(Command::AccountsDiff(args)) => accounts_diff(args),
+        Some(Command::Statement(args)) => statement(args),
+        #[cfg(any(feature = "server", feature = "grpc"))]
+        Some(Command::Serve(args)) => serve(args),
+        #[cfg(feature = "kafka")]
+        Some(Command::Consume(args)) => consume(args),
     }
 }
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+
+use crate::domain::{Account, ClientID, TxnID};
+use crate::{EventObserver, TxnEvent, TxnOutcome};
+
+/// Prometheus metrics of the engine, for a long-running service to expose.
+///
+/// The metrics get updated as an [`EventObserver`], and so a clone of them is
+/// meant to be added to the engine with [`PaymentEngine::add_observer`](crate::PaymentEngine::add_observer),
+/// while the original is kept to [`Metrics::encode`] them, e.g. on a `/metrics`
+/// request. The clones share the collectors (and the transactions under dispute),
+/// which are:
+///
+/// - `payment_engine_records_total`, the records processed, by `outcome`;
+/// - `payment_engine_active_disputes`, the transactions under dispute;
+/// - `payment_engine_locked_accounts`, the accounts locked;
+/// - `payment_engine_record_seconds`, the latency of applying each record;
+/// - `payment_engine_processing_seconds`, the latency of the calls timed with
+///   [`Metrics::time`], e.g. of processing an input as a whole.
+///
+/// Note that the gauges start from zero, regardless of the state the engine
+/// may have been restored to.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    records: IntCounterVec,
+    active_disputes: IntGauge,
+    locked_accounts: IntGauge,
+    record_latency: Histogram,
+    latency: Histogram,
+    /// Transactions under dispute, along with their clients.
+    disputed: Arc<Mutex<HashMap<TxnID, ClientID>>>,
+}
+
+impl Metrics {
+    /// Create the metrics, registered with a registry of their own.
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let records = IntCounterVec::new(
+            Opts::new("payment_engine_records_total", "Records processed"),
+            &["outcome"],
+        )
+        .expect("valid metric");
+        let active_disputes = IntGauge::new(
+            "payment_engine_active_disputes",
+            "Transactions under dispute",
+        )
+        .expect("valid metric");
+        let locked_accounts = IntGauge::new("payment_engine_locked_accounts", "Accounts locked")
+            .expect("valid metric");
+        let record_latency = Histogram::with_opts(HistogramOpts::new(
+            "payment_engine_record_seconds",
+            "Latency of applying a record",
+        ))
+        .expect("valid metric");
+        let latency = Histogram::with_opts(HistogramOpts::new(
+            "payment_engine_processing_seconds",
+            "Latency of processing the input",
+        ))
+        .expect("valid metric");
+        registry
+            .register(Box::new(records.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(active_disputes.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(locked_accounts.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(record_latency.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(latency.clone()))
+            .expect("unique metric");
+        Self {
+            registry,
+            records,
+            active_disputes,
+            locked_accounts,
+            record_latency,
+            latency,
+            disputed: Arc::default(),
+        }
+    }
+
+    /// Registry of the metrics, e.g. to gather them along with other ones.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Call `f`, recording how long it took with the processing latency histogram.
+    ///
+    /// ```
+    /// use payment_engine::{Metrics, PaymentEngine};
+    ///
+    /// let metrics = Metrics::new();
+    /// let mut engine = PaymentEngine::new();
+    /// engine.add_observer(metrics.clone());
+    /// let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    /// metrics.time(|| engine.process(input.as_bytes())).unwrap();
+    /// assert!(metrics.encode().contains("payment_engine_processing_seconds_count 1"));
+    /// ```
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.latency.observe(start.elapsed().as_secs_f64());
+        result
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        prometheus::TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics written to memory");
+        String::from_utf8(buffer).expect("metrics encoded as UTF-8")
+    }
+
+    /// Stop counting the transaction of the `event` as disputed.
+    fn settled(&mut self, event: &TxnEvent) {
+        if let Some(tx) = event.tx
+            && self.disputed().remove(&tx).is_some()
+        {
+            self.active_disputes.dec();
+        }
+    }
+
+    /// Transactions under dispute, shared by the clones.
+    fn disputed(&self) -> std::sync::MutexGuard<'_, HashMap<TxnID, ClientID>> {
+        // the map is left consistent by each of the callbacks
+        self.disputed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventObserver for Metrics {
+    fn on_record(&mut self, event: &TxnEvent) {
+        let outcome = match event.outcome {
+            TxnOutcome::Applied => "applied",
+            TxnOutcome::Skipped(_) => "skipped",
+            TxnOutcome::UnknownTransaction => "unknown_transaction",
+            TxnOutcome::Ignored => "ignored",
+        };
+        self.records.with_label_values(&[outcome]).inc();
+    }
+
+    fn on_processed(&mut self, _: &TxnEvent, elapsed: Duration) {
+        self.record_latency.observe(elapsed.as_secs_f64());
+    }
+
+    fn on_dispute(&mut self, event: &TxnEvent, _: &Account) {
+        // a transaction can be disputed in parts, and still count as one
+        if let Some(tx) = event.tx
+            && self.disputed().insert(tx, event.client).is_none()
+        {
+            self.active_disputes.inc();
+        }
+    }
+
+    fn on_resolve(&mut self, event: &TxnEvent, _: &Account) {
+        self.settled(event);
+    }
+
+    fn on_chargeback(&mut self, event: &TxnEvent, _: &Account) {
+        self.settled(event);
+    }
+
+    fn on_account_locked(&mut self, _: &TxnEvent, _: &Account) {
+        self.locked_accounts.inc();
+    }
+
+    fn on_account_unlocked(&mut self, _: &TxnEvent, _: &Account) {
+        self.locked_accounts.dec();
+    }
+
+    fn on_account_reset(&mut self, event: &TxnEvent, _: &Account) {
+        // the disputes of the client are reversed by the reset
+        let mut disputed = self.disputed();
+        let before = disputed.len();
+        disputed.retain(|_, client| *client != event.client);
+        let reversed = before - disputed.len();
+        drop(disputed);
+        self.active_disputes.sub(reversed as i64);
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use crate::domain::Account;
+use crate::{SkipReason, TxnEvent};
+
+/// Observer of what the engine does with the records, e.g. to trigger webhooks,
+/// update metrics, or raise alerts, see [`PaymentEngine::add_observer`](crate::PaymentEngine::add_observer).
+///
+/// The engine calls the observers synchronously, in the order of the records,
+/// right after applying each of them, with the accounts as updated by the record
+/// (in the order of their clients, if more than one, e.g. of a transfer).
+/// All the callbacks do nothing by default.
+#[allow(unused_variables)]
+pub trait EventObserver: std::fmt::Debug + Send {
+    /// A record has been processed, whatever its outcome, before any of the
+    /// more specific callbacks.
+    fn on_record(&mut self, event: &TxnEvent) {}
+
+    /// A record has been applied (or not) in the `elapsed` time, right after
+    /// [`EventObserver::on_record`].
+    fn on_processed(&mut self, event: &TxnEvent, elapsed: Duration) {}
+
+    /// A deposit has been credited to the `account`.
+    fn on_deposit(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A withdrawal has been debited from the `account`.
+    fn on_withdrawal(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A withdrawal has not been applied for the `reason`, e.g. due to
+    /// insufficient funds.
+    ///
+    /// Called in addition to [`EventObserver::on_skipped`].
+    fn on_withdrawal_rejected(&mut self, event: &TxnEvent, reason: SkipReason) {}
+
+    /// A transfer has been debited from the sender's `account`.
+    fn on_transfer(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A transfer has been credited to the recipient's `account`, along with
+    /// [`EventObserver::on_transfer`] being called (the `event` being of the
+    /// sender's record).
+    fn on_transfer_received(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A transaction of the `account` has been put under dispute.
+    ///
+    /// The `account` of a disputed transfer is the recipient's, which holds
+    /// the funds.
+    fn on_dispute(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A dispute of a transaction of the `account` has been resolved.
+    ///
+    /// Same as for [`EventObserver::on_dispute`], the `account` of a transfer
+    /// is the recipient's.
+    fn on_resolve(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A transaction of the `account` has been charged back.
+    ///
+    /// Called for each of the accounts of a transfer charged back, in the order
+    /// of their clients, since the recipient gives up the funds the sender is
+    /// credited with.
+    fn on_chargeback(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// The `account` has been locked, e.g. due to a charge back.
+    fn on_account_locked(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// The `account` has been unlocked by an `unlock` record, see
+    /// [`ProcessOptions::allow_unlock`](crate::ProcessOptions::allow_unlock), or
+    /// by the operator, see [`PaymentEngine::unlock`](crate::PaymentEngine::unlock),
+    /// in which case the `event` is of the last record processed before.
+    fn on_account_unlocked(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// The `account` has been reset by the operator, its transactions under
+    /// dispute being reversed, see [`PaymentEngine::reset_account`](crate::PaymentEngine::reset_account),
+    /// in which case the `event` is of the last record processed before.
+    ///
+    /// Followed by [`EventObserver::on_account_unlocked`] if it was locked.
+    fn on_account_reset(&mut self, event: &TxnEvent, account: &Account) {}
+
+    /// A record has not been applied for the `reason`, see [`TxnOutcome::Skipped`](crate::TxnOutcome::Skipped).
+    fn on_skipped(&mut self, event: &TxnEvent, reason: SkipReason) {}
+}
@@ -0,0 +1,967 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::AmountError;
+use crate::domain::{Account, Amount, Timestamp, TxnRecordKind};
+
+/// Options tuning the [`PaymentEngine`](crate::PaymentEngine) behavior.
+///
+/// Defaults are aligned with the behavior described in [`crate::process`].
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    pub(crate) reject_precision_loss: bool,
+    pub(crate) lenient: bool,
+    pub(crate) verify_invariants: bool,
+    pub(crate) disputes_present: bool,
+    pub(crate) minor_units: bool,
+    pub(crate) max_records: Option<usize>,
+    pub(crate) truncate_at_max_records: bool,
+    pub(crate) column_map: ColumnMap,
+    pub(crate) idempotent: bool,
+    pub(crate) lock_on_chargeback: LockPolicy,
+    pub(crate) client_spans: bool,
+    pub(crate) history: bool,
+    pub(crate) allow_withdraw_held: bool,
+    pub(crate) undisputed_window: Option<u64>,
+    pub(crate) buffer_capacity: usize,
+    pub(crate) flush_every: Option<usize>,
+    pub(crate) strict_ordering: bool,
+    pub(crate) two_pass: bool,
+    pub(crate) quote_style: csv::QuoteStyle,
+    pub(crate) terminator: csv::Terminator,
+    pub(crate) stats: bool,
+    pub(crate) closed_column: bool,
+    pub(crate) sorted_output: bool,
+    pub(crate) delimiter: u8,
+    pub(crate) quote: u8,
+    pub(crate) has_headers: bool,
+    pub(crate) comment: Option<u8>,
+    pub(crate) on_locked: LockedAccountPolicy,
+    pub(crate) on_negative_available: NegativeAvailablePolicy,
+    pub(crate) allow_unlock: bool,
+    pub(crate) max_dispute_cycles: Option<u32>,
+    pub(crate) strict: bool,
+    pub(crate) on_duplicate: DuplicatePolicy,
+    pub(crate) rounding: Rounding,
+    pub(crate) precision: u32,
+    pub(crate) retention: Retention,
+    pub(crate) fsync: FsyncPolicy,
+    pub(crate) checkpoint: CheckpointPolicy,
+    pub(crate) input_format: InputFormat,
+    pub(crate) output_format: OutputFormat,
+    pub(crate) timestamp_order: TimestampOrder,
+    pub(crate) reorder_window: Option<usize>,
+    pub(crate) dispute_window: Option<Timestamp>,
+    pub(crate) clock: Clock,
+    pub(crate) fees: FeeSchedule,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        ProcessOptions {
+            reject_precision_loss: false,
+            lenient: false,
+            verify_invariants: false,
+            disputes_present: true,
+            minor_units: false,
+            max_records: None,
+            truncate_at_max_records: false,
+            column_map: ColumnMap::default(),
+            idempotent: false,
+            lock_on_chargeback: LockPolicy::default(),
+            client_spans: false,
+            history: false,
+            allow_withdraw_held: false,
+            undisputed_window: None,
+            buffer_capacity: 8 * (1 << 10),
+            flush_every: None,
+            strict_ordering: false,
+            two_pass: false,
+            quote_style: csv::QuoteStyle::Necessary,
+            terminator: csv::Terminator::Any(b'\n'),
+            stats: false,
+            closed_column: false,
+            sorted_output: true,
+            delimiter: b',',
+            quote: b'"',
+            has_headers: true,
+            comment: None,
+            on_locked: LockedAccountPolicy::default(),
+            on_negative_available: NegativeAvailablePolicy::default(),
+            allow_unlock: false,
+            max_dispute_cycles: None,
+            strict: false,
+            on_duplicate: DuplicatePolicy::default(),
+            rounding: Rounding::default(),
+            precision: Amount::PRECISION,
+            retention: Retention::default(),
+            fsync: FsyncPolicy::default(),
+            checkpoint: CheckpointPolicy::default(),
+            input_format: InputFormat::default(),
+            output_format: OutputFormat::default(),
+            timestamp_order: TimestampOrder::default(),
+            reorder_window: None,
+            dispute_window: None,
+            clock: Clock::default(),
+            fees: FeeSchedule::default(),
+        }
+    }
+}
+
+impl ProcessOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to fail processing when an amount has more significant decimal
+    /// places than the engine supports.
+    ///
+    /// By default, the extra places get truncated (or rounded, see
+    /// [`ProcessOptions::rounding`]) and a warning is recorded
+    /// in the [`ProcessReport`](crate::ProcessReport). Note that trailing zeros
+    /// are not significant, so `5.00000` is not considered a precision loss.
+    pub fn reject_precision_loss(mut self, yes: bool) -> Self {
+        self.reject_precision_loss = yes;
+        self
+    }
+
+    /// Whether to skip the records that cannot be parsed (e.g. of an unknown
+    /// type, or with a malformed amount) rather than failing the processing.
+    ///
+    /// The records skipped are reported in [`ProcessReport::malformed`](crate::ProcessReport::malformed),
+    /// along with their lines. Note that an input that cannot be read at all
+    /// (e.g. due to an I/O error) still fails the processing.
+    pub fn lenient(mut self, yes: bool) -> Self {
+        self.lenient = yes;
+        self
+    }
+
+    /// Whether to verify the accounting invariants of the accounts each record
+    /// changes right after applying it, i.e. that the total funds are the
+    /// available plus held ones, and that the held funds are not negative.
+    ///
+    /// Meant for debugging and verification runs, since it slows the processing
+    /// down. Should an invariant be violated, the processing fails with
+    /// [`ProcessError::InvariantViolated`](crate::ProcessError::InvariantViolated),
+    /// pointing at the offending record, see also [`PaymentEngine::reconcile`](crate::PaymentEngine::reconcile).
+    pub fn verify_invariants(mut self, yes: bool) -> Self {
+        self.verify_invariants = yes;
+        self
+    }
+
+    /// How to treat the decimal places of the amounts beyond the supported
+    /// precision, see [`Rounding`].
+    ///
+    /// Defaults to [`Rounding::Truncate`].
+    pub fn rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Number of places past the decimal point the amounts are counted in.
+    ///
+    /// Defaults to [`Amount::PRECISION`], i.e. four places. The amounts are read
+    /// and written out at this precision, while internally they are counted in
+    /// the respective minor units, e.g. cents for two places. Note that this
+    /// applies to the amounts the engine parses itself, while an [`Amount`]
+    /// handed over to it (e.g. in [`LockPolicy::AfterAmount`]) is expected to
+    /// be in the same minor units, see [`Amount::from_minor_units`].
+    ///
+    /// Will return [`AmountError::UnsupportedPrecision`] if the `places` exceed
+    /// [`Amount::MAX_PRECISION`].
+    pub fn precision(mut self, places: u32) -> Result<Self, AmountError> {
+        if places > Amount::MAX_PRECISION {
+            return Err(AmountError::UnsupportedPrecision);
+        }
+        self.precision = places;
+        Ok(self)
+    }
+
+    /// Whether the input may contain dispute resolution records.
+    ///
+    /// Defaults to `true`. When disabled, the engine does not store the processed
+    /// transactions, which saves memory and time for deposit and withdrawal heavy
+    /// workloads. Note that this also means that duplicate transactions cannot
+    /// be detected, while a dispute resolution record (which the engine would not
+    /// be able to settle) fails the processing with [`ProcessError::UnexpectedDispute`].
+    ///
+    /// [`ProcessError::UnexpectedDispute`]: crate::ProcessError::UnexpectedDispute
+    pub fn disputes_present(mut self, yes: bool) -> Self {
+        self.disputes_present = yes;
+        self
+    }
+
+    /// Whether to write the exact amounts in minor units alongside the decimal
+    /// ones, as `available_units`, `held_units`, and `total_units` columns.
+    ///
+    /// Useful for downstream ledgers working in fixed-point themselves.
+    pub fn minor_units(mut self, yes: bool) -> Self {
+        self.minor_units = yes;
+        self
+    }
+
+    /// Maximum number of records (of any type) the engine will process.
+    ///
+    /// Useful to bound the work done on behalf of untrusted input. Once the limit
+    /// is exceeded, processing fails with [`ProcessError::TooManyRecords`], unless
+    /// [`ProcessOptions::truncate_at_max_records`] is enabled. No limit by default.
+    ///
+    /// The records are counted over the lifetime of the engine rather than per
+    /// call, i.e. the ones fed with each [`PaymentEngine::process`] (or alike)
+    /// and [`PaymentEngine::apply`] add up, and so the limit bounds the work of
+    /// the engine as a whole. To bound the work per request, process each one
+    /// with an engine of its own, or merge such engines afterwards, see
+    /// [`PaymentEngine::merge`].
+    ///
+    /// [`ProcessError::TooManyRecords`]: crate::ProcessError::TooManyRecords
+    /// [`PaymentEngine::process`]: crate::PaymentEngine::process
+    /// [`PaymentEngine::apply`]: crate::PaymentEngine::apply
+    /// [`PaymentEngine::merge`]: crate::PaymentEngine::merge
+    pub fn max_records(mut self, limit: Option<usize>) -> Self {
+        self.max_records = limit;
+        self
+    }
+
+    /// Whether to silently stop processing (rather than fail) once the limit
+    /// set with [`ProcessOptions::max_records`] is reached.
+    ///
+    /// The fact that the input got truncated is recorded in the
+    /// [`ProcessReport::truncated`](crate::ProcessReport::truncated).
+    pub fn truncate_at_max_records(mut self, yes: bool) -> Self {
+        self.truncate_at_max_records = yes;
+        self
+    }
+
+    /// Whether the engine should remember the operations it has applied, so that
+    /// replaying them (e.g. when the same file is processed twice in a retry-prone
+    /// pipeline) is a no-op.
+    ///
+    /// An operation is identified by the transaction it creates, or by the
+    /// transaction it references along with the dispute resolution record type.
+    /// Note that this means that a transaction can only be disputed once, even if
+    /// the dispute has been resolved. Replays are recorded in the report with
+    /// [`SkipReason::Replayed`](crate::SkipReason::Replayed). Disabled by default.
+    pub fn idempotent(mut self, yes: bool) -> Self {
+        self.idempotent = yes;
+        self
+    }
+
+    /// Whether the funds held for dispute can be withdrawn.
+    ///
+    /// By default, a withdrawal is only possible if covered by the available
+    /// funds. When enabled, the held funds are considered usable as well (think
+    /// pending funds), in which case the available funds can become negative.
+    pub fn allow_withdraw_held(mut self, yes: bool) -> Self {
+        self.allow_withdraw_held = yes;
+        self
+    }
+
+    /// Whether to fail processing on a referentially invalid record.
+    ///
+    /// By default, such records are recorded as skipped in the [`ProcessReport`](crate::ProcessReport).
+    /// When enabled, a dispute resolution record referencing an unknown transaction
+    /// (or one of another client), a withdrawal from a non-existent account or a
+    /// duplicate transaction fail the
+    /// processing with [`ProcessError::Rejected`](crate::ProcessError::Rejected) instead,
+    /// see [`SkipReason::is_referential`](crate::SkipReason::is_referential). So does
+    /// a record that would overflow the account's funds, see [`SkipReason::Overflow`](crate::SkipReason::Overflow),
+    /// or one out of order, see [`ProcessOptions::timestamp_order`].
+    pub fn strict(mut self, yes: bool) -> Self {
+        self.strict = yes;
+        self
+    }
+
+    /// Which of the transactions to retain for the dispute resolution records
+    /// to reference, see [`Retention`].
+    ///
+    /// Defaults to [`Retention::All`]. Retaining fewer of them bounds the memory
+    /// needed to process huge inputs, see also [`ProcessOptions::disputes_present`].
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// How often to sync the write-ahead log to the disk, see [`FsyncPolicy`]
+    /// and [`PaymentEngine::open_with_wal`](crate::PaymentEngine::open_with_wal).
+    ///
+    /// Defaults to [`FsyncPolicy::Always`].
+    pub fn fsync(mut self, policy: FsyncPolicy) -> Self {
+        self.fsync = policy;
+        self
+    }
+
+    /// How often to checkpoint the processing, see [`CheckpointPolicy`] and
+    /// [`PaymentEngine::process_checkpointed`](crate::PaymentEngine::process_checkpointed).
+    ///
+    /// Defaults to [`CheckpointPolicy::Never`].
+    pub fn checkpoint(mut self, policy: CheckpointPolicy) -> Self {
+        self.checkpoint = policy;
+        self
+    }
+
+    /// What to do about a transaction with an already processed identifier, see
+    /// [`DuplicatePolicy`].
+    pub fn on_duplicate(mut self, policy: DuplicatePolicy) -> Self {
+        self.on_duplicate = policy;
+        self
+    }
+
+    /// Whether to flag the dispute resolution records referencing a transaction
+    /// which has not been seen so far.
+    ///
+    /// By default, such records are silently ignored. When enabled, they are
+    /// recorded as skipped with [`SkipReason::DisputeBeforeTransaction`](crate::SkipReason::DisputeBeforeTransaction),
+    /// since with the input being ordered chronologically, this indicates either
+    /// a reference to a transaction that never existed, or an ordering issue.
+    pub fn strict_ordering(mut self, yes: bool) -> Self {
+        self.strict_ordering = yes;
+        self
+    }
+
+    /// Whether to apply the dispute resolution records only after all the other
+    /// records of the input.
+    ///
+    /// This makes the disputes preceding their transaction in the input (e.g. due
+    /// to upstream reordering) resolve correctly, at the cost of holding on to the
+    /// dispute resolution records until the input is exhausted. Note that each
+    /// call to [`PaymentEngine::process`](crate::PaymentEngine::process) is an
+    /// input on its own, while the records fed with [`PaymentEngine::apply`](crate::PaymentEngine::apply)
+    /// are applied right away. Combined with [`ProcessOptions::strict_ordering`],
+    /// the references to the transactions not in the input are recorded as skipped
+    /// with [`SkipReason::UnknownTransaction`](crate::SkipReason::UnknownTransaction).
+    pub fn two_pass(mut self, yes: bool) -> Self {
+        self.two_pass = yes;
+        self
+    }
+
+    /// Whether a locked account still accepts deposits.
+    ///
+    /// By default, neither deposits nor withdrawals are applied to a locked
+    /// account. When enabled, the deposits are, while withdrawals and transfers
+    /// (either from or to it) are still not. Shorthand for
+    /// [`ProcessOptions::on_locked`] with [`LockedAccountPolicy::AllowDeposits`].
+    pub fn locked_deposits(mut self, yes: bool) -> Self {
+        self.on_locked = if yes {
+            LockedAccountPolicy::AllowDeposits
+        } else {
+            LockedAccountPolicy::default()
+        };
+        self
+    }
+
+    /// What to do about a deposit, withdrawal or transfer on a locked account,
+    /// see [`LockedAccountPolicy`].
+    pub fn on_locked(mut self, policy: LockedAccountPolicy) -> Self {
+        self.on_locked = policy;
+        self
+    }
+
+    /// What to do about a dispute holding more than the available funds, e.g.
+    /// of a deposit that has already been withdrawn, see [`NegativeAvailablePolicy`].
+    pub fn on_negative_available(mut self, policy: NegativeAvailablePolicy) -> Self {
+        self.on_negative_available = policy;
+        self
+    }
+
+    /// Whether to apply the `unlock` records, restoring a locked account.
+    ///
+    /// Meant for the back-office tooling to unfreeze the accounts reviewed after
+    /// a charge back. By default, such records are skipped with
+    /// [`SkipReason::UnlockNotAllowed`](crate::SkipReason::UnlockNotAllowed),
+    /// see [`PaymentEngine::unlock`](crate::PaymentEngine::unlock) otherwise.
+    pub fn allow_unlock(mut self, yes: bool) -> Self {
+        self.allow_unlock = yes;
+        self
+    }
+
+    /// Maximum number of times a transaction can be disputed.
+    ///
+    /// A resolved transaction can be disputed again (e.g. after a second
+    /// presentment), each time starting a new dispute cycle, see [`TxnRecord::cycles`](crate::TxnRecord::cycles).
+    /// A dispute beyond the limit is skipped with [`SkipReason::DisputeCyclesExceeded`](crate::SkipReason::DisputeCyclesExceeded).
+    /// Unlimited by default.
+    pub fn max_dispute_cycles(mut self, cycles: Option<u32>) -> Self {
+        self.max_dispute_cycles = cycles;
+        self
+    }
+
+    /// When to lock an account after a charge back, see [`LockPolicy`].
+    pub fn lock_on_chargeback(mut self, policy: LockPolicy) -> Self {
+        self.lock_on_chargeback = policy;
+        self
+    }
+
+    /// Whether to track the span of the input rows referencing each client,
+    /// see [`PaymentEngine::client_span`](crate::PaymentEngine::client_span).
+    ///
+    /// Useful for debugging ordering issues in large inputs. Disabled by default.
+    pub fn client_spans(mut self, yes: bool) -> Self {
+        self.client_spans = yes;
+        self
+    }
+
+    /// Whether to index the transactions by client as well, for the history of
+    /// each client to be queried, see [`PaymentEngine::history`](crate::PaymentEngine::history).
+    ///
+    /// Disabled by default. Only the transactions retained for the dispute
+    /// resolution records are in the history, see [`ProcessOptions::retention`].
+    pub fn history(mut self, yes: bool) -> Self {
+        self.history = yes;
+        self
+    }
+
+    /// Number of rows after which an undisputed transaction is considered final,
+    /// see [`PaymentEngine::clear_finalized_transactions`](crate::PaymentEngine::clear_finalized_transactions).
+    ///
+    /// By default, an undisputed transaction can be disputed at any later point,
+    /// and so it is never cleared.
+    pub fn undisputed_window(mut self, rows: Option<u64>) -> Self {
+        self.undisputed_window = rows;
+        self
+    }
+
+    /// Whether the records are expected to be in the chronological order of
+    /// their `timestamp` column, see [`TimestampOrder`].
+    ///
+    /// A record out of order is skipped with [`SkipReason::OutOfOrder`](crate::SkipReason::OutOfOrder),
+    /// unless reordered within the [`ProcessOptions::reorder_window`]. Records
+    /// without a timestamp are not checked. Not checked by default.
+    pub fn timestamp_order(mut self, order: TimestampOrder) -> Self {
+        self.timestamp_order = order;
+        self
+    }
+
+    /// Number of records held back to be put into the chronological order of
+    /// their `timestamp` column before being applied.
+    ///
+    /// Records out of order by no more than the window are applied in order,
+    /// with a record without a timestamp considered to have the one of the
+    /// record preceding it. Records are applied as they are read by default.
+    /// No checkpoints are taken before the end of the input when reordering,
+    /// see [`PaymentEngine::process_checkpointed`](crate::PaymentEngine::process_checkpointed).
+    pub fn reorder_window(mut self, records: Option<usize>) -> Self {
+        self.reorder_window = records;
+        self
+    }
+
+    /// How long after a transaction it can still be disputed, in the units of
+    /// the `timestamp` column (e.g. `90 * 86400` for 90 days of Unix time).
+    ///
+    /// A dispute past the window is skipped with [`SkipReason::DisputeWindowElapsed`](crate::SkipReason::DisputeWindowElapsed),
+    /// as told by the [`ProcessOptions::clock`]. Transactions without a timestamp
+    /// can be disputed at any time, same as any transaction by default, while
+    /// a dispute without one is taken as issued at the latest timestamp seen.
+    pub fn dispute_window(mut self, window: Option<Timestamp>) -> Self {
+        self.dispute_window = window;
+        self
+    }
+
+    /// Current time to check the [`ProcessOptions::dispute_window`] against,
+    /// see [`Clock`].
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Capacity (in bytes) of the buffer the accounts are written through.
+    ///
+    /// Defaults to 8 KiB. A row which does not fit into the remaining capacity
+    /// causes the buffer to be flushed first, see [`ProcessOptions::flush_every`].
+    pub fn buffer_capacity(mut self, bytes: usize) -> Self {
+        self.buffer_capacity = bytes;
+        self
+    }
+
+    /// Format of the input, see [`InputFormat`]. Defaults to [`InputFormat::Csv`].
+    pub fn input_format(mut self, format: InputFormat) -> Self {
+        self.input_format = format;
+        self
+    }
+
+    /// Format of the output, see [`OutputFormat`]. Defaults to [`OutputFormat::Csv`].
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// Field delimiter of both the input and the output. Defaults to `,`.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Quote character of the input. Defaults to `"`.
+    pub fn quote(mut self, quote: u8) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    /// Whether the input starts with the headers. Defaults to `true`.
+    ///
+    /// Without the headers, the columns are expected in the `type, client, tx,
+    /// amount, currency, timestamp, to_client, from_client` order, with the
+    /// trailing ones optional as usual. There being no headers to rename, the
+    /// [`ProcessOptions::column_map`] does not apply then.
+    pub fn has_headers(mut self, yes: bool) -> Self {
+        self.has_headers = yes;
+        self
+    }
+
+    /// Character marking the comment lines of the input, which are ignored.
+    /// Defaults to `None`, i.e. no comments.
+    pub fn comment(mut self, comment: Option<u8>) -> Self {
+        self.comment = comment;
+        self
+    }
+
+    /// When to quote the fields of the output.
+    ///
+    /// Defaults to [`csv::QuoteStyle::Necessary`], while [`csv::QuoteStyle::Always`]
+    /// quotes all the fields, headers included.
+    pub fn quote_style(mut self, style: csv::QuoteStyle) -> Self {
+        self.quote_style = style;
+        self
+    }
+
+    /// Terminator of the output rows. Defaults to `\n`.
+    pub fn terminator(mut self, terminator: csv::Terminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+
+    /// Whether to write the accounts ordered by client (and currency).
+    ///
+    /// Defaults to `true`, so that the outputs of two runs can be compared. When
+    /// disabled, the accounts are written in no particular order, which saves
+    /// on sorting them.
+    pub fn sorted_output(mut self, yes: bool) -> Self {
+        self.sorted_output = yes;
+        self
+    }
+
+    /// Number of accounts after which the output gets flushed.
+    ///
+    /// By default, the output is only flushed when the buffer is full (see
+    /// [`ProcessOptions::buffer_capacity`]) and once all the accounts have been
+    /// written. Either way, only complete rows are ever flushed.
+    pub fn flush_every(mut self, accounts: Option<usize>) -> Self {
+        self.flush_every = accounts;
+        self
+    }
+
+    /// Whether to accumulate the gross deposits and withdrawals per account.
+    ///
+    /// When enabled, these are tracked in the `gross_deposits` and `gross_withdrawals`
+    /// fields of each account and written to the output as the columns of the same
+    /// names. Failed withdrawals are not counted. Disabled by default.
+    pub fn stats(mut self, yes: bool) -> Self {
+        self.stats = yes;
+        self
+    }
+
+    /// Whether to always write the `closed` column, see [`Account::closed`].
+    ///
+    /// Otherwise, the column is only written if some account has been closed,
+    /// so that the output of the users not closing accounts is not affected.
+    /// [`PaymentEngine::process_streaming`] writes the accounts out before
+    /// knowing whether any is going to be closed, and so only writes the column
+    /// if enabled. Disabled by default.
+    ///
+    /// [`Account::closed`]: crate::Account::closed
+    /// [`PaymentEngine::process_streaming`]: crate::PaymentEngine::process_streaming
+    pub fn closed_column(mut self, yes: bool) -> Self {
+        self.closed_column = yes;
+        self
+    }
+
+    /// Mapping of the expected column names to the ones used in the input,
+    /// see [`ColumnMap`].
+    pub fn column_map(mut self, column_map: ColumnMap) -> Self {
+        self.column_map = column_map;
+        self
+    }
+
+    /// Fees to charge on the transactions, see [`FeeSchedule`].
+    ///
+    /// When any, the fees paid by each account are tracked in its `fees` field
+    /// and written to the output as the `fees_collected` column. No fees by default.
+    pub fn fees(mut self, schedule: FeeSchedule) -> Self {
+        self.fees = schedule;
+        self
+    }
+}
+
+/// Fees charged on the transactions, see [`ProcessOptions::fees`].
+///
+/// The fee is debited from the client's account along with the transaction's
+/// amount, and so the transaction fails due to insufficient funds unless both
+/// are covered. The fee is not refunded when the transaction gets disputed or
+/// charged back.
+///
+/// ```
+/// use payment_engine::{Amount, Fee, FeeSchedule};
+///
+/// let fees = FeeSchedule::new()
+///     .withdrawal(Fee::Flat(Amount::try_from_f64(0.5).unwrap()))?
+///     .transfer(Fee::BasisPoints(25))?;
+/// # Ok::<(), payment_engine::AmountError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FeeSchedule {
+    withdrawal: Option<Fee>,
+    transfer: Option<Fee>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Charge the `fee` on each withdrawal.
+    ///
+    /// Will return [`AmountError::Negative`] if the fee is a negative flat one.
+    pub fn withdrawal(mut self, fee: Fee) -> Result<Self, AmountError> {
+        self.withdrawal = Some(fee.validated()?);
+        Ok(self)
+    }
+
+    /// Charge the `fee` on each transfer, paid by the sender.
+    ///
+    /// Will return [`AmountError::Negative`] if the fee is a negative flat one.
+    pub fn transfer(mut self, fee: Fee) -> Result<Self, AmountError> {
+        self.transfer = Some(fee.validated()?);
+        Ok(self)
+    }
+
+    /// Whether there are no fees to charge.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.withdrawal.is_none() && self.transfer.is_none()
+    }
+
+    /// Fee of the transaction of the `kind` and `amount`, or `None` on overflow.
+    pub(crate) fn fee(&self, kind: TxnRecordKind, amount: Amount) -> Option<Amount> {
+        let fee = match kind {
+            TxnRecordKind::Deposit => None,
+            TxnRecordKind::Withdrawal => self.withdrawal,
+            TxnRecordKind::Transfer => self.transfer,
+        };
+        match fee {
+            None => Some(Amount::default()),
+            Some(Fee::Flat(fee)) => Some(fee),
+            Some(Fee::BasisPoints(points)) => amount.checked_basis_points(points),
+        }
+    }
+}
+
+/// Fee charged on a transaction, see [`FeeSchedule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fee {
+    /// The same amount regardless of the transaction's one.
+    Flat(Amount),
+
+    /// Share of the transaction's amount in basis points, i.e. hundredths of
+    /// a percent, truncated to the minor unit.
+    BasisPoints(u32),
+}
+
+impl Fee {
+    /// The fee, unless it would credit the account instead, see [`FeeSchedule`].
+    fn validated(self) -> Result<Self, AmountError> {
+        match self {
+            Fee::Flat(amount) if amount < Amount::default() => Err(AmountError::Negative),
+            fee => Ok(fee),
+        }
+    }
+}
+
+/// Policy for the transactions with an already processed identifier.
+///
+/// Either way, the original transaction is kept intact. Note that the duplicates
+/// can only be detected if the transactions are stored, see [`ProcessOptions::disputes_present`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Skip the duplicate and record it in the [`ProcessReport`](crate::ProcessReport).
+    #[default]
+    Report,
+
+    /// Silently skip the duplicate, unless in the strict mode, see [`ProcessOptions::strict`].
+    Skip,
+
+    /// Fail processing with [`ProcessError::Rejected`](crate::ProcessError::Rejected).
+    Reject,
+}
+
+/// Policy for the transactions on a locked account, see [`ProcessOptions::on_locked`].
+///
+/// The dispute resolution records on a locked account are skipped regardless.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockedAccountPolicy {
+    /// Skip the transaction, only counting it in [`ProcessReport::locked_skips`](crate::ProcessReport::locked_skips).
+    SkipSilently,
+
+    /// Skip the transaction and record it in the [`ProcessReport`](crate::ProcessReport)
+    /// with [`SkipReason::AccountLocked`](crate::SkipReason::AccountLocked).
+    #[default]
+    SkipWithWarning,
+
+    /// Apply the deposits, while skipping the withdrawals and the transfers
+    /// (either from or to the account) as with [`LockedAccountPolicy::SkipWithWarning`].
+    AllowDeposits,
+
+    /// Fail processing with [`ProcessError::Rejected`](crate::ProcessError::Rejected).
+    Error,
+}
+
+/// Policy for a dispute holding more than the available funds, see
+/// [`ProcessOptions::on_negative_available`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeAvailablePolicy {
+    /// Hold the disputed amount regardless, letting the available funds go
+    /// negative until the dispute is resolved.
+    #[default]
+    Allow,
+
+    /// Hold no more than the available funds, keeping track of the rest as
+    /// the account's [`shortfall`](crate::Account::shortfall), which is also
+    /// written out. The shortfall of each transaction is settled along with
+    /// its own dispute, see [`TxnRecord::shortfall`](crate::TxnRecord::shortfall).
+    Clamp,
+
+    /// Hold the disputed amount, and lock the account pending a review should
+    /// its available funds go negative, see [`ProcessOptions::allow_unlock`].
+    Lock,
+}
+
+/// How to treat the decimal places of an amount beyond the supported precision.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// Discard the extra places, so that `1.00019` becomes `1.0001`.
+    #[default]
+    Truncate,
+
+    /// Round half to even, so that `1.00015` becomes `1.0002`, while `1.00025`
+    /// becomes `1.0002` too.
+    Bankers,
+}
+
+/// Which of the transactions to retain, see [`ProcessOptions::retention`].
+///
+/// A transaction that has not been retained (or has been evicted since) can
+/// neither be disputed, nor recognized as a duplicate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Retention {
+    /// Retain all of them.
+    #[default]
+    All,
+
+    /// Retain the deposits only, so that the withdrawals cannot be disputed.
+    DepositsOnly,
+
+    /// Retain the given number of the most recent transactions of each client,
+    /// evicting the oldest one past it.
+    ///
+    /// The ones under dispute are evicted last, so that the funds held for them
+    /// can still be released or charged back, unless all of the client's retained
+    /// transactions are under dispute.
+    LastPerClient(usize),
+}
+
+/// How often to sync the write-ahead log to the disk, see [`ProcessOptions::fsync`].
+///
+/// Each record is handed over to the OS as soon as it is appended to the log,
+/// and so it survives a crash of the process either way, while surviving a crash
+/// of the system depends on the log having been synced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync after each record.
+    #[default]
+    Always,
+
+    /// Sync after the given number of records.
+    Every(usize),
+
+    /// Never sync, leaving it up to the OS.
+    Never,
+}
+
+/// Format of the input, see [`ProcessOptions::input_format`].
+///
+/// The JSON formats come with the `json` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InputFormat {
+    /// CSV with the headers, see [`crate::process`].
+    #[default]
+    Csv,
+
+    /// Newline-delimited JSON objects with the same fields as the CSV columns,
+    /// e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`.
+    ///
+    /// The amount can be given as either a number or a string, and is read
+    /// exactly either way. Blank lines are ignored.
+    #[cfg(feature = "json")]
+    JsonLines,
+
+    /// Tell the format from the first bytes of the input: an input starting
+    /// with `{` is read as [`InputFormat::JsonLines`], and as [`InputFormat::Csv`]
+    /// otherwise.
+    #[cfg(feature = "json")]
+    Auto,
+}
+
+/// Format of the output, see [`ProcessOptions::output_format`].
+///
+/// The amounts are written with all the places of the [`ProcessOptions::precision`],
+/// e.g. `1.5000`, and so the JSON objects have the same fields as the CSV columns,
+/// with the amounts written as strings, e.g.
+/// `{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}`,
+/// except that an account in the implicit currency goes without the `currency`
+/// field, rather than with an empty one. The CSV specific options (e.g. [`ProcessOptions::quote_style`]) do not apply.
+/// The JSON formats come with the `json` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OutputFormat {
+    /// CSV with the headers.
+    #[default]
+    Csv,
+
+    /// A JSON array of the accounts.
+    #[cfg(feature = "json")]
+    Json,
+
+    /// A JSON object per account, each on its own line.
+    #[cfg(feature = "json")]
+    JsonLines,
+}
+
+/// How often to checkpoint the processing, see [`ProcessOptions::checkpoint`].
+///
+/// Whichever the policy, a checkpoint is taken once the input is exhausted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckpointPolicy {
+    /// Only checkpoint at the end of the input.
+    #[default]
+    Never,
+
+    /// Checkpoint after the given number of records.
+    Every(usize),
+
+    /// Checkpoint after a record once the given time has passed since the
+    /// previous checkpoint.
+    Interval(Duration),
+}
+
+/// Ordering the record timestamps are validated against, see [`ProcessOptions::timestamp_order`].
+///
+/// Either way, records with equal timestamps are in order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampOrder {
+    /// Do not validate the ordering.
+    #[default]
+    Unchecked,
+
+    /// Each record is expected to be no earlier than any record before it.
+    Global,
+
+    /// Each record is expected to be no earlier than the records of the same
+    /// client before it.
+    PerClient,
+}
+
+/// Source of the current time, see [`ProcessOptions::clock`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// The timestamp of the record being applied, if any.
+    #[default]
+    Records,
+
+    /// The system time, in seconds since the Unix epoch.
+    System,
+
+    /// The given timestamp, e.g. when replaying the input as of a point in time.
+    Fixed(Timestamp),
+}
+
+impl Clock {
+    /// Current time, as of the record issued at the `timestamp` (if any).
+    pub(crate) fn now(&self, timestamp: Option<Timestamp>) -> Option<Timestamp> {
+        match self {
+            Clock::Records => timestamp,
+            Clock::System => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|elapsed| elapsed.as_secs()),
+            Clock::Fixed(now) => Some(*now),
+        }
+    }
+}
+
+/// Policy for locking an account due to charge backs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Lock the account on any charge back.
+    #[default]
+    Always,
+
+    /// Never lock the account.
+    Never,
+
+    /// Lock the account once the number of charge backs reaches the given one.
+    AfterCount(u32),
+
+    /// Lock the account once the total amount charged back reaches the given one.
+    AfterAmount(Amount),
+}
+
+impl LockPolicy {
+    pub(crate) fn should_lock(&self, account: &Account) -> bool {
+        match self {
+            LockPolicy::Always => true,
+            LockPolicy::Never => false,
+            LockPolicy::AfterCount(count) => account.chargebacks >= *count,
+            LockPolicy::AfterAmount(amount) => account.charged_back >= *amount,
+        }
+    }
+}
+
+/// Mapping of the column names the engine expects (`type`, `client`, `tx`,
+/// `amount`, and `currency`) to the ones used in the input's header.
+///
+/// ```
+/// use payment_engine::ColumnMap;
+///
+/// let columns = ColumnMap::new()
+///     .column("type", "txn_type")
+///     .column("client", "account");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ColumnMap {
+    /// Actual column names to the expected ones.
+    names: HashMap<String, String>,
+}
+
+impl ColumnMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the `actual` header name for the `expected` column.
+    pub fn column(mut self, expected: &str, actual: &str) -> Self {
+        self.names.insert(actual.to_string(), expected.to_string());
+        self
+    }
+
+    /// Rename the `headers` as read from the input to the expected ones.
+    pub(crate) fn apply(&self, headers: &csv::StringRecord) -> csv::StringRecord {
+        headers.iter().map(|header| self.expected(header)).collect()
+    }
+
+    /// Expected name of the `actual` column.
+    pub(crate) fn expected<'a>(&'a self, actual: &'a str) -> &'a str {
+        self.names.get(actual).map_or(actual, String::as_str)
+    }
+}
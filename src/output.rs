@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::io::{BufWriter, Write};
+
+use crate::domain::{Account, Amount, ClientID, Currency, format_amount};
+use crate::{NegativeAvailablePolicy, OutputFormat, ProcessError, ProcessOptions};
+
+/// Account as written to the output.
+#[derive(Debug, Serialize)]
+pub(crate) struct AccountRow {
+    client: ClientID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available: Scaled,
+    held: Scaled,
+    total: Scaled,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closed: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available_units: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    held_units: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_units: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gross_deposits: Option<Scaled>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gross_withdrawals: Option<Scaled>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fees_collected: Option<Scaled>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shortfall: Option<Scaled>,
+}
+
+/// Amount written out at the precision it is counted in, see
+/// [`ProcessOptions::precision`].
+///
+/// The amount is written as an exact decimal string, with all the places, e.g.
+/// `5.0000`, for the output not to depend on how the floats get formatted.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Scaled {
+    pub(crate) amount: Amount,
+    pub(crate) places: u32,
+}
+
+impl serde::Serialize for Scaled {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format_amount(self.amount, self.places))
+    }
+}
+
+impl AccountRow {
+    pub(crate) fn new(
+        account: &Account,
+        with_currency: bool,
+        with_closed: bool,
+        options: &ProcessOptions,
+    ) -> Self {
+        let units = |amount: Amount| options.minor_units.then(|| amount.minor_units());
+        let scaled = |amount| Scaled {
+            amount,
+            places: options.precision,
+        };
+        // unlike a CSV row, a JSON object goes without the field rather than
+        // with an empty one
+        let with_currency = with_currency
+            && (options.output_format == OutputFormat::Csv || !account.currency.is_implicit());
+        AccountRow {
+            client: account.client,
+            currency: with_currency.then_some(account.currency),
+            available: scaled(account.available),
+            held: scaled(account.held),
+            total: scaled(account.total),
+            locked: account.locked,
+            closed: (with_closed || options.closed_column).then_some(account.closed),
+            available_units: units(account.available),
+            held_units: units(account.held),
+            total_units: units(account.total),
+            gross_deposits: options.stats.then(|| scaled(account.gross_deposits)),
+            gross_withdrawals: options.stats.then(|| scaled(account.gross_withdrawals)),
+            fees_collected: (!options.fees.is_empty()).then(|| scaled(account.fees)),
+            shortfall: (options.on_negative_available == NegativeAvailablePolicy::Clamp)
+                .then(|| scaled(account.shortfall)),
+        }
+    }
+}
+
+/// Writer of the [`AccountRow`]s in CSV format, or as JSON, see [`OutputFormat`].
+///
+/// Each row is serialized on its own first and then handed over to the buffered
+/// writer as a whole; the latter only ever flushes what it has got buffered before
+/// taking in a row which does not fit, and so the underlying writer never gets
+/// to see a partially serialized row, see [`ProcessOptions::flush_every`].
+pub(crate) struct RowWriter<W: Write> {
+    format: OutputFormat,
+
+    /// Serializer of the rows in CSV format, writing the header along with the
+    /// first row, with the bytes of each row taken out once serialized.
+    csv: csv::Writer<RowBuffer>,
+
+    row: Vec<u8>,
+    out: BufWriter<W>,
+    flush_every: Option<usize>,
+    pending: usize,
+    header_written: bool,
+}
+
+impl<W: Write> RowWriter<W> {
+    pub(crate) fn new(writer: W, options: &ProcessOptions) -> Self {
+        let csv = csv::WriterBuilder::new()
+            .delimiter(options.delimiter)
+            .quote_style(options.quote_style)
+            .terminator(options.terminator)
+            .buffer_capacity(ROW_CAPACITY)
+            .from_writer(RowBuffer::default());
+        RowWriter {
+            format: options.output_format,
+            csv,
+            row: Vec::new(),
+            out: BufWriter::with_capacity(options.buffer_capacity, writer),
+            flush_every: options.flush_every,
+            pending: 0,
+            header_written: false,
+        }
+    }
+
+    pub(crate) fn write(&mut self, row: &AccountRow) -> Result<(), ProcessError> {
+        match self.format {
+            OutputFormat::Csv => {
+                self.csv.serialize(row).map_err(ProcessError::Serialize)?;
+                self.csv.flush()?;
+                // note that we are reusing the allocation of the row's bytes
+                std::mem::swap(&mut self.row, &mut self.csv.get_ref().0.borrow_mut());
+            }
+            #[cfg(feature = "json")]
+            OutputFormat::Json => {
+                self.row.push(if self.header_written { b',' } else { b'[' });
+                serde_json::to_writer(&mut self.row, row).map_err(std::io::Error::from)?;
+            }
+            #[cfg(feature = "json")]
+            OutputFormat::JsonLines => {
+                serde_json::to_writer(&mut self.row, row).map_err(std::io::Error::from)?;
+                self.row.push(b'\n');
+            }
+        }
+        self.header_written = true;
+        self.out.write_all(&self.row)?;
+        self.row.clear();
+        self.pending += 1;
+        if self.flush_every.is_some_and(|every| self.pending >= every) {
+            self.out.flush()?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), ProcessError> {
+        #[cfg(feature = "json")]
+        if self.format == OutputFormat::Json {
+            self.out
+                .write_all(if self.header_written { b"]" } else { b"[]" })?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Bytes of the last row serialized in CSV format, for them to be taken out
+/// of the serializer holding on to it, see [`RowWriter::write`].
+#[derive(Debug, Default)]
+struct RowBuffer(RefCell<Vec<u8>>);
+
+impl Write for RowBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.get_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer fanning the bytes out to each of the `sinks`, see [`crate::process_tee`].
+pub(crate) struct Tee<'a, 'b> {
+    sinks: &'a mut [&'b mut dyn Write],
+    failed: Option<std::io::ErrorKind>,
+}
+
+impl<'a, 'b> Tee<'a, 'b> {
+    pub(crate) fn new(sinks: &'a mut [&'b mut dyn Write]) -> Self {
+        Tee {
+            sinks,
+            failed: None,
+        }
+    }
+}
+
+impl Write for Tee<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // once any of the sinks has failed, we are not writing anything else,
+        // since e.g. a buffered writer retrying would otherwise get the same
+        // bytes duplicated in the sinks which succeeded
+        if let Some(kind) = self.failed {
+            return Err(kind.into());
+        }
+        // writing everything to each sink, since a partial write could not be
+        // reported back consistently for all of them
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.write_all(buf) {
+                self.failed = Some(err.kind());
+                return Err(err);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(kind) = self.failed {
+            return Err(kind.into());
+        }
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.flush() {
+                self.failed = Some(err.kind());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Capacity of the buffer a single row is serialized through.
+const ROW_CAPACITY: usize = 128;
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io::Write;
+
+use crate::ProcessError;
+use crate::domain::Record;
+use crate::wal::{HEADER, fields};
+
+/// Records that have not been applied, written out in the input CSV format
+/// along with the reason, see [`PaymentEngine::write_rejects_to`](crate::PaymentEngine::write_rejects_to).
+pub(crate) struct Rejects {
+    /// Where the rejects go, or none if they are held until the records of
+    /// the shards processed in parallel can be put back in order, see
+    /// [`Rejects::held`].
+    writer: Option<csv::Writer<Box<dyn Write + Send>>>,
+    held: Vec<(u64, Vec<String>)>,
+    places: u32,
+}
+
+impl Rejects {
+    /// Start writing the rejected records to the `writer`, with the amounts
+    /// counted in minor units of the given number of `places`.
+    pub(crate) fn new<W>(writer: W, delimiter: u8, places: u32) -> Result<Self, ProcessError>
+    where
+        W: Write + Send + 'static,
+    {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .from_writer(writer);
+        writer
+            .write_record(HEADER.iter().chain(&["timestamp", "reason"]))
+            .map_err(ProcessError::Serialize)?;
+        writer.flush()?;
+        Ok(Rejects {
+            writer: Some(writer),
+            held: Vec::new(),
+            places,
+        })
+    }
+
+    /// Hold the rejected records rather than writing them out, for them to be
+    /// [taken](Rejects::take_held) later on.
+    pub(crate) fn held(places: u32) -> Self {
+        Rejects {
+            writer: None,
+            held: Vec::new(),
+            places,
+        }
+    }
+
+    /// Fields of the `record` to write out if it gets rejected, taken before
+    /// it is applied.
+    pub(crate) fn row(&self, record: &Record) -> Vec<String> {
+        let mut row = fields(record, self.places).to_vec();
+        row.push(
+            record
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+        );
+        row
+    }
+
+    /// Fields of the `raw` record, named by the `headers`, to write out if it
+    /// cannot be read, as they are in the input.
+    pub(crate) fn raw_row(raw: &csv::StringRecord, headers: &csv::StringRecord) -> Vec<String> {
+        HEADER
+            .iter()
+            .chain(&["timestamp"])
+            .map(|name| {
+                let idx = headers.iter().position(|header| header == *name);
+                idx.and_then(|idx| raw.get(idx))
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// Write out the `row` of the record of the given input row number,
+    /// rejected for the `reason`.
+    ///
+    /// The row is flushed right away, for the rejects written so far to be
+    /// there even if the processing fails later on.
+    pub(crate) fn write(
+        &mut self,
+        at: u64,
+        mut row: Vec<String>,
+        reason: impl fmt::Display,
+    ) -> Result<(), ProcessError> {
+        row.push(reason.to_string());
+        match &mut self.writer {
+            Some(writer) => {
+                writer.write_record(&row).map_err(ProcessError::Serialize)?;
+                writer.flush()?;
+            }
+            None => self.held.push((at, row)),
+        }
+        Ok(())
+    }
+
+    /// Take the rejected records held so far, along with their row numbers.
+    pub(crate) fn take_held(&mut self) -> Vec<(u64, Vec<String>)> {
+        std::mem::take(&mut self.held)
+    }
+
+    /// Write out the `held` rejected records, in the order of their rows.
+    pub(crate) fn write_held(
+        &mut self,
+        mut held: Vec<(u64, Vec<String>)>,
+    ) -> Result<(), ProcessError> {
+        held.sort_by_key(|(at, _)| *at);
+        for (at, mut row) in held {
+            // the reason has been pushed already
+            let reason = row.pop().unwrap_or_default();
+            self.write(at, row, reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Rejects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rejects").finish_non_exhaustive()
+    }
+}
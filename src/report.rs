@@ -0,0 +1,696 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::domain::{Account, Amount, ClientID, Currency, TxnID, format_amount};
+
+/// Summary of what happened during processing.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ProcessReport {
+    /// Warnings recorded for records that were nevertheless applied.
+    pub warnings: Vec<Warning>,
+
+    /// Records that were not applied.
+    pub skipped: Vec<Skipped>,
+
+    /// Records that could not be parsed, and so were skipped, see
+    /// [`ProcessOptions::lenient`](crate::ProcessOptions::lenient).
+    pub malformed: Vec<Issue>,
+
+    /// Whether the processing stopped early because the limit on the number
+    /// of records has been reached, see [`ProcessOptions::truncate_at_max_records`].
+    ///
+    /// [`ProcessOptions::truncate_at_max_records`]: crate::ProcessOptions::truncate_at_max_records
+    pub truncated: bool,
+
+    /// Number of records read, whether applied or not.
+    pub records: u64,
+
+    /// Number of deposits applied.
+    pub deposits: u64,
+
+    /// Number of withdrawals applied.
+    pub withdrawals: u64,
+
+    /// Number of transfers applied.
+    pub transfers: u64,
+
+    /// Total fees charged on the applied transactions, see [`ProcessOptions::fees`](crate::ProcessOptions::fees).
+    pub fees_collected: Amount,
+
+    /// Number of dispute resolution operations applied.
+    pub disputes: u64,
+
+    /// Number of dispute resolution operations that had no effect, e.g. since
+    /// referencing an unknown transaction or one not under dispute.
+    ///
+    /// The ones skipped (e.g. since the account is locked) are not counted here,
+    /// but in [`ProcessReport::skipped`] or [`ProcessReport::locked_skips`].
+    pub ignored_disputes: u64,
+
+    /// Number of records skipped since the account is locked.
+    pub locked_skips: u64,
+
+    /// Number of clients whose accounts have been reset, see
+    /// [`PaymentEngine::reset_account`](crate::PaymentEngine::reset_account).
+    pub resets: u64,
+
+    /// Number of accounts unlocked, see [`PaymentEngine::unlock`](crate::PaymentEngine::unlock).
+    pub unlocks: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Warning {
+    /// Ordinal number of the record (starting from 1) among all the records
+    /// fed to the engine.
+    pub row: u64,
+
+    /// What this warning is about.
+    pub kind: WarningKind,
+}
+
+/// What a [`Warning`] is about.
+///
+/// More kinds of warnings may be added, and so matching on them calls for
+/// a wildcard arm:
+///
+/// ```
+/// use payment_engine::{PaymentEngine, WarningKind};
+///
+/// let mut engine = PaymentEngine::new();
+/// let input = "type,client,tx,amount\ndeposit,1,1,1.00005\n";
+/// engine.process(input.as_bytes()).unwrap();
+/// for warning in &engine.report().warnings {
+///     match &warning.kind {
+///         WarningKind::PrecisionLoss { tx, amount } => {
+///             assert_eq!((*tx, amount.as_str()), (1, "1.00005"));
+///         }
+///         _ => unreachable!("no other warnings for the input"),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// The transaction's amount had more significant decimal places than
+    /// supported and so got truncated.
+    PrecisionLoss { tx: TxnID, amount: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Skipped {
+    /// Ordinal number of the record (starting from 1) among all the records
+    /// fed to the engine.
+    pub row: u64,
+
+    /// Client referenced by the record.
+    pub client: ClientID,
+
+    /// Transaction created or referenced by the record, if any.
+    pub tx: Option<TxnID>,
+
+    /// Why the record was not applied.
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SkipReason {
+    /// The client's account has been closed.
+    AccountClosed,
+
+    /// The client's account has been locked due to a charge back.
+    AccountLocked,
+
+    /// The record references an account that does not exist.
+    UnknownAccount,
+
+    /// The client does not have sufficient funds for the withdrawal.
+    InsufficientFunds,
+
+    /// A transaction with the same identifier has already been processed.
+    DuplicateTransaction,
+
+    /// The very same operation has already been applied, see
+    /// [`ProcessOptions::idempotent`](crate::ProcessOptions::idempotent).
+    Replayed,
+
+    /// The dispute resolution record references a transaction of another client.
+    ClientMismatch,
+
+    /// The dispute resolution record's currency differs from the one of the
+    /// referenced transaction.
+    CurrencyMismatch,
+
+    /// The dispute resolution record references a transaction that has not
+    /// been seen (yet), see [`ProcessOptions::strict_ordering`](crate::ProcessOptions::strict_ordering).
+    DisputeBeforeTransaction,
+
+    /// The dispute resolution record references a transaction that is not in
+    /// the input, see [`ProcessOptions::two_pass`](crate::ProcessOptions::two_pass),
+    /// or (in the strict mode) has not been seen so far.
+    UnknownTransaction,
+
+    /// The client's accounts have already been written out, see
+    /// [`PaymentEngine::process_streaming`](crate::PaymentEngine::process_streaming).
+    AccountFlushed,
+
+    /// Applying the record would overflow the funds the account can hold.
+    Overflow,
+
+    /// The record's timestamp is earlier than the one of a record applied
+    /// before it, see [`ProcessOptions::timestamp_order`](crate::ProcessOptions::timestamp_order).
+    OutOfOrder,
+
+    /// The record would unlock an account, which is not allowed, see
+    /// [`ProcessOptions::allow_unlock`](crate::ProcessOptions::allow_unlock).
+    UnlockNotAllowed,
+
+    /// The dispute's amount exceeds the part of the transaction that is
+    /// neither under dispute, nor charged back, see [`TxnRecord::undisputed`](crate::TxnRecord::undisputed).
+    DisputeExceedsAmount,
+
+    /// The transaction has already been disputed as many times as allowed, see
+    /// [`ProcessOptions::max_dispute_cycles`](crate::ProcessOptions::max_dispute_cycles).
+    DisputeCyclesExceeded,
+
+    /// The transaction is too old to be disputed, see
+    /// [`ProcessOptions::dispute_window`](crate::ProcessOptions::dispute_window).
+    DisputeWindowElapsed,
+}
+
+impl SkipReason {
+    /// Whether the record is referentially invalid, as opposed to not being
+    /// applicable due to the state of the account, see [`ProcessOptions::strict`].
+    ///
+    /// [`ProcessOptions::strict`]: crate::ProcessOptions::strict
+    pub fn is_referential(&self) -> bool {
+        matches!(
+            self,
+            SkipReason::UnknownAccount
+                | SkipReason::DuplicateTransaction
+                | SkipReason::ClientMismatch
+                | SkipReason::DisputeBeforeTransaction
+                | SkipReason::UnknownTransaction
+        )
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            SkipReason::AccountClosed => "account is closed",
+            SkipReason::AccountLocked => "account is locked",
+            SkipReason::UnknownAccount => "account does not exist",
+            SkipReason::InsufficientFunds => "insufficient funds",
+            SkipReason::DuplicateTransaction => "duplicate transaction",
+            SkipReason::Replayed => "operation already applied",
+            SkipReason::ClientMismatch => "transaction of another client",
+            SkipReason::CurrencyMismatch => "currency mismatch",
+            SkipReason::DisputeBeforeTransaction => "transaction not seen yet",
+            SkipReason::UnknownTransaction => "transaction does not exist",
+            SkipReason::AccountFlushed => "account already written out",
+            SkipReason::Overflow => "amount overflow",
+            SkipReason::OutOfOrder => "record out of chronological order",
+            SkipReason::UnlockNotAllowed => "unlocking not allowed",
+            SkipReason::DisputeExceedsAmount => "disputed amount exceeds the transaction",
+            SkipReason::DisputeCyclesExceeded => "transaction disputed too many times",
+            SkipReason::DisputeWindowElapsed => "dispute window has elapsed",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// What became of a record, see [`PaymentEngine::process_with_events`](crate::PaymentEngine::process_with_events).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TxnEvent {
+    /// Ordinal number of the record, same as [`Skipped::row`].
+    pub row: u64,
+
+    /// Line of the input the record starts at, if known.
+    pub line: Option<u64>,
+
+    /// Client referenced by the record.
+    pub client: ClientID,
+
+    /// Transaction created or referenced by the record, if any.
+    pub tx: Option<TxnID>,
+
+    /// Amount of the transaction record, or the disputed part of the transaction
+    /// if given by the dispute record, see [`DisputeRecord::amount`](crate::DisputeRecord::amount).
+    pub amount: Option<Amount>,
+
+    pub outcome: TxnOutcome,
+}
+
+/// Outcome of a single record, see [`TxnEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TxnOutcome {
+    /// The record took effect.
+    Applied,
+
+    /// The record was not applied for the reason, either reported in
+    /// [`ProcessReport::skipped`] or skipped silently, as configured.
+    Skipped(SkipReason),
+
+    /// The dispute resolution record references a transaction that has not
+    /// been seen, and so it was ignored.
+    UnknownTransaction,
+
+    /// The record did not take effect for no reason worth reporting, e.g. a
+    /// resolution of a transaction that is not under dispute.
+    Ignored,
+}
+
+/// Span of the input rows that referenced a client, see
+/// [`PaymentEngine::client_span`](crate::PaymentEngine::client_span).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClientSpan {
+    /// Row of the first record that referenced the client.
+    pub first_row: u64,
+
+    /// Row of the last record that referenced the client.
+    pub last_row: u64,
+}
+
+/// Outcome of [`PaymentEngine::reconcile`](crate::PaymentEngine::reconcile).
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ReconcileReport {
+    pub violations: Vec<Violation>,
+}
+
+impl ReconcileReport {
+    /// Whether no violations were found.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Progress of reading the inputs, see [`PaymentEngine::on_progress`](crate::PaymentEngine::on_progress).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ReadProgress {
+    /// Number of records read so far, whether applied or not.
+    pub records: u64,
+
+    /// Number of bytes of the inputs read so far, in total, for the inputs read
+    /// one after another. The inputs merged by their timestamps do not count.
+    pub bytes: u64,
+}
+
+/// Overview of the accounts of an engine, see [`PaymentEngine::summary`](crate::PaymentEngine::summary).
+///
+/// Displayed a figure per line, with the amounts at the precision of the engine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Summary {
+    /// Number of records read, whether applied or not.
+    pub records: u64,
+
+    /// Number of accounts, i.e. of the clients (in each currency) touched.
+    pub accounts: usize,
+
+    /// Number of locked accounts.
+    pub locked: usize,
+
+    /// Funds held for dispute across the accounts of each currency.
+    pub held: BTreeMap<Currency, Amount>,
+
+    /// Number of places past the decimal point the amounts are counted in.
+    pub(crate) places: u32,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "records read: {}", self.records)?;
+        writeln!(f, "accounts touched: {}", self.accounts)?;
+        writeln!(f, "locked accounts: {}", self.locked)?;
+        if self.held.is_empty() {
+            let held = format_amount(Amount::default(), self.places);
+            return writeln!(f, "total held: {held}");
+        }
+        for (currency, held) in &self.held {
+            let held = format_amount(*held, self.places);
+            if currency.is_implicit() {
+                writeln!(f, "total held: {held}")?;
+            } else {
+                writeln!(f, "total held ({currency}): {held}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of [`PaymentEngine::validate`](crate::PaymentEngine::validate).
+#[derive(Debug, Clone, Default, Serialize)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    /// Number of records read, whether valid or not.
+    pub records: u64,
+
+    /// Issues found with the records, in the order of the input.
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    /// Whether no issues were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Issue found with a record, see [`ValidationReport`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Issue {
+    /// Ordinal number of the record, same as [`Skipped::row`].
+    pub row: u64,
+
+    /// Line of the input the record starts at, if known.
+    pub line: Option<u64>,
+
+    pub kind: IssueKind,
+
+    /// What is wrong with the record, along with where it is in the input.
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Kind of an [`Issue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum IssueKind {
+    /// The record does not conform to the schema, e.g. due to an unknown type
+    /// or a missing amount.
+    Malformed,
+
+    /// The amount is not a valid one, e.g. negative, out of range, or more
+    /// precise than supported.
+    InvalidAmount,
+
+    /// A transaction with the same identifier has already been read.
+    DuplicateTransaction,
+
+    /// The record references a transaction that has not been read (e.g. a
+    /// dispute of an unknown transaction) or is one of another client, or an
+    /// account that does not exist, see [`SkipReason::is_referential`].
+    InvalidReference,
+
+    /// The record has been rejected as configured, e.g. with
+    /// [`LockedAccountPolicy::Error`](crate::LockedAccountPolicy::Error).
+    Rejected,
+}
+
+impl IssueKind {
+    /// Kind of the issue with a record skipped for the `reason`, if it is one.
+    pub(crate) fn of_skipped(reason: SkipReason) -> Option<Self> {
+        match reason {
+            SkipReason::DuplicateTransaction => Some(Self::DuplicateTransaction),
+            reason if reason.is_referential() => Some(Self::InvalidReference),
+            _ => None,
+        }
+    }
+}
+
+/// Accounting invariant violation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Violation {
+    /// Account's total funds differ from its available plus held funds.
+    TotalMismatch {
+        client: ClientID,
+        currency: Currency,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+
+    /// Account's held funds differ from the sum of amounts of its transactions
+    /// currently under dispute.
+    HeldMismatch {
+        client: ClientID,
+        currency: Currency,
+        held: Amount,
+        disputed: Amount,
+    },
+
+    /// Account's held funds are negative.
+    NegativeHeld {
+        client: ClientID,
+        currency: Currency,
+        held: Amount,
+    },
+}
+
+impl Violation {
+    /// Violation of the invariants the `account`'s funds should satisfy on
+    /// their own, if any.
+    ///
+    /// Funds adding up past the range of an amount violate the invariants too.
+    pub(crate) fn of_account(account: &Account) -> Option<Self> {
+        if account.available.checked_add(account.held) != Some(account.total) {
+            return Some(Violation::TotalMismatch {
+                client: account.client,
+                currency: account.currency,
+                available: account.available,
+                held: account.held,
+                total: account.total,
+            });
+        }
+        if account.held < Amount::default() {
+            return Some(Violation::NegativeHeld {
+                client: account.client,
+                currency: account.currency,
+                held: account.held,
+            });
+        }
+        None
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::TotalMismatch {
+                client,
+                currency,
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "client {client}{}: total {total} is not available {available} plus held {held}",
+                CurrencySuffix(currency)
+            ),
+            Violation::HeldMismatch {
+                client,
+                currency,
+                held,
+                disputed,
+            } => write!(
+                f,
+                "client {client}{}: held {held} differs from disputed {disputed}",
+                CurrencySuffix(currency)
+            ),
+            Violation::NegativeHeld {
+                client,
+                currency,
+                held,
+            } => write!(
+                f,
+                "client {client}{}: held {held} is negative",
+                CurrencySuffix(currency)
+            ),
+        }
+    }
+}
+
+/// Difference of an account from the expected one, see
+/// [`PaymentEngine::compare_balances`](crate::PaymentEngine::compare_balances).
+///
+/// More kinds of differences may be added, and so matching on them calls for
+/// a wildcard arm:
+///
+/// ```
+/// use payment_engine::{Discrepancy, PaymentEngine};
+///
+/// let mut engine = PaymentEngine::new();
+/// let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+/// engine.process(input.as_bytes()).unwrap();
+/// let expected = "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n";
+/// for discrepancy in engine.compare_balances(expected.as_bytes()).unwrap() {
+///     match discrepancy {
+///         Discrepancy::Funds { client, funds, .. } => {
+///             assert_eq!(client, 1);
+///             assert!(["available", "total"].contains(&funds));
+///         }
+///         _ => unreachable!("the account is there, and not locked"),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Discrepancy {
+    /// Account expected, but not there.
+    Missing {
+        client: ClientID,
+        currency: Currency,
+    },
+
+    /// Account there, but not expected.
+    Unexpected {
+        client: ClientID,
+        currency: Currency,
+    },
+
+    /// Account's `available`, `held`, or `total` funds differ from the expected.
+    Funds {
+        client: ClientID,
+        currency: Currency,
+        funds: &'static str,
+        expected: Amount,
+        actual: Amount,
+    },
+
+    /// Account is locked unlike expected, or the other way around.
+    Locked {
+        client: ClientID,
+        currency: Currency,
+        expected: bool,
+    },
+}
+
+impl Discrepancy {
+    /// Displays the discrepancy with the amounts counted in minor units of the
+    /// given number of `places`, see [`ProcessOptions::precision`](crate::ProcessOptions::precision).
+    pub fn display(&self, places: u32) -> impl fmt::Display + '_ {
+        AtPrecision(self, places)
+    }
+
+    /// Serializes the discrepancy with the amounts counted in minor units of
+    /// the given number of `places`, same as [`Discrepancy::display`], the
+    /// default serialization taking them to be counted at [`Amount::PRECISION`].
+    pub fn serialize_at(&self, places: u32) -> impl serde::Serialize + '_ {
+        AtPrecision(self, places)
+    }
+}
+
+/// Displays the discrepancy at the default precision, see [`Discrepancy::display`].
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(Amount::PRECISION).fmt(f)
+    }
+}
+
+/// Discrepancy displayed or serialized at the precision, see [`Discrepancy::display`].
+struct AtPrecision<'a>(&'a Discrepancy, u32);
+
+impl fmt::Display for AtPrecision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let AtPrecision(discrepancy, places) = *self;
+        match discrepancy {
+            Discrepancy::Missing { client, currency } => {
+                write!(
+                    f,
+                    "client {client}{}: account missing",
+                    CurrencySuffix(currency)
+                )
+            }
+            Discrepancy::Unexpected { client, currency } => {
+                write!(
+                    f,
+                    "client {client}{}: account not expected",
+                    CurrencySuffix(currency)
+                )
+            }
+            Discrepancy::Funds {
+                client,
+                currency,
+                funds,
+                expected,
+                actual,
+            } => {
+                // the amounts are read from files, and so can be far apart
+                let delta = match actual.checked_sub(*expected) {
+                    Some(delta) => format!("delta {}", format_amount(delta, places)),
+                    None => "delta overflow".to_string(),
+                };
+                write!(
+                    f,
+                    "client {client}{}: {funds} {} instead of {} ({delta})",
+                    CurrencySuffix(currency),
+                    format_amount(*actual, places),
+                    format_amount(*expected, places),
+                )
+            }
+            Discrepancy::Locked {
+                client,
+                currency,
+                expected,
+            } => {
+                let state = |locked: bool| if locked { "locked" } else { "unlocked" };
+                write!(
+                    f,
+                    "client {client}{}: {} instead of {}",
+                    CurrencySuffix(currency),
+                    state(!expected),
+                    state(*expected)
+                )
+            }
+        }
+    }
+}
+
+impl serde::Serialize for AtPrecision<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let AtPrecision(discrepancy, places) = *self;
+        let Discrepancy::Funds {
+            client,
+            currency,
+            funds,
+            expected,
+            actual,
+        } = discrepancy
+        else {
+            // the other discrepancies have no amounts
+            return discrepancy.serialize(serializer);
+        };
+        let mut state = serializer.serialize_struct("Discrepancy", 6)?;
+        state.serialize_field("kind", "funds")?;
+        state.serialize_field("client", client)?;
+        state.serialize_field("currency", currency)?;
+        state.serialize_field("funds", funds)?;
+        state.serialize_field("expected", &format_amount(*expected, places))?;
+        state.serialize_field("actual", &format_amount(*actual, places))?;
+        state.end()
+    }
+}
+
+struct CurrencySuffix<'a>(&'a Currency);
+
+impl fmt::Display for CurrencySuffix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_implicit() {
+            return Ok(());
+        }
+        write!(f, " ({})", self.0)
+    }
+}
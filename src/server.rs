@@ -0,0 +1,1035 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use axum::Json;
+use axum::Router;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+#[cfg(feature = "prometheus")]
+use crate::Metrics;
+use crate::domain::{
+    Account, Amount, ClientID, Currency, DisputeRecordKind, Record, RecordInner, TxnID, TxnRecord,
+    TxnRecordKind, TxnState,
+};
+use crate::output::Scaled;
+use crate::{ConcurrentEngine, EventObserver, ProcessError, TxnEvent, TxnOutcome};
+
+/// Number of the account updates buffered for a subscriber lagging behind,
+/// beyond which the oldest ones are dropped.
+const UPDATES_CAPACITY: usize = 1024;
+
+/// Header of a submission naming it for its retries, see [`Server::idempotency`].
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Header of the response to a retried submission, which is the original one.
+const REPLAYED: &str = "idempotent-replayed";
+
+/// HTTP server on top of a [`ConcurrentEngine`], for the records to be submitted
+/// as they come rather than in files, exposing:
+///
+/// - `POST /transactions`, applying the records in the body, in the input
+///   format of the engine's options, and responding with what became of
+///   each of them;
+/// - `GET /accounts`, all the accounts;
+/// - `GET /accounts/{client}`, the client's accounts in all the currencies;
+/// - `GET /transactions/{tx}`, the transaction along with its dispute state;
+/// - `GET /accounts/{client}/updates`, a WebSocket pushing a JSON message whenever
+///   the client's account changes, e.g. `{"event":"dispute","row":3,"tx":1,"account":{...}}`,
+///   with the `event` being one of `deposit`, `withdrawal`, `transfer`, `transfer_received`,
+///   `dispute`, `resolve`, `chargeback`, `account_locked`, `account_unlocked` or
+///   `account_reset`. The recipient of a transfer is pushed the updates of the
+///   transfer (and of its disputes) too.
+///
+/// The responses are JSON. Unless the server is made [idempotent](Server::idempotency),
+/// a submission retried (e.g. after a timeout) is applied again. The records of a client are applied one at a time,
+/// in the order they are received, while the submissions of the clients of
+/// different shards of the engine are applied in parallel, on the blocking
+/// threads of the runtime rather than its workers. A request failing due to
+/// a panic (e.g. of an observer) is responded to with a server error, as are
+/// the later ones needing the shard it was applied on.
+#[derive(Debug)]
+pub struct Server {
+    engine: ConcurrentEngine,
+    updates: broadcast::Sender<AccountUpdate>,
+    retention: Option<Duration>,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Metrics>,
+}
+
+#[derive(Debug)]
+struct AppState {
+    engine: ConcurrentEngine,
+    updates: broadcast::Sender<AccountUpdate>,
+    replays: Option<Mutex<Replays>>,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Metrics>,
+}
+
+/// What became of a submitted record, see [`TxnEvent`].
+#[derive(Debug, Clone, Serialize)]
+struct EventBody {
+    row: u64,
+    client: ClientID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<TxnID>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<Scaled>,
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl EventBody {
+    fn new(event: TxnEvent, places: u32) -> Self {
+        let (outcome, reason) = match event.outcome {
+            TxnOutcome::Applied => ("applied", None),
+            TxnOutcome::Skipped(reason) => ("skipped", Some(reason.to_string())),
+            TxnOutcome::UnknownTransaction => ("unknown_transaction", None),
+            TxnOutcome::Ignored => ("ignored", None),
+        };
+        Self {
+            row: event.row,
+            client: event.client,
+            tx: event.tx,
+            amount: event.amount.map(|amount| Scaled { amount, places }),
+            outcome,
+            reason,
+        }
+    }
+}
+
+/// A change of a client's account, pushed to the subscribers of the client.
+#[derive(Debug, Clone, Serialize)]
+struct AccountUpdate {
+    event: &'static str,
+    row: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<TxnID>,
+    account: AccountBody,
+}
+
+/// An [`Account`] as responded with.
+#[derive(Debug, Clone, Serialize)]
+struct AccountBody {
+    client: ClientID,
+    currency: Currency,
+    available: Scaled,
+    held: Scaled,
+    total: Scaled,
+    locked: bool,
+    closed: bool,
+    chargebacks: u32,
+    charged_back: Scaled,
+    gross_deposits: Scaled,
+    gross_withdrawals: Scaled,
+    fees: Scaled,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shortfall: Option<Scaled>,
+}
+
+impl AccountBody {
+    fn new(account: &Account, places: u32) -> Self {
+        let scaled = |amount| Scaled { amount, places };
+        Self {
+            client: account.client,
+            currency: account.currency,
+            available: scaled(account.available),
+            held: scaled(account.held),
+            total: scaled(account.total),
+            locked: account.locked,
+            closed: account.closed,
+            chargebacks: account.chargebacks,
+            charged_back: scaled(account.charged_back),
+            gross_deposits: scaled(account.gross_deposits),
+            gross_withdrawals: scaled(account.gross_withdrawals),
+            fees: scaled(account.fees),
+            shortfall: (account.shortfall != Amount::default()).then(|| scaled(account.shortfall)),
+        }
+    }
+}
+
+/// A [`TxnRecord`] as responded with, along with its dispute state.
+#[derive(Debug, Serialize)]
+struct TxnBody {
+    #[serde(rename = "type")]
+    kind: TxnRecordKind,
+    client: ClientID,
+    tx: TxnID,
+    amount: Scaled,
+    #[serde(skip_serializing_if = "Currency::is_implicit")]
+    currency: Currency,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_client: Option<ClientID>,
+    state: TxnState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disputed: Option<Scaled>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reversed: Option<Scaled>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shortfall: Option<Scaled>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cycles: Option<u32>,
+}
+
+impl TxnBody {
+    fn new(txn: &TxnRecord, places: u32) -> Self {
+        let scaled = |amount| (amount != Amount::default()).then_some(Scaled { amount, places });
+        Self {
+            kind: txn.kind,
+            client: txn.client,
+            tx: txn.tx,
+            amount: Scaled {
+                amount: txn.amount,
+                places,
+            },
+            currency: txn.currency,
+            to_client: txn.to_client,
+            state: txn.state,
+            disputed: scaled(txn.disputed),
+            reversed: scaled(txn.reversed),
+            shortfall: scaled(txn.shortfall),
+            cycles: (txn.cycles != 0).then_some(txn.cycles),
+        }
+    }
+}
+
+/// Observer broadcasting the account updates to the subscribers.
+#[derive(Debug, Clone)]
+struct Broadcaster {
+    updates: broadcast::Sender<AccountUpdate>,
+    places: u32,
+}
+
+impl Broadcaster {
+    fn send(&self, event: &'static str, txn: &TxnEvent, account: &Account) {
+        // there being no subscribers is not an error
+        let _ = self.updates.send(AccountUpdate {
+            event,
+            row: txn.row,
+            tx: txn.tx,
+            account: AccountBody::new(account, self.places),
+        });
+    }
+}
+
+impl EventObserver for Broadcaster {
+    fn on_deposit(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("deposit", event, account);
+    }
+
+    fn on_withdrawal(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("withdrawal", event, account);
+    }
+
+    fn on_transfer(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("transfer", event, account);
+    }
+
+    fn on_transfer_received(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("transfer_received", event, account);
+    }
+
+    fn on_dispute(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("dispute", event, account);
+    }
+
+    fn on_resolve(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("resolve", event, account);
+    }
+
+    fn on_chargeback(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("chargeback", event, account);
+    }
+
+    fn on_account_locked(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("account_locked", event, account);
+    }
+
+    fn on_account_unlocked(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("account_unlocked", event, account);
+    }
+
+    fn on_account_reset(&mut self, event: &TxnEvent, account: &Account) {
+        self.send("account_reset", event, account);
+    }
+}
+
+/// Record a retry is recognized by, i.e. the client, the transaction, the record
+/// type and the amount of the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RecordKey {
+    client: ClientID,
+    tx: TxnID,
+    kind: RecordKind,
+    amount: Option<Amount>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RecordKind {
+    Txn(TxnRecordKind),
+    Dispute(DisputeRecordKind),
+}
+
+impl RecordKey {
+    /// Key of the `record`, unless it is an account operation, which is not replayed.
+    fn of(record: &Record) -> Option<Self> {
+        match &record.inner {
+            RecordInner::TxnRecord(r) => Some(Self {
+                client: r.client,
+                tx: r.tx,
+                kind: RecordKind::Txn(r.kind),
+                amount: Some(r.amount),
+            }),
+            RecordInner::DisputeRecord(r) => Some(Self {
+                client: r.client,
+                tx: r.tx,
+                kind: RecordKind::Dispute(r.kind),
+                amount: r.amount,
+            }),
+            RecordInner::AccountRecord(_) => None,
+        }
+    }
+}
+
+/// Outcome retained for the replays of a record or a submission.
+#[derive(Debug)]
+enum Retained {
+    Record(RecordKey),
+    Submission(String),
+}
+
+/// Outcomes of the records and the submissions retained within the window of
+/// the replays, see [`Server::idempotency`].
+#[derive(Debug)]
+struct Replays {
+    retention: Duration,
+    records: HashMap<RecordKey, (Instant, EventBody)>,
+
+    /// Dispute resolution record retained last for each of the transactions,
+    /// the one before being dropped for the transaction to be disputed again.
+    disputes: HashMap<TxnID, RecordKey>,
+
+    /// Responses to the submissions, along with the hashes of their bodies.
+    submissions: HashMap<String, (Instant, u64, StatusCode, Value)>,
+
+    /// What has been retained, in the order it was, for the expired outcomes
+    /// to be dropped.
+    retained: VecDeque<(Instant, Retained)>,
+}
+
+impl Replays {
+    fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            records: HashMap::new(),
+            disputes: HashMap::new(),
+            submissions: HashMap::new(),
+            retained: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some((at, retained)) = self.retained.pop_front() {
+            if now.duration_since(at) < self.retention {
+                self.retained.push_front((at, retained));
+                break;
+            }
+            // an outcome dropped before and retained again since is not expired
+            match retained {
+                Retained::Record(key) => {
+                    if self
+                        .records
+                        .get(&key)
+                        .is_some_and(|(since, _)| *since == at)
+                    {
+                        self.records.remove(&key);
+                        if self.disputes.get(&key.tx) == Some(&key) {
+                            self.disputes.remove(&key.tx);
+                        }
+                    }
+                }
+                Retained::Submission(key) => {
+                    if self
+                        .submissions
+                        .get(&key)
+                        .is_some_and(|(since, ..)| *since == at)
+                    {
+                        self.submissions.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, key: RecordKey) -> Option<EventBody> {
+        self.expire();
+        self.records.get(&key).map(|(_, event)| event.clone())
+    }
+
+    /// Hash of the body and the response of the submission with the `key`.
+    fn submission(&mut self, key: &str) -> Option<(u64, StatusCode, Value)> {
+        self.expire();
+        self.submissions
+            .get(key)
+            .map(|(_, hash, status, body)| (*hash, *status, body.clone()))
+    }
+
+    fn retain_record(&mut self, key: RecordKey, event: EventBody) {
+        let now = Instant::now();
+        if !retain(&mut self.records, key, (now, event)) {
+            return;
+        }
+        if let RecordKind::Dispute(_) = key.kind
+            && let Some(previous) = self.disputes.insert(key.tx, key)
+            && previous != key
+        {
+            self.records.remove(&previous);
+        }
+        self.retained.push_back((now, Retained::Record(key)));
+    }
+
+    fn retain_submission(&mut self, key: String, hash: u64, response: (StatusCode, Value)) {
+        let now = Instant::now();
+        let (status, body) = response;
+        if retain(
+            &mut self.submissions,
+            key.clone(),
+            (now, hash, status, body),
+        ) {
+            self.retained.push_back((now, Retained::Submission(key)));
+        }
+    }
+}
+
+/// Retain the `value` unless the `key` already is, e.g. by a replay applied
+/// concurrently, returning whether it was retained.
+fn retain<K, V>(retained: &mut HashMap<K, V>, key: K, value: V) -> bool
+where
+    K: Eq + Hash,
+{
+    match retained.entry(key) {
+        Entry::Vacant(entry) => {
+            entry.insert(value);
+            true
+        }
+        Entry::Occupied(_) => false,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(ErrorBody { error: message })).into_response()
+}
+
+/// Status of the response to a submission failed with the `err`.
+fn status(err: &ProcessError) -> StatusCode {
+    match err {
+        ProcessError::Io(_)
+        | ProcessError::Serialize(_)
+        | ProcessError::NotEmpty
+        | ProcessError::InvariantViolated { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        ProcessError::Csv(err) if err.is_io_error() => StatusCode::INTERNAL_SERVER_ERROR,
+        #[cfg(feature = "tokio")]
+        ProcessError::AsyncCsv(_) => StatusCode::BAD_REQUEST,
+        #[cfg(feature = "kafka")]
+        ProcessError::Kafka(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ProcessError::Csv(_)
+        | ProcessError::Malformed { .. }
+        | ProcessError::MissingAmount { .. }
+        | ProcessError::Json { .. }
+        | ProcessError::InvalidTimestamp { .. }
+        | ProcessError::InvalidTransfer { .. }
+        | ProcessError::InvalidSnapshot { .. } => StatusCode::BAD_REQUEST,
+        ProcessError::InvalidAmount(_)
+        | ProcessError::AmountRejected { .. }
+        | ProcessError::PrecisionLoss { .. }
+        | ProcessError::UnexpectedDispute { .. }
+        | ProcessError::TooManyRecords { .. }
+        | ProcessError::Merge(_)
+        | ProcessError::Rejected { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+impl Server {
+    /// Serve the `engine`, e.g. a [`PaymentEngine`](crate::PaymentEngine)
+    /// [restored](crate::PaymentEngine::restore) from a snapshot, which makes for
+    /// a single shard.
+    pub fn new(engine: impl Into<ConcurrentEngine>) -> Self {
+        let mut engine = engine.into();
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        let places = engine.options().precision;
+        engine.add_observer(Broadcaster {
+            updates: updates.clone(),
+            places,
+        });
+        Self {
+            engine,
+            updates,
+            retention: None,
+            #[cfg(feature = "prometheus")]
+            metrics: None,
+        }
+    }
+
+    /// Recognize the retries of the submissions for the `retention` since the
+    /// originals, responding to them with the outcomes of the originals instead
+    /// of applying them again.
+    ///
+    /// A submission with an `Idempotency-Key` header is a retry of the one
+    /// before with the same key, and gets its response (with an `Idempotent-Replayed`
+    /// header), unless the response was a server error. The key being reused for
+    /// a submission of another body is responded to with `422 Unprocessable Entity`.
+    ///
+    /// Otherwise, a record retried is recognized by its client, transaction,
+    /// type and amount, and gets its original outcome (e.g. a deposit sent again
+    /// after a timeout is not applied twice). Of the dispute resolution records
+    /// of a transaction only the last one is recognized, and so a transaction
+    /// disputed again after its dispute has been resolved is disputed anew.
+    ///
+    /// A retry sent while its original is still being applied is left to the
+    /// engine, which skips a transaction it already has as a duplicate.
+    pub fn idempotency(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Keep the `metrics` of the engine and the submissions, and expose them
+    /// at `GET /metrics`.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.engine.add_observer(metrics.clone());
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The routes of the server, e.g. to be nested in a larger application.
+    pub fn router(self) -> Router {
+        #[cfg(feature = "prometheus")]
+        let metrics = self.metrics.is_some();
+        let state = Arc::new(AppState {
+            engine: self.engine,
+            updates: self.updates,
+            replays: self
+                .retention
+                .map(|retention| Mutex::new(Replays::new(retention))),
+            #[cfg(feature = "prometheus")]
+            metrics: self.metrics,
+        });
+        let router = Router::new()
+            .route("/transactions", post(submit))
+            .route("/transactions/{tx}", get(transaction))
+            .route("/accounts", get(accounts))
+            .route("/accounts/{client}", get(account))
+            .route("/accounts/{client}/updates", get(subscribe));
+        #[cfg(feature = "prometheus")]
+        let router = if metrics {
+            router.route("/metrics", get(encode_metrics))
+        } else {
+            router
+        };
+        router.with_state(state)
+    }
+
+    /// Accept the connections on the `listener` until the server fails.
+    pub async fn serve(self, listener: tokio::net::TcpListener) -> std::io::Result<()> {
+        axum::serve(listener, self.router()).await
+    }
+}
+
+/// Call `f` on a thread of its own, since it locks the shards of the engine for
+/// as long as the records take to apply, responding with a server error if it
+/// panicked (e.g. due to a shard poisoned by an earlier panic), for the server
+/// to keep serving the other shards.
+async fn blocking<F>(f: F) -> Response
+where
+    F: FnOnce() -> Response + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(response) => response,
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+async fn submit(State(state): State<Arc<AppState>>, headers: HeaderMap, body: String) -> Response {
+    let key = headers
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .filter(|_| state.replays.is_some())
+        .map(str::to_string);
+    blocking(move || submit_blocking(&state, key.as_deref(), body)).await
+}
+
+/// Apply the records of the `body` submitted with the idempotency `key` (if any).
+fn submit_blocking(state: &AppState, key: Option<&str>, body: String) -> Response {
+    let hash = {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        hasher.finish()
+    };
+    if let Some(key) = key
+        && let Some((original, status, body)) = replays(state).submission(key)
+    {
+        if original != hash {
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("idempotency key \"{key}\" reused for another submission"),
+            );
+        }
+        return (status, [(REPLAYED, "true")], Json(body)).into_response();
+    }
+    let mut events = Vec::new();
+    let mut process = || {
+        state.engine.parse(body.as_bytes(), |line, record| {
+            events.push(apply(state, line, record)?);
+            Ok(())
+        })
+    };
+    #[cfg(feature = "prometheus")]
+    let result = match &state.metrics {
+        Some(metrics) => metrics.time(process),
+        None => process(),
+    };
+    #[cfg(not(feature = "prometheus"))]
+    let result = process();
+    let (status, body) = match result {
+        Ok(()) => (StatusCode::OK, serde_json::json!(events)),
+        // the records preceding the failed one have been applied nonetheless
+        Err(err) => (
+            status(&err),
+            serde_json::json!(ErrorBody {
+                error: err.to_string()
+            }),
+        ),
+    };
+    if let Some(key) = key
+        && !status.is_server_error()
+    {
+        replays(state).retain_submission(key.to_string(), hash, (status, body.clone()));
+    }
+    (status, Json(body)).into_response()
+}
+
+fn replays(state: &AppState) -> std::sync::MutexGuard<'_, Replays> {
+    let replays = state.replays.as_ref().expect("server made idempotent");
+    // each outcome is retained (or expired) in one go, and so the replays are
+    // consistent even if poisoned by a panic
+    replays.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Apply the `record`, unless it is a replay, in which case its original outcome
+/// is returned instead.
+fn apply(state: &AppState, line: Option<u64>, record: Record) -> Result<EventBody, ProcessError> {
+    let key = RecordKey::of(&record).filter(|_| state.replays.is_some());
+    if let Some(key) = key
+        && let Some(event) = replays(state).record(key)
+    {
+        return Ok(event);
+    }
+    let places = state.engine.options().precision;
+    let event = EventBody::new(state.engine.apply_at(line, record)?, places);
+    if let Some(key) = key {
+        replays(state).retain_record(key, event.clone());
+    }
+    Ok(event)
+}
+
+async fn accounts(State(state): State<Arc<AppState>>) -> Response {
+    let places = state.engine.options().precision;
+    blocking(move || match state.engine.accounts() {
+        Ok(accounts) => Json(account_bodies(&accounts, places)).into_response(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    })
+    .await
+}
+
+async fn account(State(state): State<Arc<AppState>>, Path(client): Path<ClientID>) -> Response {
+    let places = state.engine.options().precision;
+    blocking(move || match state.engine.balances(client) {
+        Ok(accounts) if accounts.is_empty() => error(
+            StatusCode::NOT_FOUND,
+            format!("no account of client {client}"),
+        ),
+        Ok(accounts) => Json(account_bodies(&accounts, places)).into_response(),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    })
+    .await
+}
+
+fn account_bodies(accounts: &[Account], places: u32) -> Vec<AccountBody> {
+    accounts
+        .iter()
+        .map(|account| AccountBody::new(account, places))
+        .collect()
+}
+
+async fn transaction(State(state): State<Arc<AppState>>, Path(tx): Path<TxnID>) -> Response {
+    let places = state.engine.options().precision;
+    blocking(move || match state.engine.transaction(tx) {
+        Ok(Some(txn)) => Json(TxnBody::new(&txn, places)).into_response(),
+        Ok(None) => error(StatusCode::NOT_FOUND, format!("no transaction {tx}")),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    })
+    .await
+}
+
+async fn subscribe(
+    State(state): State<Arc<AppState>>,
+    Path(client): Path<ClientID>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let updates = state.updates.subscribe();
+    upgrade.on_upgrade(move |socket| push_updates(socket, client, updates))
+}
+
+/// Push the `client`'s account updates over the `socket` until it is closed.
+async fn push_updates(
+    mut socket: WebSocket,
+    client: ClientID,
+    mut updates: broadcast::Receiver<AccountUpdate>,
+) {
+    loop {
+        match updates.recv().await {
+            Ok(update) if update.account.client == client => {
+                let text = serde_json::to_string(&update).expect("update encoded as JSON");
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            // a subscriber lagging behind misses the oldest updates
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+async fn encode_metrics(State(state): State<Arc<AppState>>) -> Response {
+    let metrics = state.metrics.as_ref().expect("route of the metrics");
+    metrics.encode().into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::time::Duration;
+
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::Server;
+    use crate::{PaymentEngine, ProcessOptions};
+
+    async fn call(
+        server: &axum::Router,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let (status, _, body) = send(server, request).await;
+        (status, body)
+    }
+
+    async fn send(server: &axum::Router, request: Request<Body>) -> (StatusCode, bool, Value) {
+        let response = server.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let replayed = response.headers().contains_key(super::REPLAYED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, replayed, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_the_engine() {
+        let server = Server::new(PaymentEngine::new()).router();
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "withdrawal, 1,       2,      20.0",
+            "dispute,    1,       1,",
+        ]
+        .join("\n");
+        let (status, events) = call(&server, "POST", "/transactions", &input).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            events,
+            json!([
+                {"row": 1, "client": 1, "tx": 1, "amount": "10.0000", "outcome": "applied"},
+                {"row": 2, "client": 1, "tx": 2, "amount": "20.0000", "outcome": "skipped",
+                    "reason": "insufficient funds"},
+                {"row": 3, "client": 1, "tx": 1, "outcome": "applied"},
+            ])
+        );
+
+        let (status, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(accounts[0]["held"], json!("10.0000"));
+        let (_, accounts) = call(&server, "GET", "/accounts", "").await;
+        assert_eq!(accounts.as_array().unwrap().len(), 1);
+        let (status, _) = call(&server, "GET", "/accounts/2", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, txn) = call(&server, "GET", "/transactions/1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(txn["state"], json!("disputed"));
+        let (status, _) = call(&server, "GET", "/transactions/2", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, error) = call(&server, "POST", "/transactions", "type,client\nwhat,1").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn responds_at_precision_of_the_engine() {
+        let options = ProcessOptions::new().precision(2).unwrap();
+        let server = Server::new(PaymentEngine::with_options(options));
+        let mut updates = server.updates.subscribe();
+        let server = server.router();
+        let input = "type,client,tx,amount\ndeposit,1,1,10.5\ndispute,1,1,";
+        let (_, events) = call(&server, "POST", "/transactions", input).await;
+        assert_eq!(events[0]["amount"], json!("10.50"));
+
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["held"], json!("10.50"));
+        assert_eq!(accounts[0]["available"], json!("0.00"));
+        let (_, accounts) = call(&server, "GET", "/accounts", "").await;
+        assert_eq!(accounts[0]["total"], json!("10.50"));
+        let (_, txn) = call(&server, "GET", "/transactions/1", "").await;
+        assert_eq!(txn["amount"], json!("10.50"));
+        assert_eq!(txn["disputed"], json!("10.50"));
+        let update = serde_json::to_value(updates.try_recv().unwrap()).unwrap();
+        assert_eq!(update["account"]["available"], json!("10.50"));
+    }
+
+    #[tokio::test]
+    async fn broadcasts_account_updates() {
+        let server = Server::new(PaymentEngine::new());
+        let mut updates = server.updates.subscribe();
+        let server = server.router();
+        let input = [
+            "type,       client,  tx,     amount",
+            "deposit,    1,       1,      10.0",
+            "deposit,    2,       2,      1.0",
+            "withdrawal, 1,       3,      20.0",
+            "dispute,    1,       1,",
+            "chargeback, 1,       1,",
+        ]
+        .join("\n");
+        call(&server, "POST", "/transactions", &input).await;
+        let mut received = Vec::new();
+        while let Ok(update) = updates.try_recv() {
+            received.push((update.event, update.account.client, update.row));
+        }
+        assert_eq!(
+            received,
+            [
+                ("deposit", 1, 1),
+                ("deposit", 2, 2),
+                ("dispute", 1, 4),
+                ("chargeback", 1, 5),
+                ("account_locked", 1, 5),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pushes_account_updates_over_websocket() {
+        use futures_util::StreamExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Server::new(PaymentEngine::new()).router();
+        tokio::spawn(axum::serve(listener, router.clone()).into_future());
+        let url = format!("ws://{addr}/accounts/1/updates");
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    2,       1,      10.0,",
+            "deposit,    1,       2,      5.0,",
+            "transfer,   1,       3,      2.0,    2",
+        ]
+        .join("\n");
+        call(&router, "POST", "/transactions", &input).await;
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let message = socket.next().await.unwrap().unwrap();
+            let update: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            received.push(update);
+        }
+        // only the updates of the subscribed client are pushed
+        assert_eq!(received[0]["event"], json!("deposit"));
+        assert_eq!(received[0]["row"], json!(2));
+        assert_eq!(received[1]["event"], json!("transfer"));
+        assert_eq!(received[1]["account"]["client"], json!(1));
+        assert_eq!(received[1]["account"]["available"], json!("3.0000"));
+    }
+
+    #[tokio::test]
+    async fn pushes_transfers_to_recipients() {
+        use futures_util::StreamExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Server::new(PaymentEngine::new()).router();
+        tokio::spawn(axum::serve(listener, router.clone()).into_future());
+        let url = format!("ws://{addr}/accounts/2/updates");
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let input = [
+            "type,       client,  tx,     amount, to_client",
+            "deposit,    1,       1,      10.0,",
+            "transfer,   1,       2,      4.0,    2",
+            "dispute,    1,       2,,",
+            "chargeback, 1,       2,,",
+        ]
+        .join("\n");
+        call(&router, "POST", "/transactions", &input).await;
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            let message = socket.next().await.unwrap().unwrap();
+            let update: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            assert_eq!(update["account"]["client"], json!(2));
+            received.push((update["event"].clone(), update["account"]["total"].clone()));
+        }
+        assert_eq!(
+            received,
+            [
+                (json!("transfer_received"), json!("4.0000")),
+                (json!("dispute"), json!("4.0000")),
+                (json!("chargeback"), json!("0.0000")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_requests_of_poisoned_shards() {
+        #[derive(Debug)]
+        struct Failing;
+
+        impl crate::EventObserver for Failing {
+            fn on_deposit(&mut self, event: &crate::TxnEvent, _: &crate::Account) {
+                assert_ne!(event.client, 9, "deposit of client 9 observed");
+            }
+        }
+
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(Failing);
+        let server = Server::new(engine).router();
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0";
+        let (status, _) = call(&server, "POST", "/transactions", input).await;
+        assert_eq!(status, StatusCode::OK);
+        let input = "type,client,tx,amount\ndeposit,9,2,1.0";
+        let (status, error) = call(&server, "POST", "/transactions", input).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(error["error"].is_string());
+        // the shard is poisoned for good, while the server keeps responding
+        let (status, _) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let (status, _) = call(&server, "GET", "/transactions/1", "").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn replays_retried_submissions() {
+        let retention = Duration::from_secs(3600);
+        let server = Server::new(PaymentEngine::new())
+            .idempotency(retention)
+            .router();
+        let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,1.0";
+        let (_, original) = call(&server, "POST", "/transactions", input).await;
+        // the second deposit retried along with a new one
+        let retry = "type,client,tx,amount\ndeposit,1,2,1.0\ndeposit,1,3,5.0";
+        let (status, events) = call(&server, "POST", "/transactions", retry).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(events[0], original[1]);
+        assert_eq!(events[1]["row"], json!(3));
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["available"], json!("16.0000"));
+
+        let submit = |body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/transactions")
+                .header("Idempotency-Key", "withdrawal-1")
+                .body(Body::from(body.to_owned()))
+                .unwrap()
+        };
+        let withdrawal = "type,client,tx,amount\nwithdrawal,1,4,20.0";
+        let (_, replayed, original) = send(&server, submit(withdrawal)).await;
+        assert!(!replayed);
+        assert_eq!(original[0]["reason"], json!("insufficient funds"));
+        let (status, replayed, events) = send(&server, submit(withdrawal)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(replayed);
+        assert_eq!(events, original);
+        // the key names the submission of that body only
+        let withdrawal = "type,client,tx,amount\nwithdrawal,1,5,1.0";
+        let (status, replayed, error) = send(&server, submit(withdrawal)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!replayed);
+        assert!(error["error"].as_str().unwrap().contains("withdrawal-1"));
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["available"], json!("16.0000"));
+
+        // with nothing retained, a retry is left to the engine
+        let server = Server::new(PaymentEngine::new())
+            .idempotency(Duration::ZERO)
+            .router();
+        let (_, original) = call(&server, "POST", "/transactions", input).await;
+        let (_, events) = call(&server, "POST", "/transactions", input).await;
+        assert_ne!(events, original);
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["available"], json!("11.0000"));
+    }
+
+    #[tokio::test]
+    async fn replays_records_of_the_same_operation_only() {
+        let server = Server::new(PaymentEngine::new())
+            .idempotency(Duration::from_secs(3600))
+            .router();
+        let input = "type,client,tx,amount\ndeposit,1,1,10.0";
+        let (_, original) = call(&server, "POST", "/transactions", input).await;
+        // another client or amount under the same transaction is not a retry
+        let input = "type,client,tx,amount\ndeposit,2,1,10.0\ndeposit,1,1,5.0";
+        let (_, events) = call(&server, "POST", "/transactions", input).await;
+        assert_ne!(events[0], original[0]);
+        assert_ne!(events[1], original[0]);
+        assert_eq!(events[0]["reason"], json!("duplicate transaction"));
+
+        let dispute = "type,client,tx\ndispute,1,1";
+        let (_, disputed) = call(&server, "POST", "/transactions", dispute).await;
+        let (_, events) = call(&server, "POST", "/transactions", dispute).await;
+        assert_eq!(events, disputed);
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["held"], json!("10.0000"));
+        // disputed again once resolved, rather than swallowed as a retry
+        let resolve = "type,client,tx\nresolve,1,1";
+        call(&server, "POST", "/transactions", resolve).await;
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["held"], json!("0.0000"));
+        let (_, events) = call(&server, "POST", "/transactions", dispute).await;
+        assert_eq!(events[0]["type"], disputed[0]["type"]);
+        let (_, accounts) = call(&server, "GET", "/accounts/1", "").await;
+        assert_eq!(accounts[0]["held"], json!("10.0000"));
+    }
+}
@@ -0,0 +1,427 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::domain::{Amount, Currency, TxnID, TxnRecord, TxnRecordKind, TxnState};
+use crate::{ProcessError, Rounding, TxnStore};
+
+/// Number of the updates buffered before they get written to the tree, by default.
+const BATCH_SIZE: usize = 4096;
+
+/// Version of the record format, bumped whenever the layout below changes, for
+/// the records written by another version to be told apart from malformed ones.
+const VERSION: u8 = 2;
+
+// version, kind, client, tx, amount, currency, recipient, disputed, reversed and
+// shortfall amounts, dispute cycles, timestamp (if any), and state, in this order
+const RECORD_LEN: usize = 1 + 1 + 2 + 4 + 8 + Currency::MAX_LEN + 2 + 8 + 8 + 8 + 4 + 1 + 8 + 1;
+
+/// Offset of the currency code within a record, preceded by the version, the
+/// kind, the client, the tx and the amount.
+const CURRENCY: usize = 16;
+
+/// Offset of the recipient of a transfer within a record.
+const TO_CLIENT: usize = CURRENCY + Currency::MAX_LEN;
+
+/// Offset of the disputed amount within a record, followed by the reversed one
+/// and the shortfall.
+const DISPUTED: usize = TO_CLIENT + 2;
+
+/// Offset of the number of dispute cycles within a record.
+const CYCLES: usize = DISPUTED + 24;
+
+/// Offset of the flag whether there is a timestamp within a record, followed
+/// by the timestamp itself.
+const TIMESTAMP: usize = CYCLES + 4;
+
+/// Disk-backed [`TxnStore`] on top of a [`sled::Tree`], keyed by the transaction
+/// identifier, for the inputs whose transactions do not fit in memory.
+///
+/// The updates are buffered and written to the tree in batches, see
+/// [`SledTxnStore::batch_size`], and so a failure to write them gets reported
+/// by [`TxnStore::flush`], which the engine calls once done with the input.
+/// Note that the amounts are stored in whole minor units, and so with the
+/// `rust_decimal` feature, the fractions of a minor unit (if any) are rounded
+/// half to even, see [`Rounding::Bankers`].
+pub struct SledTxnStore {
+    tree: sled::Tree,
+    pending: HashMap<TxnID, TxnRecord>,
+    batch_size: usize,
+
+    /// First failure to read or write the tree, if any.
+    failed: Cell<Option<io::Error>>,
+}
+
+impl SledTxnStore {
+    /// Open (or create) the database at the `path` to store the transactions in.
+    pub fn open<P>(path: P) -> Result<Self, ProcessError>
+    where
+        P: AsRef<Path>,
+    {
+        let db = sled::open(path).map_err(io::Error::from)?;
+        Ok(Self::from_tree(
+            db.open_tree("txns").map_err(io::Error::from)?,
+        ))
+    }
+
+    /// Store the transactions in the given `tree`.
+    pub fn from_tree(tree: sled::Tree) -> Self {
+        SledTxnStore {
+            tree,
+            pending: HashMap::new(),
+            batch_size: BATCH_SIZE,
+            failed: Cell::new(None),
+        }
+    }
+
+    /// Number of the updates to buffer before writing them to the tree.
+    ///
+    /// Defaults to `4096`. Larger batches mean fewer writes, at the expense of
+    /// the memory needed to buffer them.
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    fn fail(&self, err: io::Error) {
+        let first = self.failed.take().unwrap_or(err);
+        self.failed.set(Some(first));
+    }
+
+    fn write_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let mut batch = sled::Batch::default();
+        for (tx, txn) in self.pending.drain() {
+            batch.insert(&tx.to_be_bytes(), &encode(&txn)[..]);
+        }
+        if let Err(err) = self.tree.apply_batch(batch) {
+            self.fail(err.into());
+        }
+    }
+
+    fn read(&self, tx: TxnID) -> Result<Option<TxnRecord>, io::Error> {
+        if let Some(txn) = self.pending.get(&tx) {
+            return Ok(Some(txn.clone()));
+        }
+        let value = self.tree.get(tx.to_be_bytes())?;
+        value.map(|value| decode(&value)).transpose()
+    }
+
+    /// Visit each of the transactions written to the tree so far.
+    fn scan(&self, mut visit: impl FnMut(TxnRecord)) {
+        for entry in self.tree.iter() {
+            match entry
+                .map_err(io::Error::from)
+                .and_then(|(_, value)| decode(&value))
+            {
+                Ok(txn) => visit(txn),
+                Err(err) => return self.fail(err),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SledTxnStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SledTxnStore")
+            .field("tree", &self.tree.name())
+            .field("pending", &self.pending.len())
+            .field("batch_size", &self.batch_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TxnStore for SledTxnStore {
+    fn get(&self, tx: TxnID) -> Result<Option<TxnRecord>, ProcessError> {
+        Ok(self.read(tx)?)
+    }
+
+    fn insert(&mut self, txn: TxnRecord) {
+        self.pending.insert(txn.tx, txn);
+        if self.pending.len() >= self.batch_size {
+            self.write_pending();
+        }
+    }
+
+    fn set_state(&mut self, tx: TxnID, state: TxnState) {
+        match self.read(tx) {
+            Ok(Some(mut txn)) => {
+                txn.state = state;
+                self.insert(txn);
+            }
+            Ok(None) => {}
+            Err(err) => self.fail(err),
+        }
+    }
+
+    fn update(&mut self, txn: TxnRecord) {
+        match self.read(txn.tx) {
+            Ok(Some(_)) => self.insert(txn),
+            Ok(None) => {}
+            Err(err) => self.fail(err),
+        }
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&TxnRecord) -> bool) {
+        self.write_pending();
+        let mut batch = sled::Batch::default();
+        self.scan(|txn| {
+            if !keep(&txn) {
+                batch.remove(&txn.tx.to_be_bytes());
+            }
+        });
+        if let Err(err) = self.tree.apply_batch(batch) {
+            self.fail(err.into());
+        }
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&TxnRecord)) {
+        self.pending.values().for_each(&mut *visit);
+        self.scan(|txn| {
+            if !self.pending.contains_key(&txn.tx) {
+                visit(&txn);
+            }
+        });
+    }
+
+    fn len(&self) -> usize {
+        let written = |tx: &TxnID| self.tree.contains_key(tx.to_be_bytes()).unwrap_or(false);
+        self.tree.len() + self.pending.keys().filter(|tx| !written(tx)).count()
+    }
+
+    fn flush(&mut self) -> Result<(), ProcessError> {
+        self.write_pending();
+        if let Err(err) = self.tree.flush() {
+            self.fail(err.into());
+        }
+        match self.failed.take() {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn encode(txn: &TxnRecord) -> [u8; RECORD_LEN] {
+    let mut bytes = [0; RECORD_LEN];
+    bytes[0] = VERSION;
+    bytes[1] = match txn.kind {
+        TxnRecordKind::Deposit => 0,
+        TxnRecordKind::Withdrawal => 1,
+        TxnRecordKind::Transfer => 2,
+    };
+    bytes[2..4].copy_from_slice(&txn.client.to_le_bytes());
+    bytes[4..8].copy_from_slice(&txn.tx.to_le_bytes());
+    bytes[8..CURRENCY].copy_from_slice(&units(txn.amount).to_le_bytes());
+    let code = txn.currency.as_str().as_bytes();
+    bytes[CURRENCY..CURRENCY + code.len()].copy_from_slice(code);
+    if let Some(client) = txn.to_client {
+        bytes[TO_CLIENT..TO_CLIENT + 2].copy_from_slice(&client.to_le_bytes());
+    }
+    bytes[DISPUTED..DISPUTED + 8].copy_from_slice(&units(txn.disputed).to_le_bytes());
+    bytes[DISPUTED + 8..DISPUTED + 16].copy_from_slice(&units(txn.reversed).to_le_bytes());
+    bytes[DISPUTED + 16..CYCLES].copy_from_slice(&units(txn.shortfall).to_le_bytes());
+    bytes[CYCLES..CYCLES + 4].copy_from_slice(&txn.cycles.to_le_bytes());
+    if let Some(timestamp) = txn.timestamp {
+        bytes[TIMESTAMP] = 1;
+        bytes[TIMESTAMP + 1..TIMESTAMP + 9].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    bytes[RECORD_LEN - 1] = match txn.state {
+        TxnState::Undisputed => 0,
+        TxnState::Disputed => 1,
+        TxnState::Reversed => 2,
+    };
+    bytes
+}
+
+/// The `amount` in whole minor units, see [`SledTxnStore`].
+fn units(amount: Amount) -> i64 {
+    amount.round_minor_units(Rounding::Bankers)
+}
+
+fn decode(bytes: &[u8]) -> Result<TxnRecord, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed transaction record");
+    if let Some(&version) = bytes.first()
+        && version != VERSION
+    {
+        let message = format!("transaction record of unsupported version {version}");
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let bytes: &[u8; RECORD_LEN] = bytes.try_into().map_err(|_| invalid())?;
+    let kind = match bytes[1] {
+        0 => TxnRecordKind::Deposit,
+        1 => TxnRecordKind::Withdrawal,
+        2 => TxnRecordKind::Transfer,
+        _ => return Err(invalid()),
+    };
+    let code = &bytes[CURRENCY..TO_CLIENT];
+    let len = code.iter().position(|b| *b == 0).unwrap_or(code.len());
+    let currency = std::str::from_utf8(&code[..len])
+        .ok()
+        .and_then(Currency::new)
+        .ok_or_else(invalid)?;
+    let state = match bytes[RECORD_LEN - 1] {
+        0 => TxnState::Undisputed,
+        1 => TxnState::Disputed,
+        2 => TxnState::Reversed,
+        _ => return Err(invalid()),
+    };
+    let amount = |offset: usize| {
+        Amount::from_minor_units(i64::from_le_bytes(
+            bytes[offset..offset + 8].try_into().expect("eight bytes"),
+        ))
+    };
+    Ok(TxnRecord {
+        kind,
+        client: u16::from_le_bytes([bytes[2], bytes[3]]),
+        tx: u32::from_le_bytes(bytes[4..8].try_into().expect("four bytes")),
+        amount: amount(8),
+        currency,
+        to_client: matches!(kind, TxnRecordKind::Transfer)
+            .then(|| u16::from_le_bytes([bytes[TO_CLIENT], bytes[TO_CLIENT + 1]])),
+        state,
+        disputed: amount(DISPUTED),
+        reversed: amount(DISPUTED + 8),
+        shortfall: amount(DISPUTED + 16),
+        cycles: u32::from_le_bytes(bytes[CYCLES..CYCLES + 4].try_into().expect("four bytes")),
+        timestamp: (bytes[TIMESTAMP] == 1).then(|| {
+            u64::from_le_bytes(
+                bytes[TIMESTAMP + 1..TIMESTAMP + 9]
+                    .try_into()
+                    .expect("eight bytes"),
+            )
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SledTxnStore;
+    use crate::domain::{Amount, Currency, TxnID, TxnRecord, TxnRecordKind, TxnState};
+    use crate::{PaymentEngine, ProcessError, ProcessOptions, TxnStore};
+
+    fn store() -> SledTxnStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledTxnStore::from_tree(db.open_tree("txns").unwrap())
+    }
+
+    fn txn(tx: TxnID, currency: &str) -> TxnRecord {
+        TxnRecord {
+            kind: TxnRecordKind::Withdrawal,
+            client: 7,
+            tx,
+            amount: Amount::try_from_f64(-1.5).unwrap(),
+            currency: Currency::new(currency).unwrap(),
+            to_client: None,
+            state: TxnState::Undisputed,
+            disputed: Amount::default(),
+            reversed: Amount::default(),
+            shortfall: Amount::default(),
+            cycles: 0,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn stores_transactions_in_batches() {
+        let mut store = store().batch_size(2);
+        store.insert(txn(1, "USD"));
+        assert_eq!(store.tree.len(), 0);
+        store.insert(txn(2, ""));
+        assert_eq!(store.tree.len(), 2);
+        store.insert(txn(3, "USDT"));
+        store.set_state(1, TxnState::Disputed);
+        assert_eq!(store.len(), 3);
+
+        let stored = store.get(1).unwrap().unwrap();
+        assert_eq!(stored.client, 7);
+        assert_eq!(stored.amount.minor_units(), -15000);
+        assert_eq!(stored.currency.as_str(), "USD");
+        assert_eq!(stored.state, TxnState::Disputed);
+        assert!(matches!(stored.kind, TxnRecordKind::Withdrawal));
+        assert_eq!(store.get(3).unwrap().unwrap().currency.as_str(), "USDT");
+        assert!(store.get(4).unwrap().is_none());
+
+        store.insert(TxnRecord {
+            kind: TxnRecordKind::Transfer,
+            to_client: Some(0),
+            ..txn(4, "")
+        });
+        assert_eq!(store.get(4).unwrap().unwrap().to_client, Some(0));
+        assert_eq!(store.get(3).unwrap().unwrap().to_client, None);
+
+        store.update(TxnRecord {
+            disputed: Amount::from_minor_units(-5000),
+            reversed: Amount::from_minor_units(-2500),
+            cycles: 3,
+            timestamp: Some(1_700_000_000),
+            ..txn(3, "USDT")
+        });
+        store.update(txn(5, ""));
+        let stored = store.get(3).unwrap().unwrap();
+        assert_eq!(stored.disputed.minor_units(), -5000);
+        assert_eq!(stored.reversed.minor_units(), -2500);
+        assert_eq!(stored.cycles, 3);
+        assert_eq!(stored.timestamp, Some(1_700_000_000));
+        assert_eq!(store.get(2).unwrap().unwrap().timestamp, None);
+        assert!(store.get(5).unwrap().is_none());
+
+        store.retain(&mut |txn| txn.state == TxnState::Disputed);
+        let mut txns = Vec::new();
+        store.for_each(&mut |txn| txns.push(txn.tx));
+        assert_eq!(txns, [1]);
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn reports_failure_to_read() {
+        let store = store();
+        store.tree.insert(1u32.to_be_bytes(), &[0; 3][..]).unwrap();
+        assert!(matches!(store.get(1), Err(ProcessError::Io(_))));
+        // nor is the record of another version
+        let mut bytes = super::encode(&txn(3, ""));
+        bytes[0] = super::VERSION + 1;
+        store.tree.insert(3u32.to_be_bytes(), &bytes[..]).unwrap();
+        let err = store.get(3).unwrap_err().to_string();
+        assert!(err.contains("unsupported version"), "{err}");
+        assert!(store.contains(1).is_err());
+        assert!(!store.contains(2).unwrap());
+    }
+
+    #[cfg(feature = "rust_decimal")]
+    #[test]
+    fn rounds_fractions_of_minor_unit() {
+        use rust_decimal::Decimal;
+
+        let mut store = store().batch_size(1);
+        let amount = |value| Amount::from_decimal(Decimal::new(value, 5)).unwrap();
+        store.insert(TxnRecord {
+            amount: amount(1_00015),
+            disputed: amount(-1_00025),
+            reversed: amount(1_00026),
+            ..txn(1, "")
+        });
+        let stored = store.get(1).unwrap().unwrap();
+        assert_eq!(stored.amount.minor_units(), 10002);
+        assert_eq!(stored.disputed.minor_units(), -10002);
+        assert_eq!(stored.reversed.minor_units(), 10003);
+    }
+
+    #[test]
+    fn processes_against_disk_store() {
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 1, 10.0\n\
+            deposit, 1, 1, 10.0\n\
+            dispute, 1, 1,\n";
+        let txns = store().batch_size(1);
+        let accounts = crate::MemoryAccountStore::default();
+        let mut engine = PaymentEngine::with_stores(ProcessOptions::new(), txns, accounts);
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.report().skipped.len(), 1);
+        assert_eq!(engine.account(1).unwrap().held, 10.);
+        assert!(engine.reconcile().is_ok());
+    }
+}
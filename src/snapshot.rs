@@ -0,0 +1,339 @@
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use crate::domain::{
+    Account, Amount, ClientID, Currency, DisputeRecordKind, TxnID, TxnRecord, TxnRecordKind,
+    TxnState, format_amount,
+};
+use crate::engine::{HistoryEntry, IdempotencyKey};
+use crate::{ProcessError, Rounding};
+
+/// Version of the snapshot format, bumped whenever the columns or their meaning
+/// change, for the snapshots of another version to be rejected up front.
+const VERSION: u32 = 3;
+
+const HEADER: [&str; 24] = [
+    "kind",
+    "client",
+    "currency",
+    "tx",
+    "type",
+    "amount",
+    "state",
+    "available",
+    "held",
+    "total",
+    "locked",
+    "closed",
+    "chargebacks",
+    "charged_back",
+    "gross_deposits",
+    "gross_withdrawals",
+    "to_client",
+    "fees",
+    "disputed",
+    "reversed",
+    "cycles",
+    "timestamp",
+    "shortfall",
+    "version",
+];
+
+/// Write the `accounts`, the `txns`, the operations `applied`, the `reserved`
+/// transaction identifiers and the `history` of the clients in the snapshot CSV
+/// format, with the amounts counted in minor units of the given number of `places`.
+///
+/// Each row is an account, a transaction, an applied or a reserved one, or an
+/// entry of a history, as per the `kind` column, with the columns that do not
+/// apply to the kind left empty. The first row tells the [`VERSION`] of the
+/// format instead.
+pub(crate) fn write<'a, W>(
+    writer: W,
+    accounts: impl Iterator<Item = &'a Account>,
+    txns: &[TxnRecord],
+    applied: &[IdempotencyKey],
+    reserved: &[TxnID],
+    history: &[(ClientID, HistoryEntry)],
+    places: u32,
+) -> Result<(), ProcessError>
+where
+    W: Write,
+{
+    let mut wrt = csv::Writer::from_writer(writer);
+    wrt.write_record(HEADER).map_err(ProcessError::Serialize)?;
+    let version = VERSION.to_string();
+    let mut fields = [""; HEADER.len()];
+    fields[0] = "version";
+    fields[HEADER.len() - 1] = &version;
+    wrt.write_record(fields).map_err(ProcessError::Serialize)?;
+    let amount = |amount| format_amount(amount, places);
+    for account in accounts {
+        wrt.write_record([
+            "account",
+            &account.client.to_string(),
+            account.currency.as_str(),
+            "",
+            "",
+            "",
+            "",
+            &amount(account.available),
+            &amount(account.held),
+            &amount(account.total),
+            &account.locked.to_string(),
+            &account.closed.to_string(),
+            &account.chargebacks.to_string(),
+            &amount(account.charged_back),
+            &amount(account.gross_deposits),
+            &amount(account.gross_withdrawals),
+            "",
+            &amount(account.fees),
+            "",
+            "",
+            "",
+            "",
+            &amount(account.shortfall),
+            "",
+        ])
+        .map_err(ProcessError::Serialize)?;
+    }
+    for txn in txns {
+        let kind = match txn.kind {
+            TxnRecordKind::Deposit => "deposit",
+            TxnRecordKind::Withdrawal => "withdrawal",
+            TxnRecordKind::Transfer => "transfer",
+        };
+        let state = match txn.state {
+            TxnState::Undisputed => "undisputed",
+            TxnState::Disputed => "disputed",
+            TxnState::Reversed => "reversed",
+        };
+        wrt.write_record([
+            "txn",
+            &txn.client.to_string(),
+            txn.currency.as_str(),
+            &txn.tx.to_string(),
+            kind,
+            &amount(txn.amount),
+            state,
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            &txn.to_client
+                .map(|client| client.to_string())
+                .unwrap_or_default(),
+            "",
+            &amount(txn.disputed),
+            &amount(txn.reversed),
+            &txn.cycles.to_string(),
+            &txn.timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            &amount(txn.shortfall),
+            "",
+        ])
+        .map_err(ProcessError::Serialize)?;
+    }
+    let keys = applied.iter().map(|key| match key {
+        IdempotencyKey::Txn(tx) => ("applied", *tx, ""),
+        IdempotencyKey::Dispute(tx, kind) => ("applied", *tx, dispute_kind(*kind)),
+    });
+    for (kind, tx, operation) in keys.chain(reserved.iter().map(|tx| ("reserved", *tx, ""))) {
+        let mut fields = [""; HEADER.len()];
+        let tx = tx.to_string();
+        fields[0] = kind;
+        fields[3] = &tx;
+        fields[4] = operation;
+        wrt.write_record(fields).map_err(ProcessError::Serialize)?;
+    }
+    for (client, entry) in history {
+        let mut fields = [""; HEADER.len()];
+        let (client, tx) = (client.to_string(), entry.tx().to_string());
+        let (charged_back, timestamp) = match entry {
+            HistoryEntry::Txn(_) => (String::new(), String::new()),
+            HistoryEntry::ChargeBack {
+                amount, timestamp, ..
+            } => (
+                format_amount(*amount, places),
+                timestamp
+                    .map(|timestamp| timestamp.to_string())
+                    .unwrap_or_default(),
+            ),
+        };
+        fields[0] = "history";
+        fields[1] = &client;
+        fields[3] = &tx;
+        if let HistoryEntry::ChargeBack { .. } = entry {
+            fields[4] = "chargeback";
+        }
+        fields[5] = &charged_back;
+        fields[21] = &timestamp;
+        wrt.write_record(fields).map_err(ProcessError::Serialize)?;
+    }
+    wrt.flush()?;
+    Ok(())
+}
+
+fn dispute_kind(kind: DisputeRecordKind) -> &'static str {
+    match kind {
+        DisputeRecordKind::Dispute => "dispute",
+        DisputeRecordKind::Resolve => "resolve",
+        DisputeRecordKind::ChargeBack => "chargeback",
+    }
+}
+
+/// Entry of a snapshot, see [`read`].
+pub(crate) enum Entry {
+    Account(Account),
+    Txn(TxnRecord),
+    Applied(IdempotencyKey),
+    Reserved(TxnID),
+
+    /// Entry of the history of the client, in the order applied.
+    History(ClientID, HistoryEntry),
+}
+
+/// Read the entries of a snapshot written with [`write`] at the same `places`.
+///
+/// The rows are counted from the one following the version.
+pub(crate) fn read<R>(reader: R, places: u32) -> Result<Vec<Entry>, ProcessError>
+where
+    R: Read,
+{
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    if rdr.headers()? != HEADER.as_slice() {
+        return Err(ProcessError::InvalidSnapshot { row: 0 });
+    }
+    let mut records = rdr.records();
+    let version = records.next().transpose()?;
+    if version.as_ref().is_none_or(|raw| {
+        raw.get(0) != Some("version") || raw.get(HEADER.len() - 1) != Some(&VERSION.to_string())
+    }) {
+        return Err(ProcessError::InvalidSnapshot { row: 0 });
+    }
+    let mut entries = Vec::new();
+    for (idx, raw) in records.enumerate() {
+        let raw = raw?;
+        let row = idx as u64 + 1;
+        let invalid = || ProcessError::InvalidSnapshot { row };
+        let field = |col: usize| raw.get(col).ok_or_else(invalid);
+        let amount = |col: usize| {
+            let text = field(col)?;
+            // a snapshot taken at a higher precision is not silently truncated
+            if Amount::exceeds_places(text, places) {
+                return Err(invalid());
+            }
+            Amount::parse_at(text, places, Rounding::Truncate).map_err(|err| match err {
+                ProcessError::InvalidAmount(source) => ProcessError::AmountRejected {
+                    row,
+                    line: None,
+                    kind: raw[0].to_string(),
+                    tx: None,
+                    amount: text.to_string(),
+                    source,
+                },
+                err => err,
+            })
+        };
+        let client = || parse(&raw, 1, row);
+        let currency = Currency::new(field(2)?).ok_or_else(invalid)?;
+        let entry = match field(0)? {
+            "account" => Entry::Account(Account {
+                available: amount(7)?,
+                held: amount(8)?,
+                total: amount(9)?,
+                locked: parse(&raw, 10, row)?,
+                closed: parse(&raw, 11, row)?,
+                chargebacks: parse(&raw, 12, row)?,
+                charged_back: amount(13)?,
+                gross_deposits: amount(14)?,
+                gross_withdrawals: amount(15)?,
+                fees: amount(17)?,
+                shortfall: amount(22)?,
+                ..Account::new(client()?, currency)
+            }),
+            "txn" => Entry::Txn(TxnRecord {
+                kind: match field(4)? {
+                    "deposit" => TxnRecordKind::Deposit,
+                    "withdrawal" => TxnRecordKind::Withdrawal,
+                    "transfer" => TxnRecordKind::Transfer,
+                    _ => return Err(invalid()),
+                },
+                client: client()?,
+                tx: parse(&raw, 3, row)?,
+                amount: amount(5)?,
+                currency,
+                to_client: match field(16)? {
+                    "" => None,
+                    _ => Some(parse(&raw, 16, row)?),
+                },
+                state: match field(6)? {
+                    "undisputed" => TxnState::Undisputed,
+                    "disputed" => TxnState::Disputed,
+                    "reversed" => TxnState::Reversed,
+                    _ => return Err(invalid()),
+                },
+                disputed: amount(18)?,
+                reversed: amount(19)?,
+                shortfall: amount(22)?,
+                cycles: parse(&raw, 20, row)?,
+                timestamp: match field(21)? {
+                    "" => None,
+                    _ => Some(parse(&raw, 21, row)?),
+                },
+            }),
+            "applied" => {
+                let tx = parse(&raw, 3, row)?;
+                let kind = match field(4)? {
+                    "" => None,
+                    "dispute" => Some(DisputeRecordKind::Dispute),
+                    "resolve" => Some(DisputeRecordKind::Resolve),
+                    "chargeback" => Some(DisputeRecordKind::ChargeBack),
+                    _ => return Err(invalid()),
+                };
+                Entry::Applied(match kind {
+                    Some(kind) => IdempotencyKey::Dispute(tx, kind),
+                    None => IdempotencyKey::Txn(tx),
+                })
+            }
+            "reserved" => Entry::Reserved(parse(&raw, 3, row)?),
+            "history" => {
+                let tx = parse(&raw, 3, row)?;
+                Entry::History(
+                    client()?,
+                    match field(4)? {
+                        "" => HistoryEntry::Txn(tx),
+                        "chargeback" => HistoryEntry::ChargeBack {
+                            tx,
+                            amount: amount(5)?,
+                            timestamp: match field(21)? {
+                                "" => None,
+                                _ => Some(parse(&raw, 21, row)?),
+                            },
+                        },
+                        _ => return Err(invalid()),
+                    },
+                )
+            }
+            _ => return Err(invalid()),
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse<T>(raw: &csv::StringRecord, col: usize, row: u64) -> Result<T, ProcessError>
+where
+    T: FromStr,
+{
+    raw.get(col)
+        .and_then(|field| field.parse().ok())
+        .ok_or(ProcessError::InvalidSnapshot { row })
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+use crate::domain::{
+    Amount, ClientID, Currency, Timestamp, TxnID, TxnRecord, TxnRecordKind, TxnState, format_amount,
+};
+use crate::engine::HistoryEntry;
+use crate::{ProcessError, ProcessOptions};
+
+/// Format of a [`Statement`], see [`Statement::write`].
+///
+/// The JSON format comes with the `json` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StatementFormat {
+    /// CSV with the headers, a line per entry.
+    #[default]
+    Csv,
+
+    /// A JSON object with the client, the time range, and the entries.
+    #[cfg(feature = "json")]
+    Json,
+
+    /// An entry per line, same as the statement is displayed.
+    Text,
+}
+
+/// Statement of a client's account, see [`PaymentEngine::statement`](crate::PaymentEngine::statement).
+///
+/// Displayed an entry per line, with the amounts at the precision of the engine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    /// Client the statement is of.
+    pub client: ClientID,
+
+    /// Earliest timestamp of the entries, if limited.
+    pub from: Option<Timestamp>,
+
+    /// Latest timestamp of the entries, if limited.
+    pub to: Option<Timestamp>,
+
+    /// Transactions of the client, and their chargebacks, in the order applied.
+    pub entries: Vec<StatementEntry>,
+
+    /// Number of places past the decimal point the amounts are counted in.
+    pub(crate) places: u32,
+
+    /// Field delimiter of the CSV format, same as of the output.
+    pub(crate) delimiter: u8,
+}
+
+/// Transaction on a [`Statement`], along with the balance it resulted in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StatementEntry {
+    pub tx: TxnID,
+    pub kind: TxnRecordKind,
+    pub timestamp: Option<Timestamp>,
+    pub currency: Currency,
+
+    /// Client on the other side of a transfer.
+    pub counterparty: Option<ClientID>,
+
+    /// Change to the client's funds, i.e. negative for a debit.
+    pub amount: Amount,
+
+    /// Running balance of the client's funds in the currency, as of this entry.
+    ///
+    /// It counts the transactions before the time range as well, but not the
+    /// fees, if any, see [`ProcessOptions::fees`](crate::ProcessOptions::fees).
+    pub balance: Amount,
+
+    /// Dispute state of the transaction, as of the statement.
+    pub state: TxnState,
+
+    /// Whether the entry is a chargeback of the transaction, timestamped with
+    /// the chargeback, rather than the transaction itself.
+    pub chargeback: bool,
+}
+
+impl StatementEntry {
+    /// Type of the entry as written out, i.e. of the transaction, unless it is
+    /// a chargeback.
+    fn label(&self) -> String {
+        if self.chargeback {
+            "chargeback".to_string()
+        } else {
+            self.kind.to_string()
+        }
+    }
+}
+
+/// Entry as written out, see [`Statement::write`].
+#[derive(Debug, Serialize)]
+struct Line {
+    tx: TxnID,
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: Option<Timestamp>,
+    currency: Currency,
+    counterparty: Option<ClientID>,
+    amount: String,
+    balance: String,
+    state: TxnState,
+}
+
+/// Statement as written out in JSON, see [`StatementFormat::Json`].
+#[cfg(feature = "json")]
+#[derive(Debug, Serialize)]
+struct Document {
+    client: ClientID,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    entries: Vec<Line>,
+}
+
+impl Statement {
+    /// Statement of the `client` with the entries of its history, along with
+    /// their transactions, those with a timestamp within `from` and `to` (both
+    /// inclusive) becoming the entries.
+    ///
+    /// The entries without a timestamp are only included if the time range is
+    /// not limited.
+    pub(crate) fn new(
+        client: ClientID,
+        from: Option<Timestamp>,
+        to: Option<Timestamp>,
+        history: impl Iterator<Item = (HistoryEntry, TxnRecord)>,
+        options: &ProcessOptions,
+    ) -> Self {
+        let mut balances: HashMap<Currency, Amount> = HashMap::new();
+        let mut entries = Vec::new();
+        for (entry, txn) in history {
+            let credited = match txn.kind {
+                TxnRecordKind::Deposit => true,
+                TxnRecordKind::Withdrawal => false,
+                TxnRecordKind::Transfer => txn.to_client == Some(client),
+            };
+            let (amount, timestamp, chargeback) = match entry {
+                HistoryEntry::Txn(_) => (txn.amount, txn.timestamp, false),
+                // a chargeback moves the funds the other way round
+                HistoryEntry::ChargeBack {
+                    amount, timestamp, ..
+                } => (amount, timestamp, true),
+            };
+            let amount = if credited != chargeback {
+                amount
+            } else {
+                -amount
+            };
+            let balance = balances.entry(txn.currency).or_default();
+            *balance = balance.saturating_add(amount);
+            let within = match timestamp {
+                Some(timestamp) => {
+                    from.is_none_or(|from| timestamp >= from) && to.is_none_or(|to| timestamp <= to)
+                }
+                None => from.is_none() && to.is_none(),
+            };
+            if !within {
+                continue;
+            }
+            let counterparty = match txn.to_client {
+                Some(to_client) if to_client == client => Some(txn.client),
+                to_client => to_client,
+            };
+            entries.push(StatementEntry {
+                tx: txn.tx,
+                kind: txn.kind,
+                timestamp,
+                currency: txn.currency,
+                counterparty,
+                amount,
+                balance: *balance,
+                state: txn.state,
+                chargeback,
+            });
+        }
+        Statement {
+            client,
+            from,
+            to,
+            entries,
+            places: options.precision,
+            delimiter: options.delimiter,
+        }
+    }
+
+    /// Write the statement out in the `format`, with the CSV fields separated
+    /// by the [`ProcessOptions::delimiter`].
+    pub fn write<W>(&self, mut writer: W, format: StatementFormat) -> Result<(), ProcessError>
+    where
+        W: Write,
+    {
+        match format {
+            StatementFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .delimiter(self.delimiter)
+                    .from_writer(writer);
+                let mut lines = self.lines().peekable();
+                if lines.peek().is_none() {
+                    // the headers are written along with the first line otherwise
+                    writer
+                        .write_record([
+                            "tx",
+                            "type",
+                            "timestamp",
+                            "currency",
+                            "counterparty",
+                            "amount",
+                            "balance",
+                            "state",
+                        ])
+                        .map_err(ProcessError::Serialize)?;
+                }
+                for line in lines {
+                    writer.serialize(line).map_err(ProcessError::Serialize)?;
+                }
+                writer.flush()?;
+            }
+            #[cfg(feature = "json")]
+            StatementFormat::Json => {
+                let document = Document {
+                    client: self.client,
+                    from: self.from,
+                    to: self.to,
+                    entries: self.lines().collect(),
+                };
+                serde_json::to_writer(&mut writer, &document).map_err(std::io::Error::from)?;
+                writeln!(writer)?;
+            }
+            StatementFormat::Text => write!(writer, "{self}")?,
+        }
+        Ok(())
+    }
+
+    fn lines(&self) -> impl Iterator<Item = Line> + '_ {
+        self.entries.iter().map(|entry| Line {
+            tx: entry.tx,
+            kind: entry.label(),
+            timestamp: entry.timestamp,
+            currency: entry.currency,
+            counterparty: entry.counterparty,
+            amount: format_amount(entry.amount, self.places),
+            balance: format_amount(entry.balance, self.places),
+            state: entry.state,
+        })
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "statement of client {}", self.client)?;
+        match (self.from, self.to) {
+            (None, None) => writeln!(f)?,
+            (from, to) => {
+                let bound = |timestamp: Option<Timestamp>| match timestamp {
+                    Some(timestamp) => timestamp.to_string(),
+                    None => "...".to_string(),
+                };
+                writeln!(f, ", timestamps {} to {}", bound(from), bound(to))?;
+            }
+        }
+        for entry in &self.entries {
+            write!(f, "tx {} {}", entry.tx, entry.label())?;
+            if let Some(timestamp) = entry.timestamp {
+                write!(f, " at {timestamp}")?;
+            }
+            if let Some(client) = entry.counterparty {
+                write!(f, " with client {client}")?;
+            }
+            let amount = format_amount(entry.amount, self.places);
+            let sign = if entry.amount.is_negative() { "" } else { "+" };
+            let balance = format_amount(entry.balance, self.places);
+            let currency = match entry.currency.is_implicit() {
+                true => String::new(),
+                false => format!(" {}", entry.currency),
+            };
+            writeln!(
+                f,
+                ": {sign}{amount}{currency}, balance {balance}{currency}, {}",
+                entry.state
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatementFormat;
+    use crate::{PaymentEngine, ProcessOptions};
+
+    #[test]
+    fn writes_statement_with_running_balance() {
+        let input = "type, client, tx, amount, timestamp, to_client\n\
+            deposit, 1, 1, 10.0, 100,\n\
+            deposit, 2, 2, 5.0, 110,\n\
+            transfer, 1, 3, 4.0, 120, 2\n\
+            withdrawal, 1, 4, 1.5, 130,\n\
+            dispute, 1, 1, , 140,\n\
+            deposit, 1, 5, 2.0, 150,\n";
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().history(true));
+        engine.process(input.as_bytes()).unwrap();
+        let write = |from, to, format| {
+            let mut written = Vec::new();
+            let statement = engine.statement(1, from, to).unwrap();
+            statement.write(&mut written, format).unwrap();
+            String::from_utf8(written).unwrap()
+        };
+
+        // the balance counts the transactions before the range as well
+        assert_eq!(
+            write(Some(120), Some(140), StatementFormat::Csv),
+            "tx,type,timestamp,currency,counterparty,amount,balance,state\n\
+             3,transfer,120,,2,-4.0000,6.0000,undisputed\n\
+             4,withdrawal,130,,,-1.5000,4.5000,undisputed\n"
+        );
+        assert_eq!(
+            write(None, None, StatementFormat::Text),
+            "statement of client 1\n\
+             tx 1 deposit at 100: +10.0000, balance 10.0000, disputed\n\
+             tx 3 transfer at 120 with client 2: -4.0000, balance 6.0000, undisputed\n\
+             tx 4 withdrawal at 130: -1.5000, balance 4.5000, undisputed\n\
+             tx 5 deposit at 150: +2.0000, balance 6.5000, undisputed\n"
+        );
+        #[cfg(feature = "json")]
+        assert_eq!(
+            write(Some(150), None, StatementFormat::Json),
+            r#"{"client":1,"from":150,"to":null,"entries":[{"tx":5,"type":"deposit","timestamp":150,"currency":"","counterparty":null,"amount":"2.0000","balance":"6.5000","state":"undisputed"}]}"#
+                .to_string()
+                + "\n"
+        );
+
+        // the recipient of a transfer is credited with it
+        let statement = engine.statement(2, None, None).unwrap();
+        let balances: Vec<_> = statement
+            .entries
+            .iter()
+            .map(|entry| entry.balance.as_f64())
+            .collect();
+        assert_eq!(balances, [5.0, 9.0]);
+        assert_eq!(
+            write(Some(200), None, StatementFormat::Csv),
+            "tx,type,timestamp,currency,counterparty,amount,balance,state\n"
+        );
+    }
+
+    #[test]
+    fn lists_chargebacks_at_their_own_time() {
+        let input = "type, client, tx, amount, timestamp, to_client\n\
+            deposit, 1, 1, 10.0, 100,\n\
+            deposit, 2, 2, 5.0, 110,\n\
+            transfer, 2, 3, 4.0, 120, 1\n\
+            dispute, 1, 1, , 150,\n\
+            dispute, 2, 3, , 160,\n\
+            chargeback, 2, 3, , 170,\n\
+            chargeback, 1, 1, , 200,\n";
+        let mut engine = PaymentEngine::with_options(ProcessOptions::new().history(true));
+        engine.process(input.as_bytes()).unwrap();
+        let write = |engine: &PaymentEngine, client, from| {
+            let mut written = Vec::new();
+            let statement = engine.statement(client, from, None).unwrap();
+            statement
+                .write(&mut written, StatementFormat::Text)
+                .unwrap();
+            String::from_utf8(written).unwrap()
+        };
+
+        // the transactions keep their amounts, and so the balances before the
+        // chargebacks are the ones at the time
+        let statement = "statement of client 1\n\
+            tx 1 deposit at 100: +10.0000, balance 10.0000, reversed\n\
+            tx 3 transfer at 120 with client 2: +4.0000, balance 14.0000, reversed\n\
+            tx 3 chargeback at 170 with client 2: -4.0000, balance 10.0000, reversed\n\
+            tx 1 chargeback at 200: -10.0000, balance 0.0000, reversed\n";
+        assert_eq!(write(&engine, 1, None), statement);
+        assert_eq!(
+            write(&engine, 2, None),
+            "statement of client 2\n\
+             tx 2 deposit at 110: +5.0000, balance 5.0000, undisputed\n\
+             tx 3 transfer at 120 with client 1: -4.0000, balance 1.0000, reversed\n\
+             tx 3 chargeback at 170 with client 1: +4.0000, balance 5.0000, reversed\n"
+        );
+        assert_eq!(
+            write(&engine, 1, Some(200)),
+            "statement of client 1, timestamps 200 to ...\n\
+             tx 1 chargeback at 200: -10.0000, balance 0.0000, reversed\n"
+        );
+
+        // and are carried over to a restored engine
+        let mut snapshot = Vec::new();
+        engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = PaymentEngine::with_options(ProcessOptions::new().history(true));
+        restored.restore(snapshot.as_slice()).unwrap();
+        assert_eq!(write(&restored, 1, None), statement);
+    }
+}
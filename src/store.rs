@@ -0,0 +1,363 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::domain::{Account, ClientID, Currency, TxnID, TxnRecord, TxnRecordKind, TxnState};
+use crate::{ProcessError, Retention};
+
+/// Storage of the transactions that the dispute resolution records may reference.
+///
+/// The engine hands each applied deposit and withdrawal over to the store,
+/// which decides whether (and for how long) to retain it. A transaction that
+/// has not been retained can neither be disputed, nor recognized as a duplicate.
+pub trait TxnStore: std::fmt::Debug + Send {
+    /// The transaction `tx`, if retained, or the failure to read it.
+    fn get(&self, tx: TxnID) -> Result<Option<TxnRecord>, ProcessError>;
+
+    /// Whether the transaction `tx` is retained.
+    fn contains(&self, tx: TxnID) -> Result<bool, ProcessError> {
+        Ok(self.get(tx)?.is_some())
+    }
+
+    /// Take in the transaction, possibly evicting the other ones.
+    fn insert(&mut self, txn: TxnRecord);
+
+    /// Update the state of the transaction `tx`, if retained.
+    fn set_state(&mut self, tx: TxnID, state: TxnState);
+
+    /// Replace the transaction of the same identifier, if retained.
+    ///
+    /// Defaults to [`TxnStore::insert`]ing the transaction, as long as it replaces
+    /// the one of the same identifier, unless [`TxnStore::get`] tells it is not
+    /// retained. The transaction is inserted if the store fails to tell, for the
+    /// update not to be lost, while the failure is expected to be reported again.
+    fn update(&mut self, txn: TxnRecord) {
+        if !matches!(self.get(txn.tx), Ok(None)) {
+            self.insert(txn);
+        }
+    }
+
+    /// Drop the transactions the `keep` callback returns `false` for.
+    fn retain(&mut self, keep: &mut dyn FnMut(&TxnRecord) -> bool);
+
+    /// Visit each of the retained transactions, in no particular order.
+    fn for_each(&self, visit: &mut dyn FnMut(&TxnRecord));
+
+    /// Number of the retained transactions.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write out the buffered updates (if any), reporting the failures to
+    /// update the store so far.
+    ///
+    /// The engine calls this once done with the input, see [`PaymentEngine::process`](crate::PaymentEngine::process).
+    fn flush(&mut self) -> Result<(), ProcessError> {
+        Ok(())
+    }
+}
+
+/// In-memory [`TxnStore`] applying the [`Retention`] policy.
+#[derive(Debug, Default)]
+pub struct MemoryTxnStore {
+    retention: Retention,
+    txns: HashMap<TxnID, TxnRecord>,
+
+    /// Retained transactions of each client, oldest first, see [`Retention::LastPerClient`].
+    recent: HashMap<ClientID, VecDeque<TxnID>>,
+}
+
+impl MemoryTxnStore {
+    pub fn new(retention: Retention) -> Self {
+        MemoryTxnStore {
+            retention,
+            ..Default::default()
+        }
+    }
+}
+
+impl TxnStore for MemoryTxnStore {
+    fn get(&self, tx: TxnID) -> Result<Option<TxnRecord>, ProcessError> {
+        Ok(self.txns.get(&tx).cloned())
+    }
+
+    fn contains(&self, tx: TxnID) -> Result<bool, ProcessError> {
+        Ok(self.txns.contains_key(&tx))
+    }
+
+    fn insert(&mut self, txn: TxnRecord) {
+        if self.retention == Retention::DepositsOnly
+            && matches!(txn.kind, TxnRecordKind::Withdrawal)
+        {
+            return;
+        }
+        let client = txn.client;
+        let tx = txn.tx;
+        self.txns.insert(tx, txn);
+        let Retention::LastPerClient(limit) = self.retention else {
+            return;
+        };
+        let recent = self.recent.entry(client).or_default();
+        recent.push_back(tx);
+        if recent.len() > limit {
+            // the oldest of the transactions not under dispute goes first, for
+            // the funds held for the others to be released or charged back
+            // eventually, while the limit holds either way
+            let txns = &self.txns;
+            let idx = recent
+                .range(..recent.len() - 1)
+                .position(|tx| {
+                    txns.get(tx)
+                        .is_none_or(|txn| txn.state != TxnState::Disputed)
+                })
+                .unwrap_or(0);
+            if let Some(evicted) = recent.remove(idx) {
+                self.txns.remove(&evicted);
+            }
+        }
+    }
+
+    fn set_state(&mut self, tx: TxnID, state: TxnState) {
+        if let Some(txn) = self.txns.get_mut(&tx) {
+            txn.state = state;
+        }
+    }
+
+    fn update(&mut self, txn: TxnRecord) {
+        if let Some(retained) = self.txns.get_mut(&txn.tx) {
+            *retained = txn;
+        }
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&TxnRecord) -> bool) {
+        self.txns.retain(|_, txn| keep(txn));
+        let txns = &self.txns;
+        self.recent.retain(|_, recent| {
+            recent.retain(|tx| txns.contains_key(tx));
+            !recent.is_empty()
+        });
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&TxnRecord)) {
+        self.txns.values().for_each(visit);
+    }
+
+    fn len(&self) -> usize {
+        self.txns.len()
+    }
+}
+
+/// Storage of the clients' accounts, one per client and currency.
+///
+/// Unlike the transactions, the number of accounts is bounded by the number of
+/// clients, and so the engine expects to access them in place.
+pub trait AccountStore: std::fmt::Debug + Send {
+    /// The `client`'s account in the `currency`, if any.
+    fn get(&self, client: ClientID, currency: Currency) -> Option<&Account>;
+
+    /// Same as [`AccountStore::get`], but for the account to be updated.
+    fn get_mut(&mut self, client: ClientID, currency: Currency) -> Option<&mut Account>;
+
+    /// Store the account, replacing the one of the same client and currency (if any).
+    fn insert(&mut self, account: Account);
+
+    /// Remove the `client`'s account in the `currency`, returning it (if any).
+    fn remove(&mut self, client: ClientID, currency: Currency) -> Option<Account>;
+
+    /// Accounts in the store, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = &Account> + '_>;
+
+    /// Same as [`AccountStore::iter`], but for the accounts to be updated.
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut Account> + '_>;
+
+    /// Remove all the accounts, returning them in no particular order.
+    fn drain(&mut self) -> Vec<Account>;
+
+    /// Number of the accounts in the store.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// In-memory [`AccountStore`].
+#[derive(Debug, Default)]
+pub struct MemoryAccountStore {
+    accounts: HashMap<(ClientID, Currency), Account>,
+}
+
+impl AccountStore for MemoryAccountStore {
+    fn get(&self, client: ClientID, currency: Currency) -> Option<&Account> {
+        self.accounts.get(&(client, currency))
+    }
+
+    fn get_mut(&mut self, client: ClientID, currency: Currency) -> Option<&mut Account> {
+        self.accounts.get_mut(&(client, currency))
+    }
+
+    fn insert(&mut self, account: Account) {
+        self.accounts
+            .insert((account.client, account.currency), account);
+    }
+
+    fn remove(&mut self, client: ClientID, currency: Currency) -> Option<Account> {
+        self.accounts.remove(&(client, currency))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+        Box::new(self.accounts.values())
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut Account> + '_> {
+        Box::new(self.accounts.values_mut())
+    }
+
+    fn drain(&mut self) -> Vec<Account> {
+        self.accounts.drain().map(|(_, account)| account).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.accounts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryTxnStore, TxnStore};
+    use crate::Retention;
+    use crate::domain::{Amount, Currency, TxnID, TxnRecord, TxnRecordKind, TxnState};
+
+    fn txn(kind: TxnRecordKind, tx: TxnID) -> TxnRecord {
+        TxnRecord {
+            kind,
+            client: 1,
+            tx,
+            amount: Amount::try_from_f64(1.).unwrap(),
+            currency: Currency::default(),
+            to_client: None,
+            state: TxnState::Undisputed,
+            disputed: Amount::default(),
+            reversed: Amount::default(),
+            shortfall: Amount::default(),
+            cycles: 0,
+            timestamp: None,
+        }
+    }
+
+    fn retained(store: &MemoryTxnStore) -> Vec<TxnID> {
+        let mut txns = Vec::new();
+        store.for_each(&mut |txn| txns.push(txn.tx));
+        txns.sort_unstable();
+        txns
+    }
+
+    #[test]
+    fn retains_per_policy() {
+        let mut store = MemoryTxnStore::new(Retention::All);
+        store.insert(txn(TxnRecordKind::Deposit, 1));
+        store.insert(txn(TxnRecordKind::Withdrawal, 2));
+        assert_eq!(retained(&store), [1, 2]);
+
+        let mut store = MemoryTxnStore::new(Retention::DepositsOnly);
+        store.insert(txn(TxnRecordKind::Deposit, 1));
+        store.insert(txn(TxnRecordKind::Withdrawal, 2));
+        assert_eq!(retained(&store), [1]);
+        assert!(!store.contains(2).unwrap());
+
+        let mut store = MemoryTxnStore::new(Retention::LastPerClient(2));
+        for tx in 1..=3 {
+            store.insert(txn(TxnRecordKind::Deposit, tx));
+        }
+        assert_eq!(retained(&store), [2, 3]);
+        // the one under dispute outlives the older ones
+        store.set_state(2, TxnState::Disputed);
+        store.insert(txn(TxnRecordKind::Deposit, 4));
+        assert_eq!(retained(&store), [2, 4]);
+        assert_eq!(store.get(2).unwrap().unwrap().state, TxnState::Disputed);
+        store.update(TxnRecord {
+            disputed: Amount::try_from_f64(0.5).unwrap(),
+            ..txn(TxnRecordKind::Deposit, 4)
+        });
+        store.update(txn(TxnRecordKind::Deposit, 3));
+        assert_eq!(store.get(4).unwrap().unwrap().disputed, 0.5);
+        assert!(!store.contains(3).unwrap());
+        // but not the limit
+        store.set_state(4, TxnState::Disputed);
+        store.insert(txn(TxnRecordKind::Deposit, 5));
+        assert_eq!(retained(&store), [4, 5]);
+        // and is gone once dropped explicitly
+        store.retain(&mut |txn| txn.state != TxnState::Disputed);
+        assert_eq!(retained(&store), [5]);
+        assert_eq!(store.len(), 1);
+
+        let mut store = MemoryTxnStore::new(Retention::LastPerClient(0));
+        store.insert(txn(TxnRecordKind::Deposit, 1));
+        assert!(store.is_empty());
+    }
+
+    /// Store implementing only the required methods, as the ones written before
+    /// the others were added to the trait.
+    #[derive(Debug, Default)]
+    struct MapTxnStore(std::collections::HashMap<TxnID, TxnRecord>);
+
+    impl TxnStore for MapTxnStore {
+        fn get(&self, tx: TxnID) -> Result<Option<TxnRecord>, crate::ProcessError> {
+            Ok(self.0.get(&tx).cloned())
+        }
+
+        fn insert(&mut self, txn: TxnRecord) {
+            self.0.insert(txn.tx, txn);
+        }
+
+        fn set_state(&mut self, tx: TxnID, state: TxnState) {
+            if let Some(txn) = self.0.get_mut(&tx) {
+                txn.state = state;
+            }
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(&TxnRecord) -> bool) {
+            self.0.retain(|_, txn| keep(txn));
+        }
+
+        fn for_each(&self, visit: &mut dyn FnMut(&TxnRecord)) {
+            self.0.values().for_each(visit);
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn updates_by_default() {
+        let mut store = MapTxnStore::default();
+        store.insert(txn(TxnRecordKind::Deposit, 1));
+        store.update(TxnRecord {
+            cycles: 1,
+            ..txn(TxnRecordKind::Deposit, 1)
+        });
+        store.update(txn(TxnRecordKind::Deposit, 2));
+        assert_eq!(store.get(1).unwrap().unwrap().cycles, 1);
+        assert!(!store.contains(2).unwrap());
+    }
+
+    #[test]
+    fn bounds_retained_transactions() {
+        let mut store = MemoryTxnStore::new(Retention::LastPerClient(3));
+        for tx in 1..=1000 {
+            store.insert(txn(TxnRecordKind::Deposit, tx));
+            // every other one is disputed, half of which are resolved
+            if tx % 2 == 0 {
+                store.set_state(tx, TxnState::Disputed);
+            }
+            if tx % 4 == 0 {
+                store.set_state(tx, TxnState::Undisputed);
+            }
+            assert!(store.len() <= 3, "{tx}");
+            assert!(store.recent.values().all(|recent| recent.len() <= 3));
+        }
+        // the disputed ones outlive the others, as long as there is room for them
+        assert_eq!(retained(&store), [994, 998, 1000]);
+    }
+}
@@ -0,0 +1,251 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::domain::{
+    AccountRecordKind, DisputeRecordKind, Record, RecordInner, TxnRecordKind, format_amount,
+};
+use crate::{FsyncPolicy, ProcessError};
+
+pub(crate) const HEADER: [&str; 6] = ["type", "client", "tx", "amount", "currency", "to_client"];
+
+/// Write-ahead log of the records fed to the engine, see
+/// [`PaymentEngine::open_with_wal`](crate::PaymentEngine::open_with_wal).
+///
+/// The log is in the input CSV format, and so the recovery is a matter of
+/// processing it as if it was an input.
+#[derive(Debug)]
+pub(crate) struct Wal {
+    writer: csv::Writer<File>,
+    fsync: FsyncPolicy,
+    places: u32,
+
+    /// Records appended since the last sync.
+    unsynced: usize,
+}
+
+impl Wal {
+    /// Open the log at the `path` for appending, creating it if needed.
+    pub(crate) fn open<P>(path: P, fsync: FsyncPolicy, places: u32) -> Result<Self, ProcessError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(file);
+        if empty {
+            writer
+                .write_record(HEADER)
+                .map_err(ProcessError::Serialize)?;
+        }
+        let mut wal = Wal {
+            writer,
+            fsync,
+            places,
+            unsynced: 0,
+        };
+        wal.sync()?;
+        Ok(wal)
+    }
+
+    /// Fields of the `record` to be appended, see [`Wal::append`].
+    pub(crate) fn fields(&self, record: &Record) -> [String; HEADER.len()] {
+        fields(record, self.places)
+    }
+
+    /// Append the record of the `fields`, syncing the log to the disk as per
+    /// the policy.
+    pub(crate) fn append(&mut self, fields: [String; HEADER.len()]) -> Result<(), ProcessError> {
+        self.writer
+            .write_record(fields)
+            .map_err(ProcessError::Serialize)?;
+        // the record reaches the OS right away, so that it survives a crash
+        // of the process, while surviving a crash of the system is up to the
+        // policy, since syncing is costly
+        self.writer.flush()?;
+        self.unsynced += 1;
+        match self.fsync {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Every(records) if self.unsynced >= records => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write out the appended records and sync the log to the disk, whatever
+    /// the policy.
+    pub(crate) fn sync_data(&mut self) -> Result<(), ProcessError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Write out the appended records and sync the log to the disk.
+    pub(crate) fn sync(&mut self) -> Result<(), ProcessError> {
+        self.writer.flush()?;
+        if self.fsync != FsyncPolicy::Never {
+            self.writer.get_ref().sync_data()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Fields of the `record` in the order of the log's columns, with the amount
+/// counted in minor units of the given number of `places`.
+pub(crate) fn fields(record: &Record, places: u32) -> [String; HEADER.len()] {
+    let client = record.client().to_string();
+    let tx = record.tx().map(|tx| tx.to_string()).unwrap_or_default();
+    let mut to_client = String::new();
+    let (kind, amount, currency) = match &record.inner {
+        RecordInner::TxnRecord(r) => {
+            let kind = match r.kind {
+                TxnRecordKind::Deposit => "deposit",
+                TxnRecordKind::Withdrawal => "withdrawal",
+                TxnRecordKind::Transfer => "transfer",
+            };
+            if let Some(client) = r.to_client {
+                to_client = client.to_string();
+            }
+            (kind, format_amount(r.amount, places), r.currency)
+        }
+        RecordInner::DisputeRecord(r) => {
+            let kind = match r.kind {
+                DisputeRecordKind::Dispute => "dispute",
+                DisputeRecordKind::Resolve => "resolve",
+                DisputeRecordKind::ChargeBack => "chargeback",
+            };
+            let amount = r
+                .amount
+                .map(|amount| format_amount(amount, places))
+                .unwrap_or_default();
+            (kind, amount, r.currency)
+        }
+        RecordInner::AccountRecord(r) => match r.kind {
+            AccountRecordKind::Close => ("close", String::new(), r.currency),
+            AccountRecordKind::Unlock => ("unlock", String::new(), r.currency),
+        },
+    };
+    [
+        kind.to_string(),
+        client,
+        tx,
+        amount,
+        currency.as_str().to_string(),
+        to_client,
+    ]
+}
+
+/// Drop the last record of the log at the `path` if it has only been written
+/// partially, e.g. due to a crash, for it not to be replayed as a valid one.
+pub(crate) fn truncate_torn_tail(path: &Path) -> Result<(), ProcessError> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut end = file.metadata()?.len();
+    let mut chunk = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(idx) = chunk.iter().rposition(|b| *b == b'\n') {
+            end = start + idx as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if end < file.metadata()?.len() {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::{FsyncPolicy, PaymentEngine, ProcessOptions};
+
+    fn log_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn recovers_from_log() {
+        let path = log_path("recovers.wal");
+        let options = ProcessOptions::new().fsync(FsyncPolicy::Every(2));
+        let mut engine = PaymentEngine::open_with_wal(&path, options.clone()).unwrap();
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 1, 10.12345\n\
+            deposit, 1, 2, 5.0\n\
+            withdrawal, 1, 3, 100.0\n\
+            dispute, 1, 2,\n";
+        engine.process(input.as_bytes()).unwrap();
+        drop(engine);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            log,
+            "type,client,tx,amount,currency,to_client\n\
+             deposit,1,1,10.1234,,\n\
+             deposit,1,2,5.0000,,\n\
+             withdrawal,1,3,100.0000,,\n\
+             dispute,1,2,,,\n"
+        );
+
+        // a record torn by a crash is dropped
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"deposit,1,4,1").unwrap();
+        drop(file);
+
+        let mut engine = PaymentEngine::open_with_wal(&path, options).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, 10.1234);
+        assert_eq!(account.held, 5.);
+        // the replayed records have been reported on already
+        assert_eq!(engine.report().records, 0);
+        assert!(engine.report().skipped.is_empty());
+        // and the further records are appended
+        engine
+            .process("type, client, tx\nresolve, 1, 2".as_bytes())
+            .unwrap();
+        drop(engine);
+        let engine = PaymentEngine::open_with_wal(&path, ProcessOptions::new()).unwrap();
+        assert_eq!(engine.account(1).unwrap().available, 15.1234);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn logs_accepted_records_only() {
+        let path = log_path("accepted.wal");
+        let options = ProcessOptions::new().strict(true).max_records(Some(2));
+        let mut engine = PaymentEngine::open_with_wal(&path, options.clone()).unwrap();
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 1, 10.0\n\
+            withdrawal, 2, 2, 1.0\n";
+        assert!(engine.process(input.as_bytes()).is_err());
+        drop(engine);
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            log,
+            "type,client,tx,amount,currency,to_client\n\
+             deposit,1,1,10.0000,,\n"
+        );
+
+        // the replayed record does not count against the limit
+        let mut engine = PaymentEngine::open_with_wal(&path, options).unwrap();
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 3, 1.0\n\
+            deposit, 1, 4, 1.0\n";
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.report().records, 2);
+        assert_eq!(engine.account(1).unwrap().available, 12.);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
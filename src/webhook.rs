@@ -0,0 +1,418 @@
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::domain::{Account, Amount, ClientID, Currency, TxnID};
+use crate::{EventObserver, TxnEvent};
+
+/// Number of failed attempts after which a notification is reported as failing,
+/// and the delay stops growing, by default.
+const ATTEMPTS: u32 = 5;
+
+/// Wait before sending a notification again, doubled after each attempt, by default.
+const BACKOFF: Duration = Duration::from_millis(500);
+
+/// Time an attempt to send a notification may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of notifications pending, past which the engine waits for room.
+const QUEUE: usize = 1024;
+
+/// Time the pending notifications are still sent for once the webhook is dropped.
+const DRAIN: Duration = Duration::from_secs(30);
+
+/// Notifier of the accounts locked by charge backs, e.g. for the risk team to
+/// be paged on them right away, meant to be added to the engine with
+/// [`PaymentEngine::add_observer`](crate::PaymentEngine::add_observer).
+///
+/// Each account a charge back locks gets a JSON object `POST`ed to the URL, e.g.
+/// `{"event":"chargeback","client":1,"currency":"","tx":5,"amount":"10.0000",
+/// "available":"0.0000","held":"0.0000","total":"0.0000","locked":true}`, where
+/// the `amount` is the part of the transaction charged back, if given by the
+/// record. Of a transfer charged back, it is the sender's account that gets
+/// locked, and so reported.
+///
+/// The notifications are sent from a thread of their own, so as not to hold up
+/// the processing (unless over a thousand of them are pending), and sent again
+/// (after a growing delay) until the response is a success, while the others
+/// are sent in the meantime. Each attempt times out after 10 seconds, and the
+/// notifications still pending once the webhook is dropped are sent for up to
+/// 30 seconds longer.
+///
+/// The clones share the thread, which is waited for once all of them are dropped.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    delivery: Arc<Delivery>,
+
+    /// Row and client of the last charge back, for the account it locks to be
+    /// told apart from the one locked otherwise.
+    chargeback: Option<(u64, ClientID)>,
+
+    /// Number of places past the decimal point the amounts are counted in.
+    places: u32,
+}
+
+/// Thread sending the notifications of a [`Webhook`] and its clones.
+#[derive(Debug)]
+struct Delivery {
+    notifications: Option<SyncSender<Notification>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Payload of a [`Webhook`] notification.
+#[derive(Debug, Serialize)]
+struct Notification {
+    event: &'static str,
+    client: ClientID,
+    currency: Currency,
+    tx: Option<TxnID>,
+    amount: Option<String>,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+/// How a notification is sent again, see [`Webhook::with_retries`].
+#[derive(Debug, Clone, Copy)]
+struct Retries {
+    attempts: u32,
+    backoff: Duration,
+}
+
+/// Notification waiting to be sent, again if it has been attempted already.
+struct Pending {
+    client: ClientID,
+    body: String,
+    attempts: u32,
+    delay: Duration,
+    due: Instant,
+}
+
+impl Webhook {
+    /// Start notifying the `url` of the charge backs, with the default retries.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_retries(url, ATTEMPTS, BACKOFF)
+    }
+
+    /// Start notifying the `url` of the charge backs, waiting `backoff` after
+    /// the first failure to send a notification, and twice as long after each
+    /// one that follows, up to `attempts` of them, after which the notification
+    /// is reported as failing, and sent again at the longest delay.
+    pub fn with_retries(url: impl Into<String>, attempts: u32, backoff: Duration) -> Self {
+        let url = url.into();
+        let retries = Retries {
+            attempts: attempts.max(1),
+            backoff,
+        };
+        let (notifications, received) = mpsc::sync_channel(QUEUE);
+        let thread = std::thread::spawn(move || deliver(&url, received, retries));
+        Webhook {
+            delivery: Arc::new(Delivery {
+                notifications: Some(notifications),
+                thread: Some(thread),
+            }),
+            chargeback: None,
+            places: Amount::PRECISION,
+        }
+    }
+
+    /// Write the amounts out counted in minor units of the given number of
+    /// `places`, same as the engine, see [`ProcessOptions::precision`](crate::ProcessOptions::precision).
+    ///
+    /// Defaults to [`Amount::PRECISION`], while the `places` beyond
+    /// [`Amount::MAX_PRECISION`] are taken as the latter.
+    pub fn precision(mut self, places: u32) -> Self {
+        self.places = places.min(Amount::MAX_PRECISION);
+        self
+    }
+}
+
+impl EventObserver for Webhook {
+    fn on_chargeback(&mut self, event: &TxnEvent, account: &Account) {
+        self.chargeback = Some((event.row, account.client));
+    }
+
+    fn on_account_locked(&mut self, event: &TxnEvent, account: &Account) {
+        // both the accounts of a transfer are charged back, while only the
+        // sender's one may be locked
+        if self.chargeback.take() != Some((event.row, account.client)) {
+            return;
+        }
+        let amount = |amount: Amount| amount.format_at(self.places);
+        let notification = Notification {
+            event: "chargeback",
+            client: account.client,
+            currency: account.currency,
+            tx: event.tx,
+            amount: event.amount.map(amount),
+            available: amount(account.available),
+            held: amount(account.held),
+            total: amount(account.total),
+            locked: account.locked,
+        };
+        if let Some(notifications) = &self.delivery.notifications {
+            // the thread only stops once the senders are dropped
+            let _ = notifications.send(notification);
+        }
+    }
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        // the thread sends the pending notifications once there are no senders left
+        self.notifications = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Send the notifications `received` to the `url` until there are no senders
+/// left, and then the pending ones until they are sent or [`DRAIN`] elapses.
+fn deliver(url: &str, received: Receiver<Notification>, retries: Retries) {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .into();
+    let mut pending: Vec<Pending> = Vec::new();
+    // once the senders are gone, the pending ones are sent until then
+    let mut deadline = None;
+    loop {
+        let due = pending.iter().map(|pending| pending.due).min();
+        if deadline.is_none() && pending.len() < QUEUE {
+            // wait for another notification, or the next one to be sent again
+            let wait = due.map_or(Duration::MAX, |due| {
+                due.saturating_duration_since(Instant::now())
+            });
+            match received.recv_timeout(wait) {
+                Ok(notification) => pending.push(Pending::new(&notification, retries)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => deadline = Some(Instant::now() + DRAIN),
+            }
+        } else if let Some(due) = due {
+            let until = deadline.map_or(due, |deadline: Instant| deadline.min(due));
+            std::thread::sleep(until.saturating_duration_since(Instant::now()));
+        }
+        if deadline.is_some() && pending.is_empty() {
+            return;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            for _pending in pending {
+                emit!(
+                    error,
+                    client = _pending.client,
+                    attempts = _pending.attempts,
+                    "chargeback notification dropped"
+                );
+            }
+            return;
+        }
+        let now = Instant::now();
+        pending.retain_mut(|pending| pending.due > now || !pending.send(&agent, url, retries));
+    }
+}
+
+impl Pending {
+    fn new(notification: &Notification, retries: Retries) -> Self {
+        Pending {
+            client: notification.client,
+            body: serde_json::to_string(notification).expect("notification encoded as JSON"),
+            attempts: 0,
+            delay: retries.backoff,
+            due: Instant::now(),
+        }
+    }
+
+    /// Make an attempt to send the notification to the `url`, telling whether
+    /// it succeeded, or else when to make the next one.
+    fn send(&mut self, agent: &ureq::Agent, url: &str, retries: Retries) -> bool {
+        let result = agent
+            .post(url)
+            .header("Content-Type", "application/json")
+            .send(&self.body);
+        let Err(_err) = result else {
+            return true;
+        };
+        self.attempts += 1;
+        self.due = Instant::now() + self.delay;
+        if self.attempts < retries.attempts {
+            emit!(warn, client = self.client, attempt = self.attempts, error = %_err, "chargeback notification failed");
+            self.delay = self.delay.saturating_mul(2);
+        } else {
+            emit!(error, client = self.client, attempt = self.attempts, error = %_err, "chargeback notification failing");
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+
+    use super::Webhook;
+    use crate::{PaymentEngine, ProcessOptions};
+
+    /// Accept the requests on the `listener`, responding with the `statuses` in
+    /// turn, and return the bodies of the requests.
+    fn respond(listener: TcpListener, statuses: &[u16]) -> Vec<String> {
+        respond_with(listener, statuses, None)
+    }
+
+    /// Same as [`respond`], passing each body to the `received` before responding.
+    fn respond_with(
+        listener: TcpListener,
+        statuses: &[u16],
+        received: Option<Sender<String>>,
+    ) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for status in statuses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    length = value.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            if let Some(received) = &received {
+                received.send(body.clone()).unwrap();
+            }
+            bodies.push(body);
+            let response = format!(
+                "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+        bodies
+    }
+
+    #[test]
+    fn notifies_of_accounts_locked_by_chargebacks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/hooks/chargebacks",
+            listener.local_addr().unwrap()
+        );
+        let server = std::thread::spawn(move || respond(listener, &[500, 200, 200]));
+
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(Webhook::with_retries(url, 3, Duration::from_millis(10)));
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 1, 10.0\n\
+            deposit, 2, 2, 5.0\n\
+            dispute, 1, 1,\n\
+            chargeback, 1, 1,\n\
+            dispute, 2, 2,\n\
+            resolve, 2, 2,\n\
+            dispute, 2, 2,\n\
+            chargeback, 2, 2,\n";
+        engine.process(input.as_bytes()).unwrap();
+
+        // the first notification is sent again after the server error, while
+        // the second one may be sent in the meantime
+        let mut bodies = server.join().unwrap();
+        bodies.sort();
+        let expected = |client, tx| {
+            format!(
+                r#"{{"event":"chargeback","client":{client},"currency":"","tx":{tx},"amount":null,"available":"0.0000","held":"0.0000","total":"0.0000","locked":true}}"#
+            )
+        };
+        assert_eq!(bodies, [expected(1, 1), expected(1, 1), expected(2, 2)]);
+    }
+
+    /// Address of the `listener` as the URL of the webhook.
+    fn url(listener: &TcpListener) -> String {
+        format!("http://{}/hooks", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn sends_other_notifications_while_retrying() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&listener);
+        let server = std::thread::spawn(move || respond(listener, &[500, 200, 200]));
+
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(Webhook::with_retries(url, 3, Duration::from_millis(500)));
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 1, 10.0\n\
+            deposit, 2, 2, 5.0\n\
+            dispute, 1, 1,\n\
+            chargeback, 1, 1,\n\
+            dispute, 2, 2,\n\
+            chargeback, 2, 2,\n";
+        engine.process(input.as_bytes()).unwrap();
+
+        // the second notification is not held up by the first one failing
+        let clients: Vec<_> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|body| body[..body.find(",\"currency\"").unwrap()].to_string())
+            .collect();
+        let client = |client| format!(r#"{{"event":"chargeback","client":{client}"#);
+        assert_eq!(clients, [client(1), client(2), client(1)]);
+    }
+
+    #[test]
+    fn notifies_of_transfers_charged_back_at_precision() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&listener);
+        let server = std::thread::spawn(move || respond(listener, &[200]));
+
+        let options = ProcessOptions::new().precision(2).unwrap();
+        let mut engine = PaymentEngine::with_options(options);
+        engine.add_observer(Webhook::new(url).precision(2));
+        let input = "type, client, tx, amount, to_client\n\
+            deposit, 2, 1, 10.5,\n\
+            transfer, 2, 2, 4.25, 1\n\
+            dispute, 2, 2, 4.0,\n\
+            chargeback, 2, 2, ,\n";
+        engine.process(input.as_bytes()).unwrap();
+
+        // it is the sender that gets locked, while the recipient gives up the funds
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                r#"{"event":"chargeback","client":2,"currency":"","tx":2,"amount":null,"available":"10.25","held":"0.00","total":"10.25","locked":true}"#
+            ]
+        );
+        assert!(!engine.account(1).unwrap().locked);
+    }
+
+    #[test]
+    fn sends_pending_notifications_once_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&listener);
+        let (received, bodies) = mpsc::channel();
+        let server =
+            std::thread::spawn(move || respond_with(listener, &[500, 200], Some(received)));
+
+        let mut engine = PaymentEngine::new();
+        engine.add_observer(Webhook::with_retries(url, 3, Duration::from_millis(200)));
+        let input = "type, client, tx, amount\n\
+            deposit, 1, 1, 10.0\n\
+            dispute, 1, 1,\n\
+            chargeback, 1, 1,\n";
+        engine.process(input.as_bytes()).unwrap();
+
+        // dropping the engine waits for the notification to be sent again
+        drop(engine);
+        assert_eq!(bodies.try_iter().count(), 2);
+        server.join().unwrap();
+    }
+}
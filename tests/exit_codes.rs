@@ -0,0 +1,559 @@
+use std::io::Write;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+fn run(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_payment-engine"))
+        .args(args)
+        .output()
+        .expect("binary to be launched")
+        .status
+        .code()
+}
+
+fn run_with_stdin(args: &[&str], input: &str) -> (Option<i32>, String) {
+    let (code, stdout, _) = run_with_output(args, input);
+    (code, stdout)
+}
+
+fn run_with_output(args: &[&str], input: &str) -> (Option<i32>, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_payment-engine"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary to be launched");
+    let mut stdin = child.stdin.take().unwrap();
+    // the binary may exit without reading the input, e.g. on a usage error
+    if let Err(err) = stdin.write_all(input.as_bytes()) {
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.code(), stdout, stderr)
+}
+
+/// File in the temporary directory, removed once dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Path of the file of the `name` (unique to the test run) without creating
+    /// it, e.g. for the binary to write to.
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        TempFile(path)
+    }
+}
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn file_with(name: &str, contents: &str) -> TempFile {
+    let file = TempFile::new(name);
+    std::fs::write(&file.0, contents).unwrap();
+    file
+}
+
+#[test]
+fn succeeds_on_valid_input() {
+    let path = file_with("valid.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    assert_eq!(run(&[path.to_str().unwrap()]), Some(0));
+}
+
+#[test]
+fn fails_on_unexpected_argument() {
+    assert_eq!(run(&["--unknown"]), Some(1));
+}
+
+#[test]
+fn fails_on_missing_file() {
+    assert_eq!(run(&["definitely-missing-transactions.csv"]), Some(2));
+}
+
+#[test]
+fn fails_on_malformed_file() {
+    let path = file_with("malformed.csv", "type,client,tx,amount\nblocking,1,1,1.0\n");
+    assert_eq!(run(&[path.to_str().unwrap()]), Some(3));
+}
+
+#[test]
+fn fails_on_rejected_record_in_strict_mode() {
+    let path = file_with(
+        "dangling.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,2,\n",
+    );
+    assert_eq!(run(&[path.to_str().unwrap()]), Some(0));
+    assert_eq!(run(&["--strict", path.to_str().unwrap()]), Some(4));
+}
+
+#[test]
+fn resumes_from_checkpoint() {
+    let path = file_with("resumed.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    let checkpoint = TempFile::new("resumed.checkpoint");
+    let (path, checkpoint) = (path.to_str().unwrap(), checkpoint.to_str().unwrap());
+    assert_eq!(run(&["--resume", path]), Some(1));
+    assert_eq!(
+        run(&["--checkpoint", checkpoint, "--resume", path]),
+        Some(2)
+    );
+    assert_eq!(run(&["--checkpoint", checkpoint, path]), Some(0));
+    assert_eq!(
+        run(&["--checkpoint", checkpoint, "--resume", path]),
+        Some(0)
+    );
+    assert_eq!(run(&["--checkpoint-every", "0", path]), Some(1));
+}
+
+#[test]
+fn fails_on_balance_violation() {
+    let path = file_with("violated.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    let checkpoint = TempFile::new("violated.checkpoint");
+    let (path, checkpoint) = (path.to_str().unwrap(), checkpoint.to_str().unwrap());
+    assert_eq!(run(&["--checkpoint", checkpoint, path]), Some(0));
+    assert_eq!(run(&["--validate-balances", path]), Some(0));
+
+    // the total funds of the account restored from the tampered checkpoint are
+    // not its available plus held ones
+    let contents = std::fs::read_to_string(checkpoint).unwrap();
+    let tampered = contents.replace(",1.0000,0.0000,1.0000,", ",1.0000,0.0000,2.0000,");
+    assert_ne!(tampered, contents);
+    std::fs::write(checkpoint, tampered).unwrap();
+    let resumed = ["--checkpoint", checkpoint, "--resume", path];
+    assert_eq!(run(&resumed), Some(0));
+    let (code, stdout, stderr) =
+        run_with_output(&[&resumed[..], &["--validate-balances"]].concat(), "");
+    assert_eq!(code, Some(4));
+    assert!(stdout.is_empty());
+    assert!(stderr.contains("Balance violation: client 1: total 2.0000"));
+}
+
+#[test]
+fn reads_configured_csv_dialect() {
+    let path = file_with("dialect.csv", "# deposits\ndeposit;1;1;1.0\n");
+    let path = path.to_str().unwrap();
+    assert_eq!(run(&[path]), Some(3));
+    let args = ["--delimiter", ";", "--comment", "#", "--no-headers", path];
+    assert_eq!(run(&args), Some(0));
+    assert_eq!(run(&["--delimiter", ";;", path]), Some(1));
+}
+
+#[test]
+fn processes_multiple_files() {
+    let first = file_with(
+        "day-1.csv",
+        "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,100\n",
+    );
+    let second = file_with(
+        "day-2.csv",
+        "type,client,tx,amount,timestamp\nwithdrawal,1,2,1.0,200\n",
+    );
+    let untimed = file_with("untimed.csv", "type,client,tx,amount\ndeposit,1,3,1.0\n");
+    let pattern = std::env::temp_dir().join(format!("{}-day-*.csv", std::process::id()));
+    let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+    assert_eq!(run(&[first, second]), Some(0));
+    assert_eq!(run(&[pattern.to_str().unwrap()]), Some(0));
+    assert_eq!(run(&["--merge-by-timestamp", first, second]), Some(0));
+    let untimed = untimed.to_str().unwrap();
+    assert_eq!(run(&["--merge-by-timestamp", first, untimed]), Some(3));
+    assert_eq!(run(&["definitely-missing-*.csv"]), Some(2));
+    assert_eq!(
+        run(&["--checkpoint", "checkpoint.csv", first, second]),
+        Some(1)
+    );
+}
+
+#[test]
+fn writes_rejected_records() {
+    let path = file_with(
+        "rejected.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\n",
+    );
+    let rejects = TempFile::new("rejects.csv");
+    let (path, rejects) = (path.to_str().unwrap(), rejects.to_str().unwrap());
+    assert_eq!(run(&["--rejects-file", rejects, path]), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(rejects).unwrap(),
+        "type,client,tx,amount,currency,to_client,timestamp,reason\n\
+         withdrawal,1,2,5.0000,,,,insufficient funds\n"
+    );
+}
+
+#[test]
+fn writes_audit_log() {
+    let path = file_with(
+        "audited.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\nwithdrawal,1,3,0.5\n",
+    );
+    let audit = TempFile::new("audit.csv");
+    let (path, audit) = (path.to_str().unwrap(), audit.to_str().unwrap());
+    assert_eq!(run(&["--audit-log", audit, path]), Some(0));
+    let written = std::fs::read_to_string(audit).unwrap();
+    let lines: Vec<_> = written.lines().skip(1).collect();
+    assert_eq!(
+        lines,
+        [
+            "1,deposit,1,1.0000,1,,0.0000,0.0000,0.0000,1.0000,0.0000,1.0000,false,false",
+            "3,withdrawal,3,0.5000,1,,1.0000,0.0000,1.0000,0.5000,0.0000,0.5000,false,false",
+        ]
+    );
+}
+
+#[test]
+fn runs_subcommands() {
+    let path = file_with(
+        "subcommands.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\n",
+    );
+    let output = TempFile::new("accounts.json");
+    let (path, output) = (path.to_str().unwrap(), output.to_str().unwrap());
+    let args = ["process", "--output", output, "--format", "json", path];
+    assert_eq!(run(&args), Some(0));
+    assert!(
+        std::fs::read_to_string(output)
+            .unwrap()
+            .starts_with(r#"[{"client":1,"#)
+    );
+    assert_eq!(run(&["process", "--format", "yaml", path]), Some(1));
+    assert_eq!(run(&["validate", path]), Some(0));
+    assert_eq!(
+        run(&["validate", "definitely-missing-transactions.csv"]),
+        Some(2)
+    );
+    // the log is in the input format
+    assert_eq!(run(&["replay", "--output", output, path]), Some(0));
+    assert!(
+        std::fs::read_to_string(output)
+            .unwrap()
+            .starts_with("client,")
+    );
+}
+
+#[test]
+fn reads_standard_input() {
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let expected = "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
+    assert_eq!(run_with_stdin(&[], input), (Some(0), expected.to_string()));
+    assert_eq!(
+        run_with_stdin(&["-"], input),
+        (Some(0), expected.to_string())
+    );
+    let (code, _) = run_with_stdin(&["--checkpoint", "checkpoint.csv", "-"], input);
+    assert_eq!(code, Some(1));
+}
+
+#[test]
+fn keeps_previous_output_on_failure() {
+    let path = file_with(
+        "partial.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\nwhat,1,2,\n",
+    );
+    let output = file_with("previous-accounts.csv", "previous");
+    let (path, output) = (path.to_str().unwrap(), output.to_str().unwrap());
+    assert_eq!(run(&["--output", output, path]), Some(3));
+    assert_eq!(std::fs::read_to_string(output).unwrap(), "previous");
+}
+
+#[test]
+fn validates_without_writing_accounts() {
+    let path = file_with(
+        "invalid.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,1,2.0\ndispute,1,2,\n",
+    );
+    let path = path.to_str().unwrap();
+    let (code, report) = run_with_stdin(&["validate", path], "");
+    assert_eq!(code, Some(4));
+    assert_eq!(
+        report,
+        "line 3: record of client 1 skipped: duplicate transaction\n\
+         line 4: record of client 1 skipped: transaction does not exist\n\
+         3 records, 2 issues\n"
+    );
+    let (code, report) = run_with_stdin(&["validate", "--format", "json", path], "");
+    assert_eq!(code, Some(4));
+    assert!(report.contains(r#""kind": "duplicate_transaction""#));
+    let path = file_with("unparsed.csv", "type,client,tx,amount\nblocking,1,1,1.0\n");
+    let (code, _) = run_with_stdin(&["validate", path.to_str().unwrap()], "");
+    assert_eq!(code, Some(3));
+}
+
+#[test]
+fn reads_configuration_file() {
+    let path = file_with(
+        "configured.csv",
+        "type;client;tx;amount\ndeposit;1;1;1.0\ndispute;1;2;\n",
+    );
+    let config = file_with("payment-engine.toml", "delimiter = \";\"\nstrict = true\n");
+    let (path, config) = (path.to_str().unwrap(), config.to_str().unwrap());
+    assert_eq!(run(&[path]), Some(3));
+    assert_eq!(run(&["--config", config, path]), Some(4));
+    assert_eq!(run(&["--config", config, "--strict=false", path]), Some(0));
+    assert_eq!(run(&["validate", "--config", config, path]), Some(4));
+    let invalid = file_with("invalid.toml", "delimiter = \";;\"\n");
+    assert_eq!(run(&["--config", invalid.to_str().unwrap(), path]), Some(1));
+    assert_eq!(run(&["--config", "definitely-missing.toml", path]), Some(2));
+}
+
+#[test]
+fn summarizes_dry_run() {
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,1.0\n\
+                 deposit,2,2,2.5\n\
+                 dispute,2,2,\n\
+                 deposit,3,3,1.0\n\
+                 dispute,3,3,\n\
+                 chargeback,3,3,\n";
+    let (code, stdout, stderr) = run_with_output(&["--dry-run"], input);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "records read: 6\n\
+         accounts touched: 3\n\
+         locked accounts: 1\n\
+         total held: 2.5000\n"
+    );
+    assert_eq!(run(&["--dry-run", "--output", "accounts.csv"]), Some(1));
+}
+
+#[test]
+fn shows_progress_on_standard_error() {
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let expected = "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
+    assert_eq!(
+        run_with_stdin(&["--progress"], input),
+        (Some(0), expected.to_string())
+    );
+}
+
+#[test]
+fn logs_progress_unless_on_terminal() {
+    let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let (code, _, stderr) = run_with_output(&["--progress"], input);
+    assert_eq!(code, Some(0));
+    assert!(stderr.starts_with("Read 38 bytes, 1 records ("), "{stderr}");
+
+    let first = file_with(
+        "progress-1.csv",
+        "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,100\n",
+    );
+    let second = file_with(
+        "progress-2.csv",
+        "type,client,tx,amount,timestamp\ndeposit,1,2,1.0,50\n",
+    );
+    let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+    let args = ["--progress", "--merge-by-timestamp", first, second];
+    let (code, _, stderr) = run_with_output(&args, "");
+    assert_eq!(code, Some(0));
+    assert!(
+        stderr.starts_with("Read 103 bytes, 2 records ("),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn skips_malformed_records_if_lenient() {
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,1.0\n\
+                 blocking,1,2,1.0\n\
+                 deposit,1,3,0.5\n";
+    assert_eq!(run_with_stdin(&[], input).0, Some(3));
+    let (code, stdout, stderr) = run_with_output(&["--lenient"], input);
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+    );
+    assert_eq!(
+        stderr,
+        "Skipped malformed record: line 3: unknown type \"blocking\" in record \"blocking,1,2,1.0\"\n\
+         1 malformed records skipped\n"
+    );
+}
+
+#[test]
+fn prints_client_history() {
+    let input = "type,client,tx,amount,to_client\n\
+                 deposit,1,1,10.0,\n\
+                 transfer,1,2,4.0,2\n\
+                 dispute,1,1,,\n";
+    let (code, _, stderr) = run_with_output(&["--history", "1"], input);
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stderr,
+        "History of client 1:\n  \
+         deposit tx 1: 10.0000, disputed\n  \
+         transfer tx 2: 4.0000 to client 2, undisputed\n"
+    );
+    let (_, _, stderr) = run_with_output(&["--history", "2"], input);
+    assert_eq!(
+        stderr,
+        "History of client 2:\n  transfer tx 2: 4.0000 from client 1, undisputed\n"
+    );
+}
+
+#[test]
+fn writes_client_statement() {
+    let input = "type,client,tx,amount,timestamp\n\
+                 deposit,1,1,10.0,100\n\
+                 withdrawal,1,2,4.0,200\n";
+    let args = [
+        "statement",
+        "--client",
+        "1",
+        "--from",
+        "150",
+        "--format",
+        "csv",
+    ];
+    let (code, stdout, _) = run_with_output(&args, input);
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stdout,
+        "tx,type,timestamp,currency,counterparty,amount,balance,state\n\
+         2,withdrawal,200,,,-4.0000,6.0000,undisputed\n"
+    );
+    assert_eq!(run_with_stdin(&["statement"], input).0, Some(1));
+}
+
+#[test]
+fn reconciles_with_expected_balances() {
+    let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n";
+    let expected = file_with(
+        "expected.csv",
+        "client,available,held,total,locked,closed\n\
+         1,10.0,0.0,10.0,false,false\n\
+         2,5.0,0.0,5.0,false,false\n",
+    );
+    let expected = expected.to_str().unwrap();
+    let args = ["reconcile", "--expected", expected];
+    let (code, stdout, _) = run_with_output(&args, input);
+    assert_eq!((code, stdout.as_str()), (Some(0), "0 discrepancies\n"));
+    let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,4.5\n";
+    let (code, stdout, _) = run_with_output(&args, input);
+    assert_eq!(code, Some(4));
+    assert_eq!(
+        stdout,
+        "client 2: available 4.5000 instead of 5.0000 (delta -0.5000)\n\
+         client 2: total 4.5000 instead of 5.0000 (delta -0.5000)\n\
+         2 discrepancies\n"
+    );
+}
+
+#[test]
+fn reconciles_at_configured_precision() {
+    let input = "type,client,tx,amount\ndeposit,1,1,10.5\n";
+    let expected = file_with(
+        "expected-cents.csv",
+        "client,available,held,total,locked\n1,9.00,0.00,9.00,false\n",
+    );
+    let config = file_with("cents.toml", "precision = 2\n");
+    let (expected, config) = (expected.to_str().unwrap(), config.to_str().unwrap());
+    let args = ["reconcile", "--config", config, "--expected", expected];
+    let (code, stdout, _) = run_with_output(&args, input);
+    assert_eq!(code, Some(4));
+    assert_eq!(
+        stdout,
+        "client 1: available 10.50 instead of 9.00 (delta 1.50)\n\
+         client 1: total 10.50 instead of 9.00 (delta 1.50)\n\
+         2 discrepancies\n"
+    );
+}
+
+#[test]
+fn reports_discrepancies_in_json_at_configured_precision() {
+    let input = "type,client,tx,amount\ndeposit,1,1,10.5\n";
+    let expected = file_with(
+        "expected-cents-json.csv",
+        "client,available,held,total,locked\n1,10.50,0.00,9.00,false\n",
+    );
+    let config = file_with("cents-json.toml", "precision = 2\n");
+    let (expected, config) = (expected.to_str().unwrap(), config.to_str().unwrap());
+    let args = [
+        "reconcile",
+        "--config",
+        config,
+        "--expected",
+        expected,
+        "--format",
+        "json",
+    ];
+    let (code, stdout, _) = run_with_output(&args, input);
+    assert_eq!(code, Some(4));
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!([{
+            "kind": "funds",
+            "client": 1,
+            "currency": "",
+            "funds": "total",
+            "expected": "9.00",
+            "actual": "10.50",
+        }])
+    );
+}
+
+#[test]
+fn diffs_account_files() {
+    let old = file_with(
+        "old-accounts.csv",
+        "client,available,held,total,locked,closed\n1,1.5000,0.0000,1.5000,false,false\n2,2.0,0.0,2.0,false,false\n",
+    );
+    let new = file_with(
+        "new-accounts.csv",
+        "client,available,held,total,locked,closed\n2,2.0000,0.0000,2.0000,false,false\n1,1.5000,0.0000,1.5000,false,false\n",
+    );
+    let changed = file_with(
+        "changed-accounts.csv",
+        "client,available,held,total,locked,closed\n1,1.5,0.0,1.5,true,false\n",
+    );
+    let (old, new, changed) = (
+        old.to_str().unwrap(),
+        new.to_str().unwrap(),
+        changed.to_str().unwrap(),
+    );
+    let (code, stdout, _) = run_with_output(&["accounts-diff", old, new], "");
+    assert_eq!((code, stdout.as_str()), (Some(0), "0 discrepancies\n"));
+    let (code, stdout, _) = run_with_output(&["accounts-diff", old, changed], "");
+    assert_eq!(code, Some(4));
+    assert_eq!(
+        stdout,
+        "client 1: locked instead of unlocked\nclient 2: account missing\n2 discrepancies\n"
+    );
+    assert_eq!(
+        run(&["accounts-diff", old, "missing-accounts.csv"]),
+        Some(2)
+    );
+}
+
+#[test]
+fn rejects_account_files_listing_an_account_twice() {
+    let accounts = file_with(
+        "accounts.csv",
+        "client,available,held,total,locked,closed\n1,1.5,0.0,1.5,false,false\n",
+    );
+    let twice = file_with(
+        "accounts-twice.csv",
+        "client,available,held,total,locked,closed\n1,1.5,0.0,1.5,false,false\n1,2.5,0.0,2.5,false,false\n",
+    );
+    let (accounts, twice) = (accounts.to_str().unwrap(), twice.to_str().unwrap());
+    let (code, stdout, stderr) = run_with_output(&["accounts-diff", accounts, twice], "");
+    assert_eq!((code, stdout.as_str()), (Some(3), ""));
+    assert!(stderr.contains("duplicate account of client 1"), "{stderr}");
+}